@@ -28,6 +28,8 @@ CREATE TABLE market_models(
 	model_values_id INT UNSIGNED NOT NULL,
 	market VARCHAR(255) NOT NULL,
 	target_leverage FLOAT NOT NULL,
+	-- Serialized ExecutionAlgo, NULL sends orders directly.
+	execution BLOB,
 	FOREIGN KEY(exchange_id) REFERENCES exchanges(id),
 	FOREIGN KEY(model_values_id) REFERENCES models_values(id),
 	PRIMARY KEY (id, exchange_id, model_values_id, market)
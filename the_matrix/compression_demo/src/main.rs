@@ -944,9 +944,7 @@ fn main() -> Result<()> {
     //    wtf();
 
     panic!();
-    unsafe {
-        config::load("/home/stock/ssd/projects/the_matrix/the_matrix/config.yaml").unwrap();
-    }
+    config::load("/home/stock/ssd/projects/the_matrix/the_matrix/config.yaml").unwrap();
     plot()?;
     panic!();
     let rt = tokio::runtime::Runtime::new().unwrap();
@@ -0,0 +1,178 @@
+//! Semantic difference between two configs of one exchange, it tells a running agent what has to
+//! be changed instead of which lines of a file have changed.
+use crate::{ExchangeConfig, RiskConfig};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    /// Index of the model in the new config.
+    ModelAdded(usize),
+    /// Index of the model in the old config.
+    ModelRemoved(usize),
+    /// `model` is index in the old config.
+    TargetLeverage {
+        model: usize,
+        leverage: f32,
+    },
+    VariableValues {
+        model: usize,
+        values: Vec<f32>,
+    },
+    Execution {
+        model: usize,
+    },
+    MaxLeverage(f32),
+    MaxOrdersPerM(f32),
+    Risk(RiskConfig),
+    /// Field of exchange config that isn't compared in detail.
+    Field(&'static str),
+}
+
+/// Changes that turn `old` into `new`. Models are matched by name and market, several models with
+/// the same name and market are matched in order. Changed order of models isn't a change.
+pub fn diff_exchange(old: &ExchangeConfig, new: &ExchangeConfig) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+    for (field, changed) in [
+        ("name", old.name != new.name),
+        ("exchange", old.exchange != new.exchange),
+        ("use_testnet", old.use_testnet != new.use_testnet),
+        (
+            "use_public_data_miner",
+            old.use_public_data_miner != new.use_public_data_miner,
+        ),
+        ("api_key", old.api_key != new.api_key),
+        ("api_secret", old.api_secret != new.api_secret),
+        ("selected", old.selected != new.selected),
+        ("reconciliation", old.reconciliation != new.reconciliation),
+        ("paper", old.paper != new.paper),
+        ("admin", old.admin != new.admin),
+        ("alerts", old.alerts != new.alerts),
+        ("metrics", old.metrics != new.metrics),
+        ("vcr", old.vcr != new.vcr),
+    ] {
+        if changed {
+            changes.push(ConfigChange::Field(field));
+        }
+    }
+    if old.max_leverage != new.max_leverage {
+        changes.push(ConfigChange::MaxLeverage(new.max_leverage));
+    }
+    if old.max_orders_per_m != new.max_orders_per_m {
+        changes.push(ConfigChange::MaxOrdersPerM(new.max_orders_per_m));
+    }
+    if old.risk != new.risk {
+        changes.push(ConfigChange::Risk(new.risk.clone()));
+    }
+
+    let mut unmatched: Vec<_> = (0..old.models.len()).map(Some).collect();
+    for (new_index, new_model) in new.models.iter().enumerate() {
+        let matched = unmatched.iter_mut().find(|x| {
+            x.map_or(false, |i| {
+                old.models[i].name == new_model.name && old.models[i].market == new_model.market
+            })
+        });
+        let old_index = match matched.and_then(|x| x.take()) {
+            Some(i) => i,
+            None => {
+                changes.push(ConfigChange::ModelAdded(new_index));
+                continue;
+            }
+        };
+        let old_model = &old.models[old_index];
+        if old_model.target_leverage != new_model.target_leverage {
+            changes.push(ConfigChange::TargetLeverage {
+                model: old_index,
+                leverage: new_model.target_leverage,
+            });
+        }
+        if old_model.variable_values != new_model.variable_values {
+            changes.push(ConfigChange::VariableValues {
+                model: old_index,
+                values: new_model.variable_values.clone(),
+            });
+        }
+        if old_model.execution != new_model.execution {
+            changes.push(ConfigChange::Execution { model: old_index });
+        }
+    }
+    changes.extend(
+        unmatched
+            .into_iter()
+            .flatten()
+            .map(ConfigChange::ModelRemoved),
+    );
+    changes
+}
+
+#[cfg(test)]
+mod t_diff {
+    use super::*;
+    use crate::{ModelConfig, Secret};
+    use merovingian::order::ExecutionAlgo;
+
+    fn model(name: &str, market: &str, target_leverage: f32) -> ModelConfig {
+        ModelConfig {
+            name: name.into(),
+            market: market.into(),
+            target_leverage,
+            variable_values: vec![60., 14.],
+            ..Default::default()
+        }
+    }
+
+    fn config() -> ExchangeConfig {
+        ExchangeConfig {
+            name: "BitMEX".into(),
+            max_leverage: 2.,
+            max_orders_per_m: 60.,
+            models: vec![
+                model("rsi", "XBTUSD", 1.),
+                model("rsi", "XBTUSD", 0.5),
+                model("macd", "ETHUSD", 1.),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn t_unchanged() {
+        let mut new = config();
+        new.models.swap(1, 2);
+        assert_eq!(diff_exchange(&config(), &new), vec![]);
+    }
+
+    #[test]
+    fn t_changes() {
+        let mut new = config();
+        new.api_key = Secret::new("other");
+        new.max_leverage = 3.;
+        new.risk.max_daily_drawdown = Some(0.1);
+        new.models[1].target_leverage = 0.25;
+        new.models[1].variable_values = vec![30., 14.];
+        new.models[2].execution = ExecutionAlgo::Twap {
+            duration_s: 60,
+            slices: 2,
+        };
+        new.models.remove(0);
+        new.models.push(model("macd", "XBTUSD", 1.));
+        assert_eq!(
+            diff_exchange(&config(), &new),
+            vec![
+                ConfigChange::Field("api_key"),
+                ConfigChange::MaxLeverage(3.),
+                ConfigChange::Risk(new.risk.clone()),
+                // the first remaining rsi is matched with the first old one
+                ConfigChange::TargetLeverage {
+                    model: 0,
+                    leverage: 0.25
+                },
+                ConfigChange::VariableValues {
+                    model: 0,
+                    values: vec![30., 14.]
+                },
+                ConfigChange::Execution { model: 2 },
+                ConfigChange::ModelAdded(2),
+                ConfigChange::ModelRemoved(1),
+            ]
+        );
+    }
+}
@@ -1,47 +1,74 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::{env, fmt};
 
 use chrono::DateTime;
+use merovingian::order::ExecutionAlgo;
 use merovingian::variable::Variable;
-use mouse::error::{Result, ResultCtxExt};
+use mouse::error::{bail, Result, ResultCtxExt};
 use mouse::log::*;
-use mouse::macros::futures_util::FutureExt;
 use mouse::time::Timestamp;
-use serde::de::{DeserializeOwned, MapAccess, Visitor};
+use once_cell::sync::OnceCell;
+use serde::de::{MapAccess, Visitor};
 use serde::{de, Deserialize, Deserializer, Serialize};
 
-lazy_static::lazy_static! {
-    pub static ref CONFIG: SuperConfig = Default::default();
+pub use diff::{diff_exchange, ConfigChange};
+pub use loader::ConfigLoader;
+pub use secrets::{resolve_secrets, Secret, Vault, VaultConfig, VaultKey};
+pub use validation::{validate, validate_exchange, ConfigErrors, Schema};
+pub use watcher::ConfigWatcher;
+
+mod diff;
+mod loader;
+mod secrets;
+mod validation;
+mod watcher;
+
+static LOADED: OnceCell<SuperConfig> = OnceCell::new();
+static LOADER: OnceCell<ConfigLoader> = OnceCell::new();
+static SELECTED_EXCHANGE: OnceCell<String> = OnceCell::new();
+
+/// Config of this process, it is default until `load` or `init` is called and never changes after
+/// it is first used.
+pub static CONFIG: Config = Config(());
+
+pub struct Config(());
+
+impl Deref for Config {
+    type Target = SuperConfig;
+
+    fn deref(&self) -> &SuperConfig {
+        LOADED.get_or_init(Default::default)
+    }
 }
 
-/// Safety: Race condition possible.
-pub unsafe fn load_static<P, C, S>(_path: P, _static_config: &S) -> Result<()>
-where
-    P: AsRef<Path> + Send + Sync,
-    C: DeserializeOwned,
-{
+/// Loads config from `path` with overlay of this host and overrides from environment, see
+/// `ConfigLoader`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<()> {
+    load_with(ConfigLoader::new(path))
+}
+
+/// Loads and installs config, `watcher` reloads it with the same `loader`.
+pub fn load_with(loader: ConfigLoader) -> Result<()> {
+    init(loader.load()?)?;
+    let _ = LOADER.set(loader);
     Ok(())
 }
 
-/// todo: load this on current thread runtime then spawn_blocking other thread in main this will
-/// reduce compile times, it will also ensure that runtime would not deadlock
-pub unsafe fn load<P: AsRef<Path> + Send + Sync>(path: P) -> Result<()> {
+/// Watches files of config that has been installed by `load` or `load_with`. `CONFIG` never
+/// changes, reloaded config is only returned by the watcher.
+pub fn watcher() -> Option<ConfigWatcher> {
+    LOADER.get().map(|x| ConfigWatcher::new(x.clone()))
+}
+
+/// Installs validated config, fails if config has already been loaded or used.
+pub fn init(config: SuperConfig) -> Result<()> {
     env::set_var("RUST_BACKTRACE", "full");
-    // initialize tokio handle so that it can be shared between threads later
-    //    mouse::ext::set_tokio_handle(tokio::runtime::Handle::current());
-    //    set_tokio_handle();
-    //    std::future::ready(()).block();
-    //    Ok(
-    //        *(&*CONFIG as *const _ as *mut SuperConfig) = serde_yaml::from_reader(
-    //            File::open(path.as_ref())
-    //                .with_context(|| path.as_ref().to_str().unwrap().to_string())?,
-    //        )?,
-    //    )
-    let path = path.as_ref();
-    // NOTE: adding anyhow::context here crashes when debugging
-    let file = File::open(path)?; //.with_context(|| format!("{}", path.display()))?;
-    *(&*CONFIG as *const _ as *mut SuperConfig) = serde_yaml::from_reader(file)?;
+    if LOADED.set(config).is_err() {
+        bail!("Config has already been loaded or used before it was loaded.");
+    }
     Ok(())
 }
 
@@ -58,20 +85,51 @@ pub fn data_file_path(
     path
 }
 
-pub fn select_exchange(exchange_name: &str) {
-    unsafe {
-        let exchanges = &mut *(&CONFIG.exchanges as *const _ as *mut Vec<ExchangeConfig>);
-        for config in exchanges.iter_mut() {
-            if config.name == exchange_name {
-                config.selected = Some(());
-                break;
-            }
-        }
+/// Selects exchange that is returned by `get_exchange_config`, it can be selected only once.
+pub fn select_exchange(exchange_name: &str) -> Result<()> {
+    let selected = SELECTED_EXCHANGE.get_or_init(|| exchange_name.to_string());
+    if selected != exchange_name {
+        bail!(
+            "Cannot select exchange {}, {} is already selected.",
+            exchange_name,
+            selected
+        );
+    }
+    Ok(())
+}
+
+tokio::task_local! {
+    static TASK_EXCHANGE: String;
+}
+
+/// Runs `future` with `get_exchange_config` returning config of `exchange_name` instead of the one
+/// selected by `select_exchange`, so that several exchanges can be run in one process. `None`
+/// keeps the current selection. Tasks spawned by `future` don't inherit it.
+pub async fn with_exchange<F: Future>(exchange_name: Option<String>, future: F) -> F::Output {
+    match exchange_name {
+        Some(name) => TASK_EXCHANGE.scope(name, future).await,
+        None => future.await,
     }
 }
 
+/// Name of the exchange that `get_exchange_config` returns config of, also if it isn't configured.
+pub fn exchange_name() -> Option<String> {
+    if let Ok(name) = TASK_EXCHANGE.try_with(|x| x.clone()) {
+        return Some(name);
+    }
+    if let Some(name) = SELECTED_EXCHANGE.get() {
+        return Some(name.clone());
+    }
+    CONFIG
+        .exchanges
+        .iter()
+        .find(|x| x.selected.is_some())
+        .map(|x| x.name.clone())
+}
+
 pub fn get_exchange_config() -> Option<&'static ExchangeConfig> {
-    CONFIG.exchanges.iter().find(|x| x.selected.is_some())
+    let name = exchange_name()?;
+    CONFIG.exchanges.iter().find(|x| x.name == name)
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -87,8 +145,14 @@ pub struct SuperConfig {
     #[serde(deserialize_with = "deserialize_path_buf")]
     pub report_template_dir: PathBuf,
     pub exchanges: Vec<ExchangeConfig>,
+    /// Exchanges that are run together when matrix is started without an exchange.
+    #[serde(default)]
+    pub fleet: Option<FleetConfig>,
     //    pub construct: ConstructConfig,
     pub iaas: Option<Iaas>,
+    /// Vault that `secret://` references are resolved from.
+    #[serde(default)]
+    pub vault: Option<VaultConfig>,
     // No need to store log configs, so we use custom deserializer that configures logging.
     #[serde(deserialize_with = "deserialize_log_configs")]
     pub logs: (),
@@ -97,8 +161,8 @@ pub struct SuperConfig {
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Iaas {
     pub storage_account: String,
-    pub storage_key: String,
-    pub the_matrix_db_url: String,
+    pub storage_key: Secret,
+    pub the_matrix_db_url: Secret,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -127,25 +191,292 @@ impl ConstructModelConfig {
     }
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct ExchangeConfig {
+    /// Name of the exchange or of an account on it when `exchange` is set. Data of each name is
+    /// stored separately.
     pub name: String,
+    /// Exchange that is traded on when several accounts on one exchange are configured.
+    #[serde(default)]
+    pub exchange: Option<String>,
     pub use_testnet: bool,
     pub use_public_data_miner: bool,
-    pub api_key: String,
-    pub api_secret: String,
+    pub api_key: Secret,
+    pub api_secret: Secret,
     pub max_leverage: f32,
     pub max_orders_per_m: f32,
     pub models: Vec<ModelConfig>,
     pub selected: Option<()>,
+    #[serde(default)]
+    pub reconciliation: ReconciliationConfig,
+    #[serde(default)]
+    pub risk: RiskConfig,
+    /// Matches orders in process against live market data instead of sending them.
+    #[serde(default)]
+    pub paper: Option<PaperConfig>,
+    /// Local endpoint for inspecting and controlling a running agent, disabled if not set.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    /// Prometheus endpoint, disabled if not set.
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// Records or replays network traffic of the exchange, e.g. `vcr: {replay: session.json}`.
+    #[serde(default)]
+    pub vcr: Option<VcrConfig>,
+}
+
+impl ExchangeConfig {
+    pub fn exchange_name(&self) -> &str {
+        self.exchange.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Exchanges that are run by one process, each one is restarted on its own.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FleetConfig {
+    /// Names of exchange configs.
+    pub exchanges: Vec<String>,
+    /// Restarts of an exchange within `restart_window_s` after which it is left stopped.
+    pub max_restarts: u32,
+    pub restart_window_s: u32,
+    /// Seconds to wait before restarting an exchange that failed.
+    pub restart_delay_s: u32,
+    pub risk: PortfolioRiskConfig,
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        FleetConfig {
+            exchanges: Vec::new(),
+            max_restarts: 3,
+            restart_window_s: 60 * 60,
+            restart_delay_s: 10,
+            risk: Default::default(),
+        }
+    }
 }
 
+/// Limits over all exchanges of a fleet, checked in addition to limits of each exchange. Notional
+/// and balances of exchanges are summed as they are, so they should share a margin currency.
+/// Kill switch engaged on any exchange is engaged on all of them.
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortfolioRiskConfig {
+    pub max_notional: Option<f32>,
+    /// Max loss relative to the highest total balance since start, engages kill switch.
+    pub max_drawdown: Option<f32>,
+}
+
+/// How internal bookkeeping is compared against the exchange and what is done when it diverges.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconciliationConfig {
+    /// Seconds between reconciliations, 0 disables them.
+    pub interval_s: u32,
+    /// Cancel orders that are open on the exchange but aren't tracked by us.
+    pub cancel_phantom_orders: bool,
+    /// Forget tracked orders that are no longer open on the exchange when positions still match.
+    pub drop_missing_orders: bool,
+    /// Absolute position difference that is still considered equal.
+    pub position_tolerance: f32,
+}
+
+impl Default for ReconciliationConfig {
+    fn default() -> Self {
+        ReconciliationConfig {
+            interval_s: 300,
+            cancel_phantom_orders: true,
+            drop_missing_orders: true,
+            position_tolerance: 0.,
+        }
+    }
+}
+
+/// Pre-trade limits that are checked before orders are sent to an exchange, `None` disables a
+/// limit. Notional is in margin currency.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskConfig {
+    pub max_account_notional: Option<f32>,
+    /// Notional of all positions divided by balance.
+    pub max_account_leverage: Option<f32>,
+    pub max_model_notional: Option<f32>,
+    /// Notional of positions of a model divided by balance.
+    pub max_model_leverage: Option<f32>,
+    /// Max relative distance of a limit price from mark price, e.g. 0.05 for 5%.
+    pub max_price_deviation: Option<f32>,
+    pub max_orders_per_minute: Option<u32>,
+    /// Max loss relative to balance at the start of UTC day, e.g. 0.1 for 10%.
+    pub max_daily_drawdown: Option<f32>,
+    /// Max loss relative to the highest balance within `rolling_drawdown_window_s`.
+    pub max_rolling_drawdown: Option<f32>,
+    pub rolling_drawdown_window_s: u32,
+    /// Max absolute position of the account per market, in order amount.
+    pub max_position: HashMap<String, f32>,
+    /// Engage kill switch on drawdown instead of only blocking orders that increase positions.
+    pub kill_on_drawdown: bool,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        RiskConfig {
+            max_account_notional: None,
+            max_account_leverage: None,
+            max_model_notional: None,
+            max_model_leverage: None,
+            max_price_deviation: None,
+            max_orders_per_minute: None,
+            max_daily_drawdown: None,
+            max_rolling_drawdown: None,
+            rolling_drawdown_window_s: 60 * 60 * 24 * 7,
+            max_position: HashMap::new(),
+            kill_on_drawdown: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaperConfig {
+    /// Starting balance in margin currency.
+    pub balance: f32,
+}
+
+impl Default for PaperConfig {
+    fn default() -> Self {
+        PaperConfig { balance: 1. }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// Address of the websocket endpoint, should never be reachable from outside of the machine.
+    /// Every exchange needs its own, e.g. `127.0.0.1:7741`.
+    pub address: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Address of the http endpoint that serves metrics on `/metrics`.
+    pub address: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            address: "127.0.0.1:9741".into(),
+        }
+    }
+}
+
+/// Cassette that network traffic is recorded to or replayed from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VcrConfig {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    /// Needs a human now, never rate limited.
+    Critical,
+}
+
+impl Default for Severity {
+    fn default() -> Self {
+        Severity::Info
+    }
+}
+
+/// Where alerts are sent and when they are raised. Alerts are always logged, sinks are optional.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    pub webhooks: Vec<WebhookSinkConfig>,
+    pub smtp: Vec<SmtpSinkConfig>,
+    pub files: Vec<FileSinkConfig>,
+    pub commands: Vec<CommandSinkConfig>,
+    /// Alerts with the same key are sent once within this many seconds.
+    pub dedup_window_s: u32,
+    /// Max alerts sent by a sink within an hour.
+    pub max_per_hour: u32,
+    /// Reconnects within `reconnect_window_s` that raise a critical alert.
+    pub max_reconnects: u32,
+    pub reconnect_window_s: u32,
+    /// Fills with higher absolute value in margin currency raise an alert.
+    pub large_fill_value: Option<f32>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            webhooks: Vec::new(),
+            smtp: Vec::new(),
+            files: Vec::new(),
+            commands: Vec::new(),
+            dedup_window_s: 60 * 10,
+            max_per_hour: 20,
+            max_reconnects: 3,
+            reconnect_window_s: 60 * 10,
+            large_fill_value: None,
+        }
+    }
+}
+
+/// Posts alerts as JSON.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookSinkConfig {
+    pub url: String,
+    #[serde(default)]
+    pub min_severity: Severity,
+}
+
+/// Sends alerts through an SMTP relay that doesn't require TLS or authentication, e.g. local MTA.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SmtpSinkConfig {
+    /// Host and port of the relay.
+    pub address: String,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub min_severity: Severity,
+}
+
+/// Appends a line for each alert.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileSinkConfig {
+    #[serde(deserialize_with = "deserialize_path_buf")]
+    pub path: PathBuf,
+    #[serde(default)]
+    pub min_severity: Severity,
+}
+
+/// Runs `program` with `args` followed by title and message of an alert, e.g. `notify-send`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommandSinkConfig {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub min_severity: Severity,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct ModelConfig {
     pub name: String,
     pub market: String,
     pub target_leverage: f32,
     pub variable_values: Vec<f32>,
+    #[serde(default)]
+    pub execution: ExecutionAlgo,
 }
 
 #[cfg(test)]
@@ -156,6 +487,9 @@ where
     Ok(())
 }
 
+#[cfg(not(test))]
+static LOGGING: std::sync::Once = std::sync::Once::new();
+
 #[cfg(not(test))]
 fn deserialize_log_configs<'de, D>(deserializer: D) -> Result<(), D::Error>
 where
@@ -203,7 +537,8 @@ where
                     if default_conf {
                         println!("Warning: Using default log configuration.");
                     }
-                    c.configure().unwrap();
+                    // Logger can be set only once, reloaded configs keep the first one.
+                    LOGGING.call_once(|| c.configure().unwrap());
                 }
             }
             Ok(())
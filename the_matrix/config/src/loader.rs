@@ -0,0 +1,343 @@
+//! Builds config from layered sources, later layers override earlier ones:
+//! 1. base YAML file,
+//! 2. overlay of this host next to it, e.g. `config.<host>.yaml`,
+//! 3. environment variables, e.g. `MATRIX__EXCHANGES__0__MAX_LEVERAGE=3`,
+//! 4. overrides from command line, e.g. `exchanges.0.max_leverage=3`.
+//!
+//! Keys of environment variables are lowercased, maps with upper case keys can only be overridden
+//! from command line. Their values are parsed as YAML unless they set text, e.g. API keys, those
+//! are taken as they are.
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use mouse::error::{bail, Result, ResultCtxExt};
+use mouse::log::*;
+use serde_yaml::{Mapping, Value};
+
+use crate::secrets::resolve_secrets;
+use crate::validation::{validate, Schema};
+use crate::SuperConfig;
+
+const ENV_PREFIX: &str = "MATRIX__";
+const ENV_SEPARATOR: &str = "__";
+/// Fields that hold text, an API key `0123` or `yes` must not become a number or a bool.
+const TEXT_KEYS: &[&str] = &[
+    "api_key",
+    "api_secret",
+    "storage_key",
+    "storage_account",
+    "the_matrix_db_url",
+    "db",
+    "name",
+    "market",
+    "exchange",
+    "address",
+    "url",
+    "from",
+    "program",
+    "start_time",
+    "end_time",
+];
+
+#[derive(Clone)]
+pub struct ConfigLoader {
+    path: PathBuf,
+    host: Option<String>,
+    env_vars: Vec<(String, String)>,
+    overrides: Vec<String>,
+    schema: Schema,
+}
+
+impl ConfigLoader {
+    /// Uses overlay of this host and environment variables of this process.
+    pub fn new(path: impl AsRef<Path>) -> ConfigLoader {
+        ConfigLoader {
+            path: path.as_ref().to_path_buf(),
+            host: host_name(),
+            env_vars: env::vars().collect(),
+            overrides: Vec::new(),
+            schema: Schema::default(),
+        }
+    }
+
+    /// Host of the overlay, `None` disables it.
+    pub fn host(mut self, host: Option<String>) -> Self {
+        self.host = host;
+        self
+    }
+
+    pub fn env_vars(mut self, env_vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.env_vars = env_vars.into_iter().collect();
+        self
+    }
+
+    /// Overrides in form of `path.to.key=value`, value is parsed as YAML.
+    pub fn overrides(mut self, overrides: Vec<String>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Path of the overlay of this host, it doesn't need to exist.
+    pub fn overlay_path(&self) -> Option<PathBuf> {
+        let host = self.host.as_ref()?;
+        let stem = self.path.file_stem()?.to_str()?;
+        let name = match self.path.extension().and_then(|x| x.to_str()) {
+            Some(extension) => format!("{}.{}.{}", stem, host, extension),
+            None => format!("{}.{}", stem, host),
+        };
+        Some(self.path.with_file_name(name))
+    }
+
+    /// Files that config is loaded from, the overlay doesn't need to exist.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.path.clone()];
+        paths.extend(self.overlay_path());
+        paths
+    }
+
+    /// Merges all layers into one YAML value.
+    pub fn merged(&self) -> Result<Value> {
+        let mut value = read_yaml(&self.path)?;
+        if let Some(path) = self.overlay_path().filter(|x| x.is_file()) {
+            info!("Applying config overlay {}.", path.display());
+            merge(&mut value, read_yaml(&path)?);
+        }
+        let mut env_vars: Vec<_> = self
+            .env_vars
+            .iter()
+            .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+            .collect();
+        // same order on every run
+        env_vars.sort();
+        for (key, raw) in env_vars {
+            let path: Vec<_> = key[ENV_PREFIX.len()..]
+                .split(ENV_SEPARATOR)
+                .map(|x| x.to_lowercase())
+                .collect();
+            let new = env_value(&value, &path, raw)?;
+            set(&mut value, &path, new).with_context(|| key.clone())?;
+        }
+        for item in &self.overrides {
+            let (path, raw) = match item.split_once('=') {
+                Some(x) => x,
+                None => bail!("Override {} must be in form of path.to.key=value.", item),
+            };
+            let path: Vec<_> = path.split('.').map(|x| x.to_string()).collect();
+            set(&mut value, &path, parse_value(raw)?).with_context(|| item.clone())?;
+        }
+        Ok(value)
+    }
+
+    /// Loads config, resolves its secrets and validates it without installing it.
+    pub fn load(&self) -> Result<SuperConfig> {
+        let mut config: SuperConfig = serde_yaml::from_value(self.merged()?)
+            .with_context(|| format!("{}", self.path.display()))?;
+        resolve_secrets(&mut config)?;
+        validate(&config, &self.schema)?;
+        Ok(config)
+    }
+}
+
+/// `MATRIX_HOST` or name of this machine.
+fn host_name() -> Option<String> {
+    env::var("MATRIX_HOST")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+}
+
+fn read_yaml(path: &Path) -> Result<Value> {
+    // NOTE: adding anyhow::context here crashes when debugging
+    let file = File::open(path)?;
+    Ok(serde_yaml::from_reader(file)?)
+}
+
+fn parse_value(raw: &str) -> Result<Value> {
+    Ok(serde_yaml::from_str(raw)?)
+}
+
+/// Value of an environment variable that sets `path` of `config`, text fields get `raw` as it is.
+fn env_value(config: &Value, path: &[String], raw: &str) -> Result<Value> {
+    let is_text = matches!(path.last(), Some(key) if TEXT_KEYS.contains(&key.as_str()))
+        || matches!(get(config, path), Some(Value::String(_)));
+    match is_text {
+        true => Ok(Value::String(raw.to_string())),
+        false => parse_value(raw),
+    }
+}
+
+fn get<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let (key, rest) = match path.split_first() {
+        Some(x) => x,
+        None => return Some(value),
+    };
+    let child = match value {
+        Value::Mapping(map) => map.get(&Value::String(key.clone()))?,
+        Value::Sequence(seq) => seq.get(key.parse::<usize>().ok()?)?,
+        _ => return None,
+    };
+    get(child, rest)
+}
+
+/// Maps are merged recursively, everything else is replaced.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Sets value at `path`, missing maps are created. Items of sequences are addressed by index.
+fn set(value: &mut Value, path: &[String], new: Value) -> Result<()> {
+    let (key, rest) = match path.split_first() {
+        Some(x) => x,
+        None => {
+            *value = new;
+            return Ok(());
+        }
+    };
+    if value.is_null() {
+        *value = Value::Mapping(Mapping::new());
+    }
+    match value {
+        Value::Mapping(map) => {
+            let key = Value::String(key.clone());
+            if !map.contains_key(&key) {
+                map.insert(key.clone(), Value::Null);
+            }
+            set(map.get_mut(&key).unwrap(), rest, new)
+        }
+        Value::Sequence(seq) => {
+            let len = seq.len();
+            match key.parse::<usize>().ok().and_then(|i| seq.get_mut(i)) {
+                Some(child) => set(child, rest, new),
+                None => bail!("{} isn't an index of a sequence of length {}.", key, len),
+            }
+        }
+        _ => bail!("{} cannot be set on a value that isn't a map.", key),
+    }
+}
+
+#[cfg(test)]
+mod t_loader {
+    use super::*;
+
+    const BASE: &str = r#"
+db: base
+data_dir: /
+cache_dir: /
+reports_dir: /
+report_template_dir: /
+logs: {}
+exchanges:
+  - name: BitMEX
+    use_testnet: true
+    use_public_data_miner: false
+    api_key: ""
+    api_secret: ""
+    max_leverage: 1
+    max_orders_per_m: 60
+    models: []
+    risk:
+      max_position:
+        XBTUSD: 100
+"#;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn t_layers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let path = write(dir, "config.yaml", BASE);
+        write(dir, "config.prod.yaml", "db: prod\nexchanges: []\n");
+        write(dir, "config.dev.yaml", "db: dev\n");
+
+        let loader = ConfigLoader::new(&path)
+            .host(Some("dev".into()))
+            .env_vars(vec![
+                ("MATRIX__EXCHANGES__0__MAX_LEVERAGE".into(), "3".into()),
+                (
+                    "MATRIX__EXCHANGES__0__RISK__MAX_DAILY_DRAWDOWN".into(),
+                    "0.1".into(),
+                ),
+                ("OTHER__DB".into(), "other".into()),
+            ])
+            .overrides(vec![
+                "exchanges.0.max_leverage=5".into(),
+                "exchanges.0.risk.max_position.XBTUSD=200".into(),
+            ]);
+        assert_eq!(loader.overlay_path(), Some(dir.join("config.dev.yaml")));
+        let config = loader.load()?;
+        assert_eq!(config.db, "dev");
+        let exchange = &config.exchanges[0];
+        assert_eq!(exchange.max_leverage, 5.);
+        assert_eq!(exchange.risk.max_daily_drawdown, Some(0.1));
+        assert_eq!(exchange.risk.max_position["XBTUSD"], 200.);
+
+        let config = ConfigLoader::new(&path)
+            .host(Some("prod".into()))
+            .env_vars(vec![])
+            .load()?;
+        assert_eq!(config.db, "prod");
+        assert!(config.exchanges.is_empty());
+
+        let loader = ConfigLoader::new(&path)
+            .host(None)
+            .env_vars(vec![])
+            .overrides(vec!["exchanges.1.max_leverage=5".into()]);
+        assert!(loader.load().is_err());
+        let loader = ConfigLoader::new(&path)
+            .host(None)
+            .env_vars(vec![])
+            .overrides(vec!["exchanges.0.max_leverage=0".into()]);
+        let error = loader.load().unwrap_err().to_string();
+        assert!(
+            error.contains("exchanges[0].max_leverage: must be finite and > 0"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t_env_text_is_raw() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = write(dir.path(), "config.yaml", BASE);
+        let config = ConfigLoader::new(&path)
+            .host(None)
+            .env_vars(vec![
+                ("MATRIX__EXCHANGES__0__API_KEY".into(), "0123".into()),
+                ("MATRIX__EXCHANGES__0__API_SECRET".into(), "a: b".into()),
+                ("MATRIX__DB".into(), "yes".into()),
+                ("MATRIX__EXCHANGES__0__USE_TESTNET".into(), "false".into()),
+            ])
+            .load()?;
+        assert_eq!(config.db, "yes");
+        let exchange = &config.exchanges[0];
+        assert_eq!(exchange.api_key.expose(), "0123");
+        assert_eq!(exchange.api_secret.expose(), "a: b");
+        assert!(!exchange.use_testnet);
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+//! Credentials that are kept out of config files. Config references them as `secret://<name>` and
+//! they are resolved at load time from a vault, a file encrypted with AES-256-GCM under a key
+//! derived from a passphrase or a keyfile with PBKDF2.
+//!
+//! Vault file layout: magic, salt, PBKDF2 iterations (u32 LE), nonce, YAML map of entries sealed
+//! with the header as associated data.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use mouse::error::{bail, Result, ResultCtxExt};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::SuperConfig;
+
+pub const SECRET_SCHEME: &str = "secret://";
+/// Used when `vault.keyfile` isn't set.
+pub const PASSPHRASE_ENV: &str = "MATRIX_VAULT_PASSPHRASE";
+
+const MAGIC: &[u8; 8] = b"MXVAULT1";
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + 4 + NONCE_LEN;
+const ITERATIONS: u32 = 100_000;
+const REDACTED: &str = "<redacted>";
+
+/// A credential that never shows up in `Debug` or `Serialize` output. When it was loaded from a
+/// vault the reference is shown instead.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret {
+    value: String,
+    reference: Option<String>,
+}
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Secret {
+        Secret {
+            value: value.into(),
+            reference: None,
+        }
+    }
+
+    /// Plaintext value, it is a `secret://` reference until it is resolved.
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Name of the vault entry if value is an unresolved reference.
+    pub fn unresolved_name(&self) -> Option<&str> {
+        match self.reference {
+            Some(_) => None,
+            None => self.value.strip_prefix(SECRET_SCHEME),
+        }
+    }
+
+    pub fn resolve(&mut self, vault: &Vault) -> Result<()> {
+        if let Some(name) = self.unresolved_name() {
+            let value = match vault.get(name) {
+                Some(value) => value.to_string(),
+                None => bail!("Secret {} isn't in vault {}.", name, vault.path.display()),
+            };
+            self.reference = Some(std::mem::replace(&mut self.value, value));
+        }
+        Ok(())
+    }
+
+    fn shown(&self) -> &str {
+        match &self.reference {
+            Some(reference) => reference,
+            None if self.value.is_empty() => "",
+            None => REDACTED,
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({:?})", self.shown())
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.shown())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Secret::new(String::deserialize(deserializer)?))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VaultConfig {
+    pub path: PathBuf,
+    /// Key is derived from content of this file instead of `MATRIX_VAULT_PASSPHRASE`.
+    #[serde(default)]
+    pub keyfile: Option<PathBuf>,
+}
+
+impl VaultConfig {
+    pub fn key(&self) -> Result<VaultKey> {
+        match &self.keyfile {
+            Some(path) => VaultKey::from_keyfile(path),
+            None => VaultKey::from_env(),
+        }
+    }
+}
+
+/// Material that vault key is derived from.
+#[derive(Clone)]
+pub struct VaultKey(Vec<u8>);
+
+impl VaultKey {
+    pub fn from_passphrase(passphrase: &str) -> Result<VaultKey> {
+        if passphrase.is_empty() {
+            bail!("Vault passphrase is empty.");
+        }
+        Ok(VaultKey(passphrase.as_bytes().to_vec()))
+    }
+
+    pub fn from_keyfile(path: impl AsRef<Path>) -> Result<VaultKey> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("{}", path.display()))?;
+        if data.is_empty() {
+            bail!("Vault keyfile {} is empty.", path.display());
+        }
+        Ok(VaultKey(data))
+    }
+
+    pub fn from_env() -> Result<VaultKey> {
+        match std::env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) => VaultKey::from_passphrase(&passphrase),
+            Err(_) => bail!("Vault is locked, set {} or vault.keyfile.", PASSPHRASE_ENV),
+        }
+    }
+
+    fn derive(&self, salt: &[u8], iterations: NonZeroU32) -> Result<LessSafeKey> {
+        let mut key = [0; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            salt,
+            &self.0,
+            &mut key,
+        );
+        match UnboundKey::new(&AES_256_GCM, &key) {
+            Ok(key) => Ok(LessSafeKey::new(key)),
+            Err(_) => bail!("Invalid vault key."),
+        }
+    }
+}
+
+impl fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VaultKey({:?})", REDACTED)
+    }
+}
+
+/// Encrypted store of named secrets, changes are written only by `save`.
+#[derive(Debug)]
+pub struct Vault {
+    path: PathBuf,
+    key: VaultKey,
+    entries: BTreeMap<String, Secret>,
+}
+
+impl Vault {
+    /// Opens existing vault or creates an empty one if file doesn't exist.
+    pub fn open(path: impl AsRef<Path>, key: VaultKey) -> Result<Vault> {
+        let path = path.as_ref().to_path_buf();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Vault {
+                    path,
+                    key,
+                    entries: BTreeMap::new(),
+                });
+            }
+            Err(e) => return Err(e).with_context(|| format!("{}", path.display())),
+        };
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            bail!("{} isn't a vault.", path.display());
+        }
+        let (header, ciphertext) = data.split_at(HEADER_LEN);
+        let salt = &header[MAGIC.len()..MAGIC.len() + SALT_LEN];
+        let mut iterations = [0; 4];
+        iterations.copy_from_slice(&header[MAGIC.len() + SALT_LEN..HEADER_LEN - NONCE_LEN]);
+        let iterations = match NonZeroU32::new(u32::from_le_bytes(iterations)) {
+            Some(iterations) => iterations,
+            None => bail!("{} isn't a vault.", path.display()),
+        };
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = match key.derive(salt, iterations)?.open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(header),
+            &mut in_out,
+        ) {
+            Ok(plaintext) => plaintext,
+            Err(_) => bail!(
+                "Cannot decrypt vault {}, wrong key or corrupted file.",
+                path.display()
+            ),
+        };
+        let entries: BTreeMap<String, String> = serde_yaml::from_slice(plaintext)?;
+        Ok(Vault {
+            path,
+            key,
+            entries: entries
+                .into_iter()
+                .map(|(name, value)| (name, Secret::new(value)))
+                .collect(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(|x| x.expose())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|x| x.as_str())
+    }
+
+    /// Fails if entry already exists, use `rotate` to replace it.
+    pub fn add(&mut self, name: &str, value: &str) -> Result<()> {
+        if name.is_empty() {
+            bail!("Secret name must not be empty.");
+        }
+        if self.entries.contains_key(name) {
+            bail!("Secret {} already exists.", name);
+        }
+        self.entries.insert(name.to_string(), Secret::new(value));
+        Ok(())
+    }
+
+    /// Replaces value of an existing entry.
+    pub fn rotate(&mut self, name: &str, value: &str) -> Result<()> {
+        match self.entries.get_mut(name) {
+            Some(secret) => *secret = Secret::new(value),
+            None => bail!("Secret {} doesn't exist.", name),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if self.entries.remove(name).is_none() {
+            bail!("Secret {} doesn't exist.", name);
+        }
+        Ok(())
+    }
+
+    /// Vault is encrypted with `key` from the next `save` on.
+    pub fn rekey(&mut self, key: VaultKey) {
+        self.key = key;
+    }
+
+    /// Encrypts with fresh salt and nonce and replaces vault file atomically, the file is readable
+    /// only by its owner.
+    pub fn save(&self) -> Result<()> {
+        let rng = SystemRandom::new();
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        if rng.fill(&mut salt).is_err() || rng.fill(&mut nonce).is_err() {
+            bail!("Cannot generate random vault salt.");
+        }
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&ITERATIONS.to_le_bytes());
+        header.extend_from_slice(&nonce);
+        let entries: BTreeMap<_, _> = self
+            .entries
+            .iter()
+            .map(|(name, value)| (name, value.expose()))
+            .collect();
+        let mut in_out = serde_yaml::to_vec(&entries)?;
+        let key = self
+            .key
+            .derive(&salt, NonZeroU32::new(ITERATIONS).unwrap())?;
+        if key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&header),
+                &mut in_out,
+            )
+            .is_err()
+        {
+            bail!("Cannot encrypt vault {}.", self.path.display());
+        }
+        header.extend_from_slice(&in_out);
+
+        if let Some(parent) = self.path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&tmp_path)
+            .with_context(|| format!("{}", tmp_path.display()))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Replaces `secret://` references in `config` with values from its vault, the vault is opened only
+/// when something references it.
+pub fn resolve_secrets(config: &mut SuperConfig) -> Result<()> {
+    let vault_config = config.vault.clone();
+    let mut secrets: Vec<_> = config
+        .exchanges
+        .iter_mut()
+        .flat_map(|x| vec![&mut x.api_key, &mut x.api_secret])
+        .chain(
+            config
+                .iaas
+                .iter_mut()
+                .flat_map(|x| vec![&mut x.storage_key, &mut x.the_matrix_db_url]),
+        )
+        .filter(|x| x.unresolved_name().is_some())
+        .collect();
+    if secrets.is_empty() {
+        return Ok(());
+    }
+    let vault_config = match vault_config {
+        Some(vault_config) => vault_config,
+        None => bail!("Config references secrets but vault isn't configured."),
+    };
+    let vault = Vault::open(&vault_config.path, vault_config.key()?)?;
+    for secret in &mut secrets {
+        secret.resolve(&vault)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod t_secrets {
+    use super::*;
+
+    #[test]
+    fn t_vault() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("vault");
+        let key = VaultKey::from_passphrase("correct horse")?;
+        let mut vault = Vault::open(&path, key.clone())?;
+        vault.add("bitmex/main", "key")?;
+        vault.add("azure", "storage")?;
+        assert!(vault.add("azure", "other").is_err());
+        vault.rotate("bitmex/main", "rotated")?;
+        assert!(vault.rotate("missing", "x").is_err());
+        vault.save()?;
+        let data = fs::read(&path)?;
+        assert!(!data.windows(7).any(|x| x == b"rotated"));
+
+        let mut vault = Vault::open(&path, key)?;
+        assert_eq!(
+            vault.names().collect::<Vec<_>>(),
+            vec!["azure", "bitmex/main"]
+        );
+        assert_eq!(vault.get("bitmex/main"), Some("rotated"));
+        assert!(Vault::open(&path, VaultKey::from_passphrase("wrong")?).is_err());
+
+        vault.remove("azure")?;
+        vault.rekey(VaultKey::from_passphrase("new")?);
+        vault.save()?;
+        let vault = Vault::open(&path, VaultKey::from_passphrase("new")?)?;
+        assert_eq!(vault.names().collect::<Vec<_>>(), vec!["bitmex/main"]);
+        Ok(())
+    }
+
+    #[test]
+    fn t_redacted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut vault = Vault::open(dir.path().join("vault"), VaultKey::from_passphrase("x")?)?;
+        vault.add("bitmex/main", "hunter2")?;
+        let mut secret: Secret = serde_yaml::from_str("secret://bitmex/main")?;
+        assert_eq!(secret.unresolved_name(), Some("bitmex/main"));
+        secret.resolve(&vault)?;
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(secret.unresolved_name(), None);
+        assert_eq!(format!("{:?}", secret), r#"Secret("secret://bitmex/main")"#);
+        assert!(serde_yaml::to_string(&secret)?.contains("secret://bitmex/main"));
+
+        let plain = Secret::new("hunter2");
+        assert!(!format!("{:?}", plain).contains("hunter2"));
+        assert!(!serde_yaml::to_string(&plain)?.contains("hunter2"));
+
+        let mut missing: Secret = serde_yaml::from_str("secret://other")?;
+        assert!(missing.resolve(&vault).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn t_resolve_secrets() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "key material")?;
+        let vault_path = dir.path().join("vault");
+        let mut vault = Vault::open(&vault_path, VaultKey::from_keyfile(&keyfile)?)?;
+        vault.add("bitmex/main", "hunter2")?;
+        vault.save()?;
+        let mut config = SuperConfig {
+            exchanges: vec![crate::ExchangeConfig {
+                api_key: Secret::new("plain"),
+                api_secret: Secret::new("secret://bitmex/main"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(resolve_secrets(&mut config).is_err());
+        config.vault = Some(VaultConfig {
+            path: vault_path.clone(),
+            keyfile: Some(keyfile.clone()),
+        });
+        resolve_secrets(&mut config)?;
+        assert_eq!(config.exchanges[0].api_key.expose(), "plain");
+        assert_eq!(config.exchanges[0].api_secret.expose(), "hunter2");
+        assert!(!format!("{:?}", config).contains("hunter2"));
+        assert!(!format!("{:?}", config).contains("plain"));
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+//! Checks that are run on a loaded config before anything uses it. All problems are collected so
+//! that a misconfigured file can be fixed in one go instead of failing deep inside agents.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use crate::{ExchangeConfig, SuperConfig};
+
+/// What is known about exchanges and models outside of config, checks that need unknown parts are
+/// skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    /// Markets that can be traded.
+    pub markets: Option<HashSet<String>>,
+    /// Number of variables of a model by its name.
+    pub model_variables: HashMap<String, usize>,
+}
+
+/// Every problem found in a config, one per line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid config, {} error(s):", self.0.len())?;
+        for error in &self.0 {
+            writeln!(f, "  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl ConfigErrors {
+    fn push(&mut self, path: &str, message: impl fmt::Display) {
+        self.0.push(format!("{}: {}", path, message));
+    }
+
+    fn into_result(self) -> Result<(), ConfigErrors> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+}
+
+pub fn validate(config: &SuperConfig, schema: &Schema) -> Result<(), ConfigErrors> {
+    let mut errors = ConfigErrors::default();
+    if config.data_dir.as_os_str().is_empty() {
+        errors.push("data_dir", "must be set");
+    }
+    for (path, dir) in [
+        ("data_dir", &config.data_dir),
+        ("cache_dir", &config.cache_dir),
+        ("reports_dir", &config.reports_dir),
+        ("report_template_dir", &config.report_template_dir),
+    ] {
+        validate_dir(&mut errors, path, dir);
+    }
+    let mut names = HashSet::new();
+    // Exchanges of one machine can't share an admin endpoint.
+    let mut admin_addresses = HashMap::new();
+    for (i, exchange) in config.exchanges.iter().enumerate() {
+        let path = format!("exchanges[{}]", i);
+        if !names.insert(exchange.name.as_str()) {
+            errors.push(
+                &format!("{}.name", path),
+                format_args!("{} is configured more than once", exchange.name),
+            );
+        }
+        if let Some(admin) = &exchange.admin {
+            if let Some(other) = admin_addresses.insert(&admin.address, &exchange.name) {
+                errors.push(
+                    &format!("{}.admin.address", path),
+                    format_args!("{} is already used by {}", admin.address, other),
+                );
+            }
+        }
+        validate_exchange_into(&mut errors, &path, exchange, schema);
+    }
+    if let Some(fleet) = &config.fleet {
+        for (i, name) in fleet.exchanges.iter().enumerate() {
+            if !names.contains(name.as_str()) {
+                errors.push(
+                    &format!("fleet.exchanges[{}]", i),
+                    format_args!("exchange {} isn't configured", name),
+                );
+            }
+        }
+        validate_fraction(
+            &mut errors,
+            "fleet.risk.max_drawdown",
+            fleet.risk.max_drawdown,
+        );
+        validate_positive(
+            &mut errors,
+            "fleet.risk.max_notional",
+            fleet.risk.max_notional,
+        );
+    }
+    errors.into_result()
+}
+
+/// Validates one exchange, used when markets are known only after connecting to it.
+pub fn validate_exchange(exchange: &ExchangeConfig, schema: &Schema) -> Result<(), ConfigErrors> {
+    let mut errors = ConfigErrors::default();
+    validate_exchange_into(&mut errors, &exchange.name, exchange, schema);
+    errors.into_result()
+}
+
+fn validate_exchange_into(
+    errors: &mut ConfigErrors,
+    path: &str,
+    exchange: &ExchangeConfig,
+    schema: &Schema,
+) {
+    if exchange.name.is_empty() {
+        errors.push(&format!("{}.name", path), "must be set");
+    }
+    if !is_positive(exchange.max_leverage) {
+        errors.push(
+            &format!("{}.max_leverage", path),
+            format_args!("must be finite and > 0, got {}", exchange.max_leverage),
+        );
+    }
+    if !is_positive(exchange.max_orders_per_m) {
+        errors.push(
+            &format!("{}.max_orders_per_m", path),
+            format_args!("must be finite and > 0, got {}", exchange.max_orders_per_m),
+        );
+    }
+    let risk = &exchange.risk;
+    let risk_path = format!("{}.risk", path);
+    validate_positive(
+        errors,
+        &format!("{}.max_account_leverage", risk_path),
+        risk.max_account_leverage,
+    );
+    validate_positive(
+        errors,
+        &format!("{}.max_model_leverage", risk_path),
+        risk.max_model_leverage,
+    );
+    validate_fraction(
+        errors,
+        &format!("{}.max_daily_drawdown", risk_path),
+        risk.max_daily_drawdown,
+    );
+    validate_fraction(
+        errors,
+        &format!("{}.max_rolling_drawdown", risk_path),
+        risk.max_rolling_drawdown,
+    );
+    // models with the same name must have the same variables
+    let mut variables = HashMap::new();
+    for (i, model) in exchange.models.iter().enumerate() {
+        let path = format!("{}.models[{}]", path, i);
+        if !is_positive(model.target_leverage) {
+            errors.push(
+                &format!("{}.target_leverage", path),
+                format_args!("must be finite and > 0, got {}", model.target_leverage),
+            );
+        }
+        if model.target_leverage > exchange.max_leverage {
+            errors.push(
+                &format!("{}.target_leverage", path),
+                format_args!(
+                    "{} is higher than max_leverage {}",
+                    model.target_leverage, exchange.max_leverage
+                ),
+            );
+        }
+        if model.market.is_empty() {
+            errors.push(&format!("{}.market", path), "must be set");
+        } else if let Some(markets) = &schema.markets {
+            if !markets.contains(&model.market) {
+                errors.push(
+                    &format!("{}.market", path),
+                    format_args!("{} doesn't exist", model.market),
+                );
+            }
+        }
+        // the first value is timeframe
+        if model.variable_values.is_empty() {
+            errors.push(&format!("{}.variable_values", path), "must not be empty");
+            continue;
+        }
+        let len = model.variable_values.len();
+        let expected = schema
+            .model_variables
+            .get(&model.name)
+            .copied()
+            .unwrap_or_else(|| *variables.entry(&model.name).or_insert(len));
+        if len != expected {
+            errors.push(
+                &format!("{}.variable_values", path),
+                format_args!(
+                    "model {} has {} variables, got {}",
+                    model.name, expected, len
+                ),
+            );
+        }
+    }
+}
+
+fn validate_dir(errors: &mut ConfigErrors, path: &str, dir: &Path) {
+    if !dir.as_os_str().is_empty() && !dir.is_dir() {
+        errors.push(
+            path,
+            format_args!("directory {} doesn't exist", dir.display()),
+        );
+    }
+}
+
+/// Infinity and NaN fail too, they can't be turned into decimals.
+fn is_positive(value: f32) -> bool {
+    value > 0. && value.is_finite()
+}
+
+fn validate_positive(errors: &mut ConfigErrors, path: &str, value: Option<f32>) {
+    if let Some(value) = value.filter(|x| !is_positive(*x)) {
+        errors.push(path, format_args!("must be finite and > 0, got {}", value));
+    }
+}
+
+fn validate_fraction(errors: &mut ConfigErrors, path: &str, value: Option<f32>) {
+    if let Some(value) = value.filter(|x| !(*x > 0. && *x <= 1.)) {
+        errors.push(path, format_args!("must be in (0, 1], got {}", value));
+    }
+}
+
+#[cfg(test)]
+mod t_validation {
+    use super::*;
+    use crate::{AdminConfig, FleetConfig, ModelConfig};
+
+    fn model(name: &str, market: &str, variable_values: Vec<f32>) -> ModelConfig {
+        ModelConfig {
+            name: name.into(),
+            market: market.into(),
+            target_leverage: 1.,
+            variable_values,
+            ..Default::default()
+        }
+    }
+
+    fn config() -> SuperConfig {
+        SuperConfig {
+            data_dir: std::env::temp_dir(),
+            exchanges: vec![ExchangeConfig {
+                name: "BitMEX".into(),
+                max_leverage: 2.,
+                max_orders_per_m: 60.,
+                models: vec![model("rsi", "XBTUSD", vec![60., 14.])],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn t_valid() {
+        assert_eq!(validate(&config(), &Schema::default()), Ok(()));
+    }
+
+    #[test]
+    fn t_reports_all_errors() {
+        let mut config = config();
+        config.cache_dir = "/does/not/exist".into();
+        let exchange = &mut config.exchanges[0];
+        exchange.max_leverage = 0.;
+        exchange.max_orders_per_m = f32::INFINITY;
+        exchange.risk.max_daily_drawdown = Some(2.);
+        exchange.models.push(model("rsi", "ETHUSD", vec![60.]));
+        exchange.models.push(model("macd", "XBTUSD", vec![]));
+        exchange.admin = Some(AdminConfig {
+            address: "127.0.0.1:7741".into(),
+        });
+        config.exchanges.push(config.exchanges[0].clone());
+        config.fleet = Some(FleetConfig {
+            exchanges: vec!["BitMEX".into(), "Binance".into()],
+            ..Default::default()
+        });
+        let schema = Schema {
+            markets: Some(vec!["XBTUSD".to_string()].into_iter().collect()),
+            model_variables: Default::default(),
+        };
+        let errors = validate(&config, &schema).unwrap_err().0;
+        for expected in [
+            "cache_dir: directory /does/not/exist doesn't exist",
+            "exchanges[0].max_leverage: must be finite and > 0, got 0",
+            "exchanges[0].max_orders_per_m: must be finite and > 0, got inf",
+            "exchanges[0].risk.max_daily_drawdown: must be in (0, 1], got 2",
+            "exchanges[0].models[0].target_leverage: 1 is higher than max_leverage 0",
+            "exchanges[0].models[1].market: ETHUSD doesn't exist",
+            "exchanges[0].models[1].variable_values: model rsi has 2 variables, got 1",
+            "exchanges[0].models[2].variable_values: must not be empty",
+            "exchanges[1].name: BitMEX is configured more than once",
+            "exchanges[1].admin.address: 127.0.0.1:7741 is already used by BitMEX",
+            "fleet.exchanges[1]: exchange Binance isn't configured",
+        ] {
+            assert!(
+                errors.iter().any(|x| x == expected),
+                "{} not in {:#?}",
+                expected,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn t_model_variables_from_schema() {
+        let schema = Schema {
+            markets: None,
+            model_variables: vec![("rsi".to_string(), 3)].into_iter().collect(),
+        };
+        let errors = validate_exchange(&config().exchanges[0], &schema).unwrap_err();
+        assert_eq!(
+            errors.0,
+            vec!["BitMEX.models[0].variable_values: model rsi has 3 variables, got 2"]
+        );
+    }
+}
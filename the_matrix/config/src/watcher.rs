@@ -0,0 +1,109 @@
+//! Reloads config when its files change. Modification times are polled instead of subscribing to
+//! file system events so that it works the same on every platform and on network file systems.
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use mouse::error::Result;
+
+use crate::{ConfigLoader, SuperConfig};
+
+pub struct ConfigWatcher {
+    loader: ConfigLoader,
+    interval: Duration,
+    checked_at: Option<Instant>,
+    /// Base file and overlay of this host with their last modification times.
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl ConfigWatcher {
+    /// Files are checked at most once a second, current state of files is treated as loaded.
+    pub fn new(loader: ConfigLoader) -> ConfigWatcher {
+        let files = loader
+            .paths()
+            .into_iter()
+            .map(|x| {
+                let modified = modified(&x);
+                (x, modified)
+            })
+            .collect();
+        ConfigWatcher {
+            loader,
+            interval: Duration::from_secs(1),
+            checked_at: None,
+            files,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Returns reloaded config if any of its files has changed since the last call. Config that
+    /// fails to load is returned as error once, it is loaded again when a file changes again.
+    pub fn poll(&mut self) -> Result<Option<SuperConfig>> {
+        let now = Instant::now();
+        if self
+            .checked_at
+            .map_or(false, |x| now.duration_since(x) < self.interval)
+        {
+            return Ok(None);
+        }
+        self.checked_at = Some(now);
+        let mut changed = false;
+        for (path, last_modified) in &mut self.files {
+            let modified = modified(path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        Ok(Some(self.loader.load()?))
+    }
+}
+
+/// `None` if file doesn't exist, e.g. overlay that hasn't been created yet.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+#[cfg(test)]
+mod t_watcher {
+    use super::*;
+
+    const CONFIG: &str = r#"
+db: base
+data_dir: /
+cache_dir: /
+reports_dir: /
+report_template_dir: /
+logs: {}
+exchanges: []
+"#;
+
+    #[test]
+    fn t_poll() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(&path, CONFIG)?;
+        let loader = ConfigLoader::new(&path)
+            .host(Some("dev".into()))
+            .env_vars(vec![]);
+        let mut watcher = ConfigWatcher::new(loader).interval(Duration::from_millis(0));
+        assert!(watcher.poll()?.is_none());
+
+        std::fs::write(dir.path().join("config.dev.yaml"), "db: dev\n")?;
+        assert_eq!(watcher.poll()?.unwrap().db, "dev");
+        assert!(watcher.poll()?.is_none());
+
+        // modification times may have a resolution of a second
+        std::thread::sleep(Duration::from_millis(1100));
+        std::fs::write(&path, "db: [")?;
+        assert!(watcher.poll().is_err());
+        assert!(watcher.poll()?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+//! Resumable download of historical data. Requested range is split into chunks that are downloaded
+//! concurrently into separate files. Completed chunks are recorded in a manifest so that an
+//! interrupted download continues where it stopped.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use futures_util::stream::{self, StreamExt};
+use mouse::error::Result;
+use mouse::log::*;
+use mouse::throw;
+use speedy::{Readable, Writable};
+use tokio::time::{sleep, Duration};
+
+const MANIFEST_FILE: &str = "manifest.bin";
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const MAX_RETRY_DELAY_S: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq, Readable, Writable)]
+pub struct Chunk {
+    pub start_ts: u32,
+    /// Excluding.
+    pub end_ts: u32,
+    pub done: bool,
+    /// Timestamp of the first downloaded item, `end_ts` if exchange had no data.
+    pub first_ts: u32,
+    pub items: u64,
+}
+
+/// What a chunk download has written into its file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkSummary {
+    pub first_ts: u32,
+    pub items: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Readable, Writable)]
+pub struct Manifest {
+    pub exchange: String,
+    pub market: String,
+    /// Type of downloaded data e.g. "hlcv".
+    pub kind: String,
+    pub chunk_len: u32,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Manifest {
+    /// Chunk boundaries are aligned to `chunk_len`.
+    pub fn new(
+        exchange: &str,
+        market: &str,
+        kind: &str,
+        start_ts: u32,
+        end_ts: u32,
+        chunk_len: u32,
+    ) -> Manifest {
+        let mut chunks = Vec::new();
+        let mut i = start_ts;
+        while i < end_ts {
+            let end = (i - i % chunk_len + chunk_len).min(end_ts);
+            chunks.push(Chunk {
+                start_ts: i,
+                end_ts: end,
+                done: false,
+                first_ts: end,
+                items: 0,
+            });
+            i = end;
+        }
+        Manifest {
+            exchange: exchange.into(),
+            market: market.into(),
+            kind: kind.into(),
+            chunk_len,
+            chunks,
+        }
+    }
+
+    pub async fn load(path: impl AsRef<Path>) -> Result<Option<Manifest>> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(Manifest::read_from_buffer(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Loads manifest of an interrupted download of the same range or creates a new one.
+    pub async fn load_or_create(
+        path: impl AsRef<Path>,
+        exchange: &str,
+        market: &str,
+        kind: &str,
+        start_ts: u32,
+        end_ts: u32,
+        chunk_len: u32,
+    ) -> Result<Manifest> {
+        let new = Manifest::new(exchange, market, kind, start_ts, end_ts, chunk_len);
+        match Manifest::load(&path).await? {
+            Some(manifest) if manifest.is_resumable_as(&new) => {
+                info!(
+                    "Resuming download of {} {} {}, {}/{} chunks done.",
+                    exchange,
+                    market,
+                    kind,
+                    manifest.chunks.len() - manifest.pending().len(),
+                    manifest.chunks.len()
+                );
+                Ok(manifest)
+            }
+            Some(_) => {
+                warn!("Discarding download manifest for a different range.");
+                Ok(new)
+            }
+            None => Ok(new),
+        }
+    }
+
+    /// Writes to a temporary file first so that a crash never leaves a corrupted manifest.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, self.write_to_vec()?).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    pub fn start_ts(&self) -> Option<u32> {
+        self.chunks.first().map(|x| x.start_ts)
+    }
+
+    pub fn end_ts(&self) -> Option<u32> {
+        self.chunks.last().map(|x| x.end_ts)
+    }
+
+    pub fn pending(&self) -> Vec<usize> {
+        (0..self.chunks.len())
+            .filter(|i| !self.chunks[*i].done)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.chunks.iter().all(|x| x.done)
+    }
+
+    /// Checks that chunks cover the whole range without gaps and that every chunk only contains
+    /// data from its own range.
+    pub fn verify(&self) -> Result<()> {
+        for pair in self.chunks.windows(2) {
+            if pair[0].end_ts != pair[1].start_ts {
+                throw!(
+                    "Chunks aren't continuous: {} != {}",
+                    pair[0].end_ts,
+                    pair[1].start_ts
+                );
+            }
+        }
+        for chunk in &self.chunks {
+            if !chunk.done {
+                throw!(
+                    "Chunk {}-{} isn't downloaded.",
+                    chunk.start_ts,
+                    chunk.end_ts
+                );
+            }
+            if chunk.first_ts < chunk.start_ts || chunk.first_ts > chunk.end_ts {
+                throw!(
+                    "Chunk {}-{} starts at {}.",
+                    chunk.start_ts,
+                    chunk.end_ts,
+                    chunk.first_ts
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn is_resumable_as(&self, other: &Manifest) -> bool {
+        self.exchange == other.exchange
+            && self.market == other.market
+            && self.kind == other.kind
+            && self.chunk_len == other.chunk_len
+            && self.start_ts() == other.start_ts()
+            && self.end_ts() == other.end_ts()
+    }
+}
+
+/// Downloads chunks of a manifest into `dir`.
+pub struct Downloader {
+    dir: PathBuf,
+    concurrency: usize,
+    max_retries: u32,
+}
+
+impl Downloader {
+    pub fn new(dir: impl Into<PathBuf>) -> Downloader {
+        Downloader {
+            dir: dir.into(),
+            concurrency: DEFAULT_CONCURRENCY,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Number of chunks that are downloaded at the same time. Client is responsible for
+    /// respecting rate limits of an exchange.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Downloader {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Downloader {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILE)
+    }
+
+    pub fn chunk_path(&self, chunk: &Chunk) -> PathBuf {
+        self.dir
+            .join(format!("{}-{}.chunk", chunk.start_ts, chunk.end_ts))
+    }
+
+    /// Calls `fetch` for every chunk that isn't done. `fetch` must write all data of a chunk into
+    /// provided path. Failed chunks are retried with exponential backoff.
+    pub async fn download<F, Fut>(&self, manifest: &mut Manifest, fetch: F) -> Result<()>
+    where
+        F: Fn(Chunk, PathBuf) -> Fut,
+        Fut: Future<Output = Result<ChunkSummary>>,
+    {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        manifest.save(self.manifest_path()).await?;
+        let jobs: Vec<_> = manifest
+            .pending()
+            .into_iter()
+            .map(|i| (i, manifest.chunks[i].clone()))
+            .collect();
+        let total = manifest.chunks.len();
+        let fetch = &fetch;
+        let mut results = stream::iter(jobs)
+            .map(|(i, chunk)| async move { (i, self.fetch_with_retries(fetch, chunk).await) })
+            .buffer_unordered(self.concurrency);
+        while let Some((i, result)) = results.next().await {
+            let summary = result?;
+            let chunk = &mut manifest.chunks[i];
+            chunk.done = true;
+            chunk.first_ts = summary.first_ts;
+            chunk.items = summary.items;
+            manifest.save(self.manifest_path()).await?;
+            debug!(
+                "Downloaded chunk {}/{} of {} {}.",
+                total - manifest.pending().len(),
+                total,
+                manifest.market,
+                manifest.kind
+            );
+        }
+        Ok(())
+    }
+
+    /// Removes manifest and all chunk files.
+    pub async fn remove(&self) -> Result<()> {
+        match tokio::fs::remove_dir_all(&self.dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn fetch_with_retries<F, Fut>(&self, fetch: &F, chunk: Chunk) -> Result<ChunkSummary>
+    where
+        F: Fn(Chunk, PathBuf) -> Fut,
+        Fut: Future<Output = Result<ChunkSummary>>,
+    {
+        let path = self.chunk_path(&chunk);
+        let mut attempt = 0;
+        loop {
+            match fetch(chunk.clone(), path.clone()).await {
+                Ok(summary) => return Ok(summary),
+                Err(e) if attempt < self.max_retries => {
+                    let delay = (1u64 << attempt).min(MAX_RETRY_DELAY_S);
+                    warn!(
+                        "Chunk {}-{} failed, retrying in {}s: {:?}",
+                        chunk.start_ts, chunk.end_ts, delay, e
+                    );
+                    sleep(Duration::from_secs(delay)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod t_downloader {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    use mouse::error::anyhow;
+
+    use super::*;
+
+    /// Writes start of a chunk into its file.
+    async fn fetch(chunk: Chunk, path: PathBuf) -> Result<ChunkSummary> {
+        tokio::fs::write(&path, chunk.start_ts.to_le_bytes()).await?;
+        Ok(ChunkSummary {
+            first_ts: chunk.start_ts,
+            items: 1,
+        })
+    }
+
+    #[test]
+    fn t_split_into_aligned_chunks() {
+        let manifest = Manifest::new("BitMEX", "XBTUSD", "hlcv", 150, 420, 100);
+        let bounds: Vec<_> = manifest
+            .chunks
+            .iter()
+            .map(|x| (x.start_ts, x.end_ts))
+            .collect();
+        assert_eq!(bounds, vec![(150, 200), (200, 300), (300, 400), (400, 420)]);
+        assert_eq!(manifest.pending(), vec![0, 1, 2, 3]);
+        assert!(manifest.verify().is_err());
+    }
+
+    #[test]
+    fn t_resume_and_verify() -> Result<()> {
+        let mut manifest = Manifest::new("BitMEX", "XBTUSD", "hlcv", 0, 300, 100);
+        manifest.chunks[1].done = true;
+        manifest.chunks[1].first_ts = 150;
+        let manifest = Manifest::read_from_buffer(&manifest.write_to_vec()?)?;
+        assert_eq!(manifest.pending(), vec![0, 2]);
+        assert!(manifest.is_resumable_as(&Manifest::new("BitMEX", "XBTUSD", "hlcv", 0, 300, 100)));
+        assert!(!manifest.is_resumable_as(&Manifest::new("BitMEX", "XBTUSD", "hlcv", 0, 400, 100)));
+
+        let mut manifest = manifest;
+        for chunk in &mut manifest.chunks {
+            chunk.done = true;
+        }
+        manifest.verify()?;
+        manifest.chunks[2].first_ts = 50;
+        assert!(manifest.verify().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_download_chunks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let downloader = Downloader::new(dir.path()).with_concurrency(2);
+        let mut manifest = Manifest::new("BitMEX", "XBTUSD", "hlcv", 0, 350, 100);
+        downloader.download(&mut manifest, fetch).await?;
+        assert!(manifest.is_complete());
+        manifest.verify()?;
+        for chunk in &manifest.chunks {
+            let data = tokio::fs::read(downloader.chunk_path(chunk)).await?;
+            assert_eq!(data, chunk.start_ts.to_le_bytes());
+            assert_eq!(chunk.items, 1);
+        }
+        assert_eq!(
+            Manifest::load(downloader.manifest_path()).await?,
+            Some(manifest)
+        );
+        downloader.remove().await?;
+        assert!(!dir.path().exists());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn t_retry() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let attempts = AtomicU32::new(0);
+        let flaky = |chunk: Chunk, path| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt < 2 {
+                    return Err(anyhow!("timeout"));
+                }
+                fetch(chunk, path).await
+            }
+        };
+        let mut manifest = Manifest::new("BitMEX", "XBTUSD", "hlcv", 0, 100, 100);
+        let downloader = Downloader::new(dir.path()).with_max_retries(1);
+        assert!(downloader.download(&mut manifest, flaky).await.is_err());
+        assert_eq!(manifest.pending(), vec![0]);
+        let downloader = downloader.with_max_retries(2);
+        downloader.download(&mut manifest, flaky).await?;
+        assert!(manifest.is_complete());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_resume() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let downloader = Downloader::new(dir.path())
+            .with_concurrency(1)
+            .with_max_retries(0);
+        let fetched = Mutex::new(Vec::new());
+        let fetch_until = |failing_ts: u32| {
+            let fetched = &fetched;
+            move |chunk: Chunk, path| {
+                fetched.lock().unwrap().push(chunk.start_ts);
+                async move {
+                    if chunk.start_ts == failing_ts {
+                        return Err(anyhow!("interrupted"));
+                    }
+                    fetch(chunk, path).await
+                }
+            }
+        };
+        let new = || {
+            Manifest::load_or_create(
+                downloader.manifest_path(),
+                "BitMEX",
+                "XBTUSD",
+                "hlcv",
+                0,
+                300,
+                100,
+            )
+        };
+        let mut manifest = new().await?;
+        assert!(downloader
+            .download(&mut manifest, fetch_until(200))
+            .await
+            .is_err());
+        // Completed chunks have been saved before the failure.
+        let mut manifest = new().await?;
+        assert_eq!(manifest.pending(), vec![2]);
+        fetched.lock().unwrap().clear();
+        downloader
+            .download(&mut manifest, fetch_until(u32::MAX))
+            .await?;
+        assert_eq!(*fetched.lock().unwrap(), vec![200]);
+        assert!(manifest.is_complete());
+        Ok(())
+    }
+}
@@ -0,0 +1,733 @@
+//! Export of optimizer outputs to CSV, Arrow IPC and Parquet so that results can be analyzed with
+//! pandas or DuckDB instead of tank. Variable values of each combination are reconstructed from
+//! its number and written next to its output values. Arrow IPC and Parquet are exported with
+//! feature `columnar`. Top combinations can also be converted into `BacktestRun`s for reports.
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "columnar")]
+use std::sync::Arc;
+
+#[cfg(feature = "columnar")]
+use arrow::{
+    array::{ArrayRef, Float32Array, UInt64Array},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    ipc::writer::FileWriter,
+    record_batch::RecordBatch,
+};
+use merovingian::account::StatAccount;
+use merovingian::compression::DecompressionMethod;
+use merovingian::parallel_coordinates::{Axis, ParallelCoordinatesFilter};
+use merovingian::report::{self, BacktestRun};
+use merovingian::structs::RangeInclusive;
+use merovingian::variable::{Variable, Variables};
+use mouse::error::{anyhow, bail, Result, ResultCtxExt};
+use mouse::num::f16;
+#[cfg(feature = "columnar")]
+use parquet::arrow::ArrowWriter;
+use serde::Deserialize;
+
+use crate::output_query::{
+    decode_block, open_indexed, open_output, OutputBlocks, Rank, RowDecoder,
+};
+
+/// Name of the column with the number of a combination.
+pub const COMBINATION_COLUMN: &str = "combination";
+
+/// Describes an output, outputs don't store names of variables and output values.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExportSpec {
+    /// Variables of the optimized model, the last one changes with every combination.
+    pub variables: Vec<VariableSpec>,
+    /// Names of output values in the order they are stored.
+    pub columns: Vec<String>,
+    pub precision: Precision,
+    /// Blocks are zstd compressed on their own.
+    #[serde(default)]
+    pub compressed_blocks: bool,
+    /// Exchange, market and model of the optimization, only used in reports.
+    #[serde(default)]
+    pub exchange: String,
+    #[serde(default)]
+    pub market: String,
+    #[serde(default)]
+    pub model: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct VariableSpec {
+    pub name: String,
+    pub min: f32,
+    /// Excluding.
+    pub max: f32,
+    pub stride: f32,
+}
+
+/// Float type of output values.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    F16,
+    F32,
+    F64,
+}
+
+impl ExportSpec {
+    /// Reads a YAML spec.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path).with_context(|| format!("{}", path.display()))?;
+        serde_yaml::from_str(&data).with_context(|| format!("Invalid spec {}", path.display()))
+    }
+
+    fn variables(&self) -> Variables {
+        Variables::new(
+            self.variables
+                .iter()
+                .map(|x| Variable::min(x.min, x.max, x.stride))
+                .collect(),
+        )
+    }
+
+    /// Reads all combinations of `output` into a filter with an axis for each variable followed
+    /// by an axis for each output value, output values are normalized by ranges in headers.
+    pub async fn parallel_coordinates(&self, output: &Path) -> Result<ParallelCoordinatesFilter> {
+        let axes = self
+            .variables
+            .iter()
+            .map(|x| Axis::new(&x.name, RangeInclusive::new(x.min, x.max)))
+            .chain(
+                self.columns
+                    .iter()
+                    .map(|x| Axis::new(x, RangeInclusive::new(0., 0.))),
+            )
+            .collect();
+        let mut filter = ParallelCoordinatesFilter::new(axes);
+        let decompression = self.decompression();
+        let mut blocks = OutputBlocks::new(open_output(output).await?, self.columns.len());
+        let mut variables = self.variables();
+        let mut rows = self.row_decoder();
+        let mut headers = Vec::new();
+        let mut values = Vec::with_capacity(self.variables.len() + self.columns.len());
+        loop {
+            let header = match blocks.header().await? {
+                Some(header) => header,
+                None => break,
+            };
+            if header.end_combination_inclusive >= variables.max_combinations() {
+                bail!(
+                    "Output has combination {}, variables have {} combinations.",
+                    header.end_combination_inclusive,
+                    variables.max_combinations()
+                );
+            }
+            let data = blocks.block(&header).await?;
+            for row in decode_block(data, decompression, &mut rows).await? {
+                variables.set_combination(row.combination);
+                values.clear();
+                values.extend(variables.variables().iter().map(|x| x.value));
+                values.extend_from_slice(&row.values);
+                filter.push(row.combination, &values)?;
+            }
+            headers.push(header);
+        }
+        filter.set_bounds_from_headers(self.variables.len(), &headers)?;
+        Ok(filter)
+    }
+
+    /// `k` best combinations by output value `metric` as backtest runs, best first. Columns named
+    /// like fields of `StatAccount` are its stats, other stats are `NaN`. Outputs don't store
+    /// equity nor trades so they are empty.
+    pub async fn top_runs(
+        &self,
+        output: &Path,
+        metric: &str,
+        k: usize,
+        rank: report::Rank,
+    ) -> Result<Vec<BacktestRun>> {
+        let column = self
+            .columns
+            .iter()
+            .position(|x| x == metric)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown column {}, columns are: {}",
+                    metric,
+                    self.columns.join(", ")
+                )
+            })?;
+        let rank = match rank {
+            report::Rank::Highest => Rank::Highest,
+            report::Rank::Lowest => Rank::Lowest,
+        };
+        let mut query = open_indexed(output, self.columns.len(), self.row_decoder())
+            .await?
+            .decompression(self.decompression());
+        let mut variables = self.variables();
+        let mut runs = Vec::new();
+        for row in query.top_k(column, k, rank).await? {
+            if row.combination >= variables.max_combinations() {
+                bail!(
+                    "Output has combination {}, variables have {} combinations.",
+                    row.combination,
+                    variables.max_combinations()
+                );
+            }
+            variables.set_combination(row.combination);
+            let mut stats = StatAccount::default();
+            for (name, value) in self.columns.iter().zip(&row.values) {
+                stats.set(name, *value);
+            }
+            runs.push(BacktestRun {
+                exchange: self.exchange.clone(),
+                market: self.market.clone(),
+                model: self.model.clone(),
+                variables: self
+                    .variables
+                    .iter()
+                    .zip(variables.variables())
+                    .map(|(spec, x)| (spec.name.clone(), x.value))
+                    .collect(),
+                stats,
+                equity: Vec::new(),
+                trades: Vec::new(),
+            });
+        }
+        Ok(runs)
+    }
+
+    fn decompression(&self) -> DecompressionMethod {
+        match self.compressed_blocks {
+            true => DecompressionMethod::Zstd,
+            false => DecompressionMethod::None,
+        }
+    }
+
+    fn row_decoder(&self) -> impl RowDecoder {
+        let precision = self.precision;
+        let n_columns = self.columns.len();
+        move |reader: &mut &[u8], values: &mut Vec<f32>| -> Result<()> {
+            for _ in 0..n_columns {
+                values.push(match precision {
+                    Precision::F16 => bincode::deserialize_from::<_, f16>(&mut *reader)?.to_f32(),
+                    Precision::F32 => bincode::deserialize_from(&mut *reader)?,
+                    Precision::F64 => bincode::deserialize_from::<_, f64>(&mut *reader)? as f32,
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    #[cfg(feature = "columnar")]
+    Arrow,
+    #[cfg(feature = "columnar")]
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Format by extension of `path`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = mouse::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "csv" => ExportFormat::Csv,
+            #[cfg(feature = "columnar")]
+            "arrow" | "ipc" | "feather" => ExportFormat::Arrow,
+            #[cfg(feature = "columnar")]
+            "parquet" => ExportFormat::Parquet,
+            #[cfg(not(feature = "columnar"))]
+            "arrow" | "ipc" | "feather" | "parquet" => {
+                bail!("Export format {} needs dozer with feature columnar.", s)
+            }
+            _ => bail!("Unknown export format {}, use csv, arrow or parquet.", s),
+        })
+    }
+}
+
+/// Range of a column or an axis, parsed from `name=min..max` where min or max may be omitted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnRange {
+    pub name: String,
+    pub range: RangeInclusive<f32>,
+}
+
+impl FromStr for ColumnRange {
+    type Err = mouse::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = || -> Option<ColumnRange> {
+            let (name, range) = s.split_once('=')?;
+            let (start, end) = range.split_once("..")?;
+            let start = match start {
+                "" => f32::MIN,
+                _ => start.parse().ok()?,
+            };
+            let end = match end {
+                "" => f32::MAX,
+                _ => end.parse().ok()?,
+            };
+            Some(ColumnRange {
+                name: name.into(),
+                range: RangeInclusive::new(start, end),
+            })
+        };
+        parse().ok_or_else(|| {
+            anyhow!(
+                "Invalid range {}, expected `name=min..max`, min or max may be omitted.",
+                s
+            )
+        })
+    }
+}
+
+pub struct Exporter {
+    spec: ExportSpec,
+    columns: Vec<String>,
+    filters: Vec<(String, RangeInclusive<f32>)>,
+}
+
+impl Exporter {
+    pub fn new(spec: ExportSpec) -> Self {
+        Self {
+            spec,
+            columns: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    /// Columns to write in this order, all columns are written by default.
+    pub fn columns(mut self, columns: Vec<String>) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    /// Only combinations with `column` in `range` are written, filters on output values also skip
+    /// whole blocks.
+    pub fn filter(mut self, column: impl Into<String>, range: RangeInclusive<f32>) -> Self {
+        self.filters.push((column.into(), range));
+        self
+    }
+
+    /// Combination followed by variables and output values.
+    pub fn all_columns(&self) -> Vec<&str> {
+        std::iter::once(COMBINATION_COLUMN)
+            .chain(self.spec.variables.iter().map(|x| x.name.as_str()))
+            .chain(self.spec.columns.iter().map(|x| x.as_str()))
+            .collect()
+    }
+
+    /// Writes combinations of `output` into `dest`, returns how many have been written.
+    pub async fn export(&self, output: &Path, format: ExportFormat, dest: &Path) -> Result<u64> {
+        let all_columns = self.all_columns();
+        let column = |name: &str| {
+            all_columns.iter().position(|x| *x == name).ok_or_else(|| {
+                anyhow!(
+                    "Unknown column {}, columns are: {}",
+                    name,
+                    all_columns.join(", ")
+                )
+            })
+        };
+        let selected = match self.columns.is_empty() {
+            true => (0..all_columns.len()).collect(),
+            false => self
+                .columns
+                .iter()
+                .map(|x| column(x))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        let mut filters = Vec::with_capacity(self.filters.len());
+        for (name, range) in &self.filters {
+            let i = column(name)?;
+            if i == 0 {
+                bail!("Column {} can't be filtered.", COMBINATION_COLUMN);
+            }
+            filters.push((i, *range));
+        }
+        let n_variables = self.spec.variables.len();
+        // index of an output value in headers
+        let block_filters: Vec<_> = filters
+            .iter()
+            .filter(|(i, _)| *i > n_variables)
+            .map(|(i, range)| (i - n_variables - 1, *range))
+            .collect();
+        let decompression = self.spec.decompression();
+
+        let mut blocks = OutputBlocks::new(open_output(output).await?, self.spec.columns.len());
+        let mut variables = self.spec.variables();
+        let mut rows = self.spec.row_decoder();
+        let names: Vec<_> = selected.iter().map(|x| all_columns[*x]).collect();
+        let mut writer = TableWriter::create(format, dest, &names)?;
+        let mut n_written = 0;
+        loop {
+            let header = match blocks.header().await? {
+                Some(header) => header,
+                None => break,
+            };
+            if header.end_combination_inclusive >= variables.max_combinations() {
+                bail!(
+                    "Output has combination {}, variables have {} combinations.",
+                    header.end_combination_inclusive,
+                    variables.max_combinations()
+                );
+            }
+            if !block_filters
+                .iter()
+                .all(|(i, range)| header.ranges[*i].overlaps(range))
+            {
+                blocks.skip(&header).await?;
+                continue;
+            }
+            let data = blocks.block(&header).await?;
+            let mut table = Table::new(&selected);
+            for row in decode_block(data, decompression, &mut rows).await? {
+                variables.set_combination(row.combination);
+                let value = |i: usize| match i <= n_variables {
+                    true => variables[i - 1].value,
+                    false => row.values[i - n_variables - 1],
+                };
+                if filters.iter().all(|(i, range)| range.contains(&value(*i))) {
+                    table.push(row.combination, &selected, value);
+                }
+            }
+            n_written += table.len() as u64;
+            writer.write(table)?;
+        }
+        writer.finish()?;
+        Ok(n_written)
+    }
+}
+
+enum Column {
+    Combination(Vec<u64>),
+    Values(Vec<f32>),
+}
+
+/// Selected columns of exported combinations.
+struct Table(Vec<Column>);
+
+impl Table {
+    fn new(selected: &[usize]) -> Self {
+        Table(
+            selected
+                .iter()
+                .map(|x| match x {
+                    0 => Column::Combination(Vec::new()),
+                    _ => Column::Values(Vec::new()),
+                })
+                .collect(),
+        )
+    }
+
+    fn push(&mut self, combination: u64, selected: &[usize], value: impl Fn(usize) -> f32) {
+        for (column, i) in self.0.iter_mut().zip(selected) {
+            match column {
+                Column::Combination(x) => x.push(combination),
+                Column::Values(x) => x.push(value(*i)),
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self.0.first() {
+            Some(Column::Combination(x)) => x.len(),
+            Some(Column::Values(x)) => x.len(),
+            None => 0,
+        }
+    }
+
+    #[cfg(feature = "columnar")]
+    fn to_record_batch(&self, schema: &SchemaRef) -> Result<RecordBatch> {
+        let columns = self
+            .0
+            .iter()
+            .map(|x| match x {
+                Column::Combination(x) => Arc::new(UInt64Array::from(x.clone())) as ArrayRef,
+                Column::Values(x) => Arc::new(Float32Array::from(x.clone())) as ArrayRef,
+            })
+            .collect();
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+enum TableWriter {
+    Csv(csv::Writer<File>),
+    #[cfg(feature = "columnar")]
+    Arrow(FileWriter<File>, SchemaRef),
+    #[cfg(feature = "columnar")]
+    Parquet(ArrowWriter<File>, SchemaRef),
+}
+
+impl TableWriter {
+    fn create(format: ExportFormat, path: &Path, names: &[&str]) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("{}", path.display()))?;
+        Ok(match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer.write_record(names)?;
+                TableWriter::Csv(writer)
+            }
+            #[cfg(feature = "columnar")]
+            ExportFormat::Arrow => {
+                let schema = Self::schema(names);
+                TableWriter::Arrow(FileWriter::try_new(file, &schema)?, schema)
+            }
+            #[cfg(feature = "columnar")]
+            ExportFormat::Parquet => {
+                let schema = Self::schema(names);
+                TableWriter::Parquet(ArrowWriter::try_new(file, schema.clone(), None)?, schema)
+            }
+        })
+    }
+
+    #[cfg(feature = "columnar")]
+    fn schema(names: &[&str]) -> SchemaRef {
+        let fields: Vec<_> = names
+            .iter()
+            .map(|&x| match x {
+                COMBINATION_COLUMN => Field::new(x, DataType::UInt64, false),
+                _ => Field::new(x, DataType::Float32, false),
+            })
+            .collect();
+        Arc::new(Schema::new(fields))
+    }
+
+    fn write(&mut self, table: Table) -> Result<()> {
+        if table.len() == 0 {
+            return Ok(());
+        }
+        match self {
+            TableWriter::Csv(writer) => {
+                for row in 0..table.len() {
+                    writer.write_record(table.0.iter().map(|x| match x {
+                        Column::Combination(x) => x[row].to_string(),
+                        Column::Values(x) => x[row].to_string(),
+                    }))?;
+                }
+            }
+            #[cfg(feature = "columnar")]
+            TableWriter::Arrow(writer, schema) => writer.write(&table.to_record_batch(schema)?)?,
+            #[cfg(feature = "columnar")]
+            TableWriter::Parquet(writer, schema) => {
+                writer.write(&table.to_record_batch(schema)?)?
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            TableWriter::Csv(mut writer) => writer.flush()?,
+            #[cfg(feature = "columnar")]
+            TableWriter::Arrow(mut writer, _) => writer.finish()?,
+            #[cfg(feature = "columnar")]
+            TableWriter::Parquet(writer, _) => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod t_export {
+    use async_compression::tokio::write::ZstdEncoder;
+    use merovingian::structs::BatchHeader;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn spec() -> ExportSpec {
+        serde_yaml::from_str(
+            r#"
+variables:
+  - { name: period, min: 10, max: 13, stride: 1 }
+  - { name: level, min: 0, max: 1, stride: 0.5 }
+columns: [profit, drawdown]
+precision: f64
+"#,
+        )
+        .unwrap()
+    }
+
+    /// Combinations 0-3 in the first block and 4-5 in the second one.
+    fn output_data() -> Vec<u8> {
+        let mut output = Vec::new();
+        for combinations in [0..4, 4..6] {
+            let combinations: Vec<u64> = combinations.collect();
+            let mut block = bincode::serialize(&(combinations.len() as u64)).unwrap();
+            let mut profits = Vec::new();
+            for combination in &combinations {
+                let profit = *combination as f64 / 2.;
+                profits.push(profit as f32);
+                bincode::serialize_into(&mut block, combination).unwrap();
+                bincode::serialize_into(&mut block, &[profit, -0.1]).unwrap();
+            }
+            let header = BatchHeader {
+                start_combination: combinations[0],
+                end_combination_inclusive: *combinations.last().unwrap(),
+                block_size: block.len() as u64,
+                n_combinations: combinations.len() as u32,
+                ranges: vec![
+                    RangeInclusive::new(profits[0], *profits.last().unwrap()),
+                    RangeInclusive::new(-0.1, -0.1),
+                ],
+            };
+            bincode::serialize_into(&mut output, &header).unwrap();
+            output.extend(block);
+        }
+        output
+    }
+
+    #[tokio::test]
+    async fn t_export() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("rsi.out");
+        std::fs::write(&output, output_data())?;
+
+        let csv = dir.path().join("rsi.csv");
+        let n = Exporter::new(spec())
+            .export(&output, ExportFormat::Csv, &csv)
+            .await?;
+        assert_eq!(n, 6);
+        assert_eq!(
+            std::fs::read_to_string(&csv)?,
+            "combination,period,level,profit,drawdown\n\
+             0,10,0,0,-0.1\n\
+             1,10,0.5,0.5,-0.1\n\
+             2,11,0,1,-0.1\n\
+             3,11,0.5,1.5,-0.1\n\
+             4,12,0,2,-0.1\n\
+             5,12,0.5,2.5,-0.1\n"
+        );
+
+        let n = Exporter::new(spec())
+            .columns(vec!["profit".into(), "period".into(), "combination".into()])
+            .filter("level", RangeInclusive::new(0.5, 1.))
+            .filter("profit", RangeInclusive::new(2., 10.))
+            .export(&output, ExportFormat::Csv, &csv)
+            .await?;
+        assert_eq!(n, 1);
+        assert_eq!(
+            std::fs::read_to_string(&csv)?,
+            "profit,period,combination\n2.5,12,5\n"
+        );
+        let unknown = Exporter::new(spec())
+            .columns(vec!["sharpe".into()])
+            .export(&output, ExportFormat::Csv, &csv)
+            .await;
+        assert!(unknown.is_err());
+
+        let compressed = dir.path().join("rsi.out.zst");
+        let mut encoder = ZstdEncoder::new(Vec::new());
+        encoder.write_all(&output_data()).await?;
+        encoder.shutdown().await?;
+        std::fs::write(&compressed, encoder.into_inner())?;
+        let n = Exporter::new(spec())
+            .filter("profit", RangeInclusive::new(2., 10.))
+            .export(&compressed, ExportFormat::Csv, &csv)
+            .await?;
+        assert_eq!(n, 2);
+        Ok(())
+    }
+
+    #[cfg(feature = "columnar")]
+    #[tokio::test]
+    async fn t_export_columnar() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("rsi.out");
+        std::fs::write(&output, output_data())?;
+
+        for (format, extension, magic) in [
+            (ExportFormat::Arrow, "arrow", &b"ARROW1"[..]),
+            (ExportFormat::Parquet, "parquet", &b"PAR1"[..]),
+        ] {
+            let dest = dir.path().join(format!("rsi.{}", extension));
+            assert_eq!(ExportFormat::from_path(&dest), Some(format));
+            let n = Exporter::new(spec()).export(&output, format, &dest).await?;
+            assert_eq!(n, 6);
+            let data = std::fs::read(&dest)?;
+            assert!(data.starts_with(magic) && data.ends_with(magic));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t_column_range() -> Result<()> {
+        assert_eq!(
+            "profit=0.1..".parse::<ColumnRange>()?,
+            ColumnRange {
+                name: "profit".into(),
+                range: RangeInclusive::new(0.1, f32::MAX),
+            }
+        );
+        assert_eq!(
+            "level=..-1".parse::<ColumnRange>()?.range,
+            RangeInclusive::new(f32::MIN, -1.)
+        );
+        assert!("profit".parse::<ColumnRange>().is_err());
+        assert!("profit=a..1".parse::<ColumnRange>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_parallel_coordinates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("rsi.out");
+        std::fs::write(&output, output_data())?;
+
+        let mut filter = spec().parallel_coordinates(&output).await?;
+        assert_eq!(filter.n_rows(), 6);
+        let names: Vec<_> = filter.axes().iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["period", "level", "profit", "drawdown"]);
+        assert_eq!(filter.axes()[2].bounds, RangeInclusive::new(0., 2.5));
+        filter.set_brush(1, Some(RangeInclusive::new(0.5, 1.)));
+        filter.set_brush(2, Some(RangeInclusive::new(1., 10.)));
+        assert_eq!(filter.selected_combinations().collect::<Vec<_>>(), [3, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_top_runs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("rsi.out");
+        std::fs::write(&output, output_data())?;
+        let mut spec = spec();
+        spec.columns = vec!["balance".into(), "max_drawdown".into()];
+        spec.model = "rsi".into();
+
+        let runs = spec
+            .top_runs(&output, "balance", 2, report::Rank::Highest)
+            .await?;
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].model, "rsi");
+        assert_eq!(
+            runs[0].variables,
+            [("period".into(), 12.), ("level".into(), 0.5)]
+        );
+        assert_eq!(runs[0].stats.balance, 2.5);
+        assert_eq!(runs[0].stats.max_drawdown, -0.1);
+        assert!(runs[0].stats.sharpe_ratio.is_nan());
+        assert_eq!(runs[1].stats.balance, 2.);
+        assert!(runs[0].equity.is_empty());
+
+        let runs = spec
+            .top_runs(&output, "balance", 1, report::Rank::Lowest)
+            .await?;
+        assert_eq!(runs[0].variables[0], ("period".into(), 10.));
+        assert!(spec
+            .top_runs(&output, "sharpe_ratio", 1, report::Rank::Highest)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -21,11 +21,15 @@ use async_compression::tokio::bufread::ZstdDecoder;
 use mouse::traits::AsyncReadSeek;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
+pub mod downloader;
+pub mod export;
 pub mod loaders;
+pub mod output_query;
 pub mod provider;
 
 #[async_trait]
 pub trait SeekableDecoder {
+    async fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize>;
     async fn read_exact(&mut self, data: &mut [u8]) -> std::io::Result<usize>;
     async fn read_to_end(&mut self, data: &mut Vec<u8>) -> std::io::Result<usize>;
     async fn read_exact_uncompressed(&mut self, data: &mut [u8]) -> std::io::Result<usize>;
@@ -34,6 +38,9 @@ pub trait SeekableDecoder {
 
 #[async_trait]
 impl SeekableDecoder for Box<dyn SeekableDecoder + Sync + Send + 'static> {
+    async fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        self.as_mut().read(data).await
+    }
     async fn read_exact(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
         self.as_mut().read_exact(data).await
     }
@@ -92,9 +99,13 @@ impl<R> Decoder<R> {
 }
 
 #[async_trait]
-impl<R: NestedStream<Inner: AsyncReadSeek + Unpin + Send> + AsyncReadSeek + Unpin + Send>
+impl<R: NestedStream<Inner: AsyncReadSeek + Unpin + Send> + AsyncRead + Unpin + Send>
     SeekableDecoder for Decoder<R>
 {
+    async fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.as_mut().unwrap().read(data).await
+    }
+
     async fn read_exact(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
         self.reader.as_mut().unwrap().read_exact(data).await
     }
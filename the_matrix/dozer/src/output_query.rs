@@ -0,0 +1,564 @@
+//! Random access into optimizer outputs through their sidecar index: fetching a combination, top
+//! combinations by an output value and filtering by ranges of output values. Blocks that can't
+//! contain what is asked for are pruned by their ranges without being read.
+//!
+//! Zstd compressed outputs are read through `Decoder`, seeking restarts decompression at the start
+//! of a frame. Outputs written as a frame per block or per few blocks are fast to query, a single
+//! frame output works as well but every read decompresses it from the start.
+use std::io::{ErrorKind, SeekFrom};
+use std::path::Path;
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use merovingian::compression::DecompressionMethod;
+use merovingian::output_index::{IndexedBlock, OutputIndex};
+use merovingian::structs::{BatchHeader, RangeInclusive};
+use mouse::error::{bail, Result, ResultCtxExt};
+use mouse::log::*;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+
+use crate::{Decoder, SeekableDecoder};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub type BoxedDecoder = Box<dyn SeekableDecoder + Sync + Send + 'static>;
+
+/// Combination and its output values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub combination: u64,
+    pub values: Vec<f32>,
+}
+
+/// Deserializes output values of one combination, blocks store the number of combinations and
+/// then each combination followed by its result.
+pub trait RowDecoder: Send {
+    fn decode(&mut self, reader: &mut &[u8], values: &mut Vec<f32>) -> Result<()>;
+}
+
+impl<F: FnMut(&mut &[u8], &mut Vec<f32>) -> Result<()> + Send> RowDecoder for F {
+    fn decode(&mut self, reader: &mut &[u8], values: &mut Vec<f32>) -> Result<()> {
+        self(reader, values)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rank {
+    Highest,
+    Lowest,
+}
+
+/// Opens an output file, zstd compressed outputs are recognized by their magic number.
+pub async fn open_output(path: &Path) -> Result<BoxedDecoder> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("{}", path.display()))?;
+    let mut magic = [0; 4];
+    let compressed = match file.read_exact(&mut magic).await {
+        Ok(_) => magic == ZSTD_MAGIC,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    file.seek(SeekFrom::Start(0)).await?;
+    let reader = BufReader::new(file);
+    Ok(match compressed {
+        true => Box::new(Decoder::new(ZstdDecoder::new(reader))),
+        false => Box::new(Decoder::new(reader)),
+    })
+}
+
+/// Opens an output with its index, index is built and saved next to the output if it doesn't
+/// exist or is stale, outputs written by `IndexedOutputWriter` are never scanned.
+pub async fn open_indexed<R: RowDecoder>(
+    path: &Path,
+    n_output_params: usize,
+    rows: R,
+) -> Result<OutputQuery<BoxedDecoder, R>> {
+    let decoder = open_output(path).await?;
+    if let Some(index) = OutputIndex::load_for(path)? {
+        return Ok(OutputQuery::new(decoder, index, rows));
+    }
+    info!("Indexing {}...", path.display());
+    let mut query = OutputQuery::scan(decoder, n_output_params, rows).await?;
+    if let Err(e) = query.index.save_for(path) {
+        warn!("Index of {} not saved: {:#}", path.display(), e);
+    }
+    Ok(query)
+}
+
+pub struct OutputQuery<D, R> {
+    index: OutputIndex,
+    reader: BlockReader<D, R>,
+}
+
+impl<D: SeekableDecoder + Send, R: RowDecoder> OutputQuery<D, R> {
+    pub fn new(decoder: D, index: OutputIndex, rows: R) -> Self {
+        Self {
+            index,
+            reader: BlockReader {
+                stream: Stream::new(decoder),
+                rows,
+                decompression: DecompressionMethod::None,
+            },
+        }
+    }
+
+    /// Reads all headers of an output to build its index.
+    pub async fn scan(decoder: D, n_output_params: usize, rows: R) -> Result<Self> {
+        let mut stream = Stream::new(decoder);
+        let mut index = OutputIndex::new(n_output_params);
+        let mut buf = vec![0; header_size(n_output_params)];
+        while stream.read(&mut buf).await? {
+            let header: BatchHeader = bincode::deserialize(&buf).context("Output is corrupted")?;
+            index.push(&header, stream.offset, stream.pos)?;
+            stream.discard(header.block_size).await?;
+        }
+        for block in &mut index.blocks {
+            if stream.plain_frames.contains(&block.offset) {
+                block.offset += block.skip;
+                block.skip = 0;
+            }
+        }
+        Ok(Self {
+            index,
+            reader: BlockReader {
+                stream,
+                rows,
+                decompression: DecompressionMethod::None,
+            },
+        })
+    }
+
+    /// How blocks are compressed on their own, independent of compression of the whole output.
+    pub fn decompression(mut self, decompression: DecompressionMethod) -> Self {
+        self.reader.decompression = decompression;
+        self
+    }
+
+    pub fn index(&self) -> &OutputIndex {
+        &self.index
+    }
+
+    /// `None` if combination isn't in the output.
+    pub async fn combination(&mut self, combination: u64) -> Result<Option<Row>> {
+        let block = match self.index.find(combination) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        Ok(self
+            .reader
+            .read(block)
+            .await?
+            .into_iter()
+            .find(|x| x.combination == combination))
+    }
+
+    /// `k` combinations with the highest or lowest value of `column`, best first. Blocks are read
+    /// from the one with the best range until no block can contain a better value.
+    pub async fn top_k(&mut self, column: usize, k: usize, rank: Rank) -> Result<Vec<Row>> {
+        self.check_column(column)?;
+        // ranked by score, higher is better
+        let score = |x: f32| match rank {
+            Rank::Highest => x,
+            Rank::Lowest => -x,
+        };
+        let best_score = |block: &IndexedBlock| match rank {
+            Rank::Highest => block.ranges[column].end,
+            Rank::Lowest => -block.ranges[column].start,
+        };
+        let mut blocks: Vec<_> = self.index.blocks.iter().collect();
+        blocks.sort_by(|a, b| {
+            best_score(b)
+                .partial_cmp(&best_score(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let mut top: Vec<(f32, Row)> = Vec::with_capacity(k + 1);
+        for block in blocks {
+            if top.len() == k && top[k - 1].0 >= best_score(block) {
+                break;
+            }
+            for row in self.reader.read(block).await? {
+                let score = match row.values.get(column) {
+                    Some(x) if !x.is_nan() => score(*x),
+                    _ => continue,
+                };
+                if top.len() == k && score <= top[k - 1].0 {
+                    continue;
+                }
+                let i = top.iter().position(|x| x.0 < score).unwrap_or(top.len());
+                top.insert(i, (score, row));
+                top.truncate(k);
+            }
+        }
+        Ok(top.into_iter().map(|x| x.1).collect())
+    }
+
+    /// Combinations with values in all of the ranges, `ranges` are pairs of column and range.
+    pub async fn filter(&mut self, ranges: &[(usize, RangeInclusive<f32>)]) -> Result<Vec<Row>> {
+        for (column, _) in ranges {
+            self.check_column(*column)?;
+        }
+        let mut rows = Vec::new();
+        for block in self.index.blocks.iter().filter(|x| x.may_contain(ranges)) {
+            rows.extend(self.reader.read(block).await?.into_iter().filter(|row| {
+                ranges
+                    .iter()
+                    .all(|(i, range)| row.values.get(*i).map_or(false, |x| range.contains(x)))
+            }));
+        }
+        Ok(rows)
+    }
+
+    fn check_column(&self, column: usize) -> Result<()> {
+        if column >= self.index.n_output_params {
+            bail!(
+                "Output has {} columns, got column {}.",
+                self.index.n_output_params,
+                column
+            );
+        }
+        Ok(())
+    }
+}
+
+struct BlockReader<D, R> {
+    stream: Stream<D>,
+    rows: R,
+    decompression: DecompressionMethod,
+}
+
+impl<D: SeekableDecoder + Send, R: RowDecoder> BlockReader<D, R> {
+    async fn read(&mut self, block: &IndexedBlock) -> Result<Vec<Row>> {
+        self.stream.seek(block.offset, block.skip).await?;
+        let mut data = vec![0; block.block_size as usize];
+        if !self.stream.read(&mut data).await? {
+            bail!(
+                "Output ends before block of combinations {}-{}.",
+                block.start_combination,
+                block.end_combination_inclusive
+            );
+        }
+        decode_block(data, self.decompression, &mut self.rows).await
+    }
+}
+
+/// Reads headers and blocks of an output one after another from its start.
+pub(crate) struct OutputBlocks<D> {
+    stream: Stream<D>,
+    header: Vec<u8>,
+}
+
+impl<D: SeekableDecoder + Send> OutputBlocks<D> {
+    pub(crate) fn new(decoder: D, n_output_params: usize) -> Self {
+        Self {
+            stream: Stream::new(decoder),
+            header: vec![0; header_size(n_output_params)],
+        }
+    }
+
+    /// `None` if the output has ended.
+    pub(crate) async fn header(&mut self) -> Result<Option<BatchHeader>> {
+        if !self.stream.read(&mut self.header).await? {
+            return Ok(None);
+        }
+        Ok(Some(
+            bincode::deserialize(&self.header).context("Output is corrupted")?,
+        ))
+    }
+
+    pub(crate) async fn skip(&mut self, header: &BatchHeader) -> Result<()> {
+        Ok(self.stream.discard(header.block_size).await?)
+    }
+
+    pub(crate) async fn block(&mut self, header: &BatchHeader) -> Result<Vec<u8>> {
+        let mut data = vec![0; header.block_size as usize];
+        if !self.stream.read(&mut data).await? {
+            bail!(
+                "Output ends before block of combinations {}-{}.",
+                header.start_combination,
+                header.end_combination_inclusive
+            );
+        }
+        Ok(data)
+    }
+}
+
+/// Rows of a block as it has been read from an output.
+pub(crate) async fn decode_block(
+    data: Vec<u8>,
+    decompression: DecompressionMethod,
+    rows: &mut impl RowDecoder,
+) -> Result<Vec<Row>> {
+    let data = match decompression {
+        DecompressionMethod::None => data,
+        DecompressionMethod::Zstd => {
+            let mut decompressed = Vec::new();
+            ZstdDecoder::new(&data[..])
+                .read_to_end(&mut decompressed)
+                .await?;
+            decompressed
+        }
+        _ => bail!("Unsupported decompression method."),
+    };
+    let mut reader = &data[..];
+    let n_rows: u64 = bincode::deserialize_from(&mut reader).context("Block is corrupted")?;
+    let mut decoded = Vec::with_capacity(n_rows as usize);
+    for _ in 0..n_rows {
+        let combination = bincode::deserialize_from(&mut reader).context("Block is corrupted")?;
+        let mut values = Vec::new();
+        rows.decode(&mut reader, &mut values)?;
+        decoded.push(Row {
+            combination,
+            values,
+        });
+    }
+    Ok(decoded)
+}
+
+/// Decompressed content of an output that keeps track of the frame it is reading.
+struct Stream<D> {
+    decoder: D,
+    /// Position of the current frame in the file.
+    offset: u64,
+    /// Decompressed bytes read from the current frame.
+    pos: u64,
+    /// Frames that turned out to be stored as is, for uncompressed outputs it is the whole file.
+    plain_frames: Vec<u64>,
+}
+
+impl<D: SeekableDecoder + Send> Stream<D> {
+    fn new(decoder: D) -> Self {
+        Self {
+            decoder,
+            offset: 0,
+            pos: 0,
+            plain_frames: Vec::new(),
+        }
+    }
+
+    /// Positions the stream `skip` decompressed bytes after the frame at `offset`, reading
+    /// continues without seeking if the position is further in the current frame.
+    async fn seek(&mut self, offset: u64, skip: u64) -> std::io::Result<()> {
+        if offset != self.offset || skip < self.pos {
+            self.decoder.seek(SeekFrom::Start(offset)).await?;
+            self.offset = offset;
+            self.pos = 0;
+        }
+        self.discard(skip - self.pos).await
+    }
+
+    async fn discard(&mut self, mut n: u64) -> std::io::Result<()> {
+        let mut buf = vec![0; n.min(1 << 16) as usize];
+        while n > 0 {
+            let len = n.min(buf.len() as u64) as usize;
+            if !self.read(&mut buf[..len]).await? {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            n -= len as u64;
+        }
+        Ok(())
+    }
+
+    /// Fills `data` continuing with the next frame when one ends, false if the output has ended.
+    async fn read(&mut self, data: &mut [u8]) -> std::io::Result<bool> {
+        let mut filled = 0;
+        while filled < data.len() {
+            let len = self.decoder.read(&mut data[filled..]).await?;
+            if len != 0 {
+                filled += len;
+                self.pos += len as u64;
+                continue;
+            }
+            // decoder stops at the end of a frame, seeking restarts it at the next one
+            let next = self.decoder.seek(SeekFrom::Current(0)).await?;
+            if next == self.offset && self.pos == 0 {
+                return match filled {
+                    0 => Ok(false),
+                    _ => Err(ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            if next - self.offset == self.pos {
+                self.plain_frames.push(self.offset);
+            }
+            self.offset = next;
+            self.pos = 0;
+        }
+        Ok(true)
+    }
+}
+
+fn header_size(n_output_params: usize) -> usize {
+    bincode::serialized_size(&BatchHeader {
+        ranges: vec![Default::default(); n_output_params],
+        ..Default::default()
+    })
+    .unwrap() as usize
+}
+
+#[cfg(test)]
+mod t_output_query {
+    use async_compression::tokio::write::ZstdEncoder;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    const N_OUTPUT_PARAMS: usize = 2;
+    const BLOCK_LEN: u64 = 10;
+    const N_BLOCKS: u64 = 5;
+
+    /// Output values of a combination, second value is high in the middle of the output.
+    fn values(combination: u64) -> Vec<f32> {
+        let x = combination as f32;
+        vec![x, -(x - 23.).abs()]
+    }
+
+    fn decode(reader: &mut &[u8], values: &mut Vec<f32>) -> Result<()> {
+        let result: [f32; N_OUTPUT_PARAMS] = bincode::deserialize_from(reader)?;
+        values.extend_from_slice(&result);
+        Ok(())
+    }
+
+    /// Headers and blocks of an output.
+    fn blocks() -> Vec<Vec<u8>> {
+        (0..N_BLOCKS)
+            .map(|i| {
+                let combinations: Vec<_> = (i * BLOCK_LEN..(i + 1) * BLOCK_LEN)
+                    .filter(|x| x % 7 != 0)
+                    .collect();
+                let mut block = bincode::serialize(&(combinations.len() as u64)).unwrap();
+                for combination in &combinations {
+                    let values = values(*combination);
+                    bincode::serialize_into(&mut block, combination).unwrap();
+                    bincode::serialize_into(&mut block, &[values[0], values[1]]).unwrap();
+                }
+                let mut ranges = vec![RangeInclusive::new(f32::MAX, f32::MIN); N_OUTPUT_PARAMS];
+                for combination in &combinations {
+                    for (range, x) in ranges.iter_mut().zip(values(*combination)) {
+                        range.start = range.start.min(x);
+                        range.end = range.end.max(x);
+                    }
+                }
+                let header = BatchHeader {
+                    start_combination: i * BLOCK_LEN,
+                    end_combination_inclusive: (i + 1) * BLOCK_LEN - 1,
+                    block_size: block.len() as u64,
+                    n_combinations: combinations.len() as u32,
+                    ranges,
+                };
+                let mut data = bincode::serialize(&header).unwrap();
+                data.extend(block);
+                data
+            })
+            .collect()
+    }
+
+    async fn zstd(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZstdEncoder::new(Vec::new());
+        encoder.write_all(data).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    async fn check(path: &Path) -> Result<()> {
+        let mut query = open_indexed(path, N_OUTPUT_PARAMS, decode).await?;
+        assert!(OutputIndex::load_for(path)?.is_some());
+        assert_eq!(query.index().blocks.len(), N_BLOCKS as usize);
+        assert_eq!(query.index().n_combinations(), 42);
+        assert_eq!(
+            query.combination(23).await?,
+            Some(Row {
+                combination: 23,
+                values: values(23),
+            })
+        );
+        assert_eq!(query.combination(21).await?, None);
+        assert_eq!(query.combination(4).await?.unwrap().values, values(4));
+        assert_eq!(query.combination(50).await?, None);
+
+        let top: Vec<_> = query
+            .top_k(1, 3, Rank::Highest)
+            .await?
+            .into_iter()
+            .map(|x| x.combination)
+            .collect();
+        assert_eq!(top, vec![23, 22, 24]);
+        let bottom: Vec<_> = query
+            .top_k(0, 2, Rank::Lowest)
+            .await?
+            .into_iter()
+            .map(|x| x.combination)
+            .collect();
+        assert_eq!(bottom, vec![1, 2]);
+        assert!(query.top_k(2, 1, Rank::Highest).await.is_err());
+
+        let filtered: Vec<_> = query
+            .filter(&[
+                (0, RangeInclusive::new(10., 39.)),
+                (1, RangeInclusive::new(-3., 0.)),
+            ])
+            .await?
+            .into_iter()
+            .map(|x| x.combination)
+            .collect();
+        assert_eq!(filtered, vec![20, 22, 23, 24, 25, 26]);
+
+        // loaded index gives the same results
+        let mut query = open_indexed(path, N_OUTPUT_PARAMS, decode).await?;
+        assert_eq!(query.combination(48).await?.unwrap().values, values(48));
+        assert_eq!(query.combination(13).await?.unwrap().values, values(13));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_output_query() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let blocks = blocks();
+
+        let plain = dir.path().join("plain.out");
+        std::fs::write(&plain, blocks.concat())?;
+        check(&plain).await?;
+        // blocks are read directly from their offsets
+        let index = OutputIndex::load_for(&plain)?.unwrap();
+        assert!(index.blocks.iter().all(|x| x.skip == 0));
+        assert_eq!(
+            index.blocks[1].offset,
+            (blocks[0].len() + header_size(N_OUTPUT_PARAMS)) as u64
+        );
+
+        // frames end in the middle of blocks
+        let mut framed = Vec::new();
+        let concat = blocks.concat();
+        for chunk in concat.chunks(concat.len() / 3 + 1) {
+            framed.extend(zstd(chunk).await);
+        }
+        let framed_path = dir.path().join("framed.out");
+        std::fs::write(&framed_path, framed)?;
+        check(&framed_path).await?;
+        let index = OutputIndex::load_for(&framed_path)?.unwrap();
+        assert!(index.blocks.iter().any(|x| x.offset != 0 && x.skip != 0));
+
+        let single = dir.path().join("single.out");
+        std::fs::write(&single, zstd(&concat).await)?;
+        check(&single).await?;
+
+        // blocks compressed on their own
+        let mut compressed = Vec::new();
+        for block in &blocks {
+            let mut header: BatchHeader = bincode::deserialize(block)?;
+            let data = zstd(&block[header_size(N_OUTPUT_PARAMS)..]).await;
+            header.block_size = data.len() as u64;
+            compressed.extend(bincode::serialize(&header)?);
+            compressed.extend(data);
+        }
+        let compressed_path = dir.path().join("compressed_blocks.out");
+        std::fs::write(&compressed_path, compressed)?;
+        let mut query = open_indexed(&compressed_path, N_OUTPUT_PARAMS, decode)
+            .await?
+            .decompression(DecompressionMethod::Zstd);
+        assert_eq!(query.combination(33).await?.unwrap().values, values(33));
+        Ok(())
+    }
+}
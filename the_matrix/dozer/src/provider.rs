@@ -1,5 +1,7 @@
 use mouse::error::Result;
+mod funding_provider;
 mod hlcv_provider;
+pub use funding_provider::*;
 pub use hlcv_provider::*;
 
 #[async_trait]
@@ -0,0 +1,255 @@
+//! Funding history of perpetual contracts. It is cached next to hlcv in
+//! `<cache_dir>/<exchange>/funding/<market>.bin`, only ranges that aren't cached yet are downloaded.
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, TimeZone, Utc};
+use config::CONFIG;
+use futures_util::StreamExt;
+use merovingian::minable_models::Funding;
+use mouse::error::Result;
+use mouse::log::*;
+use mouse::num::rust_decimal::prelude::ToPrimitive;
+use mouse::time::{IntoDateTime, Timestamp};
+use nebuchadnezzar::core::client::SuperClient;
+use nebuchadnezzar::core::paginators::WhileSuperPaginator;
+use nebuchadnezzar::core::requests::FundingGetRequest;
+use speedy::{Readable, Writable};
+
+use super::hlcv_provider::new_client;
+
+#[derive(Clone, Debug, PartialEq, Readable, Writable)]
+pub struct FundingHistory {
+    pub start_ts: u32,
+    /// Excluding.
+    pub end_ts: u32,
+    /// Sorted by timestamp.
+    pub funding: Vec<Funding>,
+}
+
+impl FundingHistory {
+    pub async fn load(path: impl AsRef<Path>) -> Result<Option<FundingHistory>> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(FundingHistory::read_from_buffer(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes to a temporary file first so that a crash never leaves a corrupted cache.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, self.write_to_vec()?).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    pub fn contains(&self, start_ts: u32, end_ts: u32) -> bool {
+        self.start_ts <= start_ts && end_ts <= self.end_ts
+    }
+
+    /// Funding with timestamp in [start_ts, end_ts).
+    pub fn range(&self, start_ts: u32, end_ts: u32) -> &[Funding] {
+        let start = self.funding.partition_point(|x| x.timestamp_s < start_ts);
+        let end = self.funding.partition_point(|x| x.timestamp_s < end_ts);
+        &self.funding[start..end]
+    }
+
+    /// Extends covered range with funding downloaded for [start_ts, end_ts), ranges must touch or
+    /// overlap.
+    pub fn merge(&mut self, start_ts: u32, end_ts: u32, funding: Vec<Funding>) {
+        self.funding.extend(funding);
+        self.funding.sort_by_key(|x| x.timestamp_s);
+        self.funding.dedup_by_key(|x| x.timestamp_s);
+        self.start_ts = self.start_ts.min(start_ts);
+        self.end_ts = self.end_ts.max(end_ts);
+    }
+}
+
+/// Returns funding with timestamp in [start_ts, end_ts), oldest first.
+pub async fn load_funding(
+    exchange: &str,
+    market: &str,
+    start_ts: u32,
+    end_ts: u32,
+) -> Result<Vec<Funding>> {
+    let path = funding_path(exchange, market);
+    // Funding that hasn't happened yet is downloaded once it has.
+    let covered_end_ts = end_ts.min(Utc::now().timestamp() as u32);
+    let mut history = match FundingHistory::load(&path).await? {
+        Some(history) if history.contains(start_ts, covered_end_ts) => {
+            return Ok(history.range(start_ts, end_ts).to_vec());
+        }
+        Some(history) => history,
+        None => FundingHistory {
+            start_ts,
+            end_ts: start_ts,
+            funding: Vec::new(),
+        },
+    };
+    info!("Loading funding...");
+    let client = new_client(exchange)?;
+    if start_ts < history.start_ts {
+        let funding = fetch_funding(&*client, market, start_ts, history.start_ts).await?;
+        history.merge(start_ts, history.start_ts, funding);
+    }
+    if covered_end_ts > history.end_ts {
+        let funding = fetch_funding(&*client, market, history.end_ts, covered_end_ts).await?;
+        history.merge(history.end_ts, covered_end_ts, funding);
+    }
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    history.save(&path).await?;
+    info!("Loading funding...DONE");
+    Ok(history.range(start_ts, end_ts).to_vec())
+}
+
+/// Returns cached funding with timestamp in [start_ts, end_ts) without downloading anything. It is
+/// empty when the market has no funding cached, e.g. because the exchange has none.
+pub async fn load_cached_funding(
+    exchange: &str,
+    market: &str,
+    start_ts: u32,
+    end_ts: u32,
+) -> Result<Vec<Funding>> {
+    read_cached_funding(funding_path(exchange, market), market, start_ts, end_ts).await
+}
+
+async fn read_cached_funding(
+    path: impl AsRef<Path>,
+    market: &str,
+    start_ts: u32,
+    end_ts: u32,
+) -> Result<Vec<Funding>> {
+    let history = match FundingHistory::load(path).await? {
+        Some(history) => history,
+        None => {
+            debug!("No funding of {} is cached.", market);
+            return Ok(Vec::new());
+        }
+    };
+    if !history.contains(start_ts, end_ts) {
+        warn!(
+            "Funding of {} is cached only from {} to {}.",
+            market,
+            history.start_ts.into_date_time(),
+            history.end_ts.into_date_time()
+        );
+    }
+    Ok(history.range(start_ts, end_ts).to_vec())
+}
+
+fn funding_path(exchange: &str, market: &str) -> PathBuf {
+    let mut path = CONFIG.cache_dir.join(exchange).join("funding").join(market);
+    path.set_extension("bin");
+    path
+}
+
+async fn fetch_funding(
+    client: &dyn SuperClient,
+    market: &str,
+    start_ts: u32,
+    end_ts: u32,
+) -> Result<Vec<Funding>> {
+    debug!(
+        "Fetching funding of {} from {} to {}",
+        market,
+        start_ts.into_date_time(),
+        end_ts.into_date_time()
+    );
+    let end_date_time = Utc.timestamp(end_ts as i64, 0);
+    let paginator = Box::pin(WhileSuperPaginator::new(
+        Ok(FundingGetRequest {
+            symbol: market.to_string(),
+            count: None,
+            start_time: Some(Utc.timestamp(start_ts as i64, 0)),
+            end_time: Some(end_date_time),
+        }),
+        move |result, max_count| match result {
+            Ok(response) => {
+                let last_ts = response.last()?.timestamp;
+                if last_ts >= end_date_time {
+                    return None;
+                }
+                Some(Ok(FundingGetRequest {
+                    symbol: market.to_string(),
+                    count: Some(max_count),
+                    // funding happens at most once per second
+                    start_time: Some(last_ts + Duration::seconds(1)),
+                    end_time: Some(end_date_time),
+                }))
+            }
+            Err(_) => None,
+        },
+    ));
+    let mut stream = client.paginate_funding(paginator);
+    let mut funding = Vec::new();
+    while let Some(result) = stream.next().await {
+        for item in result? {
+            let timestamp_s = item.timestamp.timestamp_s();
+            if timestamp_s < start_ts || timestamp_s >= end_ts {
+                continue;
+            }
+            funding.push(Funding {
+                rate: item.rate.to_f32().unwrap(),
+                daily_rate: item.daily_rate.to_f32().unwrap(),
+                timestamp_s,
+            });
+        }
+    }
+    Ok(funding)
+}
+
+#[cfg(test)]
+mod t_funding_provider {
+    use super::*;
+
+    fn funding(timestamp_s: u32) -> Funding {
+        Funding {
+            rate: 0.0001,
+            daily_rate: 0.0003,
+            timestamp_s,
+        }
+    }
+
+    #[test]
+    fn t_merge_and_range() -> Result<()> {
+        let mut history = FundingHistory {
+            start_ts: 100,
+            end_ts: 200,
+            funding: vec![funding(120), funding(160)],
+        };
+        history.merge(200, 300, vec![funding(240), funding(280)]);
+        history.merge(50, 120, vec![funding(80), funding(120)]);
+        let history = FundingHistory::read_from_buffer(&history.write_to_vec()?)?;
+        assert_eq!((history.start_ts, history.end_ts), (50, 300));
+        let timestamps: Vec<_> = history.funding.iter().map(|x| x.timestamp_s).collect();
+        assert_eq!(timestamps, vec![80, 120, 160, 240, 280]);
+        assert!(history.contains(50, 300));
+        assert!(!history.contains(50, 301));
+        let range: Vec<_> = history
+            .range(120, 240)
+            .iter()
+            .map(|x| x.timestamp_s)
+            .collect();
+        assert_eq!(range, vec![120, 160]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_read_cached_funding() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("XBTUSD.bin");
+        let funding = read_cached_funding(&path, "XBTUSD", 0, 300).await?;
+        assert!(funding.is_empty());
+        let history = FundingHistory {
+            start_ts: 100,
+            end_ts: 200,
+            funding: vec![funding(120), funding(160)],
+        };
+        history.save(&path).await?;
+        // Range that is only partly cached returns what is cached.
+        let funding = read_cached_funding(&path, "XBTUSD", 150, 300).await?;
+        assert_eq!(funding, vec![history.funding[1].clone()]);
+        Ok(())
+    }
+}
@@ -1,10 +1,9 @@
 use std::borrow::{Borrow, BorrowMut};
 use std::cell::{Cell, RefCell};
-use std::hint::unreachable_unchecked;
 use std::io::{Read, SeekFrom, Write};
 use std::path::PathBuf;
 
-use chrono::{DateTime, Datelike, Duration, Timelike};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
 use config::{get_exchange_config, CONFIG};
 // use db::DB;
 use futures_util::StreamExt;
@@ -22,7 +21,9 @@ use mouse::helpers::{
 use mouse::num::rust_decimal::prelude::ToPrimitive;
 use mouse::num::NumExt;
 use mouse::prelude::*;
+use mouse::throw;
 use mouse::time::{IntoDateTime, Timestamp};
+use nebuchadnezzar::core::client::SuperClient;
 use nebuchadnezzar::core::paginators::WhileSuperPaginator;
 use nebuchadnezzar::core::requests::TradesGetRequest;
 use nebuchadnezzar::core::Credentials;
@@ -32,7 +33,12 @@ use tokio::io::{
     AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
 };
 
+use super::funding_provider::load_funding;
+use crate::downloader::{Chunk, ChunkSummary, Downloader, Manifest};
+
 const BLOCK_SIZE: usize = 128 * 1024;
+// one day per downloaded chunk
+const HLCV_CHUNK_LEN: u32 = 24 * 60 * 60;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -171,6 +177,10 @@ pub async fn load_hlcv(
         hlcv = load_hlcv_no_file(exchange, market, start_ts, end_ts, path).await?;
     }
     info!("Loading hlcv...DONE");
+    // Cached along with hlcv so that backtests can apply funding offline.
+    if let Err(e) = load_funding(exchange, market, start_ts, end_ts).await {
+        warn!("Funding of {} isn't cached: {:#}", market, e);
+    }
 
     Ok(hlcv)
 }
@@ -241,20 +251,40 @@ async fn append_hlcv(
     writer: &mut (impl AsyncWrite + AsyncSeek + Unpin),
     first_trade_ts: &mut u32,
 ) -> Result<()> {
-    // trace!("write");
-    // let total_ts = (end_ts - start_ts) as f32;
-    let mut tmp_path = CONFIG
-        .cache_dir
-        .join(exchange_name)
-        .join("hlcv")
-        .join(market);
-    tmp_path.set_extension("tmp");
+    let client = new_client(exchange_name)?;
+    // interrupted download is continued from the last completed chunk
+    let downloader = Downloader::new(
+        CONFIG
+            .cache_dir
+            .join(exchange_name)
+            .join("hlcv")
+            .join(format!("{}_download", market)),
+    );
+    let mut manifest = Manifest::load_or_create(
+        downloader.manifest_path(),
+        exchange_name,
+        market,
+        "hlcv",
+        start_ts,
+        end_ts,
+        HLCV_CHUNK_LEN,
+    )
+    .await?;
     info!("Fetching public trades...");
-    let mut tmp = BufWriter::new(open_rwc_async(&tmp_path).await?);
-    // set file length just in case if there was process tremination and file was left
-    // if the file is bigger than current requested file then we have copied what we shouldn't
-    tmp.get_mut().set_len(0).await?;
+    let client = &*client;
+    downloader
+        .download(&mut manifest, move |chunk, path| {
+            download_hlcv_chunk(client, market, chunk, path)
+        })
+        .await?;
+    *first_trade_ts = merge_hlcv_chunks(&downloader, &manifest, writer).await?;
+    writer.seek(SeekFrom::Start(4)).await?;
+    writer.write_all(end_ts.as_u8_slice()).await?;
+    downloader.remove().await?;
+    Ok(())
+}
 
+pub(super) fn new_client(exchange_name: &str) -> Result<Box<dyn SuperClient>> {
     let mut client = nebuchadnezzar::exchanges()
         .into_iter()
         .find(|x| x.name() == exchange_name)
@@ -263,125 +293,238 @@ async fn append_hlcv(
     if let Some(config) = get_exchange_config() {
         if !config.api_key.is_empty() {
             info!("Authenticating...");
-            client.authenticate(Credentials::new(&config.api_key, &config.api_secret))?;
+            client.authenticate(Credentials::new(
+                config.api_key.expose(),
+                config.api_secret.expose(),
+            ))?;
         }
     }
-    let mut date_time = start_ts.into_date_time();
+    Ok(client)
+}
+
+/// hlcv at `ts` contains trades in (ts - 1, ts]
+fn hlcv_ts(trade_ts: &DateTime<Utc>) -> i64 {
+    trade_ts.timestamp() + (trade_ts.timestamp_subsec_nanos() != 0) as i64
+}
+
+/// Writes hlcv for every second from the first trade in chunk until the end of chunk.
+async fn download_hlcv_chunk(
+    client: &dyn SuperClient,
+    market: &str,
+    chunk: Chunk,
+    path: PathBuf,
+) -> Result<ChunkSummary> {
+    let mut tmp = BufWriter::with_capacity(BLOCK_SIZE, open_rwc_async(&path).await?);
+    // previous attempt could have left partially written file
+    tmp.get_mut().set_len(0).await?;
+    let end_ts = chunk.end_ts as i64;
+    let end_date_time = Utc.timestamp(end_ts, 0);
+    let mut date_time = Utc.timestamp(chunk.start_ts as i64 - 1, 0);
     let mut offset = 0;
-    // Safety: do not spawn a task
-    let end_date_time = end_ts.into_date_time();
     let paginator = Box::pin(WhileSuperPaginator::new(
         Ok(TradesGetRequest {
             symbol: market.to_string(),
-            count: Some(1),
+            count: None,
             offset: None,
             start_time: Some(date_time),
-            end_time: None,
+            end_time: Some(end_date_time),
         }),
-        |result, max_count| match result {
+        move |result, max_count| match result {
             Ok(response) => {
-                if response.is_empty() {
-                    None
+                let last_ts = response.last()?.timestamp;
+                if last_ts >= end_date_time {
+                    return None;
+                }
+                if last_ts != date_time {
+                    date_time = last_ts;
+                    offset = response
+                        .iter()
+                        .rev()
+                        .filter(|x| x.timestamp == date_time)
+                        .count() as i32;
                 } else {
-                    // info!("{}%", (end_ts - date_time.timestamp_s()) as f32 / total_ts);
-                    let last_ts = response.last().unwrap().timestamp;
-                    if last_ts >= *end_date_time.borrow() {
-                        // debug!("{:#?}", response);
-                        return None;
-                    }
-                    if last_ts != date_time {
-                        date_time = last_ts;
-                        offset = response
-                            .iter()
-                            .rev()
-                            .filter(|x| x.timestamp == date_time)
-                            .count() as i32;
-                    } else {
-                        offset += response.len() as i32;
-                    }
-                    Some(Ok(TradesGetRequest {
-                        symbol: market.to_string(),
-                        count: Some(max_count),
-                        offset: Some(offset),
-                        start_time: Some(date_time),
-                        end_time: None,
-                    }))
+                    offset += response.len() as i32;
                 }
+                Some(Ok(TradesGetRequest {
+                    symbol: market.to_string(),
+                    count: Some(max_count),
+                    offset: Some(offset),
+                    start_time: Some(date_time),
+                    end_time: Some(end_date_time),
+                }))
             }
             Err(_) => None,
         },
     ));
     let mut stream = client.paginate_trades(paginator);
-    let mut trades = stream.next().await.unwrap()?.into_iter();
-    let mut first_trade = trades.next().unwrap();
-    let mut hlcv = Hlcv {
-        high: first_trade.price.to_f32().unwrap(),
-        low: first_trade.price.to_f32().unwrap(),
-        close: first_trade.price.to_f32().unwrap(),
-        volume: first_trade.amount.to_f32().unwrap(),
-    };
-    let slice = unsafe { object_as_slice(&hlcv, hlcv.size_of_val()) };
-    if first_trade.timestamp.nanosecond() != 0 {
-        first_trade.timestamp = first_trade
-            .timestamp
-            .with_nanosecond(0)
-            .unwrap()
-            .with_second(1)
-            .unwrap();
-    }
-    // debug!("{:#?}", end_date_time.borrow());
-    let mut ts = first_trade.timestamp;
-    loop {
-        match trades.next() {
-            None => match stream.next().await {
-                None => break,
-                Some(result) => trades = result?.into_iter(),
-            },
-            Some(trade) if trade.timestamp > ts => {
-                // debug!("{:#?}", trade);
-                let mut duration = trade.timestamp - ts;
-                if duration.num_milliseconds() % 1000 != 0 {
-                    duration = duration + Duration::seconds(1)
+    let mut hlcv: Option<Hlcv> = None;
+    let mut ts = 0;
+    let mut first_ts = chunk.end_ts;
+    let mut items = 0;
+    'pages: while let Some(result) = stream.next().await {
+        for trade in result? {
+            let trade_ts = hlcv_ts(&trade.timestamp);
+            if trade_ts < chunk.start_ts as i64 {
+                continue;
+            }
+            if trade_ts >= end_ts {
+                break 'pages;
+            }
+            let price = trade.price.to_f32().unwrap();
+            let amount = trade.amount.to_f32().unwrap();
+            match &mut hlcv {
+                Some(hlcv) if trade_ts == ts => {
+                    hlcv.close = price;
+                    hlcv.high.max_mut(price);
+                    hlcv.low.min_mut(price);
+                    hlcv.volume += amount;
+                    continue;
                 }
-                // trace!("filling {}", duration.num_seconds() + 1);
-                // filling gapse between hlcv
-                tmp.write_all(slice).await?;
-                hlcv.volume = 0.;
-                for _ in 1..duration.num_seconds() + 1 {
-                    // trace!("executed");
-                    // zstd with level 4 is the fastest
-                    tmp.write_all(slice).await?;
+                Some(hlcv) => {
+                    // filling gaps between hlcv
+                    tmp.write_all(hlcv.as_u8_slice()).await?;
+                    hlcv.volume = 0.;
+                    for _ in ts + 1..trade_ts {
+                        tmp.write_all(hlcv.as_u8_slice()).await?;
+                    }
+                    items += (trade_ts - ts) as u64;
                 }
-                if trade.timestamp >= *end_date_time.borrow() {
-                    break;
+                None => first_ts = trade_ts as u32,
+            }
+            hlcv = Some(Hlcv {
+                high: price,
+                low: price,
+                close: price,
+                volume: amount,
+            });
+            ts = trade_ts;
+        }
+    }
+    if let Some(mut hlcv) = hlcv {
+        tmp.write_all(hlcv.as_u8_slice()).await?;
+        hlcv.volume = 0.;
+        for _ in ts + 1..end_ts {
+            tmp.write_all(hlcv.as_u8_slice()).await?;
+        }
+        items += (end_ts - ts) as u64;
+    }
+    tmp.shutdown().await?;
+    Ok(ChunkSummary { first_ts, items })
+}
+
+/// Copies downloaded chunks into `writer`. Every chunk only contains hlcv from its first trade, so
+/// the time before it is filled with the last hlcv of previous chunks. Returns timestamp of the
+/// first hlcv.
+async fn merge_hlcv_chunks(
+    downloader: &Downloader,
+    manifest: &Manifest,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<u32> {
+    manifest.verify()?;
+    let mut first_ts = None;
+    let mut last: Option<Hlcv> = None;
+    for chunk in &manifest.chunks {
+        if chunk.items == 0 {
+            if let Some(last) = &last {
+                for _ in chunk.start_ts..chunk.end_ts {
+                    writer.write_all(last.as_u8_slice()).await?;
                 }
-                hlcv.high = trade.price.to_f32().unwrap();
-                hlcv.low = trade.price.to_f32().unwrap();
-                hlcv.close = trade.price.to_f32().unwrap();
-                hlcv.volume = trade.amount.to_f32().unwrap();
-                ts = ts + duration;
             }
-            Some(trade) if trade.timestamp <= ts => {
-                // trace!("constructing");
-                hlcv.close = trade.price.to_f32().unwrap();
-                hlcv.high.max_mut(hlcv.close);
-                hlcv.low.min_mut(hlcv.close);
-                hlcv.volume += trade.amount.to_f32().unwrap();
+            continue;
+        }
+        let path = downloader.chunk_path(chunk);
+        let mut file = File::open(&path).await?;
+        let len = file.metadata().await?.len();
+        // hlcv must be continuous after the first trade
+        if chunk.items != (chunk.end_ts - chunk.first_ts) as u64
+            || len != chunk.items * Hlcv::size() as u64
+        {
+            throw!("Chunk {:?} is corrupted.", path);
+        }
+        if let Some(last) = &last {
+            for _ in chunk.start_ts..chunk.first_ts {
+                writer.write_all(last.as_u8_slice()).await?;
             }
-            _ => unsafe { unreachable_unchecked() },
         }
+        first_ts.get_or_insert(chunk.first_ts);
+        io::copy(&mut BufReader::with_capacity(BLOCK_SIZE, &mut file), writer).await?;
+        let mut hlcv = unsafe { Hlcv::uninitialized_unsafe() };
+        file.seek(SeekFrom::End(-(Hlcv::size() as i64))).await?;
+        file.read_exact(unsafe { hlcv.as_u8_slice_mut() }).await?;
+        hlcv.volume = 0.;
+        last = Some(hlcv);
     }
-    tmp.shutdown().await?;
-    // trace!("executed");
-    let mut tmp = tmp.into_inner();
+    match first_ts {
+        Some(first_ts) => Ok(first_ts),
+        None => throw!(
+            "No trades for {} between {} and {}.",
+            manifest.market,
+            manifest.start_ts().unwrap_or_default().into_date_time(),
+            manifest.end_ts().unwrap_or_default().into_date_time()
+        ),
+    }
+}
 
-    tmp.seek(SeekFrom::Start(0)).await?;
-    let mut tmp = BufReader::with_capacity(BLOCK_SIZE, tmp);
-    io::copy(&mut tmp, writer).await?;
-    drop(tmp);
-    tokio::fs::remove_file(&tmp_path).await?;
-    writer.seek(SeekFrom::Start(4)).await?;
-    writer.write_all(ts.timestamp_s().as_u8_slice()).await?;
-    *first_trade_ts = first_trade.timestamp.timestamp_s();
-    Ok(())
+#[cfg(test)]
+mod t_hlcv_provider {
+    use super::*;
+
+    fn hlcv(close: f32, volume: f32) -> Hlcv {
+        Hlcv {
+            high: close,
+            low: close,
+            close,
+            volume,
+        }
+    }
+
+    fn as_bytes(hlcvs: &[Hlcv]) -> Vec<u8> {
+        hlcvs
+            .iter()
+            .flat_map(|x| x.as_u8_slice().to_vec())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn t_merge_hlcv_chunks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let downloader = Downloader::new(dir.path());
+        let mut manifest = Manifest::new("BitMEX", "XBTUSD", "hlcv", 0, 9, 3);
+        // First trades at 1 and 4, no trades in the last chunk.
+        let chunks = [
+            (1, vec![hlcv(1., 1.), hlcv(2., 1.)]),
+            (4, vec![hlcv(3., 1.); 2]),
+        ];
+        for (chunk, (first_ts, hlcvs)) in manifest.chunks.iter_mut().zip(chunks.iter()) {
+            tokio::fs::write(downloader.chunk_path(chunk), as_bytes(hlcvs)).await?;
+            chunk.first_ts = *first_ts;
+            chunk.items = hlcvs.len() as u64;
+        }
+        for chunk in &mut manifest.chunks {
+            chunk.done = true;
+        }
+        let mut merged = Vec::new();
+        assert_eq!(
+            merge_hlcv_chunks(&downloader, &manifest, &mut merged).await?,
+            1
+        );
+        let expected = vec![
+            hlcv(1., 1.),
+            hlcv(2., 1.),
+            // gap before the first trade of the second chunk
+            hlcv(2., 0.),
+            hlcv(3., 1.),
+            hlcv(3., 1.),
+            hlcv(3., 0.),
+            hlcv(3., 0.),
+            hlcv(3., 0.),
+        ];
+        assert_eq!(merged, as_bytes(&expected));
+
+        manifest.chunks[1].items = 1;
+        assert!(merge_hlcv_chunks(&downloader, &manifest, &mut Vec::new())
+            .await
+            .is_err());
+        Ok(())
+    }
 }
@@ -25,7 +25,7 @@ pub async fn archive(
     let storage_account_client = StorageAccountClient::new_access_key(
         http_client.clone(),
         &iaas.storage_account,
-        &iaas.storage_key,
+        iaas.storage_key.expose(),
     );
     let storage_client = storage_account_client.as_storage_client();
     let blob = storage_client
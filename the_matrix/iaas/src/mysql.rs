@@ -19,7 +19,7 @@ use crate::mysql::models::{ExchangeConfig, ModelConfig, NewMaintenance, NewModel
 
 lazy_static::lazy_static! {
     static ref POOL: Pool<ConnectionManager<MysqlConnection>> = {
-        let manager = ConnectionManager::new(&CONFIG.iaas.as_ref().expect("no iaas in config").the_matrix_db_url);
+        let manager = ConnectionManager::new(&CONFIG.iaas.as_ref().expect("no iaas in config").the_matrix_db_url.expose());
         let pool = Pool::builder().max_size(1).build(manager).unwrap();
         pool
     };
@@ -84,6 +84,7 @@ pub fn load_model_configs(exchange_id_: u16) -> Result<Vec<ModelConfig>> {
             target_leverage,
             models_values::model_source_id,
             models_values::variable_values,
+            execution,
         ))
         .load::<ModelConfig>(&con())?)
 }
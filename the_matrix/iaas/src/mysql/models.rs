@@ -4,6 +4,7 @@ use std::convert::TryInto;
 use itertools::Itertools;
 use merovingian::minable_models::Maintenance;
 use merovingian::model_snapshot::ModelSnapshot;
+use merovingian::order::ExecutionAlgo;
 use merovingian::speedy::Readable;
 use merovingian::Writable;
 
@@ -17,7 +18,9 @@ pub struct ExchangeConfig {
     pub id: u16,
     pub use_testnet: bool,
     pub use_public_data_miner: bool,
+    #[from(with = "other.api_key.expose().to_string()")]
     pub api_key: String,
+    #[from(with = "other.api_secret.expose().to_string()")]
     pub api_secret: String,
     #[from(with = "other.max_leverage")]
     pub max_leverage: f32,
@@ -31,9 +34,12 @@ pub struct ModelConfig {
     pub target_leverage: f32,
     pub model_source_id: u16,
     pub serialized_variable_values: Vec<u8>,
+    /// `ExecutionAlgo::Direct` if not set.
+    pub serialized_execution: Option<Vec<u8>>,
 }
 
 impl ModelConfig {
+    /// Models without a database are told apart by their position in config.
     pub fn from_configs(configs: &Vec<config::ModelConfig>) -> Vec<Self> {
         let map: HashMap<_, _> = configs
             .iter()
@@ -43,18 +49,26 @@ impl ModelConfig {
             .collect();
         configs
             .iter()
-            .map(|x| ModelConfig {
-                market_model_id: u32::MAX,
+            .enumerate()
+            .map(|(i, x)| ModelConfig {
+                market_model_id: i as u32,
                 market: x.market.clone(),
                 target_leverage: x.target_leverage,
                 model_source_id: *map.get(&x.name).unwrap(),
                 serialized_variable_values: Writable::write_to_vec(&x.variable_values).unwrap(),
+                serialized_execution: Some(Writable::write_to_vec(&x.execution).unwrap()),
             })
             .collect()
     }
     pub fn variable_values(&self) -> Vec<f32> {
         Readable::read_from_buffer(&self.serialized_variable_values).unwrap()
     }
+    pub fn execution(&self) -> ExecutionAlgo {
+        match &self.serialized_execution {
+            None => ExecutionAlgo::Direct,
+            Some(buffer) => Readable::read_from_buffer(buffer).unwrap(),
+        }
+    }
 }
 
 #[derive(Queryable)]
@@ -44,6 +44,7 @@ table! {
         model_values_id -> Unsigned<Integer>,
         market -> Varchar,
         target_leverage -> Float,
+        execution -> Nullable<Blob>,
     }
 }
 
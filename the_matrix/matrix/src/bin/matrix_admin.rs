@@ -0,0 +1,99 @@
+//! Inspects and controls a running matrix process through its admin endpoint. The endpoint must be
+//! enabled with `admin` in exchange config.
+use std::path::PathBuf;
+
+use clap::Clap;
+use config::{get_exchange_config, select_exchange, CONFIG};
+use matrix_core::agents::{AdminClient, AdminMaintenance, AdminRequest};
+use mouse::error::{bail, Result};
+use tokio::runtime::Runtime;
+
+#[derive(Clap)]
+#[clap(version, about, author)]
+pub struct Args {
+    #[clap(long, short, parse(from_os_str), default_value = "config.yaml")]
+    /// Path to config file.
+    pub config: PathBuf,
+    #[clap(long, short)]
+    /// Exchange name of the running process.
+    pub exchange: String,
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Lists loaded models.
+    Models,
+    /// Lists positions of each model and positions reported by exchange.
+    Positions,
+    /// Lists open orders.
+    OpenOrders,
+    /// Shows balance and leverage.
+    Margin,
+    /// Reloads when there are no open orders and positions.
+    ReloadSafe,
+    /// Shuts down when there are no open orders and positions.
+    ShutdownSafe,
+    /// Shuts down immediately.
+    Shutdown,
+    /// Model can only reduce its positions until it is resumed.
+    Pause {
+        model_id: u32,
+    },
+    Resume {
+        model_id: u32,
+    },
+    /// Replaces variable values of a model, new values are used from the next candle on.
+    SetVariableValues {
+        model_id: u32,
+        #[clap(required = true, allow_hyphen_values = true)]
+        values: Vec<f32>,
+    },
+    /// Cancels orders on a market and closes its positions with market orders.
+    Flatten {
+        market: String,
+    },
+}
+
+impl From<Command> for AdminRequest {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Models => AdminRequest::Models,
+            Command::Positions => AdminRequest::Positions,
+            Command::OpenOrders => AdminRequest::OpenOrders,
+            Command::Margin => AdminRequest::Margin,
+            Command::ReloadSafe => AdminRequest::Maintenance(AdminMaintenance::ReloadSafe),
+            Command::ShutdownSafe => AdminRequest::Maintenance(AdminMaintenance::ShutdownSafe),
+            Command::Shutdown => AdminRequest::Maintenance(AdminMaintenance::Shutdown),
+            Command::Pause { model_id } => AdminRequest::Pause { model_id },
+            Command::Resume { model_id } => AdminRequest::Resume { model_id },
+            Command::SetVariableValues { model_id, values } => {
+                AdminRequest::SetVariableValues { model_id, values }
+            }
+            Command::Flatten { market } => AdminRequest::Flatten { market },
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    config::load(&args.config)?;
+    select_exchange(&args.exchange)?;
+    let exchange_config = get_exchange_config();
+    let mut dir = CONFIG.data_dir.join(&args.exchange);
+    if exchange_config.map_or(false, |x| x.paper.is_some()) {
+        dir = dir.join("paper");
+    }
+    let address = match exchange_config.and_then(|x| x.admin.as_ref()) {
+        Some(admin) => admin.address.clone(),
+        None => bail!("Admin endpoint of {} isn't enabled.", args.exchange),
+    };
+    let rt = Runtime::new()?;
+    let response = rt.block_on(async {
+        let mut client = AdminClient::connect(&address, &dir).await?;
+        client.request(args.command.into()).await
+    })?;
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Exports optimizer and backtest results to CSV, Arrow IPC or Parquet, the latter two need
+//! feature `columnar`. Outputs don't store names of variables and output values, they are
+//! described by a spec file:
+//!
+//! ```yaml
+//! variables:
+//!   - { name: period, min: 1, max: 14400, stride: 1 }
+//!   - { name: level, min: 0, max: 100, stride: 1 }
+//! columns: [profit, max_drawdown, n_trades]
+//! precision: f32
+//! compressed_blocks: true
+//! ```
+use std::path::PathBuf;
+
+use clap::Clap;
+use dozer::export::{ColumnRange, ExportFormat, ExportSpec, Exporter};
+use mouse::error::{anyhow, Result};
+
+#[derive(Clap)]
+#[clap(version, about, author)]
+pub struct Args {
+    #[clap(parse(from_os_str))]
+    /// Output of the optimizer.
+    pub output: PathBuf,
+    #[clap(long, short, parse(from_os_str))]
+    /// Spec of the output.
+    pub spec: PathBuf,
+    #[clap(long, short = 'o', parse(from_os_str))]
+    /// Destination file.
+    pub dest: PathBuf,
+    #[clap(long, short)]
+    /// csv, arrow or parquet, taken from extension of destination by default.
+    pub format: Option<ExportFormat>,
+    #[clap(long, short)]
+    /// Comma separated columns to export, e.g. `combination,period,profit`, all by default.
+    pub columns: Option<String>,
+    #[clap(long = "where")]
+    /// Exports only combinations with a column in range, e.g. `--where profit=0.1..10`.
+    pub filters: Vec<ColumnRange>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let spec = ExportSpec::load(&args.spec)?;
+    let format = match args.format {
+        Some(format) => format,
+        None => ExportFormat::from_path(&args.dest)
+            .ok_or_else(|| anyhow!("Unknown format of {}, use --format.", args.dest.display()))?,
+    };
+    let mut exporter = Exporter::new(spec);
+    if let Some(columns) = &args.columns {
+        exporter = exporter.columns(columns.split(',').map(|x| x.trim().into()).collect());
+    }
+    for filter in args.filters {
+        exporter = exporter.filter(filter.name, filter.range);
+    }
+    let n = exporter.export(&args.output, format, &args.dest).await?;
+    println!("Exported {} combinations to {}.", n, args.dest.display());
+    Ok(())
+}
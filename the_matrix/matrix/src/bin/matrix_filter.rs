@@ -0,0 +1,96 @@
+//! Filters optimizer results like the parallel coordinates plot of tank does. Prints number of
+//! selected combinations and a histogram of each axis, selected combinations can be written into a
+//! file, one per line. Output is described by the same spec as for `matrix_export`.
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Clap;
+use dozer::export::{ColumnRange, ExportSpec};
+use merovingian::parallel_coordinates::ParallelCoordinatesFilter;
+use mouse::error::{anyhow, Result, ResultCtxExt};
+
+#[derive(Clap)]
+#[clap(version, about, author)]
+pub struct Args {
+    #[clap(parse(from_os_str))]
+    /// Output of the optimizer.
+    pub output: PathBuf,
+    #[clap(long, short, parse(from_os_str))]
+    /// Spec of the output.
+    pub spec: PathBuf,
+    #[clap(long, short)]
+    /// Selects combinations with an axis in range, e.g. `--brush profit=0.1..10`.
+    pub brush: Vec<ColumnRange>,
+    #[clap(long)]
+    /// Comma separated axes in the order they are printed, all axes have to be given.
+    pub order: Option<String>,
+    #[clap(long)]
+    /// Prints histogram of an axis from its end to its start.
+    pub invert: Vec<String>,
+    #[clap(long, default_value = "10")]
+    /// Number of bins of histograms.
+    pub bins: usize,
+    #[clap(long, short = 'o', parse(from_os_str))]
+    /// File that selected combinations are written into.
+    pub dest: Option<PathBuf>,
+}
+
+fn axis(filter: &ParallelCoordinatesFilter, name: &str) -> Result<usize> {
+    filter.axis(name).ok_or_else(|| {
+        let names: Vec<_> = filter.axes().iter().map(|x| x.name.as_str()).collect();
+        anyhow!("Unknown axis {}, axes are: {}", name, names.join(", "))
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let spec = ExportSpec::load(&args.spec)?;
+    let mut filter = spec.parallel_coordinates(&args.output).await?;
+    for brush in &args.brush {
+        let i = axis(&filter, &brush.name)?;
+        filter.set_brush(i, Some(brush.range));
+    }
+    if let Some(order) = &args.order {
+        let order = order
+            .split(',')
+            .map(|x| axis(&filter, x.trim()))
+            .collect::<Result<Vec<_>>>()?;
+        filter.reorder(order)?;
+    }
+    for name in &args.invert {
+        let i = axis(&filter, name)?;
+        filter.invert(i, true);
+    }
+
+    println!(
+        "Selected {} of {} combinations.",
+        filter.count(),
+        filter.n_rows()
+    );
+    for (i, histogram) in filter.order().iter().zip(filter.histograms(args.bins)) {
+        let axis = &filter.axes()[*i];
+        let (histogram, from, to) = match axis.inverted {
+            true => (
+                histogram.into_iter().rev().collect(),
+                axis.bounds.end,
+                axis.bounds.start,
+            ),
+            false => (histogram, axis.bounds.start, axis.bounds.end),
+        };
+        let brush = match filter.brush(*i) {
+            Some(brush) => format!(", brush {}..{}", brush.start, brush.end),
+            None => String::new(),
+        };
+        println!("{} {}..{}{}: {:?}", axis.name, from, to, brush, histogram);
+    }
+    if let Some(dest) = &args.dest {
+        let file = std::fs::File::create(dest).with_context(|| format!("{}", dest.display()))?;
+        let mut writer = std::io::BufWriter::new(file);
+        for combination in filter.selected_combinations() {
+            writeln!(writer, "{}", combination)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Renders HTML or Markdown report of backtests without tank. Each input file is a JSON or YAML
+//! `BacktestRun` or a list of them, see `merovingian::report::BacktestRun` for its format. Other
+//! inputs are optimizer outputs described by `--spec` like for `matrix_export`, their best
+//! combinations by the metric are reported without equity. A report of top combinations is
+//! rendered if there is more than one run. Reports are written into `reports_dir` from config
+//! using templates from `report_template_dir`, see `merovingian::report`.
+use std::path::{Path, PathBuf};
+
+use clap::Clap;
+use config::ConfigLoader;
+use dozer::export::ExportSpec;
+use merovingian::non_minable_models::BacktestPlotFlags;
+use merovingian::report::{BacktestRun, Rank, Report, ReportFormat};
+use mouse::error::{anyhow, bail, Result, ResultCtxExt};
+use serde::Deserialize;
+
+#[derive(Clap)]
+#[clap(version, about, author)]
+pub struct Args {
+    #[clap(parse(from_os_str), required = true)]
+    /// Backtest results, `.json`, `.yaml` or `.yml`, or optimizer outputs.
+    pub inputs: Vec<PathBuf>,
+    #[clap(long, short, parse(from_os_str))]
+    /// Spec of optimizer outputs in inputs.
+    pub spec: Option<PathBuf>,
+    #[clap(long, short, parse(from_os_str), default_value = "config.yaml")]
+    /// Path to config file.
+    pub config: PathBuf,
+    #[clap(long, parse(from_os_str))]
+    /// Directory reports are written into, overrides config.
+    pub reports_dir: Option<PathBuf>,
+    #[clap(long, parse(from_os_str))]
+    /// Directory with report templates, overrides config.
+    pub template_dir: Option<PathBuf>,
+    #[clap(long, short, default_value = "html")]
+    /// html or md.
+    pub format: ReportFormat,
+    #[clap(long, short, default_value = "balance")]
+    /// Stat that runs are ranked by.
+    pub metric: String,
+    #[clap(long)]
+    /// highest or lowest metric is the best, lowest for losses and risk like max_drawdown by
+    /// default.
+    pub rank: Option<Rank>,
+    #[clap(long, short, default_value = "report")]
+    /// File name of the report without extension.
+    pub name: String,
+    #[clap(long)]
+    /// Reports only this many best runs, 100 best combinations of each optimizer output by
+    /// default.
+    pub top: Option<usize>,
+    #[clap(long)]
+    /// Adds chart of balance after each trade.
+    pub trade_curve: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Runs {
+    One(Box<BacktestRun>),
+    Many(Vec<BacktestRun>),
+}
+
+/// Number of runs read from an optimizer output without `--top`.
+const DEFAULT_TOP: usize = 100;
+
+async fn read_runs(
+    path: &Path,
+    spec: Option<&ExportSpec>,
+    args: &Args,
+) -> Result<Vec<BacktestRun>> {
+    let read =
+        |path: &Path| std::fs::read_to_string(path).with_context(|| format!("{}", path.display()));
+    let runs = match path.extension().and_then(|x| x.to_str()) {
+        Some("json") => serde_json::from_str(&read(path)?)?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&read(path)?)?,
+        _ => match spec {
+            Some(spec) => {
+                let rank = args.rank.unwrap_or_else(|| Rank::of(&args.metric));
+                let k = args.top.unwrap_or(DEFAULT_TOP);
+                return spec.top_runs(path, &args.metric, k, rank).await;
+            }
+            None => bail!(
+                "Unknown format of {}, use .json or .yaml, or --spec for an optimizer output.",
+                path.display()
+            ),
+        },
+    };
+    Ok(match runs {
+        Runs::One(run) => vec![*run],
+        Runs::Many(runs) => runs,
+    })
+}
+
+/// Directory from arguments or config.
+fn dir(arg: &Option<PathBuf>, config: &Path, key: &str) -> Result<PathBuf> {
+    if let Some(dir) = arg {
+        return Ok(dir.clone());
+    }
+    let merged = ConfigLoader::new(config).merged()?;
+    let dir = merged
+        .get(key)
+        .ok_or_else(|| anyhow!("{} isn't configured in {}.", key, config.display()))?;
+    Ok(serde_yaml::from_value(dir.clone())?)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let spec = args.spec.as_deref().map(ExportSpec::load).transpose()?;
+    let mut runs = Vec::new();
+    for input in &args.inputs {
+        runs.extend(
+            read_runs(input, spec.as_ref(), &args)
+                .await
+                .with_context(|| format!("Invalid {}", input.display()))?,
+        );
+    }
+    let rank = args.rank.unwrap_or_else(|| Rank::of(&args.metric));
+    if let Some(top) = args.top {
+        let metric = |run: &BacktestRun| run.stats.get(&args.metric).unwrap_or(f32::NAN);
+        runs.sort_by(|a, b| rank.compare(metric(a), metric(b)));
+        runs.truncate(top);
+    }
+    let reports_dir = dir(&args.reports_dir, &args.config, "reports_dir")?;
+    let template_dir = dir(&args.template_dir, &args.config, "report_template_dir")?;
+    let mut flags = BacktestPlotFlags::TIMESTAMP;
+    if args.trade_curve {
+        flags |= BacktestPlotFlags::TRADE_CURVE;
+    }
+    let path = Report::new(runs)
+        .format(args.format)
+        .metric(args.metric)
+        .rank(rank)
+        .name(args.name)
+        .flags(flags)
+        .write(&reports_dir, &template_dir)?;
+    println!("Report written to {}.", path.display());
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Manages the vault that `secret://<name>` references in config are resolved from. Vault path and
+//! keyfile are taken from `vault` in config unless they are given, without a keyfile the passphrase
+//! is read from `MATRIX_VAULT_PASSPHRASE` or asked for.
+use std::path::PathBuf;
+
+use clap::Clap;
+use config::{ConfigLoader, Vault, VaultConfig, VaultKey};
+use mouse::error::{bail, Result};
+
+#[derive(Clap)]
+#[clap(version, about, author)]
+pub struct Args {
+    #[clap(long, short, parse(from_os_str), default_value = "config.yaml")]
+    /// Path to config file.
+    pub config: PathBuf,
+    #[clap(long, parse(from_os_str))]
+    /// Path to vault file, overrides config.
+    pub vault: Option<PathBuf>,
+    #[clap(long, parse(from_os_str))]
+    /// File that vault key is derived from, overrides config.
+    pub keyfile: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Lists names of secrets.
+    List,
+    /// Adds a secret, its value is asked for.
+    Add {
+        name: String,
+    },
+    /// Replaces value of a secret, new value is asked for.
+    Rotate {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// Encrypts vault with a new passphrase or with `--new-keyfile`.
+    Rekey {
+        #[clap(long, parse(from_os_str))]
+        new_keyfile: Option<PathBuf>,
+    },
+}
+
+fn vault_config(args: &Args) -> Result<VaultConfig> {
+    let mut vault_config = match (&args.vault, args.config.is_file()) {
+        (Some(path), _) => VaultConfig {
+            path: path.clone(),
+            keyfile: None,
+        },
+        (None, true) => {
+            let merged = ConfigLoader::new(&args.config).merged()?;
+            match merged.get("vault") {
+                Some(vault) => serde_yaml::from_value(vault.clone())?,
+                None => bail!("Vault isn't configured in {}.", args.config.display()),
+            }
+        }
+        (None, false) => bail!("Either --vault or --config with vault must be given."),
+    };
+    if args.keyfile.is_some() {
+        vault_config.keyfile = args.keyfile.clone();
+    }
+    Ok(vault_config)
+}
+
+fn key(vault_config: &VaultConfig) -> Result<VaultKey> {
+    match &vault_config.keyfile {
+        Some(path) => VaultKey::from_keyfile(path),
+        None => VaultKey::from_env().or_else(|_| {
+            VaultKey::from_passphrase(&rpassword::read_password_from_tty(Some(
+                "Vault passphrase: ",
+            ))?)
+        }),
+    }
+}
+
+fn read_value(name: &str) -> Result<String> {
+    let value = rpassword::read_password_from_tty(Some(&format!("Value of {}: ", name)))?;
+    if value.is_empty() {
+        bail!("Value of {} must not be empty.", name);
+    }
+    Ok(value)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let vault_config = vault_config(&args)?;
+    let mut vault = Vault::open(&vault_config.path, key(&vault_config)?)?;
+    match &args.command {
+        Command::List => {
+            for name in vault.names() {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        Command::Add { name } => vault.add(name, &read_value(name)?)?,
+        Command::Rotate { name } => vault.rotate(name, &read_value(name)?)?,
+        Command::Remove { name } => vault.remove(name)?,
+        Command::Rekey { new_keyfile } => {
+            let key = match new_keyfile {
+                Some(path) => VaultKey::from_keyfile(path)?,
+                None => {
+                    let passphrase =
+                        rpassword::read_password_from_tty(Some("New vault passphrase: "))?;
+                    let repeated =
+                        rpassword::read_password_from_tty(Some("Repeat new vault passphrase: "))?;
+                    if passphrase != repeated {
+                        bail!("Passphrases don't match.");
+                    }
+                    VaultKey::from_passphrase(&passphrase)?
+                }
+            };
+            vault.rekey(key);
+        }
+    }
+    vault.save()?;
+    println!("Saved {}.", vault.path().display());
+    Ok(())
+}
@@ -12,18 +12,22 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use clap::Clap;
-use config::select_exchange;
+use config::{get_exchange_config, select_exchange, CONFIG};
 use iaas::mysql::load_configs;
 use iaas::mysql::models::{ExchangeConfig, ModelConfig};
 use matrix_core::agents::network_agents::*;
-use matrix_core::agents::{build_and_kill, run_message_loop};
+use matrix_core::agents::{alert_kill_failed, build_and_kill, run_message_loop, NetworkClient};
 use merovingian::non_minable_models::ExitCode;
-use mouse::error::Result;
+use mouse::error::{bail, Result};
 use mouse::log::*;
 use nebuchadnezzar::core::Exchange;
 use nebuchadnezzar::exchanges::*;
 use tokio::runtime::Runtime;
 use tokio::task;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+mod supervisor;
 
 #[derive(Clap)]
 #[clap(version, about, author)]
@@ -32,8 +36,12 @@ pub struct Args {
     /// Path to config file.
     pub config: PathBuf,
     #[clap(long, short)]
-    /// Exchange name to use
-    pub exchange: String,
+    /// Exchange name to use, all exchanges of `fleet` in config are run if not set.
+    pub exchange: Option<String>,
+    #[clap(long = "set")]
+    /// Overrides config value, e.g. `--set exchanges.0.max_leverage=3`. Applied after overlay of
+    /// this host and `MATRIX__` environment variables.
+    pub overrides: Vec<String>,
 }
 
 //"2 or rsi 23 33 54"
@@ -58,8 +66,10 @@ async fn run_agent<A: NetworkAgent>(
     model_configs: Vec<ModelConfig>,
 ) -> ExitCode {
     let config = exchange_config.clone();
+    // Spawned task doesn't inherit exchange of a fleet.
+    let exchange = get_exchange_config().map(|x| x.name.clone());
 
-    let task = task::spawn(async move {
+    let task = task::spawn(config::with_exchange(exchange, async move {
         match A::build(exchange_config, model_configs).await {
             Ok(agent) => run_message_loop(Arc::new(tokio::sync::Mutex::new(agent))).await,
             Err(e) => {
@@ -68,7 +78,7 @@ async fn run_agent<A: NetworkAgent>(
                 ExitCode::Fatal
             }
         }
-    });
+    }));
     match task.await {
         Err(e) => {
             error!("{:?}", e);
@@ -82,6 +92,7 @@ async fn run_agent<A: NetworkAgent>(
                 }
                 Err(e) => {
                     error!("FATAL: Killing agent FAILED. {:#?}.", e);
+                    alert_kill_failed(&e, &*client.clock()).await;
                     ExitCode::Fatal
                 }
             }
@@ -95,10 +106,13 @@ async fn start_trading(
     exchange_config: ExchangeConfig,
     model_configs: Vec<ModelConfig>,
 ) -> ExitCode {
-    if Bitmex::new(false).name() == exchange_name {
-        run_agent::<BitmexAgent>(exchange_config, model_configs).await
-    } else if Bitmex::new(true).name() == exchange_name {
-        run_agent::<BitmexAgent>(exchange_config, model_configs).await
+    let paper = get_exchange_config().map_or(false, |x| x.paper.is_some());
+    let exchange_name = get_exchange_config().map_or(exchange_name, |x| x.exchange_name());
+    if Bitmex::new(false).name() == exchange_name || Bitmex::new(true).name() == exchange_name {
+        match paper {
+            true => run_agent::<PaperBitmexAgent>(exchange_config, model_configs).await,
+            false => run_agent::<BitmexAgent>(exchange_config, model_configs).await,
+        }
     } else {
         error!("Unknown exchange id.");
         ExitCode::Fatal
@@ -141,16 +155,25 @@ Stack backtrace:
 fn main() -> Result<()> {
     // TODO: does price drop/increse when there is funding
     let args = Args::parse();
-    unsafe {
-        config::load(&args.config)?;
-    }
+    config::load_with(config::ConfigLoader::new(&args.config).overrides(args.overrides.clone()))?;
     // panic!("");
     mouse::handlers::setup_ctrlc_handler()?;
+    // Durations of message loop spans are printed when enabled by `MATRIX_TRACE=matrix_core=info`.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_env("MATRIX_TRACE"))
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
     let mut exit_code;
     let rt = Runtime::new().unwrap();
-    select_exchange(&args.exchange);
-    let configs = load_configs(&args.exchange)?;
-    exit_code = rt.block_on(start_trading(&args.exchange, configs.0, configs.1));
+    exit_code = match (&args.exchange, &CONFIG.fleet) {
+        (Some(exchange), _) => {
+            select_exchange(exchange)?;
+            let configs = load_configs(exchange)?;
+            rt.block_on(start_trading(exchange, configs.0, configs.1))
+        }
+        (None, Some(fleet)) => rt.block_on(supervisor::run_fleet(fleet)),
+        (None, None) => bail!("Exchange must be set when there is no fleet in config."),
+    };
     let instant = Instant::now();
     rt.shutdown_timeout(tokio::time::Duration::from_secs(60));
     if instant.elapsed().as_secs() >= 60 {
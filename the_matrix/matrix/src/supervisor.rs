@@ -0,0 +1,182 @@
+//! Runs all exchanges of a fleet in one process. Each exchange is restarted on its own according to
+//! its exit code, while limits and kill switch of the portfolio are shared by all of them.
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use config::FleetConfig;
+use iaas::mysql::load_configs;
+use matrix_core::agents::portfolio;
+use merovingian::non_minable_models::ExitCode;
+use mouse::log::*;
+use tokio::task;
+
+use crate::start_trading;
+
+/// Returns the most severe exit code of all exchanges once every one of them has stopped.
+pub async fn run_fleet(fleet: &'static FleetConfig) -> ExitCode {
+    let mut names = HashSet::new();
+    if let Some(name) = fleet.exchanges.iter().find(|x| !names.insert(*x)) {
+        error!("Exchange {} is listed in fleet more than once.", name);
+        return ExitCode::Fatal;
+    }
+    let handles: Vec<_> = fleet
+        .exchanges
+        .iter()
+        .map(|name| {
+            task::spawn(config::with_exchange(
+                Some(name.clone()),
+                supervise(name, fleet),
+            ))
+        })
+        .collect();
+    let mut exit_code = ExitCode::Success;
+    for handle in handles {
+        let code = handle.await.unwrap_or_else(|e| {
+            error!("Supervisor panicked: {:?}", e);
+            ExitCode::Fatal
+        });
+        if severity(code) > severity(exit_code) {
+            exit_code = code;
+        }
+    }
+    exit_code
+}
+
+async fn supervise(name: &str, fleet: &FleetConfig) -> ExitCode {
+    restart(name, fleet, || async move {
+        match load_configs(name) {
+            Ok((exchange_config, model_configs)) => {
+                start_trading(name, exchange_config, model_configs).await
+            }
+            Err(e) => {
+                error!("Loading configs of {} failed: {:?}", name, e);
+                ExitCode::FailedSafely
+            }
+        }
+    })
+    .await
+}
+
+/// Runs `start` again for as long as its exit code allows it.
+async fn restart<F, Fut>(name: &str, fleet: &FleetConfig, mut start: F) -> ExitCode
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ExitCode>,
+{
+    let window = Duration::from_secs(fleet.restart_window_s as u64);
+    let mut restarts = VecDeque::new();
+    loop {
+        info!("Starting {}.", name);
+        let exit_code = start().await;
+        if portfolio().map_or(false, |x| x.is_killed()) {
+            error!("{} stopped by kill switch of the portfolio.", name);
+            return exit_code;
+        }
+        match exit_code {
+            ExitCode::Success => {
+                info!("{} has shut down.", name);
+                return exit_code;
+            }
+            ExitCode::Fatal => {
+                error!(
+                    "{} failed and may have open positions, it is not restarted.",
+                    name
+                );
+                return exit_code;
+            }
+            ExitCode::Reload => info!("Reloading {}.", name),
+            ExitCode::FailedSafely => {
+                let now = Instant::now();
+                while restarts
+                    .front()
+                    .map_or(false, |x| now.duration_since(*x) > window)
+                {
+                    restarts.pop_front();
+                }
+                if restarts.len() >= fleet.max_restarts as usize {
+                    error!(
+                        "{} failed {} times, it is not restarted.",
+                        name,
+                        restarts.len() + 1
+                    );
+                    return exit_code;
+                }
+                restarts.push_back(now);
+                warn!("Restarting {} in {}s.", name, fleet.restart_delay_s);
+                tokio::time::sleep(Duration::from_secs(fleet.restart_delay_s as u64)).await;
+            }
+        }
+    }
+}
+
+fn severity(exit_code: ExitCode) -> u8 {
+    match exit_code {
+        ExitCode::Success => 0,
+        ExitCode::Reload => 1,
+        ExitCode::FailedSafely => 2,
+        ExitCode::Fatal => 3,
+    }
+}
+
+#[cfg(test)]
+mod t_supervisor {
+    use super::*;
+
+    async fn run(max_restarts: u32, exit_codes: Vec<ExitCode>) -> (ExitCode, usize) {
+        let fleet = FleetConfig {
+            max_restarts,
+            restart_delay_s: 0,
+            ..Default::default()
+        };
+        let mut exit_codes = VecDeque::from(exit_codes);
+        let mut runs = 0;
+        let exit_code = restart("a", &fleet, || {
+            runs += 1;
+            let exit_code = exit_codes.pop_front().unwrap();
+            async move { exit_code }
+        })
+        .await;
+        (exit_code, runs)
+    }
+
+    #[tokio::test]
+    async fn t_failed_safely_is_restarted_until_limit() {
+        let (exit_code, runs) = run(2, vec![ExitCode::FailedSafely; 4]).await;
+        assert!(matches!(exit_code, ExitCode::FailedSafely));
+        assert_eq!(runs, 3);
+    }
+
+    #[tokio::test]
+    async fn t_reload_is_not_counted_as_restart() {
+        let exit_codes = vec![
+            ExitCode::Reload,
+            ExitCode::Reload,
+            ExitCode::FailedSafely,
+            ExitCode::Success,
+        ];
+        let (exit_code, runs) = run(1, exit_codes).await;
+        assert!(matches!(exit_code, ExitCode::Success));
+        assert_eq!(runs, 4);
+    }
+
+    #[tokio::test]
+    async fn t_fatal_is_not_restarted() {
+        let (exit_code, runs) = run(3, vec![ExitCode::Fatal, ExitCode::Success]).await;
+        assert!(matches!(exit_code, ExitCode::Fatal));
+        assert_eq!(runs, 1);
+    }
+
+    #[test]
+    fn t_severity() {
+        let exit_codes = [
+            ExitCode::Success,
+            ExitCode::Reload,
+            ExitCode::FailedSafely,
+            ExitCode::Fatal,
+        ];
+        assert!(exit_codes
+            .windows(2)
+            .all(|x| severity(x[0]) < severity(x[1])));
+    }
+}
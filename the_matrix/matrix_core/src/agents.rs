@@ -1,4 +1,8 @@
-pub use network_agent::{build_and_kill, run_message_loop};
+pub use network_agent::{
+    alert_kill_failed, build_and_kill, portfolio, register_model, run_message_loop, AdminClient,
+    AdminMaintenance, AdminRequest, AdminResponse, Alert, Alerter, NetworkClient, Notifier,
+    PaperClient, Portfolio,
+};
 mod data_agents;
 mod network_agent;
 pub mod network_agents;
@@ -1,5 +1,14 @@
+pub use admin::{AdminClient, AdminMaintenance, AdminRequest, AdminResponse};
+pub use alerts::{alert_kill_failed, Alert, Alerter, Notifier};
 pub use client::NetworkClient;
 pub use exchange_state::{build_and_kill, NetworkAgentState};
+pub use metrics::{record_ws_latency, record_ws_message};
+pub use models::{model_variables, register_model, register_models};
+pub use paper::PaperClient;
+pub use portfolio::{portfolio, Portfolio};
+pub use reconciliation::OrderSnapshot;
+pub use replay::{read_recording, replay, RecordedEvent, ReplayWs};
+pub use risk::{Limit, RiskBreach};
 pub use websocket::{run_message_loop, Ws};
 
 // Must be at the top of a file otherwise modules that are in this module would not see this macro.
@@ -40,6 +49,18 @@ macro_rules! broadcast_async {
     }};
 }
 
+mod admin;
+mod alerts;
 mod client;
 mod exchange_state;
+mod execution;
+mod hot_reload;
+mod journal;
+mod metrics;
+mod models;
+mod paper;
+mod portfolio;
+mod reconciliation;
+mod replay;
+mod risk;
 mod websocket;
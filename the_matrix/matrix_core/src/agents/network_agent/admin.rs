@@ -0,0 +1,400 @@
+//! Local websocket endpoint for inspecting and controlling a running agent. Requests are JSON
+//! messages `{"token": "...", "request": ...}`, every response is `{"Ok": ...}` or `{"Err": "..."}`.
+//!
+//! A new token is generated on every start and written next to the journal, only users that can
+//! read the data dir can control the agent. Every request, including rejected ones, is appended to
+//! the audit log in the same dir.
+//!
+//! Requests are only queued here, the message loop handles them between websocket messages.
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use config::AdminConfig;
+use futures::{SinkExt, StreamExt};
+use merovingian::minable_models::MaintenanceMode;
+use mouse::error::{anyhow, Result, ResultCtxExt};
+use mouse::log::*;
+use mouse::num::Decimal;
+use nebuchadnezzar_core::clock::Clock;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub const TOKEN_FILE: &str = "admin.token";
+pub const AUDIT_LOG_FILE: &str = "admin_audit.log";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AdminRequest {
+    Models,
+    /// Positions of models and positions reported by the exchange.
+    Positions,
+    OpenOrders,
+    Margin,
+    Maintenance(AdminMaintenance),
+    /// Model can only reduce its positions until it is resumed.
+    Pause {
+        model_id: u32,
+    },
+    Resume {
+        model_id: u32,
+    },
+    /// Replaces variable values of a model, new values are used from the next candle on.
+    SetVariableValues {
+        model_id: u32,
+        values: Vec<f32>,
+    },
+    /// Cancels orders and closes positions of all models on a market. Models keep trading, pause
+    /// them first to keep the market flat.
+    Flatten {
+        market: String,
+    },
+}
+
+/// Maintenance modes that can be requested remotely.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AdminMaintenance {
+    ReloadSafe,
+    ShutdownSafe,
+    Shutdown,
+}
+
+impl From<AdminMaintenance> for MaintenanceMode {
+    fn from(maintenance: AdminMaintenance) -> Self {
+        match maintenance {
+            AdminMaintenance::ReloadSafe => MaintenanceMode::ReloadSafe,
+            AdminMaintenance::ShutdownSafe => MaintenanceMode::ShutdownSafe,
+            AdminMaintenance::Shutdown => MaintenanceMode::Shutdown,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Models(Vec<ModelInfo>),
+    Positions(Vec<PositionInfo>),
+    OpenOrders(Vec<OrderInfo>),
+    Margin { balance: Decimal, leverage: Decimal },
+    Done,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub model_id: u32,
+    pub market: String,
+    pub target_leverage: f32,
+    pub variable_values: Vec<f32>,
+    pub paused: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PositionInfo {
+    /// `None` for a position reported by the exchange.
+    pub model_id: Option<u32>,
+    pub market: String,
+    pub amount: Decimal,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderInfo {
+    pub order_id: String,
+    pub market: String,
+    pub amount_left: Decimal,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    token: String,
+    request: AdminRequest,
+}
+
+type Response = std::result::Result<AdminResponse, String>;
+
+/// Request that waits for the agent to handle it.
+pub struct PendingRequest {
+    pub request: AdminRequest,
+    responder: oneshot::Sender<Response>,
+}
+
+impl PendingRequest {
+    pub fn respond(self, response: Response) {
+        // Client might have disconnected in the meantime.
+        let _ = self.responder.send(response);
+    }
+}
+
+pub struct AdminServer {
+    address: SocketAddr,
+    requests: mpsc::UnboundedReceiver<PendingRequest>,
+}
+
+impl AdminServer {
+    /// Writes a new token into `dir` and starts accepting connections, audit entries are
+    /// timestamped with `clock`.
+    pub async fn bind(
+        config: &AdminConfig,
+        dir: &Path,
+        clock: Arc<dyn Clock>,
+    ) -> Result<AdminServer> {
+        let token = new_token();
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(dir.join(TOKEN_FILE)).await?;
+        file.write_all(token.as_bytes()).await?;
+        file.sync_all().await?;
+        let audit_log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(AUDIT_LOG_FILE))
+            .await?;
+        let listener = TcpListener::bind(&config.address)
+            .await
+            .with_context(|| format!("Binding admin endpoint to {}", config.address))?;
+        let address = listener.local_addr()?;
+        let (sender, requests) = mpsc::unbounded_channel();
+        tokio::spawn(accept(
+            listener,
+            Arc::new(token),
+            sender,
+            Arc::new(Mutex::new(audit_log)),
+            clock,
+        ));
+        info!("Admin endpoint listening on {}.", address);
+        Ok(AdminServer { address, requests })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Requests that have been received since the last call.
+    pub fn drain(&mut self) -> Vec<PendingRequest> {
+        let mut requests = Vec::new();
+        while let Ok(request) = self.requests.try_recv() {
+            requests.push(request);
+        }
+        requests
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    token: Arc<String>,
+    sender: mpsc::UnboundedSender<PendingRequest>,
+    audit_log: Arc<Mutex<File>>,
+    clock: Arc<dyn Clock>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // Server has been dropped, e.g. agent is restarted in the same process.
+            _ = sender.closed() => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Accepting admin connection failed: {:?}", e);
+                continue;
+            }
+        };
+        let (token, sender, audit_log, clock) = (
+            token.clone(),
+            sender.clone(),
+            audit_log.clone(),
+            clock.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, peer, &token, &sender, &audit_log, &*clock).await {
+                warn!("Admin connection from {} failed: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    peer: SocketAddr,
+    token: &str,
+    sender: &mpsc::UnboundedSender<PendingRequest>,
+    audit_log: &Mutex<File>,
+    clock: &dyn Clock,
+) -> Result<()> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    while let Some(message) = ws.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let (request, response) = match serde_json::from_str::<Envelope>(&text) {
+            Err(e) => (None, Err(format!("Invalid request: {}", e))),
+            Ok(envelope) if !token_matches(&envelope.token, token) => {
+                (Some(envelope.request), Err("Invalid token.".to_string()))
+            }
+            Ok(envelope) => {
+                let (responder, response) = oneshot::channel();
+                let pending = PendingRequest {
+                    request: envelope.request.clone(),
+                    responder,
+                };
+                let response = match sender.send(pending) {
+                    Ok(()) => response
+                        .await
+                        .unwrap_or_else(|_| Err("Agent stopped before responding.".to_string())),
+                    Err(_) => Err("Agent is not running.".to_string()),
+                };
+                (Some(envelope.request), response)
+            }
+        };
+        audit(audit_log, clock, peer, request.as_ref(), &response).await?;
+        ws.send(Message::Text(serde_json::to_string(&response)?))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Appends a JSON line, responses to queries aren't logged.
+async fn audit(
+    audit_log: &Mutex<File>,
+    clock: &dyn Clock,
+    peer: SocketAddr,
+    request: Option<&AdminRequest>,
+    response: &Response,
+) -> Result<()> {
+    let entry = serde_json::json!({
+        "timestamp": clock.now().to_rfc3339(),
+        "peer": peer.to_string(),
+        "request": request,
+        "error": response.as_ref().err(),
+    });
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    let mut audit_log = audit_log.lock().await;
+    audit_log.write_all(&line).await?;
+    audit_log.sync_data().await?;
+    Ok(())
+}
+
+fn new_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Takes the same time for every token of the same length.
+fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub struct AdminClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    token: String,
+}
+
+impl AdminClient {
+    /// Connects to the endpoint of an agent that stores its data in `dir`.
+    pub async fn connect(address: &str, dir: &Path) -> Result<AdminClient> {
+        let path = dir.join(TOKEN_FILE);
+        let token = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Reading admin token from {}", path.display()))?;
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", address)).await?;
+        Ok(AdminClient {
+            ws,
+            token: token.trim().to_string(),
+        })
+    }
+
+    pub async fn request(&mut self, request: AdminRequest) -> Result<AdminResponse> {
+        let envelope = Envelope {
+            token: self.token.clone(),
+            request,
+        };
+        self.ws
+            .send(Message::Text(serde_json::to_string(&envelope)?))
+            .await?;
+        while let Some(message) = self.ws.next().await {
+            if let Message::Text(text) = message? {
+                let response: Response = serde_json::from_str(&text)?;
+                return response.map_err(|e| anyhow!(e));
+            }
+        }
+        Err(anyhow!("Admin endpoint closed the connection."))
+    }
+}
+
+#[cfg(test)]
+mod t_admin {
+    use chrono::{TimeZone, Utc};
+    use nebuchadnezzar_core::clock::SimulatedClock;
+
+    use super::*;
+
+    #[test]
+    fn t_token_matches() {
+        let token = new_token();
+        assert_eq!(token.len(), 64);
+        assert!(token_matches(&token, &token));
+        assert!(!token_matches(&new_token(), &token));
+        assert!(!token_matches(&token[1..], &token));
+    }
+
+    #[tokio::test]
+    async fn t_requests_are_authenticated_and_audited() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = AdminConfig {
+            address: "127.0.0.1:0".into(),
+        };
+        let clock = Arc::new(SimulatedClock::new(Utc.timestamp(90, 0)));
+        let mut server = AdminServer::bind(&config, dir.path(), clock).await?;
+        let address = server.address().to_string();
+        // Plays the message loop.
+        let agent = tokio::spawn(async move {
+            loop {
+                for pending in server.drain() {
+                    let response = match &pending.request {
+                        AdminRequest::Pause { model_id: 1 } => Ok(AdminResponse::Done),
+                        _ => Err("Unknown model.".to_string()),
+                    };
+                    pending.respond(response);
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+            }
+        });
+        let mut client = AdminClient::connect(&address, dir.path()).await?;
+        assert_eq!(
+            client.request(AdminRequest::Pause { model_id: 1 }).await?,
+            AdminResponse::Done
+        );
+        let error = client.request(AdminRequest::Pause { model_id: 2 }).await;
+        assert_eq!(error.unwrap_err().to_string(), "Unknown model.");
+        client.token = new_token();
+        let error = client.request(AdminRequest::Resume { model_id: 1 }).await;
+        assert_eq!(error.unwrap_err().to_string(), "Invalid token.");
+        agent.abort();
+
+        let audit_log = std::fs::read_to_string(dir.path().join(AUDIT_LOG_FILE))?;
+        let entries: Vec<serde_json::Value> = audit_log
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["request"]["Pause"]["model_id"], 1);
+        assert_eq!(entries[0]["timestamp"], "1970-01-01T00:01:30+00:00");
+        assert!(entries[0]["error"].is_null());
+        assert_eq!(entries[1]["error"], "Unknown model.");
+        assert_eq!(entries[2]["request"]["Resume"]["model_id"], 1);
+        assert_eq!(entries[2]["error"], "Invalid token.");
+        Ok(())
+    }
+}
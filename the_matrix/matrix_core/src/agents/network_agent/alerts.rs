@@ -0,0 +1,737 @@
+//! Alerts that need a human. `Alerter` deduplicates and rate limits alerts before they are sent to
+//! `Notifier`s, `AlertAgent` decides which events are worth an alert.
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use config::{
+    get_exchange_config, AlertConfig, CommandSinkConfig, FileSinkConfig, Severity, SmtpSinkConfig,
+    WebhookSinkConfig,
+};
+use merovingian::minable_models::{Maintenance, MaintenanceMode};
+use mouse::error::{bail, ensure, Error, Result};
+use mouse::log::*;
+use mouse::num::{Decimal, IntoDecimal};
+use mouse::time::{IntoDateTime, Timestamp};
+use nebuchadnezzar_core::clock::Clock;
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::risk::{Limit, RiskBreach};
+use crate::agents::network_agents::{ExchangeListener, Execution, InstrumentConfig};
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+/// Hanging sink would hold back every later alert of the sink.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alert {
+    pub severity: Severity,
+    /// Alerts with the same key are considered duplicates.
+    pub key: String,
+    pub title: String,
+    pub message: String,
+    pub timestamp_ns: u64,
+}
+
+#[async_trait]
+pub trait Notifier: Debug + Send + Sync {
+    async fn notify(&self, alert: &Alert) -> Result<()>;
+}
+
+#[derive(Debug)]
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: &WebhookSinkConfig) -> WebhookNotifier {
+        WebhookNotifier {
+            client: reqwest::Client::new(),
+            url: config.url.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        self.client
+            .post(&self.url)
+            .json(alert)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct SmtpNotifier {
+    config: SmtpSinkConfig,
+}
+
+impl SmtpNotifier {
+    pub fn new(config: &SmtpSinkConfig) -> SmtpNotifier {
+        SmtpNotifier {
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        let (read, mut write) = TcpStream::connect(&self.config.address).await?.into_split();
+        let mut read = BufReader::new(read);
+        read_reply(&mut read, 220).await?;
+        command(&mut write, &mut read, "EHLO localhost", 250).await?;
+        let line = format!("MAIL FROM:<{}>", self.config.from);
+        command(&mut write, &mut read, &line, 250).await?;
+        for to in &self.config.to {
+            command(&mut write, &mut read, &format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        command(&mut write, &mut read, "DATA", 354).await?;
+        let mut mail = format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: [{:?}] {}\r\nDate: {}\r\nContent-Type: text/plain; \
+             charset=utf-8\r\n\r\n",
+            self.config.from,
+            self.config
+                .to
+                .iter()
+                .map(|x| format!("<{}>", x))
+                .collect::<Vec<_>>()
+                .join(", "),
+            alert.severity,
+            alert.title,
+            alert.timestamp_ns.into_date_time().to_rfc2822(),
+        );
+        for line in alert.message.lines() {
+            // Line with a single dot would end the mail.
+            if line.starts_with('.') {
+                mail.push('.');
+            }
+            mail.push_str(line);
+            mail.push_str("\r\n");
+        }
+        mail.push('.');
+        command(&mut write, &mut read, &mail, 250).await?;
+        command(&mut write, &mut read, "QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+async fn command<W, R>(write: &mut W, read: &mut R, line: &str, code: u16) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    write.write_all(line.as_bytes()).await?;
+    write.write_all(b"\r\n").await?;
+    read_reply(read, code).await
+}
+
+/// Reads a reply that can span multiple lines, e.g. `250-first\r\n250 last\r\n`.
+async fn read_reply<R: AsyncBufReadExt + Unpin>(read: &mut R, code: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            bail!("SMTP connection closed.");
+        }
+        ensure!(
+            line.starts_with(&code.to_string()),
+            "Expected SMTP reply {}, got {}",
+            code,
+            line.trim_end()
+        );
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileNotifier {
+    path: PathBuf,
+}
+
+impl FileNotifier {
+    pub fn new(config: &FileSinkConfig) -> FileNotifier {
+        FileNotifier {
+            path: config.path.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        let line = format!(
+            "{} [{:?}] {}: {}\n",
+            alert.timestamp_ns.into_date_time().to_rfc3339(),
+            alert.severity,
+            alert.title,
+            alert.message
+        );
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        // Tokio writes in background, alert would be lost if the process exits right after.
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CommandNotifier {
+    config: CommandSinkConfig,
+}
+
+impl CommandNotifier {
+    pub fn new(config: &CommandSinkConfig) -> CommandNotifier {
+        CommandNotifier {
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for CommandNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        let status = Command::new(&self.config.program)
+            .args(&self.config.args)
+            .arg(&alert.title)
+            .arg(&alert.message)
+            .status()
+            .await?;
+        ensure!(
+            status.success(),
+            "{} exited with {}",
+            self.config.program,
+            status
+        );
+        Ok(())
+    }
+}
+
+struct Sink {
+    name: String,
+    /// Alerts are sent by a task of the sink so that a slow sink stalls neither the message loop
+    /// nor other sinks.
+    sender: mpsc::UnboundedSender<Alert>,
+    task: JoinHandle<()>,
+    min_severity: Severity,
+    /// Timestamps of alerts sent within the last hour.
+    sent_ns: VecDeque<u64>,
+}
+
+pub struct Alerter {
+    sinks: Vec<Sink>,
+    dedup_window_ns: u64,
+    max_per_hour: usize,
+    /// Timestamp of the last alert that has been sent and number of alerts suppressed since then.
+    last_sent: HashMap<String, (u64, u32)>,
+}
+
+impl Alerter {
+    pub fn new(config: &AlertConfig) -> Alerter {
+        let mut alerter = Alerter {
+            sinks: Vec::new(),
+            dedup_window_ns: config.dedup_window_s as u64 * 1_000_000_000,
+            max_per_hour: config.max_per_hour as usize,
+            last_sent: HashMap::new(),
+        };
+        for x in &config.webhooks {
+            alerter.push(Box::new(WebhookNotifier::new(x)), x.min_severity);
+        }
+        for x in &config.smtp {
+            alerter.push(Box::new(SmtpNotifier::new(x)), x.min_severity);
+        }
+        for x in &config.files {
+            alerter.push(Box::new(FileNotifier::new(x)), x.min_severity);
+        }
+        for x in &config.commands {
+            alerter.push(Box::new(CommandNotifier::new(x)), x.min_severity);
+        }
+        alerter
+    }
+
+    pub fn push(&mut self, notifier: Box<dyn Notifier>, min_severity: Severity) {
+        let (sender, mut alerts) = mpsc::unbounded_channel::<Alert>();
+        let name = format!("{:?}", notifier);
+        let task = tokio::spawn(async move {
+            while let Some(alert) = alerts.recv().await {
+                match tokio::time::timeout(NOTIFY_TIMEOUT, notifier.notify(&alert)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("{:?} failed sending alert: {:#}", notifier, e),
+                    Err(_) => error!("{:?} timed out sending alert.", notifier),
+                }
+            }
+        });
+        self.sinks.push(Sink {
+            name,
+            sender,
+            task,
+            min_severity,
+            sent_ns: VecDeque::new(),
+        });
+    }
+
+    /// Logs `alert` and queues it for sinks unless an alert with the same key has been sent
+    /// recently. Critical alerts are never rate limited. Failures of sinks are only logged.
+    pub fn raise(&mut self, mut alert: Alert) {
+        match alert.severity {
+            Severity::Info => info!("{}: {}", alert.title, alert.message),
+            Severity::Warning => warn!("{}: {}", alert.title, alert.message),
+            Severity::Critical => error!("{}: {}", alert.title, alert.message),
+        }
+        if let Some((last_sent_ns, suppressed)) = self.last_sent.get_mut(&alert.key) {
+            if alert.timestamp_ns < *last_sent_ns + self.dedup_window_ns {
+                *suppressed += 1;
+                return;
+            }
+            if *suppressed != 0 {
+                alert.message = format!(
+                    "{}\n{} similar alerts suppressed.",
+                    alert.message, suppressed
+                );
+            }
+        }
+        self.last_sent
+            .insert(alert.key.clone(), (alert.timestamp_ns, 0));
+        for sink in &mut self.sinks {
+            if alert.severity < sink.min_severity {
+                continue;
+            }
+            while sink
+                .sent_ns
+                .front()
+                .map_or(false, |x| x + HOUR_NS <= alert.timestamp_ns)
+            {
+                sink.sent_ns.pop_front();
+            }
+            if alert.severity != Severity::Critical && sink.sent_ns.len() >= self.max_per_hour {
+                debug!("Alert rate limited by {}.", sink.name);
+                continue;
+            }
+            sink.sent_ns.push_back(alert.timestamp_ns);
+            let _ = sink.sender.send(alert.clone());
+        }
+    }
+
+    /// Waits until queued alerts have been sent, alerts raised afterwards are dropped.
+    pub async fn close(&mut self) {
+        for sink in self.sinks.drain(..) {
+            let Sink {
+                name, sender, task, ..
+            } = sink;
+            drop(sender);
+            if let Err(e) = task.await {
+                error!("{} stopped sending alerts: {:?}", name, e);
+            }
+        }
+    }
+}
+
+/// Sends a critical alert to sinks of the selected exchange when killing an agent has failed and
+/// orders or positions may still be open. There is no agent left to deliver it.
+pub async fn alert_kill_failed(error: &Error, clock: &dyn Clock) {
+    let config = get_exchange_config()
+        .map(|x| x.alerts.clone())
+        .unwrap_or_default();
+    let mut alerter = Alerter::new(&config);
+    alerter.raise(Alert {
+        severity: Severity::Critical,
+        key: "kill failed".into(),
+        title: "Killing agent FAILED".into(),
+        message: format!(
+            "Orders and positions may still be open, check the exchange.\n{:?}",
+            error
+        ),
+        timestamp_ns: clock.now().timestamp_ns(),
+    });
+    alerter.close().await;
+}
+
+/// Raises alerts on risk breaches, large fills, crashes and repeated reconnects.
+pub struct AlertAgent {
+    alerter: Alerter,
+    /// Clock of the agent, risk breaches don't carry their own timestamp.
+    clock: Arc<dyn Clock>,
+    max_reconnects: usize,
+    reconnect_window_ns: u64,
+    reconnects_ns: VecDeque<u64>,
+    large_fill_value: Option<Decimal>,
+}
+
+impl AlertAgent {
+    pub fn new(config: &AlertConfig, clock: Arc<dyn Clock>) -> AlertAgent {
+        AlertAgent {
+            alerter: Alerter::new(config),
+            clock,
+            max_reconnects: config.max_reconnects as usize,
+            reconnect_window_ns: config.reconnect_window_s as u64 * 1_000_000_000,
+            reconnects_ns: VecDeque::new(),
+            large_fill_value: config.large_fill_value.map(|x| x.to_decimal().unwrap()),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+#[async_trait]
+impl ExchangeListener for AlertAgent {
+    async fn on_execution<'a>(
+        &'a mut self,
+        execution: &'a Execution,
+        _instruments: &'a HashMap<String, InstrumentConfig>,
+    ) -> Result<()> {
+        if let Some(max) = self.large_fill_value {
+            if execution.value.abs() > max {
+                self.alerter.raise(Alert {
+                    severity: Severity::Warning,
+                    key: format!("large fill {}", execution.order_id.to_string()),
+                    title: format!("Large fill on {}", execution.market),
+                    message: format!(
+                        "Order {} filled {} at {}, value {}.",
+                        execution.order_id.to_string(),
+                        execution.amount,
+                        execution.executed_price,
+                        execution.value
+                    ),
+                    timestamp_ns: execution.timestamp_ns,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_risk_breach(&mut self, breach: &RiskBreach) -> Result<()> {
+        let severity = match breach.limit {
+            Limit::KillSwitch | Limit::PortfolioDrawdown => Severity::Critical,
+            _ => Severity::Warning,
+        };
+        self.alerter.raise(Alert {
+            severity,
+            key: format!("risk {:?}", breach.limit),
+            title: format!("Risk limit {:?} hit", breach.limit),
+            message: format!(
+                "Value {} exceeds {}, order {:?}.",
+                breach.value, breach.max, breach.order_id
+            ),
+            timestamp_ns: self.clock.now().timestamp_ns(),
+        });
+        Ok(())
+    }
+
+    async fn on_maintenance(&mut self, maintenance: &Maintenance) -> Result<()> {
+        let timestamp_ns = maintenance.timestamp_s as u64 * 1_000_000_000;
+        match maintenance.mode {
+            MaintenanceMode::Reconnect => {
+                self.reconnects_ns.push_back(timestamp_ns);
+                while self
+                    .reconnects_ns
+                    .front()
+                    .map_or(false, |x| x + self.reconnect_window_ns <= timestamp_ns)
+                {
+                    self.reconnects_ns.pop_front();
+                }
+                if self.reconnects_ns.len() >= self.max_reconnects {
+                    self.alerter.raise(Alert {
+                        severity: Severity::Critical,
+                        key: "reconnects".into(),
+                        title: "Reconnecting repeatedly".into(),
+                        message: format!(
+                            "{} reconnects within {} s.",
+                            self.reconnects_ns.len(),
+                            self.reconnect_window_ns / 1_000_000_000
+                        ),
+                        timestamp_ns,
+                    });
+                }
+            }
+            MaintenanceMode::Crash => {
+                self.alerter.raise(Alert {
+                    severity: Severity::Critical,
+                    key: "crash".into(),
+                    title: "Agent crashed".into(),
+                    message: "Agent has been killed, orders were canceled and positions closed."
+                        .into(),
+                    timestamp_ns,
+                });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn on_shutdown(&mut self) -> Result<()> {
+        self.alerter.close().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod t_alerts {
+    use std::sync::Mutex;
+
+    use chrono::{TimeZone, Utc};
+    use merovingian::order::IdGenerator;
+    use mouse::num::traits::Zero;
+    use nebuchadnezzar_core::clock::SimulatedClock;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        alerts: Arc<Mutex<Vec<Alert>>>,
+    }
+
+    #[async_trait]
+    impl Notifier for Recorder {
+        async fn notify(&self, alert: &Alert) -> Result<()> {
+            self.alerts.lock().unwrap().push(alert.clone());
+            Ok(())
+        }
+    }
+
+    fn alert(severity: Severity, key: &str, timestamp_s: u64) -> Alert {
+        Alert {
+            severity,
+            key: key.into(),
+            title: key.into(),
+            message: "message".into(),
+            timestamp_ns: timestamp_s * 1_000_000_000,
+        }
+    }
+
+    fn recording_alerter(config: &AlertConfig) -> (Alerter, Arc<Mutex<Vec<Alert>>>) {
+        let recorder = Recorder::default();
+        let alerts = recorder.alerts.clone();
+        let mut alerter = Alerter::new(config);
+        alerter.push(Box::new(recorder), Severity::Warning);
+        (alerter, alerts)
+    }
+
+    fn keys(alerts: &Arc<Mutex<Vec<Alert>>>) -> Vec<String> {
+        alerts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|x| x.key.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn t_dedup_and_rate_limit() {
+        let config = AlertConfig {
+            dedup_window_s: 60,
+            max_per_hour: 2,
+            ..Default::default()
+        };
+        let (mut alerter, alerts) = recording_alerter(&config);
+        alerter.raise(alert(Severity::Info, "info", 0));
+        alerter.raise(alert(Severity::Warning, "a", 0));
+        alerter.raise(alert(Severity::Warning, "a", 30));
+        alerter.raise(alert(Severity::Warning, "a", 60));
+        // Limit of 2 alerts per hour is reached.
+        alerter.raise(alert(Severity::Warning, "b", 120));
+        alerter.raise(alert(Severity::Critical, "c", 120));
+        alerter.raise(alert(Severity::Warning, "b", 3660));
+        alerter.close().await;
+        assert_eq!(keys(&alerts), vec!["a", "a", "c", "b"]);
+        assert_eq!(
+            alerts.lock().unwrap()[1].message,
+            "message\n1 similar alerts suppressed."
+        );
+        // Closed alerter drops alerts.
+        alerter.raise(alert(Severity::Critical, "d", 3660));
+        assert_eq!(alerts.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn t_escalation() -> Result<()> {
+        let config = AlertConfig {
+            max_reconnects: 2,
+            reconnect_window_s: 60,
+            large_fill_value: Some(1.),
+            ..Default::default()
+        };
+        let (alerter, alerts) = recording_alerter(&config);
+        let clock = Arc::new(SimulatedClock::new(Utc.timestamp(90, 0)));
+        let mut agent = AlertAgent::new(&config, clock);
+        agent.alerter = alerter;
+        let reconnect = |timestamp_s| Maintenance {
+            mode: MaintenanceMode::Reconnect,
+            timestamp_s,
+        };
+        // Only the third reconnect is within the window of the first two.
+        agent.on_maintenance(&reconnect(0)).await?;
+        agent.on_maintenance(&reconnect(60)).await?;
+        agent.on_maintenance(&reconnect(61)).await?;
+        agent
+            .on_risk_breach(&RiskBreach {
+                limit: Limit::KillSwitch,
+                order_id: None,
+                value: Decimal::zero(),
+                max: Decimal::zero(),
+            })
+            .await?;
+        let mut execution = Execution {
+            market: "XBTUSD".into(),
+            order_id: IdGenerator::new_order_id(0),
+            value: Decimal::new(-1, 0),
+            amount: Decimal::new(1, 0),
+            amount_left: Decimal::zero(),
+            fee_paid: Decimal::zero(),
+            executed_price: Decimal::new(1, 0),
+            timestamp_ns: 0,
+        };
+        agent.on_execution(&execution, &HashMap::new()).await?;
+        execution.value = Decimal::new(-2, 0);
+        agent.on_execution(&execution, &HashMap::new()).await?;
+        // Queued alerts are sent before shutting down.
+        agent.on_shutdown().await?;
+        let alerts = alerts.lock().unwrap();
+        assert_eq!(alerts.len(), 3);
+        assert_eq!(alerts[0].key, "reconnects");
+        assert_eq!(alerts[1].severity, Severity::Critical);
+        assert_eq!(alerts[1].timestamp_ns, 90_000_000_000);
+        assert_eq!(alerts[2].title, "Large fill on XBTUSD");
+        Ok(())
+    }
+
+    /// Accepts one HTTP request and returns its body.
+    async fn http_stub(listener: TcpListener) -> Result<String> {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(i) = text.find("\r\n\r\n") {
+                let length: usize = text[..i]
+                    .to_lowercase()
+                    .lines()
+                    .find_map(|x| {
+                        x.strip_prefix("content-length: ")
+                            .map(|x| x.parse().unwrap())
+                    })
+                    .unwrap_or(0);
+                if request.len() >= i + 4 + length {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await?;
+                    return Ok(text[i + 4..].to_string());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn t_webhook() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/alert", listener.local_addr()?);
+        let stub = tokio::spawn(http_stub(listener));
+        let notifier = WebhookNotifier::new(&WebhookSinkConfig {
+            url,
+            min_severity: Severity::Info,
+        });
+        let alert = alert(Severity::Critical, "a", 1);
+        notifier.notify(&alert).await?;
+        let body: serde_json::Value = serde_json::from_str(&stub.await??)?;
+        assert_eq!(body["severity"], "critical");
+        assert_eq!(body["title"], "a");
+        Ok(())
+    }
+
+    /// Accepts one SMTP session and returns the mail.
+    async fn smtp_stub(listener: TcpListener) -> Result<String> {
+        let (stream, _) = listener.accept().await?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+        write.write_all(b"220 stub\r\n").await?;
+        let mut mail = String::new();
+        loop {
+            let mut line = String::new();
+            read.read_line(&mut line).await?;
+            let reply: &[u8] = match line.trim_end() {
+                "EHLO localhost" => b"250-stub\r\n250 OK\r\n",
+                "DATA" => {
+                    write.write_all(b"354 go on\r\n").await?;
+                    loop {
+                        let mut line = String::new();
+                        read.read_line(&mut line).await?;
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        mail.push_str(&line);
+                    }
+                    b"250 OK\r\n"
+                }
+                "QUIT" => {
+                    write.write_all(b"221 bye\r\n").await?;
+                    return Ok(mail);
+                }
+                _ => b"250 OK\r\n",
+            };
+            write.write_all(reply).await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn t_smtp() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+        let stub = tokio::spawn(smtp_stub(listener));
+        let notifier = SmtpNotifier::new(&SmtpSinkConfig {
+            address,
+            from: "matrix@localhost".into(),
+            to: vec!["a@localhost".into(), "b@localhost".into()],
+            min_severity: Severity::Info,
+        });
+        let mut alert = alert(Severity::Critical, "a", 1);
+        alert.message = "first\n.second".into();
+        notifier.notify(&alert).await?;
+        let mail = stub.await??;
+        assert!(mail.contains("To: <a@localhost>, <b@localhost>\r\n"));
+        assert!(mail.contains("Subject: [Critical] a\r\n"));
+        assert!(mail.ends_with("\r\n\r\nfirst\r\n..second\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("alerts.log");
+        let notifier = FileNotifier::new(&FileSinkConfig {
+            path: path.clone(),
+            min_severity: Severity::Info,
+        });
+        notifier.notify(&alert(Severity::Warning, "a", 0)).await?;
+        notifier.notify(&alert(Severity::Critical, "b", 0)).await?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "1970-01-01T00:00:00+00:00 [Warning] a: message\n1970-01-01T00:00:00+00:00 \
+             [Critical] b: message\n"
+        );
+        Ok(())
+    }
+}
@@ -1,14 +1,31 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use merovingian::candles::Candles;
+use merovingian::minable_models::{Margin, Position, Trade};
 use merovingian::order::{Order, OrderId};
+use merovingian::order_book::OrderBook;
 use mouse::error::Result;
+use nebuchadnezzar_core::clock::ServerClock;
 use nebuchadnezzar_core::Exchange;
 
+use super::reconciliation::OrderSnapshot;
+use crate::agents::network_agents::{Execution, InstrumentConfig};
+
 #[async_trait]
 pub trait NetworkClient: Send + Sync {
     type Exchange: Exchange + Send + Sync;
 
     fn exchange(&self) -> Self::Exchange;
+    /// Exchange server time estimate that drives signing and candle ticks. Defaults to local
+    /// clock.
+    fn clock(&self) -> Arc<ServerClock> {
+        Default::default()
+    }
+    /// Orders are matched in process instead of being sent to the exchange.
+    fn is_simulated(&self) -> bool {
+        false
+    }
     /// Fetches candles between start and including end timestamp, implementors also need to auto
     /// paginate and rate limit.
     async fn fetch_candles(
@@ -22,6 +39,22 @@ pub trait NetworkClient: Send + Sync {
 
     async fn post_orders(&self, orders: &Vec<Order>) -> Result<()>;
     async fn cancel_orders(&self, orders: &Vec<OrderId>) -> Result<()>;
+    /// Orders that are currently open on the exchange, including the ones not placed by us.
+    async fn fetch_open_orders(&self) -> Result<Vec<OrderSnapshot>>;
+    async fn fetch_positions(&self) -> Result<Vec<Position>>;
+    async fn fetch_margin(&self) -> Result<Margin>;
     /// Closes all positions and cancels all orders.
     async fn kill(&self) -> Result<()>;
+
+    // Simulated clients match orders against market data received by websocket.
+    async fn on_public_trade(&self, _trade: &Trade, _market: &str, _config: &InstrumentConfig) {}
+    async fn on_order_book(&self, _order_book: &OrderBook, _config: &InstrumentConfig) {}
+    /// Executions of simulated orders since the last call.
+    async fn take_executions(&self) -> Vec<Execution> {
+        Vec::new()
+    }
+    /// Orders of a simulated client that have been canceled since the last call.
+    async fn take_canceled(&self) -> Vec<OrderId> {
+        Vec::new()
+    }
 }
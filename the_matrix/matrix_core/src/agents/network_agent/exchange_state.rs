@@ -1,30 +1,48 @@
 use std::collections::{HashMap, HashSet};
-use std::io::ErrorKind;
 use std::sync::Arc;
+use std::time::Instant;
 
-use chrono::Utc;
-use config::{get_exchange_config, CONFIG};
+use config::{get_exchange_config, ConfigChange, CONFIG};
 use futures::FutureExt;
 use iaas::mysql::models::{ExchangeConfig, ModelConfig};
-use iaas::mysql::{load_exchange_config, load_last_maintenance};
+use iaas::mysql::load_exchange_config;
+#[cfg(not(feature = "test"))]
+use iaas::mysql::load_last_maintenance;
 use merovingian::candles::Candles;
 use merovingian::candles_builder::CandlesBuilder;
 use merovingian::minable_models::*;
 use merovingian::order;
-use merovingian::order::{Order, OrderId};
+use merovingian::order::{IdGenerator, Order, OrderId};
+use merovingian::order_book::OrderBook;
 use mouse::error::*;
 use mouse::log::*;
-use mouse::num::traits::Zero;
+use mouse::num::traits::{ToPrimitive, Zero};
 use mouse::num::{Decimal, IntoDecimal, NumExt};
 use mouse::time::Timestamp;
+use nebuchadnezzar_core::client::ClientCapability;
+use nebuchadnezzar_core::clock::Clock;
+use nebuchadnezzar_core::metrics;
 use nebuchadnezzar_core::Exchange;
-use sorted_vec::ReverseSortedVec;
-use tokio::fs::{create_dir_all, metadata, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use speedy::Writable;
+use tokio::fs::{create_dir_all, metadata};
 use tokio::sync::Mutex;
+use tracing::{info_span, Instrument};
 use zion::{Command, Zion};
 
+use super::admin::{AdminRequest, AdminResponse, AdminServer, ModelInfo, OrderInfo, PositionInfo};
+use super::alerts::AlertAgent;
 use super::client::NetworkClient;
+use super::execution::Executor;
+use super::hot_reload::HotReload;
+use super::journal::{Journal, JournalEntry, JournaledOrder};
+use super::metrics::{
+    exchange_label, record_ws_latency, serve_metrics, MetricsAgent, CANDLE_TO_ORDER,
+    ON_NEW_CANDLE_DURATION, OPEN_ORDERS, OPEN_ORDERS_DURATION,
+};
+use super::models::register_models;
+use super::portfolio::portfolio;
+use super::reconciliation::{self, OrderSnapshot, Reconciler, Repair};
+use super::risk::{Limit, RiskEngine};
 use super::websocket::Ws;
 use crate::agents::data_agents::{DbAgent, ExchangeDataAgent};
 use crate::agents::network_agents::{
@@ -34,6 +52,9 @@ use crate::agents::trade_guard::TradeGuard;
 use crate::error::MatrixError;
 use crate::event::Listeners;
 
+/// Model of orders that close positions no model owns, e.g. when flattening a market.
+const NO_MODEL_ID: u32 = u32::MAX;
+
 enum MaintenanceState {
     Normal,
     SafeReload,
@@ -48,12 +69,30 @@ pub struct NetworkAgentState<C: NetworkClient, WS: Ws> {
     #[cfg(not(feature = "test"))]
     listeners: Listeners<dyn ExchangeListener>,
     client: C,
+    /// Server clock of the client unless replaced by `set_clock`.
+    clock: Arc<dyn Clock>,
     pub(super) ws: Option<WS>,
     candles_builder: CandlesBuilder,
     active_instruments: HashMap<String, InstrumentConfig>,
     /// Market orders that have been sent to the exchange but haven't been executed.
     // executing_market_orders: Arc<Mutex<HashMap<Uuid, Vec<ExecutingMarketOrder>>>>,
     open_orders: HashMap<OrderId, OpenedOrder>,
+    /// Every change of `open_orders` is written here first so that it survives a restart.
+    journal: Journal,
+    reconciler: Reconciler,
+    /// Checks orders of models before they are sent.
+    risk: RiskEngine,
+    /// Splits orders of models that don't use `ExecutionAlgo::Direct`.
+    executor: Executor,
+    /// Orders that close positions of a flattened market. They are sent without going through
+    /// `executor` and are reduce-only for risk checks.
+    direct_orders: HashSet<OrderId>,
+    model_configs: Vec<ModelConfig>,
+    admin: Option<AdminServer>,
+    /// Set when config has been loaded from a file that can be watched.
+    hot_reload: Option<HotReload>,
+    /// Changes requested by the admin endpoint, applied with config changes on the next candle.
+    admin_changes: Vec<ConfigChange>,
     maintenance_state: MaintenanceState,
     tmp_sub_orders_to_open: Arc<Mutex<Vec<Order>>>,
     tmp_sub_orders_to_cancel: Arc<Mutex<Vec<OrderId>>>,
@@ -70,17 +109,47 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         instruments: Vec<(String, Instrument)>,
         balance: Decimal,
         leverage: Decimal,
-        supported_timeframes: &ReverseSortedVec<u32>,
+        capability: &ClientCapability,
         max_candles_fetched_at_once: usize,
     ) -> Result<NetworkAgentState<C, WS>> {
         #[cfg(feature = "test")]
         println!("test feature enabled");
         #[cfg(feature = "assert")]
         println!("assert feature enabled");
+        let mut hot_reload = None;
+        if let Some(exchange_config) = get_exchange_config() {
+            // Markets are known only after connecting and models of other agents only once they
+            // are registered, the rest has been validated when loading.
+            let markets = Some(instrument_configs.keys().cloned().collect());
+            let schema = register_models(exchange_config, markets)?;
+            hot_reload = config::watcher()
+                .map(|watcher| HotReload::new(watcher, exchange_config.clone(), schema));
+        }
+        let name = account_name(client.exchange().name());
+        let mut risk = RiskEngine::new(
+            &get_exchange_config()
+                .map(|x| x.risk.clone())
+                .unwrap_or_default(),
+            balance,
+        );
+        if let Some(portfolio) = portfolio() {
+            risk = risk.with_portfolio(name, portfolio);
+        }
+        let max_leverage = config
+            .max_leverage
+            .to_decimal()
+            .ok_or_else(|| anyhow!("max_leverage {} isn't a number.", config.max_leverage))?;
+        risk.set_exchange_limits(max_leverage, config.max_orders_per_m);
+        let executor = Executor::new(
+            model_configs
+                .iter()
+                .map(|x| (x.market_model_id, x.execution()))
+                .collect(),
+        );
         let mut listeners = Listeners::<dyn ExchangeListener>::new();
         listeners.push(Box::new(TradeGuard::new(
-            model_configs,
-            config.max_leverage.to_decimal().unwrap(),
+            model_configs.clone(),
+            max_leverage,
             config.max_orders_per_m,
             config.id,
             client.exchange().name().to_string(),
@@ -88,32 +157,69 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
             balance,
             &instrument_configs,
         )?));
+        listeners.push(Box::new(MetricsAgent::default()));
         push_non_essential_listeners(
             &mut listeners,
             config.use_public_data_miner,
-            client.exchange().name(),
+            name,
             config.id,
+            client.clock(),
         );
+        let mut exchange_path = CONFIG.data_dir.join(name);
+        // Simulated orders must never be mistaken for real ones.
+        if client.is_simulated() {
+            exchange_path = exchange_path.join("paper");
+        }
+        if !metadata(&exchange_path).await.is_ok() {
+            create_dir_all(&exchange_path).await?;
+        }
+        let journal = Journal::open(&exchange_path).await?;
+        risk = risk.with_positions(journal.state().positions.clone());
+        let admin = match get_exchange_config().and_then(|x| x.admin.as_ref()) {
+            Some(config) => {
+                Some(AdminServer::bind(config, &exchange_path, client.clock()).await?)
+            }
+            None => None,
+        };
+        if let Some(config) = get_exchange_config().and_then(|x| x.metrics.as_ref()) {
+            serve_metrics(config).await?;
+        }
+        let open_orders = journal
+            .state()
+            .open_orders
+            .iter()
+            .map(|(id, order)| (*id, order.into()))
+            .collect();
         let mut state = NetworkAgentState {
             #[cfg(not(feature = "test"))]
             zion: Zion::new(),
             listeners,
+            clock: client.clock(),
             client,
             ws: Some(ws),
             candles_builder: CandlesBuilder::new(),
             active_instruments: instrument_configs,
-            open_orders: Default::default(),
+            open_orders,
+            journal,
+            reconciler: Reconciler::new(
+                get_exchange_config()
+                    .map(|x| x.reconciliation.clone())
+                    .unwrap_or_default(),
+            ),
+            risk,
+            executor,
+            direct_orders: HashSet::new(),
+            model_configs,
+            admin,
+            hot_reload,
+            admin_changes: Vec::new(),
             tmp_sub_orders_to_cancel: Arc::new(Mutex::new(Vec::new())),
             tmp_sub_orders_to_open: Arc::new(Default::default()),
             tmp_orders_to_open: vec![],
             maintenance_state: MaintenanceState::Normal,
         };
         state
-            .init(
-                instruments,
-                supported_timeframes,
-                max_candles_fetched_at_once,
-            )
+            .init(instruments, capability, max_candles_fetched_at_once)
             .await?;
         Ok(state)
     }
@@ -121,20 +227,16 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
     async fn init(
         &mut self,
         instruments: Vec<(String, Instrument)>,
-        supported_timeframes: &ReverseSortedVec<u32>,
+        capability: &ClientCapability,
         max_candles_fetched_at_once: usize,
     ) -> Result<()> {
-        let exchange_path = CONFIG.data_dir.join(self.client.exchange().name());
-        if !metadata(&exchange_path).await.is_ok() {
-            create_dir_all(&exchange_path).await?;
-        }
         // There could be network delays where we would send orders but instruments haven't been
         // initialized. It actually happened.
         for (market, instrument) in instruments {
             self.on_instrument_changed(instrument, &market).await?;
         }
 
-        self.load_required_candles(supported_timeframes, max_candles_fetched_at_once)
+        self.load_required_candles(capability, max_candles_fetched_at_once)
             .await?;
 
         // In order for test to be valid we need to call all models on first candle.
@@ -161,10 +263,30 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         &self.active_instruments
     }
 
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Replaces the clock used for timestamps and candle ticks, e.g. with `SimulatedClock` when
+    /// replaying recorded data.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        for listener in self.listeners.iter_mut() {
+            if let Some(alerts) = listener.downcast_mut::<AlertAgent>() {
+                alerts.set_clock(clock.clone());
+            }
+        }
+        self.clock = clock;
+    }
+
     pub fn client(&self) -> &C {
         &self.client
     }
 
+    /// Executions from this timestamp haven't been processed yet, `u64::MAX` if there weren't any.
+    pub(super) fn catch_up_start_ns(&self) -> u64 {
+        self.journal.state().catch_up_start_ns()
+    }
+
     pub async fn on_funding_execution(
         &mut self,
         funding_execution: FundingExecution,
@@ -174,22 +296,31 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
             .broadcast_async(|x| x.on_funding_execution(&funding_execution, &instruments))
             .await?;
         // Doing sequentially to ensure if error happens we can still process orders on next boot
-        save_execution_time(
-            self.client.exchange().name(),
-            funding_execution.timestamp_ns,
-        )
-        .await?;
+        self.journal
+            .append(&[JournalEntry::Checkpoint {
+                timestamp_ns: funding_execution.timestamp_ns,
+            }])
+            .await?;
         Ok(())
     }
 
     pub async fn on_execution(&mut self, execution: Execution) -> Result<()> {
-        let partial_execution = match self.open_orders.get_mut(&execution.order_id) {
-            Some(partial_execution) => partial_execution,
-            None => {
+        if !self.open_orders.contains_key(&execution.order_id) {
+            if execution.order_id.model_id() != NO_MODEL_ID {
                 error!("Unknown order executed, ignoring it.");
-                return Ok(());
             }
-        };
+            return Ok(());
+        }
+        self.journal
+            .append(&[JournalEntry::Fill {
+                order_id: execution.order_id,
+                amount: execution.amount,
+                value: execution.value,
+                fee_paid: execution.fee_paid,
+                timestamp_ns: execution.timestamp_ns,
+            }])
+            .await?;
+        let partial_execution = self.open_orders.get_mut(&execution.order_id).unwrap();
         partial_execution.amount += execution.amount;
         partial_execution.value += execution.value;
         partial_execution.fee_paid += execution.fee_paid;
@@ -199,6 +330,23 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         Ok(())
     }
 
+    /// Settles an order canceled by the exchange, its partial execution is attributed to models.
+    pub async fn on_order_canceled(&mut self, order_id: OrderId, timestamp_ns: u64) -> Result<()> {
+        let open_order = match self.open_orders.remove(&order_id) {
+            Some(open_order) => open_order,
+            None => return Ok(()),
+        };
+        self.executor.on_child_done(order_id, open_order.amount);
+        let mut entries = if open_order.amount.is_zero() {
+            Vec::with_capacity(1)
+        } else {
+            self.attribute_fills(open_order, timestamp_ns).await?
+        };
+        entries.push(JournalEntry::Canceled { order_id });
+        self.journal.append(&entries).await?;
+        self.maybe_go_under_maintenance().await
+    }
+
     /// Calls 'on_instrument_changed'.
     pub async fn on_new_instrument(
         &mut self,
@@ -216,6 +364,9 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         instrument: Instrument,
         market: &String,
     ) -> Result<()> {
+        if let Some(mark_price) = instrument.mark_price.to_decimal() {
+            self.risk.on_mark_price(market, mark_price);
+        }
         let config = self.active_instruments.get(market);
         broadcast_async!(self, on_instrument_changed, instrument, market, config);
         Ok(())
@@ -236,6 +387,8 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                 Err(funding) => self.on_funding_execution(funding).await?,
             }
         }
+        // Backtests and replays have no database to recover from.
+        #[cfg(not(feature = "test"))]
         if get_exchange_config().is_none() {
             if let Some(Maintenance {
                 mode: MaintenanceMode::Crash,
@@ -266,6 +419,11 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         Ok(())
     }
     pub async fn on_public_trade(&mut self, trade: Trade, symbol: String) -> Result<()> {
+        record_ws_latency("trade", self.clock.now().timestamp_ns(), trade.timestamp_ns);
+        if let Some(config) = self.active_instruments.get(&symbol) {
+            self.client.on_public_trade(&trade, &symbol, config).await;
+        }
+        self.process_simulated_executions().await?;
         broadcast_async!(self, on_public_trade, trade, symbol);
         let completed_timestamp_s = self.candles_builder.tick(
             &symbol,
@@ -314,8 +472,29 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         broadcast_async!(self, on_order_book_updated, order_book_update, symbol);
         Ok(())
     }
+    pub async fn on_order_book_changed(&mut self, order_book: &OrderBook) -> Result<()> {
+        self.executor.on_order_book(order_book);
+        if let Some(config) = self.active_instruments.get(order_book.market()) {
+            self.client.on_order_book(order_book, config).await;
+        }
+        self.process_simulated_executions().await?;
+        self.listeners
+            .broadcast_async(|x| x.on_order_book_changed(order_book))
+            .await?;
+        Ok(())
+    }
     pub async fn on_margin_changed(&mut self, margin: Margin) -> Result<()> {
         broadcast_async!(self, on_margin_changed, margin);
+        self.risk.report_notional(&self.active_instruments);
+        let mut kill = false;
+        for breach in self.risk.on_balance(margin.balance, margin.timestamp_ns) {
+            error!("Risk limit breached: {:?}", breach);
+            kill |= self.risk.kill_on_drawdown() || breach.limit == Limit::PortfolioDrawdown;
+            broadcast_async!(self, on_risk_breach, breach);
+        }
+        if kill {
+            self.kill_switch().await?;
+        }
         Ok(())
     }
     pub async fn on_position_changed(&mut self, position: Position) -> Result<()> {
@@ -337,6 +516,400 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
         Ok(())
     }
 
+    pub(super) fn reconciliation_interval_s(&self) -> u32 {
+        self.reconciler.interval_s()
+    }
+
+    fn local_order_snapshots(&self) -> Vec<OrderSnapshot> {
+        self.open_orders
+            .iter()
+            .map(|(id, order)| OrderSnapshot {
+                id: *id,
+                market: order.market.clone(),
+                amount_left: order.max_amount - order.amount,
+            })
+            .collect()
+    }
+
+    /// Compares open orders, positions and margin against the exchange. Repairs mismatches if
+    /// possible otherwise goes under safe shutdown.
+    pub(super) async fn reconcile(&mut self) -> Result<()> {
+        let fetched = try_join!(
+            self.client.fetch_open_orders(),
+            self.client.fetch_positions(),
+            self.client.fetch_margin()
+        );
+        let (remote_orders, remote_positions, margin) = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                warn!(
+                    "Skipping reconciliation, fetching exchange state failed: {:?}",
+                    e
+                );
+                return Ok(());
+            }
+        };
+        self.on_margin_changed(margin).await?;
+        let local_orders = self.local_order_snapshots();
+        let local_positions = reconciliation::net_positions(self.risk.positions());
+        let mismatches = reconciliation::diff(
+            &local_orders,
+            &remote_orders,
+            Some(&local_positions),
+            &remote_positions,
+            self.reconciler.tolerance(),
+        );
+        for mismatch in &mismatches {
+            warn!("Reconciliation mismatch: {:?}", mismatch);
+        }
+        let mut orders_to_cancel = Vec::new();
+        let mut orders_to_forget = Vec::new();
+        let mut escalate = false;
+        for repair in self.reconciler.plan(mismatches, true) {
+            match repair {
+                Repair::Cancel(id) => orders_to_cancel.push(id),
+                Repair::Forget(id) => orders_to_forget.push(id),
+                Repair::Escalate(mismatch) => {
+                    error!("Cannot repair {:?}, shutting down safely.", mismatch);
+                    escalate = true;
+                }
+            }
+        }
+        if !orders_to_cancel.is_empty() {
+            info!("Canceling untracked orders: {:?}", orders_to_cancel);
+            self.client.cancel_orders(&orders_to_cancel).await?;
+        }
+        if !orders_to_forget.is_empty() {
+            info!(
+                "Forgetting orders missing on exchange: {:?}",
+                orders_to_forget
+            );
+            let entries: Vec<_> = orders_to_forget
+                .iter()
+                .map(|id| JournalEntry::Canceled { order_id: *id })
+                .collect();
+            self.journal.append(&entries).await?;
+            for id in &orders_to_forget {
+                if let Some(open_order) = self.open_orders.remove(id) {
+                    self.executor.on_child_done(*id, open_order.amount);
+                }
+            }
+        }
+        if escalate {
+            self.handle_maintenance(MaintenanceMode::ShutdownSafe)
+                .await?;
+        }
+        self.maybe_go_under_maintenance().await
+    }
+
+    /// Cancels and sends child orders of execution algorithms.
+    pub(super) async fn poll_execution_algos(&mut self) -> Result<()> {
+        if self.executor.is_empty() {
+            return Ok(());
+        }
+        let timestamp_ns = self.clock.now().timestamp_ns();
+        let cancels = self.executor.cancels(timestamp_ns);
+        // Children are settled once the exchange confirms the cancel or by their last fill, a
+        // child may still be filled while it's being canceled.
+        if !cancels.is_empty() {
+            if let Err(e) = self.client.cancel_orders(&cancels).await {
+                warn!("Failed to cancel children {:?}: {:?}", cancels, e);
+                self.executor.on_cancel_failed(&cancels);
+            }
+        }
+        let children = self.executor.orders(timestamp_ns, &self.active_instruments);
+        if children.is_empty() {
+            return Ok(());
+        }
+        let mut intents = Vec::with_capacity(children.len());
+        let mut orders = Vec::with_capacity(children.len());
+        for (child, parent_id) in children {
+            let open_sub_orders = vec![OpenedSubOrder {
+                amount: child.amount,
+                id: parent_id,
+            }];
+            let open_order = OpenedOrder::new(child.market.clone(), child.amount, open_sub_orders);
+            intents.push(open_order.intent(&child));
+            self.open_orders.insert(child.id, open_order);
+            orders.push(child);
+        }
+        self.journal.append(&intents).await?;
+        self.client.post_orders(&orders).await?;
+        let entries: Vec<_> = orders
+            .iter()
+            .map(|x| JournalEntry::Placed { order_id: x.id })
+            .collect();
+        self.journal.append(&entries).await?;
+        Ok(())
+    }
+
+    /// Handles requests that have been received by the admin endpoint since the last call.
+    pub(super) async fn handle_admin_requests(&mut self) -> Result<()> {
+        let requests = match &mut self.admin {
+            Some(admin) => admin.drain(),
+            None => return Ok(()),
+        };
+        for pending in requests {
+            info!("Admin request: {:?}", pending.request);
+            match self.handle_admin_request(pending.request.clone()).await {
+                Ok(response) => pending.respond(Ok(response)),
+                // Reload and shutdown are requested by returning an error.
+                Err(e) if e.downcast_ref::<MatrixError>().is_some() => {
+                    pending.respond(Ok(AdminResponse::Done));
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Admin request failed: {:?}", e);
+                    pending.respond(Err(format!("{:#}", e)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_admin_request(&mut self, request: AdminRequest) -> Result<AdminResponse> {
+        let response = match request {
+            AdminRequest::Models => AdminResponse::Models(
+                self.model_configs
+                    .iter()
+                    .map(|x| ModelInfo {
+                        model_id: x.market_model_id,
+                        market: x.market.clone(),
+                        target_leverage: x.target_leverage,
+                        variable_values: x.variable_values(),
+                        paused: self.risk.is_paused(x.market_model_id),
+                    })
+                    .collect(),
+            ),
+            AdminRequest::Positions => {
+                let mut positions: Vec<_> = self
+                    .risk
+                    .positions()
+                    .iter()
+                    .filter(|(_, amount)| !amount.is_zero())
+                    .map(|((model_id, market), amount)| PositionInfo {
+                        model_id: Some(*model_id),
+                        market: market.clone(),
+                        amount: *amount,
+                    })
+                    .collect();
+                positions.sort_by(|a, b| (a.model_id, &a.market).cmp(&(b.model_id, &b.market)));
+                let remote = self.client.fetch_positions().await?;
+                positions.extend(remote.into_iter().map(|x| PositionInfo {
+                    model_id: None,
+                    market: x.market,
+                    amount: x.amount,
+                }));
+                AdminResponse::Positions(positions)
+            }
+            AdminRequest::OpenOrders => AdminResponse::OpenOrders(
+                self.local_order_snapshots()
+                    .into_iter()
+                    .map(|x| OrderInfo {
+                        order_id: x.id.to_string(),
+                        market: x.market,
+                        amount_left: x.amount_left,
+                    })
+                    .collect(),
+            ),
+            AdminRequest::Margin => {
+                let margin = self.client.fetch_margin().await?;
+                AdminResponse::Margin {
+                    balance: margin.balance,
+                    leverage: margin.leverage,
+                }
+            }
+            AdminRequest::Maintenance(maintenance) => {
+                self.handle_maintenance(maintenance.into()).await?;
+                self.maybe_go_under_maintenance().await?;
+                AdminResponse::Done
+            }
+            AdminRequest::Pause { model_id } => {
+                self.model_config_mut(model_id)?;
+                self.risk.pause(model_id);
+                AdminResponse::Done
+            }
+            AdminRequest::Resume { model_id } => {
+                self.model_config_mut(model_id)?;
+                self.risk.resume(model_id);
+                AdminResponse::Done
+            }
+            AdminRequest::SetVariableValues { model_id, values } => {
+                let model = self.model_index(model_id)?;
+                // Models have been built with the number of values they take.
+                let expected = self.model_configs[model].variable_values().len();
+                ensure!(
+                    values.len() == expected,
+                    "Model {} has {} variable values, got {}.",
+                    model_id,
+                    expected,
+                    values.len()
+                );
+                self.admin_changes
+                    .push(ConfigChange::VariableValues { model, values });
+                AdminResponse::Done
+            }
+            AdminRequest::Flatten { market } => {
+                self.flatten(&market).await?;
+                AdminResponse::Done
+            }
+        };
+        Ok(response)
+    }
+
+    /// Queues changes of the config file, they are applied on the next candle.
+    pub(super) fn poll_config(&mut self) {
+        if let Some(hot_reload) = &mut self.hot_reload {
+            hot_reload.poll();
+        }
+    }
+
+    /// Applies changes requested by the admin endpoint and config changes that have been accepted
+    /// since the last candle, models are indexed in the same order as in config.
+    async fn apply_config_changes(&mut self) -> Result<()> {
+        let mut changes = std::mem::take(&mut self.admin_changes);
+        let (max_leverage, max_orders_per_m) = match &mut self.hot_reload {
+            Some(hot_reload) => {
+                changes.extend(hot_reload.take_pending());
+                (
+                    hot_reload.current().max_leverage,
+                    hot_reload.current().max_orders_per_m,
+                )
+            }
+            None if changes.is_empty() => return Ok(()),
+            None => Default::default(),
+        };
+        let mut limits_changed = false;
+        for change in changes {
+            match change {
+                // requested by the admin endpoint
+                ConfigChange::VariableValues { model, values } => {
+                    let model_id = self.model_configs[model].market_model_id;
+                    broadcast_async!(self, on_variable_values_changed, model_id, values);
+                    self.model_configs[model].serialized_variable_values =
+                        Writable::write_to_vec(&values)?;
+                }
+                ConfigChange::MaxLeverage(_) | ConfigChange::MaxOrdersPerM(_) => {
+                    limits_changed = true;
+                }
+                ConfigChange::Risk(risk) => self.risk.set_limits(&risk),
+                // rejected by hot reload
+                ConfigChange::TargetLeverage { .. }
+                | ConfigChange::ModelAdded(_)
+                | ConfigChange::ModelRemoved(_)
+                | ConfigChange::Execution { .. }
+                | ConfigChange::Field(_) => {}
+            }
+        }
+        if limits_changed {
+            // Validated when reloading, a leverage that still can't be converted keeps old limits.
+            let max_leverage = max_leverage
+                .to_decimal()
+                .ok_or_else(|| anyhow!("max_leverage {} isn't a number.", max_leverage));
+            match max_leverage {
+                Ok(max_leverage) => {
+                    self.risk
+                        .set_exchange_limits(max_leverage, max_orders_per_m);
+                    broadcast_async!(
+                        self,
+                        on_exchange_limits_changed,
+                        max_leverage,
+                        max_orders_per_m
+                    );
+                }
+                Err(e) => error!("Exchange limits not changed: {:#}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn model_config_mut(&mut self, model_id: u32) -> Result<&mut ModelConfig> {
+        let model = self.model_index(model_id)?;
+        Ok(&mut self.model_configs[model])
+    }
+
+    /// Position of model `model_id` in config.
+    fn model_index(&self, model_id: u32) -> Result<usize> {
+        self.model_configs
+            .iter()
+            .position(|x| x.market_model_id == model_id)
+            .ok_or_else(|| anyhow!("Unknown model {}.", model_id))
+    }
+
+    /// Cancels orders and execution algorithms on `market` and closes its position on the exchange
+    /// with market orders. Positions of models are closed in their name, the rest of the exchange
+    /// position is adopted by no model and closed with them.
+    async fn flatten(&mut self, market: &str) -> Result<()> {
+        let is_inverse = match self.active_instruments.get(market) {
+            Some(config) => config.is_inverse,
+            None => bail!("Unknown market {}.", market),
+        };
+        let price = match self.risk.mark_price(market) {
+            Some(price) => price,
+            None => bail!("Mark price of {} is unknown.", market),
+        };
+        warn!("Flattening {}.", market);
+        for parent_id in self.executor.parents_on(market) {
+            self.executor.cancel(parent_id);
+        }
+        // Children must be canceled by the executor before the rest of the orders.
+        self.poll_execution_algos().await?;
+        let remote: Decimal = self
+            .client
+            .fetch_positions()
+            .await?
+            .iter()
+            .filter(|x| x.market == market)
+            .map(|x| x.amount)
+            .sum();
+        let owned: Decimal = self
+            .risk
+            .positions()
+            .iter()
+            .filter(|((_, x), _)| x == market)
+            .map(|(_, amount)| *amount)
+            .sum();
+        let unowned = remote - owned;
+        if !unowned.is_zero() {
+            warn!("{} of {} isn't owned by any model.", unowned, market);
+            // Adopted so that the order closing it is journaled like orders of models, its fills
+            // aren't reported to models.
+            let position = self.risk.position(NO_MODEL_ID, market) + unowned;
+            self.risk.set_position(NO_MODEL_ID, market, position);
+            let entry = self.position_entry(NO_MODEL_ID, market);
+            self.journal.append(&[entry]).await?;
+        }
+        let timestamp_ns = self.clock.now().timestamp_ns();
+        let orders: Vec<_> = self
+            .risk
+            .positions()
+            .iter()
+            .filter(|((_, x), amount)| x == market && !amount.is_zero())
+            .map(|((model_id, _), amount)| Order {
+                amount: -*amount,
+                trigger_price: None,
+                limit: None,
+                executed_price: None,
+                market: market.to_string(),
+                id: IdGenerator::new_order_id(*model_id),
+                predicted_price: price.to_f32().unwrap(),
+                value: Some(order::value(price, -*amount, is_inverse)),
+                timestamp_ns,
+                post_only: false,
+                display_amount: None,
+            })
+            .collect();
+        self.direct_orders.extend(orders.iter().map(|x| x.id));
+        self.tmp_sub_orders_to_open.lock().await.extend(orders);
+        self.tmp_sub_orders_to_cancel.lock().await.extend(
+            self.open_orders
+                .iter()
+                .filter(|(_, x)| x.market == market)
+                .map(|(id, _)| *id),
+        );
+        self.open_orders().await
+    }
+
     pub(super) fn min_timeframe(&self) -> u32 {
         self.candles_builder.min_timeframe()
     }
@@ -363,12 +936,68 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
 
     pub(super) async fn kill(&mut self) -> Result<()> {
         self.client.kill().await?;
+        // Simulated clients report closing trades and canceled orders right away, margin isn't
+        // checked again since the kill switch may be what got us here.
+        self.settle_simulated_orders().await?;
         self.handle_maintenance(MaintenanceMode::Crash).await?;
         Ok(())
     }
+
+    /// Engages kill switch when it has been engaged on another exchange of the portfolio.
+    pub(super) async fn follow_portfolio_kill_switch(&mut self) -> Result<()> {
+        if self.risk.is_killed_by_portfolio() {
+            warn!("Kill switch engaged on another exchange of the portfolio.");
+            self.kill_switch().await?;
+        }
+        Ok(())
+    }
+
+    /// Cancels all orders, closes all positions and shuts down. Risk engine rejects every order
+    /// from now on in case anything fails.
+    pub async fn kill_switch(&mut self) -> Result<()> {
+        let breach = self.risk.engage_kill_switch();
+        error!("Kill switch engaged.");
+        broadcast_async!(self, on_risk_breach, breach);
+        self.kill().await?;
+        let entries: Vec<_> = self
+            .open_orders
+            .keys()
+            .map(|id| JournalEntry::Canceled { order_id: *id })
+            .collect();
+        self.journal.append(&entries).await?;
+        self.open_orders.clear();
+        self.handle_maintenance(MaintenanceMode::Shutdown).await
+    }
 }
 
 impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
+    /// Processes executions of a simulated client as if they were received by websocket.
+    async fn process_simulated_executions(&mut self) -> Result<()> {
+        if !self.client.is_simulated() {
+            return Ok(());
+        }
+        if !self.settle_simulated_orders().await? {
+            return Ok(());
+        }
+        let margin = self.client.fetch_margin().await?;
+        self.on_margin_changed(margin).await
+    }
+
+    /// Processes executions and cancels of a simulated client, returns false if nothing has been
+    /// executed.
+    async fn settle_simulated_orders(&mut self) -> Result<bool> {
+        let executions = self.client.take_executions().await;
+        let has_executions = !executions.is_empty();
+        for execution in executions {
+            self.on_execution(execution).await?;
+        }
+        let timestamp_ns = self.clock.now().timestamp_ns();
+        for order_id in self.client.take_canceled().await {
+            self.on_order_canceled(order_id, timestamp_ns).await?;
+        }
+        Ok(has_executions)
+    }
+
     fn trade_guard_mut(&mut self) -> &mut TradeGuard {
         self.listeners
             .iter_mut()
@@ -379,6 +1008,22 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
     }
 
     async fn on_new_candle(&mut self, completed_candle_timestamp_s: u32) -> Result<()> {
+        let start = Instant::now();
+        let span = info_span!("on_new_candle", timestamp_s = completed_candle_timestamp_s);
+        self.apply_config_changes().await?;
+        self.call_models(completed_candle_timestamp_s)
+            .instrument(span)
+            .await?;
+        metrics::observe(
+            &ON_NEW_CANDLE_DURATION,
+            &[("exchange", &exchange_label())],
+            start.elapsed().as_secs_f64(),
+        );
+        Ok(())
+    }
+
+    /// Calls models with the completed candle and sends their orders.
+    async fn call_models(&mut self, completed_candle_timestamp_s: u32) -> Result<()> {
         let candles = self.candles_builder.candles();
         let instruments = &self.active_instruments;
         let tmp_sub_orders_to_open = &self.tmp_sub_orders_to_open;
@@ -395,13 +1040,24 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                 )
             })
             .await?;
+        let has_orders = !self.tmp_sub_orders_to_open.lock().await.is_empty();
         self.open_orders().await?;
+        if has_orders {
+            // Candle timestamps mark the close of a candle.
+            let close_ns = completed_candle_timestamp_s as u64 * 1_000_000_000;
+            let latency_ns = self.clock.now().timestamp_ns().saturating_sub(close_ns);
+            metrics::observe(
+                &CANDLE_TO_ORDER,
+                &[("exchange", &exchange_label())],
+                latency_ns as f64 / 1e9,
+            );
+        }
         Ok(())
     }
 
     async fn load_required_candles(
         &mut self,
-        supported_timeframes: &ReverseSortedVec<u32>,
+        capability: &ClientCapability,
         max_candles_fetched_at_once: usize,
     ) -> Result<()> {
         // TODO: if n_requests > 60 panic because of ratelimit
@@ -410,22 +1066,28 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
             .broadcast_result(|x| x.required_candles(&mut req))?;
         let mut total_fetches = 0usize;
 
-        for supported_timeframe in supported_timeframes.iter() {
-            for (market, map) in &mut req {
-                // Get maximum candles count for specific market and timeframe that can be used to
-                // construct other timeframes.
+        for (market, map) in req {
+            // Timeframes that are constructed from the same base timeframe are fetched together.
+            let mut by_base = HashMap::<u32, Vec<(u32, usize)>>::new();
+            for (timeframe, len) in map {
+                let base = match capability.base_timeframe(timeframe) {
+                    Some(base) => base,
+                    None => bail!(
+                        "{} s candles can't be constructed from timeframes {:?} of {}.",
+                        timeframe,
+                        capability.timeframes,
+                        self.client.exchange().name()
+                    ),
+                };
+                by_base.entry(base).or_default().push((timeframe, len));
+            }
+            for (supported_timeframe, timeframes) in by_base {
+                // Get maximum candles count that can be used to construct other timeframes.
                 let mut max_len = 0;
                 let mut max_ratio = 1;
-                for (timeframe, len) in &*map {
-                    if *timeframe < 60 {
-                        unimplemented!("Subminute timeframes aren't implemented.");
-                    }
-                    if timeframe % supported_timeframe != 0 {
-                        continue;
-                    }
-                    let ratio = *timeframe as usize / (*supported_timeframe) as usize;
-                    let count = ratio * len;
-                    max_len = max_len.max(count);
+                for (timeframe, len) in &timeframes {
+                    let ratio = (timeframe / supported_timeframe) as usize;
+                    max_len = max_len.max(ratio * len);
                     max_ratio.max_mut(ratio);
                 }
                 if max_len == 0 {
@@ -439,7 +1101,7 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                     market.clone(),
                     max_len,
                 );
-                let now = Utc::now().timestamp_s();
+                let now = self.clock.now().timestamp_s();
                 let end = now - now % supported_timeframe + supported_timeframe;
                 let period = max_len as u32 * supported_timeframe;
                 let mut current = end - period;
@@ -455,29 +1117,25 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                         supported_timeframe
                     );
                     self.client
-                        .fetch_candles(&market, *supported_timeframe, current, end, &mut candles)
+                        .fetch_candles(&market, supported_timeframe, current, end, &mut candles)
                         .await?;
                     current += count as u32 * supported_timeframe;
                     total_fetches += 1;
                 }
                 // Fixing candles.
-                candles.fix_integrity(*supported_timeframe);
+                candles.fix_integrity(supported_timeframe);
                 // Applying candles.
                 assert_eq!(candles.len(), max_len);
-                for (timeframe, len) in &*map {
-                    if timeframe % supported_timeframe != 0 {
-                        continue;
-                    }
-                    let ratio = *timeframe as usize / (*supported_timeframe) as usize;
-                    if ratio != 1 {
+                for (timeframe, len) in timeframes {
+                    if timeframe != supported_timeframe {
                         let mut constructed_candles = Candles::with_default_value(
                             self.client.exchange().name().into(),
                             market.clone(),
-                            *len + 1,
+                            len + 1,
                         );
                         let count =
-                            candles.increase_timeframe(&mut constructed_candles, *timeframe, true);
-                        if count != *len + 1 {
+                            candles.increase_timeframe(&mut constructed_candles, timeframe, true);
+                        if count != len + 1 {
                             // Candles didn't contain partial candles so adding manually.
                             constructed_candles.set_candle_partial(constructed_candles.len() - 1);
                         }
@@ -486,14 +1144,12 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                         self.candles_builder.insert(&market, candles.clone());
                     }
                 }
-                // Removing already processed timeframes.
-                map.retain(|timeframe, _| timeframe % supported_timeframe != 0);
             }
         }
 
         // Checking if some of the candles are outdated.
         // By now there could be outdated candles if there were large amount of requests.
-        let now = Utc::now().timestamp_s();
+        let now = self.clock.now().timestamp_s();
         loop {
             let mut fetched = None;
             let mut fetched_market = String::new();
@@ -515,12 +1171,10 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                 if start == 0 {
                     continue;
                 }
-                for supported_timeframe in supported_timeframes.iter() {
-                    if min_timeframe % supported_timeframe == 0 {
-                        min_timeframe = *supported_timeframe;
-                        break;
-                    }
-                }
+                // Loaded timeframes always have a base timeframe.
+                min_timeframe = capability
+                    .base_timeframe(min_timeframe)
+                    .unwrap_or(min_timeframe);
                 let count = ((now - now % min_timeframe - start) / min_timeframe) as usize;
                 let new_candles = Candles::with_capacity(
                     self.client.exchange().name().into(),
@@ -577,51 +1231,147 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
     }
 
     async fn on_order_filled(&mut self, execution: &Execution) -> Result<()> {
+        let open_order = self.open_orders.remove(&execution.order_id).unwrap();
+        self.executor
+            .on_child_done(execution.order_id, open_order.amount);
+        let mut entries = self
+            .attribute_fills(open_order, execution.timestamp_ns)
+            .await?;
+        // Doing sequentially to ensure if error happens we can still process orders on next boot
+        entries.push(JournalEntry::Filled {
+            order_id: execution.order_id,
+            timestamp_ns: execution.timestamp_ns,
+        });
+        self.journal.append(&entries).await?;
+        // If websocket doesn't provide many messages or if next websocket message is new candle we
+        // might open a position but we want to go under maintenance.
+        self.maybe_go_under_maintenance().await?;
+        Ok(())
+    }
+
+    /// Notifies models about executions of their sub-orders. Market orders from models are bundled
+    /// into one but models are still notified that their market orders have been executed.
+    /// Returns new positions of the models, they must be journaled with the entry that settles
+    /// the order.
+    async fn attribute_fills(
+        &mut self,
+        open_order: OpenedOrder,
+        timestamp_ns: u64,
+    ) -> Result<Vec<JournalEntry>> {
         let is_inverse = self
             .active_instruments
-            .get(&execution.market)
+            .get(&open_order.market)
             .unwrap()
             .is_inverse;
         let instruments = &self.active_instruments;
-        // Market orders from models are bundled into one.
-        // Models are still notified that their market orders have been executed.
-        // Preventing recording of bundled market order.
-        let open_order = self.open_orders.remove(&execution.order_id).unwrap();
         let absolute_amount = open_order.max_amount.abs();
         let mark_price = order::executed_price(open_order.amount, open_order.value, is_inverse);
-
+        // Less than 1 only when a child of an execution algorithm is canceled.
+        let filled_ratio = open_order.amount / open_order.max_amount;
+        let mut positions = Vec::with_capacity(open_order.open_sub_orders.len());
         for sub_order in open_order.open_sub_orders {
             let weight = sub_order.amount.abs() / absolute_amount;
+            let amount = sub_order.amount * filled_ratio;
             let sub_execution = Execution {
-                market: execution.market.clone(),
+                market: open_order.market.clone(),
                 order_id: sub_order.id,
-                value: order::value(mark_price, sub_order.amount, is_inverse),
-                amount: sub_order.amount,
-                amount_left: Decimal::zero(),
+                value: order::value(mark_price, amount, is_inverse),
+                amount,
+                amount_left: self
+                    .executor
+                    .amount_left(sub_order.id)
+                    .unwrap_or_else(Decimal::zero),
                 fee_paid: open_order.fee_paid * weight,
                 executed_price: mark_price,
-                timestamp_ns: execution.timestamp_ns,
+                timestamp_ns,
             };
-            broadcast_async!(self, on_execution, sub_execution, instruments);
+            self.risk.on_execution(&sub_execution);
+            positions.push(self.position_entry(sub_order.id.model_id(), &open_order.market));
+            if sub_order.id.model_id() != NO_MODEL_ID {
+                broadcast_async!(self, on_execution, sub_execution, instruments);
+            }
+        }
+        Ok(positions)
+    }
+
+    fn position_entry(&self, model_id: u32, market: &str) -> JournalEntry {
+        JournalEntry::Position {
+            model_id,
+            market: market.to_string(),
+            amount: self.risk.position(model_id, market),
         }
-        // Doing sequentially to ensure if error happens we can still process orders on next boot
-        save_execution_time(self.client.exchange().name(), execution.timestamp_ns).await?;
-        // If websocket doesn't provide many messages or if next websocket message is new candle we
-        // might open a position but we want to go under maintenance.
-        self.maybe_go_under_maintenance().await?;
-        Ok(())
     }
 
     async fn open_orders(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.send_orders()
+            .instrument(info_span!("open_orders"))
+            .await?;
+        let exchange = exchange_label();
+        let labels = [("exchange", exchange.as_str())];
+        metrics::observe(
+            &OPEN_ORDERS_DURATION,
+            &labels,
+            start.elapsed().as_secs_f64(),
+        );
+        metrics::set(&OPEN_ORDERS, &labels, self.open_orders.len() as f64);
+        Ok(())
+    }
+
+    /// Checks orders of models, journals them and sends them to exchange.
+    async fn send_orders(&mut self) -> Result<()> {
         let mut tmp_sub_orders_to_open = self.tmp_sub_orders_to_open.lock().await;
         let mut tmp_sub_orders_to_cancel = self.tmp_sub_orders_to_cancel.lock().await;
+        if !tmp_sub_orders_to_open.is_empty() {
+            let (accepted, rejected) = self.risk.check(
+                tmp_sub_orders_to_open.drain(..).collect(),
+                &self.direct_orders,
+                &self.active_instruments,
+                self.clock.now().timestamp_ns(),
+            );
+            *tmp_sub_orders_to_open = accepted;
+            for (order, breach) in rejected {
+                warn!("Order rejected: {:?}", breach);
+                broadcast_async!(self, on_risk_breach, breach);
+                // Model is told that its order is done without anything being executed.
+                let execution = Execution {
+                    market: order.market.clone(),
+                    order_id: order.id,
+                    value: Decimal::zero(),
+                    amount: Decimal::zero(),
+                    amount_left: Decimal::zero(),
+                    fee_paid: Decimal::zero(),
+                    executed_price: order
+                        .limit
+                        .or_else(|| order.predicted_price.to_decimal())
+                        .unwrap_or_else(Decimal::zero),
+                    timestamp_ns: self.clock.now().timestamp_ns(),
+                };
+                let instruments = &self.active_instruments;
+                self.listeners
+                    .broadcast_async(|x| x.on_execution(&execution, instruments))
+                    .await?;
+            }
+        }
+        let (executor, direct_orders) = (&mut self.executor, &self.direct_orders);
+        let timestamp_ns = self.clock.now().timestamp_ns();
+        let submitted: Vec<_> = tmp_sub_orders_to_open
+            .drain_filter(|x| !direct_orders.contains(&x.id) && executor.submit(x, timestamp_ns))
+            .collect();
+        if !submitted.is_empty() {
+            broadcast_async!(self, on_orders_placed, submitted);
+        }
+        let mut intents = Vec::new();
         loop {
             if tmp_sub_orders_to_open.is_empty() {
                 break;
             }
             let mut order = tmp_sub_orders_to_open.pop().unwrap();
             if order.is_canceled() {
-                tmp_sub_orders_to_cancel.push(order.id);
+                // Children of execution algorithms are canceled when polled.
+                if !self.executor.cancel(order.id) {
+                    tmp_sub_orders_to_cancel.push(order.id);
+                }
             } else if order.is_market() {
                 let amount = tmp_sub_orders_to_open
                     .iter()
@@ -651,8 +1401,15 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                             amount_left: Decimal::zero(),
                             fee_paid: Decimal::zero(),
                             executed_price: sub_order.predicted_price.to_decimal().unwrap(),
-                            timestamp_ns: Utc::now().timestamp_ns(),
+                            timestamp_ns: self.clock.now().timestamp_ns(),
                         };
+                        self.risk.on_execution(&execution);
+                        let position =
+                            self.position_entry(sub_order.id.model_id(), &sub_order.market);
+                        self.journal.append(&[position]).await?;
+                        if sub_order.id.model_id() == NO_MODEL_ID {
+                            continue;
+                        }
                         let instruments = &self.active_instruments;
                         self.listeners
                             .broadcast_async(|x| x.on_execution(&execution, instruments))
@@ -672,17 +1429,23 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
                             true
                         }
                     });
-                    let open_order = OpenedOrder::new(order.amount, open_sub_orders);
+                    let open_order =
+                        OpenedOrder::new(order.market.clone(), order.amount, open_sub_orders);
+                    intents.push(open_order.intent(&order));
                     self.open_orders.insert(order.id, open_order);
                     self.tmp_orders_to_open.push(order);
                 }
             } else {
                 let open_sub_orders = vec![OpenedSubOrder::from(&order)];
-                let open_order = OpenedOrder::new(order.amount, open_sub_orders);
+                let open_order =
+                    OpenedOrder::new(order.market.clone(), order.amount, open_sub_orders);
+                intents.push(open_order.intent(&order));
                 self.open_orders.insert(order.id, open_order);
                 self.tmp_orders_to_open.push(order);
             }
         }
+        // Written before sending so that fills can be attributed to models after a crash.
+        self.journal.append(&intents).await?;
         let cancel_fut = if tmp_sub_orders_to_cancel.is_empty() {
             async { Ok(()) }.boxed()
         } else {
@@ -694,22 +1457,35 @@ impl<C: NetworkClient, WS: Ws> NetworkAgentState<C, WS> {
             async { Ok(()) }.boxed()
         };
         try_join!(cancel_fut, post_fut)?;
+        let mut entries: Vec<_> = self
+            .tmp_orders_to_open
+            .iter()
+            .map(|x| JournalEntry::Placed { order_id: x.id })
+            .collect();
+        for id in &*tmp_sub_orders_to_cancel {
+            if self.open_orders.contains_key(id) {
+                entries.push(JournalEntry::Canceled { order_id: *id });
+            }
+        }
+        self.journal.append(&entries).await?;
         let orders_to_open = &self.tmp_orders_to_open;
         broadcast_async!(self, on_orders_placed, orders_to_open);
         for id in &*tmp_sub_orders_to_cancel {
             self.open_orders.remove(id);
         }
         self.tmp_orders_to_open.clear();
+        self.direct_orders.clear();
         tmp_sub_orders_to_open.clear();
         tmp_sub_orders_to_cancel.clear();
-
-        Ok(())
+        drop(tmp_sub_orders_to_open);
+        drop(tmp_sub_orders_to_cancel);
+        self.poll_execution_algos().await
     }
 
     async fn handle_maintenance(&mut self, maintenance: MaintenanceMode) -> Result<()> {
         let maintenance = Maintenance {
             mode: maintenance,
-            timestamp_s: Utc::now().timestamp_s(),
+            timestamp_s: self.clock.now().timestamp_s(),
         };
         broadcast_async!(self, on_maintenance, maintenance);
         match maintenance.mode {
@@ -760,12 +1536,13 @@ pub async fn build_and_kill(
     push_non_essential_listeners(
         &mut listeners,
         use_public_data_miner,
-        client.exchange().name(),
+        account_name(client.exchange().name()),
         exchange_id,
+        client.clock(),
     );
     let maintenance = Maintenance {
         mode: MaintenanceMode::Crash,
-        timestamp_s: Utc::now().timestamp_s(),
+        timestamp_s: client.clock().now().timestamp_s(),
     };
     listeners
         .broadcast_async(|x| x.on_maintenance(&maintenance))
@@ -774,33 +1551,30 @@ pub async fn build_and_kill(
     Ok(())
 }
 
-pub(super) async fn load_last_execution_time(exchange_name: &str) -> Result<u64> {
-    match File::open(CONFIG.data_dir.join(exchange_name).join("state.bin")).await {
-        Ok(mut file) => Ok(file.read_u64_le().await?),
-        Err(e) => match e.kind() {
-            ErrorKind::NotFound => Ok(u64::MAX),
-            _ => Err(e.into()),
-        },
-    }
-}
-
-async fn save_execution_time(exchange_name: &str, timestamp_ns: u64) -> Result<()> {
-    let mut file = File::create(CONFIG.data_dir.join(exchange_name).join("state.bin")).await?;
-    file.write_u64_le(timestamp_ns).await?;
-
-    Ok(())
+/// Several accounts on one exchange are told apart by name of their config.
+fn account_name(exchange_name: &str) -> &str {
+    get_exchange_config()
+        .map(|x| x.name.as_str())
+        .unwrap_or(exchange_name)
 }
 
 fn push_non_essential_listeners(
     listeners: &mut Listeners<dyn ExchangeListener>,
     use_public_data_miner: bool,
-    exchange_name: &str,
+    account_name: &str,
     exchange_id: u16,
+    clock: Arc<dyn Clock>,
 ) {
+    listeners.push(Box::new(AlertAgent::new(
+        &get_exchange_config()
+            .map(|x| x.alerts.clone())
+            .unwrap_or_default(),
+        clock,
+    )));
     if use_public_data_miner {
         listeners.push(Box::new(ExchangeDataAgent::new(
             &CONFIG.data_dir,
-            exchange_name,
+            account_name,
         )));
     }
     if get_exchange_config().is_none() {
@@ -810,6 +1584,7 @@ fn push_non_essential_listeners(
 
 #[derive(Debug)]
 struct OpenedOrder {
+    market: String,
     value: Decimal,
     fee_paid: Decimal,
     amount: Decimal,
@@ -818,8 +1593,13 @@ struct OpenedOrder {
 }
 
 impl OpenedOrder {
-    fn new(max_amount: Decimal, open_sub_orders: Vec<OpenedSubOrder>) -> OpenedOrder {
+    fn new(
+        market: String,
+        max_amount: Decimal,
+        open_sub_orders: Vec<OpenedSubOrder>,
+    ) -> OpenedOrder {
         OpenedOrder {
+            market,
             value: Decimal::zero(),
             fee_paid: Decimal::zero(),
             amount: Decimal::zero(),
@@ -827,6 +1607,39 @@ impl OpenedOrder {
             open_sub_orders,
         }
     }
+
+    fn intent(&self, order: &Order) -> JournalEntry {
+        JournalEntry::Intent {
+            order_id: order.id,
+            market: self.market.clone(),
+            max_amount: self.max_amount,
+            sub_orders: self
+                .open_sub_orders
+                .iter()
+                .map(|x| (x.id, x.amount))
+                .collect(),
+        }
+    }
+}
+
+impl From<&JournaledOrder> for OpenedOrder {
+    fn from(order: &JournaledOrder) -> Self {
+        OpenedOrder {
+            market: order.market.clone(),
+            value: order.value(),
+            fee_paid: order.fee_paid(),
+            amount: order.amount(),
+            max_amount: order.max_amount,
+            open_sub_orders: order
+                .sub_orders
+                .iter()
+                .map(|(id, amount)| OpenedSubOrder {
+                    amount: *amount,
+                    id: *id,
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -843,3 +1656,380 @@ impl From<&Order> for OpenedSubOrder {
         }
     }
 }
+
+#[cfg(all(test, feature = "test"))]
+pub(super) mod t_exchange_state {
+    use std::path::Path;
+
+    use chrono::{TimeZone, Utc};
+    use config::{ConfigLoader, ConfigWatcher, Schema};
+    use merovingian::order::ExecutionAlgo;
+    use nebuchadnezzar_core::clock::SimulatedClock;
+
+    use super::*;
+    use crate::agents::network_agent::hot_reload::t_hot_reload;
+    use crate::agents::network_agents::mock_network_agent::{MockClient, MockWebsocket};
+
+    pub(crate) const MARKET: &str = "XBTUSD";
+    const MODEL_ID: u32 = 0;
+
+    /// Keeps executions that have been attributed to models.
+    #[derive(Default)]
+    struct Recorder {
+        executions: Vec<(OrderId, Decimal)>,
+    }
+
+    #[async_trait::async_trait]
+    impl ExchangeListener for Recorder {
+        async fn on_execution<'a>(
+            &'a mut self,
+            execution: &'a Execution,
+            _instruments: &'a HashMap<String, InstrumentConfig>,
+        ) -> Result<()> {
+            self.executions.push((execution.order_id, execution.amount));
+            Ok(())
+        }
+    }
+
+    /// State of `MARKET` with `listener` as the only listener and simulated time at 0. Candles of a
+    /// minute are at 0 and 60, the first one completes at 60.
+    pub(crate) async fn mock_state<WS: Ws>(
+        dir: &Path,
+        listener: Box<dyn ExchangeListener>,
+        algos: HashMap<u32, ExecutionAlgo>,
+    ) -> Result<NetworkAgentState<MockClient, WS>> {
+        let mut listeners = Listeners::<dyn ExchangeListener>::new();
+        listeners.push(listener);
+        let mut active_instruments = HashMap::new();
+        active_instruments.insert(
+            MARKET.to_string(),
+            InstrumentConfig {
+                lot_size: Decimal::from(1),
+                ..Default::default()
+            },
+        );
+        let candles = Candles {
+            timestamp: vec![0, 60],
+            open: vec![100.; 2],
+            high: vec![100.; 2],
+            low: vec![100.; 2],
+            close: vec![100.; 2],
+            volume: vec![0.; 2],
+            exchange: String::new(),
+            market: MARKET.to_string(),
+        };
+        let mut candles_builder = CandlesBuilder::new();
+        candles_builder.insert(&MARKET.to_string(), candles);
+        Ok(NetworkAgentState {
+            listeners,
+            client: MockClient::with_position(MARKET, Decimal::zero()),
+            clock: Arc::new(SimulatedClock::new(Utc.timestamp(0, 0))),
+            ws: None,
+            candles_builder,
+            active_instruments,
+            open_orders: HashMap::new(),
+            journal: Journal::open(dir).await?,
+            reconciler: Reconciler::new(Default::default()),
+            risk: RiskEngine::new(&Default::default(), Decimal::from(1000)),
+            executor: Executor::new(algos),
+            direct_orders: HashSet::new(),
+            model_configs: Vec::new(),
+            admin: None,
+            hot_reload: None,
+            admin_changes: Vec::new(),
+            maintenance_state: MaintenanceState::Normal,
+            tmp_sub_orders_to_open: Arc::new(Default::default()),
+            tmp_sub_orders_to_cancel: Arc::new(Mutex::new(Vec::new())),
+            tmp_orders_to_open: vec![],
+        })
+    }
+
+    async fn state(dir: &Path) -> Result<NetworkAgentState<MockClient, MockWebsocket>> {
+        let mut algos = HashMap::new();
+        algos.insert(MODEL_ID, ExecutionAlgo::Iceberg { display_amount: 2. });
+        mock_state(dir, Box::new(Recorder::default()), algos).await
+    }
+
+    fn parent(amount: i32) -> Order {
+        Order {
+            amount: Decimal::from(amount),
+            trigger_price: None,
+            limit: None,
+            executed_price: None,
+            market: MARKET.to_string(),
+            id: IdGenerator::new_order_id(MODEL_ID),
+            predicted_price: 100.,
+            value: None,
+            timestamp_ns: 0,
+            post_only: false,
+            display_amount: None,
+        }
+    }
+
+    fn fill(order_id: OrderId, amount: i32) -> Execution {
+        Execution {
+            market: MARKET.to_string(),
+            order_id,
+            value: order::value(Decimal::from(100), Decimal::from(amount), false),
+            amount: Decimal::from(amount),
+            amount_left: Decimal::zero(),
+            fee_paid: Decimal::zero(),
+            executed_price: Decimal::from(100),
+            timestamp_ns: 1,
+        }
+    }
+
+    /// Sends the first child of `parent` and returns its id.
+    async fn send_child(
+        state: &mut NetworkAgentState<MockClient, MockWebsocket>,
+        parent: &Order,
+    ) -> Result<OrderId> {
+        assert!(state.executor.submit(parent, 0));
+        state.poll_execution_algos().await?;
+        let open_orders = state.client.fetch_open_orders().await?;
+        assert_eq!(open_orders.len(), 1);
+        assert_eq!(open_orders[0].amount_left, parent.amount);
+        assert!(state.open_orders.contains_key(&open_orders[0].id));
+        Ok(open_orders[0].id)
+    }
+
+    fn executions(
+        state: &mut NetworkAgentState<MockClient, MockWebsocket>,
+    ) -> Vec<(OrderId, Decimal)> {
+        let recorder: &mut Recorder = state
+            .listeners
+            .iter_mut()
+            .next()
+            .unwrap()
+            .downcast_mut()
+            .unwrap();
+        std::mem::take(&mut recorder.executions)
+    }
+
+    #[tokio::test]
+    async fn t_canceled_child_is_settled_on_confirmation() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = state(dir.path()).await?;
+        let parent = parent(10);
+        let child_id = send_child(&mut state, &parent).await?;
+        state.on_execution(fill(child_id, 1)).await?;
+        assert!(executions(&mut state).is_empty());
+
+        // Child stays open until the exchange confirms the cancel.
+        assert!(state.executor.cancel(parent.id));
+        state.poll_execution_algos().await?;
+        assert!(state.client.fetch_open_orders().await?.is_empty());
+        assert!(state.open_orders.contains_key(&child_id));
+        assert!(state.journal.state().open_orders.contains_key(&child_id));
+
+        // Partial fill is attributed to the model.
+        state.on_order_canceled(child_id, 2).await?;
+        assert_eq!(executions(&mut state), vec![(parent.id, Decimal::from(1))]);
+        assert_eq!(state.risk.position(MODEL_ID, MARKET), Decimal::from(1));
+        assert!(state.open_orders.is_empty());
+        assert!(state.executor.is_empty());
+        let journal = state.journal.state();
+        assert!(journal.open_orders.is_empty());
+        assert_eq!(
+            journal.positions.get(&(MODEL_ID, MARKET.to_string())),
+            Some(&Decimal::from(1))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_child_filled_while_canceling() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = state(dir.path()).await?;
+        let parent = parent(10);
+        let child_id = send_child(&mut state, &parent).await?;
+        assert!(state.executor.cancel(parent.id));
+        state.poll_execution_algos().await?;
+
+        // Last fill settles the child, late confirmation of the cancel is ignored.
+        state.on_execution(fill(child_id, 10)).await?;
+        assert_eq!(executions(&mut state), vec![(parent.id, Decimal::from(10))]);
+        state.on_order_canceled(child_id, 2).await?;
+        assert!(executions(&mut state).is_empty());
+        assert!(state.open_orders.is_empty());
+        assert!(state.executor.is_empty());
+        assert_eq!(state.risk.position(MODEL_ID, MARKET), Decimal::from(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_rejected_child_is_sent_again() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = state(dir.path()).await?;
+        let parent = parent(10);
+        let child_id = send_child(&mut state, &parent).await?;
+        // Exchange cancels the child on its own, e.g. post-only order would take liquidity.
+        state.on_order_canceled(child_id, 1).await?;
+        assert!(executions(&mut state).is_empty());
+        assert_eq!(
+            state.executor.amount_left(parent.id),
+            Some(Decimal::from(10))
+        );
+        assert!(state.open_orders.is_empty());
+        state.poll_execution_algos().await?;
+        assert_eq!(state.open_orders.len(), 1);
+        assert!(!state.open_orders.contains_key(&child_id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_flatten_closes_exchange_position() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = state(dir.path()).await?;
+        // Exchange holds 2 more than the model, e.g. after a manual trade.
+        state.client = MockClient::with_position(MARKET, Decimal::from(5));
+        let mut positions = HashMap::new();
+        positions.insert((MODEL_ID, MARKET.to_string()), Decimal::from(3));
+        state.risk =
+            RiskEngine::new(&Default::default(), Decimal::from(1000)).with_positions(positions);
+        state.risk.on_mark_price(MARKET, Decimal::from(100));
+        // Flattening still works once trading has been stopped.
+        state.risk.engage_kill_switch();
+        state.flatten(MARKET).await?;
+        let orders = state.client.fetch_open_orders().await?;
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].amount_left, Decimal::from(-5));
+        // Both positions are closed by one journaled order.
+        let mut sub_orders: Vec<_> = state.journal.state().open_orders[&orders[0].id]
+            .sub_orders
+            .iter()
+            .map(|(id, amount)| (id.model_id(), *amount))
+            .collect();
+        sub_orders.sort();
+        assert_eq!(
+            sub_orders,
+            vec![
+                (MODEL_ID, Decimal::from(-3)),
+                (NO_MODEL_ID, Decimal::from(-2))
+            ]
+        );
+        state.on_execution(fill(orders[0].id, -5)).await?;
+        assert!(state.risk.positions().values().all(|x| x.is_zero()));
+        // Only the model is told about its fill.
+        let executions = executions(&mut state);
+        assert_eq!(executions.len(), 1);
+        assert_eq!(executions[0].0.model_id(), MODEL_ID);
+        Ok(())
+    }
+
+    /// Stands in for trade guard, keeps max leverage and variable values it is called with.
+    #[derive(Default)]
+    struct Model {
+        max_leverage: Option<Decimal>,
+        values: Vec<f32>,
+        candles: Vec<(Option<Decimal>, Vec<f32>)>,
+    }
+
+    #[async_trait::async_trait]
+    impl ExchangeListener for Model {
+        async fn on_variable_values_changed(
+            &mut self,
+            model_id: &u32,
+            values: &Vec<f32>,
+        ) -> Result<()> {
+            if *model_id == MODEL_ID {
+                self.values = values.clone();
+            }
+            Ok(())
+        }
+
+        async fn on_exchange_limits_changed(
+            &mut self,
+            max_leverage: &Decimal,
+            _max_orders_per_m: &f32,
+        ) -> Result<()> {
+            self.max_leverage = Some(*max_leverage);
+            Ok(())
+        }
+
+        async fn on_new_candle<'a>(
+            &'a mut self,
+            _candles: &'a HashMap<String, HashMap<u32, Candles>>,
+            _last_timestamp_s: u32,
+            _active_instruments: &'a HashMap<String, InstrumentConfig>,
+            _orders_to_open: &Arc<Mutex<Vec<Order>>>,
+            _orders_to_cancel: &Arc<Mutex<Vec<OrderId>>>,
+        ) -> Result<()> {
+            self.candles.push((self.max_leverage, self.values.clone()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn t_reloaded_config_is_seen_on_next_candle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        let config = t_hot_reload::config();
+        t_hot_reload::write(&path, &config)?;
+        let watcher = ConfigWatcher::new(ConfigLoader::new(&path).host(None).env_vars(vec![]))
+            .interval(std::time::Duration::from_millis(0));
+        let mut state: NetworkAgentState<MockClient, MockWebsocket> =
+            mock_state(dir.path(), Box::new(Model::default()), HashMap::new()).await?;
+        state.model_configs = ModelConfig::from_configs(&config.models);
+        state.hot_reload = Some(HotReload::new(watcher, config.clone(), Schema::default()));
+
+        let mut new = config;
+        new.max_leverage = 3.;
+        new.max_orders_per_m = 1.;
+        t_hot_reload::write(&path, &new)?;
+        state.hot_reload.as_mut().unwrap().poll();
+        let check = |state: &mut NetworkAgentState<MockClient, MockWebsocket>| {
+            let orders = vec![parent(1), parent(1)];
+            let (accepted, _) =
+                state
+                    .risk
+                    .check(orders, &HashSet::new(), &state.active_instruments, 0);
+            accepted.len()
+        };
+        // Nothing is applied before the candle.
+        assert_eq!(check(&mut state), 2);
+        state.on_new_candle(60).await?;
+        let model: &mut Model = state
+            .listeners
+            .iter_mut()
+            .next()
+            .unwrap()
+            .downcast_mut()
+            .unwrap();
+        assert_eq!(model.candles, vec![(Some(Decimal::from(3)), vec![])]);
+        assert_eq!(check(&mut state), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_admin_variable_values_are_seen_on_next_candle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state: NetworkAgentState<MockClient, MockWebsocket> =
+            mock_state(dir.path(), Box::new(Model::default()), HashMap::new()).await?;
+        state.model_configs = ModelConfig::from_configs(&t_hot_reload::config().models);
+
+        let request = |values| AdminRequest::SetVariableValues {
+            model_id: MODEL_ID,
+            values,
+        };
+        let error = state
+            .handle_admin_request(request(vec![60., 20., 30.]))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Model 0 has 2 variable values, got 3.");
+        state.handle_admin_request(request(vec![60., 20.])).await?;
+        // Nothing is applied before the candle.
+        assert_eq!(state.model_configs[0].variable_values(), vec![60., 14.]);
+        state.on_new_candle(60).await?;
+        let model: &mut Model = state
+            .listeners
+            .iter_mut()
+            .next()
+            .unwrap()
+            .downcast_mut()
+            .unwrap();
+        assert_eq!(model.candles, vec![(None, vec![60., 20.])]);
+        assert_eq!(state.model_configs[0].variable_values(), vec![60., 20.]);
+        Ok(())
+    }
+}
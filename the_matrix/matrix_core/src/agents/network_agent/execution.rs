@@ -0,0 +1,624 @@
+//! Execution algorithms turn an order of a model (parent) into orders that are sent to an exchange
+//! (children). Every child is tracked as an `OpenedOrder` whose only sub-order is its parent, so
+//! fills are attributed to models the same way as fills of bundled market orders.
+//!
+//! Only children are journaled, amount of a parent that hasn't been sent yet is abandoned on
+//! restart.
+//!
+//! A child is done once the exchange has filled it or confirmed that it is canceled. Children that
+//! are canceled without being asked to, e.g. post-only ones that would take liquidity, are sent
+//! again. After `MAX_REJECTIONS` of them in a row chase falls back to a market order and other
+//! algorithms give up on the rest of the parent.
+use std::collections::HashMap;
+
+use merovingian::order::{value, ExecutionAlgo, IdGenerator, Order, OrderId};
+use merovingian::order_book::OrderBook;
+use mouse::log::*;
+use mouse::num::traits::{ToPrimitive, Zero};
+use mouse::num::{Decimal, IntoDecimal};
+
+use crate::agents::network_agents::InstrumentConfig;
+
+const SECOND_NS: u64 = 1_000_000_000;
+const MAX_REJECTIONS: u32 = 3;
+
+struct Child {
+    id: OrderId,
+    amount: Decimal,
+    limit: Option<Decimal>,
+    /// Cancel has been requested, waiting for `on_child_done`.
+    canceling: bool,
+}
+
+struct Parent {
+    order: Order,
+    algo: ExecutionAlgo,
+    started_ns: u64,
+    /// Signed amount that hasn't been executed yet.
+    left: Decimal,
+    children: Vec<Child>,
+    slices_sent: u32,
+    /// Chase has run out of time, the rest is sent as market order.
+    timed_out: bool,
+    /// Model has canceled its order.
+    canceled: bool,
+    /// Children in a row that have been canceled by the exchange.
+    rejections: u32,
+}
+
+impl Parent {
+    /// Amount that isn't covered by open children.
+    fn unsent(&self) -> Decimal {
+        self.left - self.children.iter().map(|x| x.amount).sum::<Decimal>()
+    }
+}
+
+pub struct Executor {
+    /// Algorithm of each model, models that aren't here use `ExecutionAlgo::Direct`.
+    algos: HashMap<u32, ExecutionAlgo>,
+    parents: HashMap<OrderId, Parent>,
+    /// Maps children to their parents.
+    children: HashMap<OrderId, OrderId>,
+    /// Best bid and ask of each market.
+    touches: HashMap<String, (Option<Decimal>, Option<Decimal>)>,
+}
+
+impl Executor {
+    pub fn new(algos: HashMap<u32, ExecutionAlgo>) -> Executor {
+        Executor {
+            algos,
+            parents: HashMap::new(),
+            children: HashMap::new(),
+            touches: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    /// Takes over an order if its model uses an execution algorithm. Stop orders are always sent
+    /// directly and limit orders only support iceberg.
+    pub fn submit(&mut self, order: &Order, timestamp_ns: u64) -> bool {
+        let algo = match self.algos.get(&order.id.model_id()) {
+            None | Some(ExecutionAlgo::Direct) => return false,
+            Some(algo) => algo.clone(),
+        };
+        if order.is_canceled()
+            || order.trigger_price.is_some()
+            || (order.limit.is_some() && !matches!(algo, ExecutionAlgo::Iceberg { .. }))
+        {
+            return false;
+        }
+        self.parents.insert(
+            order.id,
+            Parent {
+                order: order.clone(),
+                algo,
+                started_ns: timestamp_ns,
+                left: order.amount,
+                children: Vec::new(),
+                slices_sent: 0,
+                timed_out: false,
+                canceled: false,
+                rejections: 0,
+            },
+        );
+        true
+    }
+
+    /// Model has canceled its order, open children get canceled by `cancels`. Returns false if the
+    /// order isn't executed here.
+    pub fn cancel(&mut self, parent_id: OrderId) -> bool {
+        match self.parents.get_mut(&parent_id) {
+            None => false,
+            Some(parent) => {
+                parent.canceled = true;
+                true
+            }
+        }
+    }
+
+    pub fn on_order_book(&mut self, order_book: &OrderBook) {
+        self.touches.insert(
+            order_book.market().to_string(),
+            (
+                order_book.best_bid().map(|(price, _)| price),
+                order_book.best_ask().map(|(price, _)| price),
+            ),
+        );
+    }
+
+    /// Parents of orders on `market`.
+    pub fn parents_on(&self, market: &str) -> Vec<OrderId> {
+        self.parents
+            .iter()
+            .filter(|(_, x)| x.order.market == market)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Signed amount of a parent that hasn't been executed yet.
+    pub fn amount_left(&self, parent_id: OrderId) -> Option<Decimal> {
+        self.parents.get(&parent_id).map(|x| x.left)
+    }
+
+    /// Children that have to be canceled, each of them must be reported back through
+    /// `on_child_done`.
+    pub fn cancels(&mut self, timestamp_ns: u64) -> Vec<OrderId> {
+        let touches = &self.touches;
+        let mut cancels = Vec::new();
+        for parent in self.parents.values_mut() {
+            let touch = match parent.algo {
+                ExecutionAlgo::Chase { timeout_s } => {
+                    if timestamp_ns >= parent.started_ns + timeout_s as u64 * SECOND_NS {
+                        parent.timed_out = true;
+                    }
+                    Some(touch(touches, &parent.order))
+                }
+                _ => None,
+            };
+            for child in &mut parent.children {
+                let reprice = match touch {
+                    // Market order that is sent after timeout has no limit.
+                    Some(touch) => {
+                        child.limit.is_some() && (parent.timed_out || touch != child.limit)
+                    }
+                    None => false,
+                };
+                if !child.canceling && (parent.canceled || reprice) {
+                    child.canceling = true;
+                    cancels.push(child.id);
+                }
+            }
+        }
+        cancels
+    }
+
+    /// Cancel request of children has failed, they are canceled again unless they get filled in
+    /// the meantime.
+    pub fn on_cancel_failed(&mut self, child_ids: &[OrderId]) {
+        for parent in self.parents.values_mut() {
+            for child in &mut parent.children {
+                if child_ids.contains(&child.id) {
+                    child.canceling = false;
+                }
+            }
+        }
+    }
+
+    /// Child has been filled or canceled after executing `filled`. Returns false if it isn't a
+    /// child.
+    pub fn on_child_done(&mut self, child_id: OrderId, filled: Decimal) -> bool {
+        let parent_id = match self.children.remove(&child_id) {
+            None => return false,
+            Some(parent_id) => parent_id,
+        };
+        if let Some(parent) = self.parents.get_mut(&parent_id) {
+            parent.left -= filled;
+            let rejected = filled.is_zero()
+                && parent
+                    .children
+                    .iter()
+                    .any(|x| x.id == child_id && !x.canceling);
+            parent.children.retain(|x| x.id != child_id);
+            if rejected {
+                parent.rejections += 1;
+            } else if !filled.is_zero() {
+                parent.rejections = 0;
+            }
+            if parent.rejections >= MAX_REJECTIONS {
+                parent.rejections = 0;
+                match parent.algo {
+                    ExecutionAlgo::Chase { .. } if !parent.timed_out => parent.timed_out = true,
+                    _ => {
+                        warn!(
+                            "Children of {} keep getting rejected, giving up on the rest.",
+                            parent_id.to_string()
+                        );
+                        parent.canceled = true;
+                    }
+                }
+            }
+            if parent.children.is_empty() && (parent.left.is_zero() || parent.canceled) {
+                self.parents.remove(&parent_id);
+            }
+        }
+        true
+    }
+
+    /// Children that should be sent now, paired with their parents.
+    pub fn orders(
+        &mut self,
+        timestamp_ns: u64,
+        instruments: &HashMap<String, InstrumentConfig>,
+    ) -> Vec<(Order, OrderId)> {
+        self.parents
+            .retain(|_, parent| !(parent.canceled && parent.children.is_empty()));
+        let touches = &self.touches;
+        let children = &mut self.children;
+        let mut orders = Vec::new();
+        for (parent_id, parent) in self.parents.iter_mut() {
+            let unsent = parent.unsent();
+            if parent.canceled || unsent.is_zero() {
+                continue;
+            }
+            let instrument = instruments.get(&parent.order.market);
+            let lot_size = instrument.map_or_else(Decimal::zero, |x| x.lot_size);
+            let child = match parent.algo {
+                ExecutionAlgo::Direct => unreachable!("Direct orders aren't submitted."),
+                ExecutionAlgo::Twap { duration_s, slices } => {
+                    let slices = slices.max(1);
+                    let elapsed_ns = timestamp_ns.saturating_sub(parent.started_ns);
+                    let due = match duration_s {
+                        0 => slices,
+                        duration_s => {
+                            let due = elapsed_ns * slices as u64 / (duration_s as u64 * SECOND_NS);
+                            (due as u32 + 1).min(slices)
+                        }
+                    };
+                    if due <= parent.slices_sent {
+                        continue;
+                    }
+                    let amount = if due == slices {
+                        unsent
+                    } else {
+                        let amount = parent.order.amount * Decimal::from(due - parent.slices_sent)
+                            / Decimal::from(slices);
+                        let amount = round_to_lot(amount, lot_size);
+                        // Waiting for more slices to become due if a single slice is below lot
+                        // size or minimal order value.
+                        if amount.is_zero()
+                            || is_below_min_notional(&parent.order, amount, instrument)
+                        {
+                            continue;
+                        }
+                        if amount.abs() > unsent.abs() {
+                            unsent
+                        } else {
+                            amount
+                        }
+                    };
+                    parent.slices_sent = due;
+                    new_child(&parent.order, amount, None)
+                }
+                ExecutionAlgo::Iceberg { display_amount } => {
+                    if !parent.children.is_empty() {
+                        continue;
+                    }
+                    let limit = parent
+                        .order
+                        .limit
+                        .or_else(|| touch(touches, &parent.order))
+                        .or_else(|| parent.order.predicted_price.to_decimal());
+                    let mut child = new_child(&parent.order, unsent, limit);
+                    child.display_amount = display_amount
+                        .to_decimal()
+                        .map(|x| round_to_lot(x.abs(), lot_size));
+                    child
+                }
+                ExecutionAlgo::Chase { .. } => {
+                    if !parent.children.is_empty() {
+                        continue;
+                    }
+                    match touch(touches, &parent.order) {
+                        Some(price) if !parent.timed_out => {
+                            let mut child = new_child(&parent.order, unsent, Some(price));
+                            child.post_only = true;
+                            child
+                        }
+                        _ => {
+                            parent.timed_out = true;
+                            new_child(&parent.order, unsent, None)
+                        }
+                    }
+                }
+            };
+            children.insert(child.id, *parent_id);
+            parent.children.push(Child {
+                id: child.id,
+                amount: child.amount,
+                limit: child.limit,
+                canceling: false,
+            });
+            orders.push((child, *parent_id));
+        }
+        orders
+    }
+}
+
+/// Best bid for buy orders and best ask for sell orders.
+fn touch(
+    touches: &HashMap<String, (Option<Decimal>, Option<Decimal>)>,
+    order: &Order,
+) -> Option<Decimal> {
+    let (bid, ask) = touches.get(&order.market)?;
+    match order.amount.is_sign_positive() {
+        true => *bid,
+        false => *ask,
+    }
+}
+
+fn is_below_min_notional(
+    order: &Order,
+    amount: Decimal,
+    instrument: Option<&InstrumentConfig>,
+) -> bool {
+    let instrument = match instrument {
+        Some(x) if !x.min_notional.is_zero() => x,
+        _ => return false,
+    };
+    match order.predicted_price.to_decimal() {
+        Some(price) => value(price, amount.abs(), instrument.is_inverse) < instrument.min_notional,
+        None => false,
+    }
+}
+
+fn round_to_lot(amount: Decimal, lot_size: Decimal) -> Decimal {
+    if lot_size.is_zero() {
+        return amount;
+    }
+    (amount / lot_size).trunc() * lot_size
+}
+
+fn new_child(parent: &Order, amount: Decimal, limit: Option<Decimal>) -> Order {
+    Order {
+        amount,
+        trigger_price: None,
+        limit,
+        executed_price: None,
+        market: parent.market.clone(),
+        id: IdGenerator::new_order_id(parent.id.model_id()),
+        predicted_price: limit
+            .and_then(|x| x.to_f32())
+            .unwrap_or(parent.predicted_price),
+        value: None,
+        timestamp_ns: parent.timestamp_ns,
+        post_only: false,
+        display_amount: None,
+    }
+}
+
+#[cfg(test)]
+mod t_execution {
+    use merovingian::order_book::{BookSide, L2Level};
+
+    use super::*;
+
+    const MARKET: &str = "XBTUSD";
+    const TWAP: u32 = 0;
+    const ICEBERG: u32 = 1;
+    const CHASE: u32 = 2;
+
+    fn executor() -> Executor {
+        let mut algos = HashMap::new();
+        algos.insert(
+            TWAP,
+            ExecutionAlgo::Twap {
+                duration_s: 60,
+                slices: 3,
+            },
+        );
+        algos.insert(ICEBERG, ExecutionAlgo::Iceberg { display_amount: 2. });
+        algos.insert(CHASE, ExecutionAlgo::Chase { timeout_s: 10 });
+        Executor::new(algos)
+    }
+
+    fn instruments() -> HashMap<String, InstrumentConfig> {
+        let mut instruments = HashMap::new();
+        instruments.insert(
+            MARKET.to_string(),
+            InstrumentConfig {
+                lot_size: Decimal::from(1),
+                ..Default::default()
+            },
+        );
+        instruments
+    }
+
+    fn order(model_id: u32, amount: i32) -> Order {
+        Order {
+            amount: Decimal::from(amount),
+            trigger_price: None,
+            limit: None,
+            executed_price: None,
+            market: MARKET.to_string(),
+            id: IdGenerator::new_order_id(model_id),
+            predicted_price: 100.,
+            value: None,
+            timestamp_ns: 0,
+            post_only: false,
+            display_amount: None,
+        }
+    }
+
+    fn book(bid: i32, ask: i32) -> OrderBook {
+        let mut book = OrderBook::new(MARKET);
+        let level = |id: i64, side: BookSide, price: i32| L2Level {
+            id,
+            side,
+            price: Some(Decimal::from(price)),
+            size: Some(Decimal::from(1)),
+        };
+        book.apply_snapshot(
+            &[level(1, BookSide::Bid, bid), level(2, BookSide::Ask, ask)],
+            0,
+        )
+        .unwrap();
+        book
+    }
+
+    fn amounts(orders: &[(Order, OrderId)]) -> Vec<Decimal> {
+        orders.iter().map(|(x, _)| x.amount).collect()
+    }
+
+    #[test]
+    fn t_direct_orders_are_not_taken() {
+        let mut executor = executor();
+        assert!(!executor.submit(&order(3, 10), 0));
+        let mut stop = order(TWAP, 10);
+        stop.trigger_price = Some(Decimal::from(90));
+        assert!(!executor.submit(&stop, 0));
+        let mut limit = order(CHASE, 10);
+        limit.limit = Some(Decimal::from(90));
+        assert!(!executor.submit(&limit, 0));
+        assert!(executor.is_empty());
+    }
+
+    #[test]
+    fn t_twap() {
+        let mut executor = executor();
+        let parent = order(TWAP, -10);
+        assert!(executor.submit(&parent, 0));
+        let first = executor.orders(0, &instruments());
+        assert_eq!(amounts(&first), vec![Decimal::from(-3)]);
+        assert_eq!(first[0].1, parent.id);
+        assert!(first[0].0.limit.is_none());
+        assert!(executor.orders(19 * SECOND_NS, &instruments()).is_empty());
+        let second = executor.orders(20 * SECOND_NS, &instruments());
+        assert_eq!(amounts(&second), vec![Decimal::from(-3)]);
+        assert!(executor.on_child_done(first[0].0.id, Decimal::from(-3)));
+        assert_eq!(executor.amount_left(parent.id), Some(Decimal::from(-7)));
+        // Last slice takes the remainder.
+        let third = executor.orders(45 * SECOND_NS, &instruments());
+        assert_eq!(amounts(&third), vec![Decimal::from(-4)]);
+        assert!(executor.orders(100 * SECOND_NS, &instruments()).is_empty());
+        executor.on_child_done(second[0].0.id, Decimal::from(-3));
+        executor.on_child_done(third[0].0.id, Decimal::from(-4));
+        assert!(executor.is_empty());
+        assert!(!executor.on_child_done(third[0].0.id, Decimal::from(-4)));
+    }
+
+    #[test]
+    fn t_twap_min_notional() {
+        let mut executor = executor();
+        let mut instruments = instruments();
+        instruments.get_mut(MARKET).unwrap().min_notional = Decimal::from(500);
+        let parent = order(TWAP, 10);
+        executor.submit(&parent, 0);
+        // a single slice is worth 300
+        assert!(executor.orders(0, &instruments).is_empty());
+        let first = executor.orders(20 * SECOND_NS, &instruments);
+        assert_eq!(amounts(&first), vec![Decimal::from(6)]);
+        let last = executor.orders(40 * SECOND_NS, &instruments);
+        assert_eq!(amounts(&last), vec![Decimal::from(4)]);
+    }
+
+    #[test]
+    fn t_iceberg() {
+        let mut executor = executor();
+        executor.on_order_book(&book(99, 101));
+        let parent = order(ICEBERG, 10);
+        executor.submit(&parent, 0);
+        let children = executor.orders(0, &instruments());
+        assert_eq!(amounts(&children), vec![Decimal::from(10)]);
+        assert_eq!(children[0].0.limit, Some(Decimal::from(99)));
+        assert_eq!(children[0].0.display_amount, Some(Decimal::from(2)));
+        assert!(executor.orders(SECOND_NS, &instruments()).is_empty());
+        assert!(executor.cancels(SECOND_NS).is_empty());
+    }
+
+    #[test]
+    fn t_chase() {
+        let mut executor = executor();
+        executor.on_order_book(&book(99, 101));
+        let parent = order(CHASE, -10);
+        executor.submit(&parent, 0);
+        let first = executor.orders(0, &instruments());
+        assert_eq!(first[0].0.limit, Some(Decimal::from(101)));
+        assert!(first[0].0.post_only);
+        assert!(executor.cancels(SECOND_NS).is_empty());
+
+        // Touch moved, order is replaced with what's left.
+        executor.on_order_book(&book(98, 100));
+        assert_eq!(executor.cancels(2 * SECOND_NS), vec![first[0].0.id]);
+        assert!(executor.cancels(2 * SECOND_NS).is_empty());
+        assert!(executor.orders(2 * SECOND_NS, &instruments()).is_empty());
+        executor.on_child_done(first[0].0.id, Decimal::from(-4));
+        let second = executor.orders(2 * SECOND_NS, &instruments());
+        assert_eq!(amounts(&second), vec![Decimal::from(-6)]);
+        assert_eq!(second[0].0.limit, Some(Decimal::from(100)));
+
+        // Timeout falls back to market order.
+        assert_eq!(executor.cancels(10 * SECOND_NS), vec![second[0].0.id]);
+        executor.on_child_done(second[0].0.id, Decimal::zero());
+        let third = executor.orders(10 * SECOND_NS, &instruments());
+        assert_eq!(amounts(&third), vec![Decimal::from(-6)]);
+        assert!(third[0].0.limit.is_none());
+        assert!(!third[0].0.post_only);
+        assert!(executor.cancels(11 * SECOND_NS).is_empty());
+    }
+
+    #[test]
+    fn t_rejected_children() {
+        let mut executor = executor();
+        executor.on_order_book(&book(99, 101));
+        let chase = order(CHASE, 10);
+        executor.submit(&chase, 0);
+        for _ in 0..MAX_REJECTIONS {
+            let child = executor.orders(0, &instruments());
+            assert!(child[0].0.post_only);
+            executor.on_child_done(child[0].0.id, Decimal::zero());
+        }
+        let market = executor.orders(0, &instruments());
+        assert!(market[0].0.limit.is_none());
+
+        let iceberg = order(ICEBERG, 10);
+        executor.submit(&iceberg, 0);
+        let done = |executor: &mut Executor, filled: i32| {
+            let child = executor.orders(0, &instruments());
+            executor.on_child_done(child[0].0.id, Decimal::from(filled));
+        };
+        for _ in 1..MAX_REJECTIONS {
+            done(&mut executor, 0);
+        }
+        // Partial fill resets rejections.
+        done(&mut executor, 2);
+        for _ in 1..MAX_REJECTIONS {
+            done(&mut executor, 0);
+        }
+        assert_eq!(executor.amount_left(iceberg.id), Some(Decimal::from(8)));
+        done(&mut executor, 0);
+        assert_eq!(executor.amount_left(iceberg.id), None);
+        assert_eq!(executor.amount_left(chase.id), Some(Decimal::from(10)));
+    }
+
+    #[test]
+    fn t_cancel_failed() {
+        let mut executor = executor();
+        let parent = order(TWAP, 9);
+        executor.submit(&parent, 0);
+        let children = executor.orders(0, &instruments());
+        executor.cancel(parent.id);
+        assert_eq!(executor.cancels(0), vec![children[0].0.id]);
+        assert!(executor.cancels(0).is_empty());
+        executor.on_cancel_failed(&[children[0].0.id]);
+        assert_eq!(executor.cancels(0), vec![children[0].0.id]);
+        // Child has been filled before the cancel got through.
+        executor.on_child_done(children[0].0.id, Decimal::from(3));
+        assert!(executor.is_empty());
+    }
+
+    #[test]
+    fn t_cancel_parent() {
+        let mut executor = executor();
+        let parent = order(TWAP, 9);
+        executor.submit(&parent, 0);
+        assert_eq!(executor.parents_on(MARKET), vec![parent.id]);
+        assert!(executor.parents_on("ETHUSD").is_empty());
+        let children = executor.orders(0, &instruments());
+        assert!(executor.cancel(parent.id));
+        assert!(!executor.cancel(order(TWAP, 1).id));
+        assert_eq!(executor.cancels(0), vec![children[0].0.id]);
+        assert!(executor.orders(60 * SECOND_NS, &instruments()).is_empty());
+        executor.on_child_done(children[0].0.id, Decimal::from(1));
+        assert!(executor.is_empty());
+
+        // Parent without children is removed right away.
+        let parent = order(TWAP, 9);
+        executor.submit(&parent, 0);
+        executor.cancel(parent.id);
+        assert!(executor.orders(0, &instruments()).is_empty());
+        assert!(executor.is_empty());
+    }
+}
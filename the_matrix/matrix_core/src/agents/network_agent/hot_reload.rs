@@ -0,0 +1,285 @@
+//! Applies edits of the config file to a running agent. Reloaded config is compared with the one
+//! the agent runs with and only changes that can be made without losing track of orders and
+//! positions are accepted: `max_leverage`, `max_orders_per_m` and risk limits, they are enforced
+//! by the risk engine. Anything else, e.g. a model that has been added or target leverage of a
+//! model that models are built with, rejects the whole reload and still needs
+//! `MaintenanceMode::ReloadSafe`.
+//!
+//! Accepted changes wait for the next candle so that models never see a half-applied config.
+use config::{
+    diff_exchange, validate_exchange, ConfigChange, ConfigWatcher, ExchangeConfig, Schema,
+};
+use mouse::log::*;
+
+pub(super) struct HotReload {
+    watcher: ConfigWatcher,
+    /// Config the agent runs with including accepted changes, models keep their original order.
+    current: ExchangeConfig,
+    schema: Schema,
+    pending: Vec<ConfigChange>,
+}
+
+impl HotReload {
+    pub fn new(watcher: ConfigWatcher, current: ExchangeConfig, schema: Schema) -> HotReload {
+        HotReload {
+            watcher,
+            current,
+            schema,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn current(&self) -> &ExchangeConfig {
+        &self.current
+    }
+
+    /// Checks config files and queues changes if they can be applied, a rejected reload is only
+    /// logged.
+    pub fn poll(&mut self) {
+        let config = match self.watcher.poll() {
+            Ok(Some(config)) => config,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Config reload failed, keeping the running config: {:#}", e);
+                return;
+            }
+        };
+        let new = match config
+            .exchanges
+            .iter()
+            .find(|x| x.name == self.current.name)
+        {
+            Some(new) => new,
+            None => {
+                error!(
+                    "Config reload rejected, exchange {} has been removed.",
+                    self.current.name
+                );
+                return;
+            }
+        };
+        match self.accept(new) {
+            Ok(changes) if changes.is_empty() => {}
+            Ok(changes) => {
+                info!(
+                    "Config reloaded, applying on the next candle: {:?}",
+                    changes
+                );
+                self.pending.extend(changes);
+            }
+            Err(reasons) => error!(
+                "Config reload rejected, restart with ReloadSafe to apply it:\n  {}",
+                reasons.join("\n  ")
+            ),
+        }
+    }
+
+    /// Changes that are waiting for the next candle.
+    pub fn take_pending(&mut self) -> Vec<ConfigChange> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Returns accepted changes and patches them into `current` or returns why they have been
+    /// rejected.
+    fn accept(&mut self, new: &ExchangeConfig) -> Result<Vec<ConfigChange>, Vec<String>> {
+        let mut reasons = match validate_exchange(new, &self.schema) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.0,
+        };
+        let changes = diff_exchange(&self.current, new);
+        reasons.extend(
+            changes
+                .iter()
+                .filter_map(|x| rejection(&self.current, new, x)),
+        );
+        if !reasons.is_empty() {
+            return Err(reasons);
+        }
+        for change in &changes {
+            patch(&mut self.current, change);
+        }
+        Ok(changes)
+    }
+}
+
+/// Why `change` cannot be applied to a running agent, `None` if it can.
+fn rejection(old: &ExchangeConfig, new: &ExchangeConfig, change: &ConfigChange) -> Option<String> {
+    let model = |config: &ExchangeConfig, i: usize| {
+        format!(
+            "model {} on {}",
+            config.models[i].name, config.models[i].market
+        )
+    };
+    match change {
+        ConfigChange::ModelAdded(i) => Some(format!("{} has been added", model(new, *i))),
+        ConfigChange::ModelRemoved(i) => Some(format!("{} has been removed", model(old, *i))),
+        ConfigChange::Execution { model: i } => Some(format!(
+            "execution of {} has changed, its orders may be executing",
+            model(old, *i)
+        )),
+        ConfigChange::VariableValues { model: i, values }
+            if values.len() != old.models[*i].variable_values.len() =>
+        {
+            Some(format!(
+                "{} has {} variable values, got {}",
+                model(old, *i),
+                old.models[*i].variable_values.len(),
+                values.len()
+            ))
+        }
+        ConfigChange::TargetLeverage { model: i, .. } => Some(format!(
+            "target leverage of {} can only be changed by restarting",
+            model(old, *i)
+        )),
+        ConfigChange::VariableValues { model: i, .. } => Some(format!(
+            "variable values of {} can only be changed by restarting or by the admin endpoint",
+            model(old, *i)
+        )),
+        ConfigChange::Field(field) => Some(format!("{} can only be changed by restarting", field)),
+        ConfigChange::MaxLeverage(_) | ConfigChange::MaxOrdersPerM(_) | ConfigChange::Risk(_) => {
+            None
+        }
+    }
+}
+
+fn patch(config: &mut ExchangeConfig, change: &ConfigChange) {
+    match change {
+        ConfigChange::MaxLeverage(max_leverage) => config.max_leverage = *max_leverage,
+        ConfigChange::MaxOrdersPerM(max_orders_per_m) => {
+            config.max_orders_per_m = *max_orders_per_m;
+        }
+        ConfigChange::Risk(risk) => config.risk = risk.clone(),
+        // rejected
+        ConfigChange::TargetLeverage { .. }
+        | ConfigChange::VariableValues { .. }
+        | ConfigChange::ModelAdded(_)
+        | ConfigChange::ModelRemoved(_)
+        | ConfigChange::Execution { .. }
+        | ConfigChange::Field(_) => {}
+    }
+}
+
+#[cfg(test)]
+pub(super) mod t_hot_reload {
+    use config::{ConfigLoader, ModelConfig, SuperConfig};
+    use mouse::error::Result;
+
+    use super::*;
+
+    pub(crate) fn config() -> ExchangeConfig {
+        ExchangeConfig {
+            name: "BitMEX".into(),
+            max_leverage: 2.,
+            max_orders_per_m: 60.,
+            models: vec![
+                ModelConfig {
+                    name: "rsi".into(),
+                    market: "XBTUSD".into(),
+                    target_leverage: 1.,
+                    variable_values: vec![60., 14.],
+                    ..Default::default()
+                },
+                ModelConfig {
+                    name: "macd".into(),
+                    market: "XBTUSD".into(),
+                    target_leverage: 1.,
+                    variable_values: vec![60., 12., 26.],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn write(path: &std::path::Path, exchange: &ExchangeConfig) -> Result<()> {
+        let config = SuperConfig {
+            data_dir: "/".into(),
+            cache_dir: "/".into(),
+            reports_dir: "/".into(),
+            report_template_dir: "/".into(),
+            exchanges: vec![exchange.clone()],
+            ..Default::default()
+        };
+        let mut value = serde_yaml::to_value(&config)?;
+        value["logs"] = serde_yaml::from_str("{}")?;
+        std::fs::write(path, serde_yaml::to_string(&value)?)?;
+        // modification times may have a resolution of a second
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        Ok(())
+    }
+
+    #[test]
+    fn t_hot_reload() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        write(&path, &config())?;
+        let watcher = ConfigWatcher::new(ConfigLoader::new(&path).host(None).env_vars(vec![]))
+            .interval(std::time::Duration::from_millis(0));
+        let mut reload = HotReload::new(watcher, config(), Schema::default());
+
+        let mut new = config();
+        new.models.swap(0, 1);
+        new.max_leverage = 3.;
+        new.max_orders_per_m = 30.;
+        write(&path, &new)?;
+        reload.poll();
+        assert_eq!(
+            reload.take_pending(),
+            vec![
+                ConfigChange::MaxLeverage(3.),
+                ConfigChange::MaxOrdersPerM(30.)
+            ]
+        );
+        assert_eq!(reload.current().models[0].name, "rsi");
+        assert_eq!(reload.current().max_leverage, 3.);
+
+        // one unsafe change rejects everything
+        let mut rejected = new.clone();
+        rejected.max_orders_per_m = 10.;
+        rejected.models[0].target_leverage = 0.5;
+        rejected.models[1].variable_values = vec![30.];
+        rejected.models.push(ModelConfig {
+            name: "ema".into(),
+            market: "XBTUSD".into(),
+            target_leverage: 1.,
+            variable_values: vec![60.],
+            ..Default::default()
+        });
+        write(&path, &rejected)?;
+        reload.poll();
+        assert!(reload.take_pending().is_empty());
+        let reasons = reload.accept(&rejected).unwrap_err();
+        assert_eq!(
+            reasons,
+            vec![
+                "target leverage of model macd on XBTUSD can only be changed by restarting",
+                "model rsi on XBTUSD has 2 variable values, got 1",
+                "model ema on XBTUSD has been added",
+            ]
+        );
+        assert_eq!(reload.current().max_orders_per_m, 30.);
+
+        let mut unsafe_values = new.clone();
+        unsafe_values.models[1].variable_values = vec![30., 14.];
+        let reasons = reload.accept(&unsafe_values).unwrap_err();
+        assert_eq!(
+            reasons,
+            vec![
+                "variable values of model rsi on XBTUSD can only be changed by restarting or by \
+                 the admin endpoint"
+            ]
+        );
+
+        let mut too_high = new;
+        too_high.max_leverage = 0.5;
+        let reasons = reload.accept(&too_high).unwrap_err();
+        assert_eq!(
+            reasons,
+            vec![
+                "BitMEX.models[0].target_leverage: 1 is higher than max_leverage 0.5",
+                "BitMEX.models[1].target_leverage: 1 is higher than max_leverage 0.5"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,571 @@
+//! Append-only write-ahead journal of orders that have been sent to an exchange. Every change of
+//! open orders is written and synced before it is acted upon so that after a crash in-memory state
+//! can be rebuilt and fills can be attributed to models.
+//!
+//! Record layout: `[len: u32][checksum: u32][entry]`. Record that was only partially written during
+//! a crash fails checksum and gets truncated together with everything after it.
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use merovingian::order::OrderId;
+use mouse::error::Result;
+use mouse::log::*;
+use mouse::num::traits::Zero;
+use mouse::num::Decimal;
+use speedy::{Readable, Writable};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+const JOURNAL_FILE: &str = "journal.bin";
+/// Used to store only the last execution time before journal was introduced.
+const LEGACY_STATE_FILE: &str = "state.bin";
+const HEADER_SIZE: usize = 8;
+/// Journal is rewritten with only open orders after this many records.
+const COMPACT_AFTER: usize = 10_000;
+/// Executions are fetched starting one second after the last processed execution.
+const CATCH_UP_SKIP_NS: u64 = 1_000_000_000;
+
+#[derive(Clone, Debug, PartialEq, Readable, Writable)]
+pub enum JournalEntry {
+    /// Order is about to be sent to the exchange. Market orders from models are bundled into one,
+    /// `sub_orders` maps it back to orders of models.
+    Intent {
+        order_id: OrderId,
+        market: String,
+        max_amount: Decimal,
+        sub_orders: Vec<(OrderId, Decimal)>,
+    },
+    /// Exchange has accepted the order.
+    Placed {
+        order_id: OrderId,
+    },
+    /// Partial or full execution of an order.
+    Fill {
+        order_id: OrderId,
+        amount: Decimal,
+        value: Decimal,
+        fee_paid: Decimal,
+        timestamp_ns: u64,
+    },
+    /// Order has been completely filled and models have been notified.
+    Filled {
+        order_id: OrderId,
+        timestamp_ns: u64,
+    },
+    Canceled {
+        order_id: OrderId,
+    },
+    /// Every execution up to and including this timestamp has been processed.
+    Checkpoint {
+        timestamp_ns: u64,
+    },
+    /// Position of a model after its executions have been processed, written together with the
+    /// entry that completes the order so that catch up doesn't count fills twice.
+    Position {
+        model_id: u32,
+        market: String,
+        amount: Decimal,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournaledOrder {
+    pub market: String,
+    pub max_amount: Decimal,
+    pub sub_orders: Vec<(OrderId, Decimal)>,
+    pub placed: bool,
+    pub fills: Vec<JournaledFill>,
+}
+
+impl JournaledOrder {
+    pub fn amount(&self) -> Decimal {
+        self.fills.iter().map(|x| x.amount).sum()
+    }
+
+    pub fn value(&self) -> Decimal {
+        self.fills.iter().map(|x| x.value).sum()
+    }
+
+    pub fn fee_paid(&self) -> Decimal {
+        self.fills.iter().map(|x| x.fee_paid).sum()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournaledFill {
+    pub amount: Decimal,
+    pub value: Decimal,
+    pub fee_paid: Decimal,
+    pub timestamp_ns: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JournalState {
+    pub open_orders: HashMap<OrderId, JournaledOrder>,
+    /// Timestamp of the last processed execution.
+    pub last_execution_ns: Option<u64>,
+    /// Position of each model on each market.
+    pub positions: HashMap<(u32, String), Decimal>,
+}
+
+impl JournalState {
+    /// Timestamp from which executions need to be fetched after a restart, `u64::MAX` if nothing
+    /// has been executed yet.
+    pub fn catch_up_start_ns(&self) -> u64 {
+        match self.last_execution_ns {
+            Some(ns) => ns.saturating_add(CATCH_UP_SKIP_NS),
+            None => u64::MAX,
+        }
+    }
+
+    /// Fills that will be fetched again during catch up are skipped, otherwise they would be
+    /// counted twice.
+    fn rebuild(entries: &[JournalEntry]) -> JournalState {
+        let mut state = JournalState::default();
+        for entry in entries {
+            match entry {
+                JournalEntry::Filled { timestamp_ns, .. }
+                | JournalEntry::Checkpoint { timestamp_ns } => {
+                    state.last_execution_ns = Some(*timestamp_ns)
+                }
+                _ => {}
+            }
+        }
+        let catch_up_start_ns = state.catch_up_start_ns();
+        state.last_execution_ns = None;
+        for entry in entries {
+            match entry {
+                JournalEntry::Fill { timestamp_ns, .. } if *timestamp_ns >= catch_up_start_ns => {}
+                _ => state.apply(entry),
+            }
+        }
+        state
+    }
+
+    fn apply(&mut self, entry: &JournalEntry) {
+        match entry {
+            JournalEntry::Intent {
+                order_id,
+                market,
+                max_amount,
+                sub_orders,
+            } => {
+                self.open_orders.insert(
+                    *order_id,
+                    JournaledOrder {
+                        market: market.clone(),
+                        max_amount: *max_amount,
+                        sub_orders: sub_orders.clone(),
+                        placed: false,
+                        fills: Vec::new(),
+                    },
+                );
+            }
+            JournalEntry::Placed { order_id } => {
+                if let Some(order) = self.open_orders.get_mut(order_id) {
+                    order.placed = true;
+                }
+            }
+            JournalEntry::Fill {
+                order_id,
+                amount,
+                value,
+                fee_paid,
+                timestamp_ns,
+            } => {
+                if let Some(order) = self.open_orders.get_mut(order_id) {
+                    order.fills.push(JournaledFill {
+                        amount: *amount,
+                        value: *value,
+                        fee_paid: *fee_paid,
+                        timestamp_ns: *timestamp_ns,
+                    });
+                }
+            }
+            JournalEntry::Filled {
+                order_id,
+                timestamp_ns,
+            } => {
+                self.open_orders.remove(order_id);
+                self.last_execution_ns = Some(*timestamp_ns);
+            }
+            JournalEntry::Canceled { order_id } => {
+                self.open_orders.remove(order_id);
+            }
+            JournalEntry::Checkpoint { timestamp_ns } => {
+                self.last_execution_ns = Some(*timestamp_ns);
+            }
+            JournalEntry::Position {
+                model_id,
+                market,
+                amount,
+            } => {
+                let key = (*model_id, market.clone());
+                if amount.is_zero() {
+                    self.positions.remove(&key);
+                } else {
+                    self.positions.insert(key, *amount);
+                }
+            }
+        }
+    }
+
+    /// Smallest list of entries that rebuilds this state.
+    fn snapshot(&self) -> Vec<JournalEntry> {
+        let mut entries = Vec::with_capacity(self.open_orders.len() * 2 + self.positions.len() + 1);
+        if let Some(timestamp_ns) = self.last_execution_ns {
+            entries.push(JournalEntry::Checkpoint { timestamp_ns });
+        }
+        for ((model_id, market), amount) in &self.positions {
+            entries.push(JournalEntry::Position {
+                model_id: *model_id,
+                market: market.clone(),
+                amount: *amount,
+            });
+        }
+        for (order_id, order) in &self.open_orders {
+            entries.push(JournalEntry::Intent {
+                order_id: *order_id,
+                market: order.market.clone(),
+                max_amount: order.max_amount,
+                sub_orders: order.sub_orders.clone(),
+            });
+            if order.placed {
+                entries.push(JournalEntry::Placed {
+                    order_id: *order_id,
+                });
+            }
+            // Timestamps are kept so that fills after the last checkpoint are still skipped.
+            for fill in &order.fills {
+                entries.push(JournalEntry::Fill {
+                    order_id: *order_id,
+                    amount: fill.amount,
+                    value: fill.value,
+                    fee_paid: fill.fee_paid,
+                    timestamp_ns: fill.timestamp_ns,
+                });
+            }
+        }
+        entries
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    state: JournalState,
+    records: usize,
+}
+
+impl Journal {
+    /// Replays journal in `dir`, a corrupted tail is truncated.
+    pub async fn open(dir: impl AsRef<Path>) -> Result<Journal> {
+        let dir = dir.as_ref();
+        let path = dir.join(JOURNAL_FILE);
+        let entries = match tokio::fs::read(&path).await {
+            Ok(data) => {
+                let (entries, valid_len) = decode(&data);
+                if valid_len != data.len() {
+                    warn!(
+                        "Journal {:?} has a corrupted tail, dropping {} bytes.",
+                        path,
+                        data.len() - valid_len
+                    );
+                    let file = OpenOptions::new().write(true).open(&path).await?;
+                    file.set_len(valid_len as u64).await?;
+                    file.sync_all().await?;
+                }
+                entries
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => load_legacy_state(dir).await?,
+            Err(e) => return Err(e.into()),
+        };
+        let state = JournalState::rebuild(&entries);
+        if !state.open_orders.is_empty() {
+            info!(
+                "Recovered {} open orders from journal.",
+                state.open_orders.len()
+            );
+        }
+        for (order_id, order) in &state.open_orders {
+            if !order.placed {
+                warn!(
+                    "Order {} on {} might not have been placed.",
+                    order_id.to_string(),
+                    order.market
+                );
+            }
+        }
+        let mut journal = Journal {
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?,
+            path,
+            state,
+            records: entries.len(),
+        };
+        journal.compact().await?;
+        let legacy_path = dir.join(LEGACY_STATE_FILE);
+        if tokio::fs::metadata(&legacy_path).await.is_ok() {
+            tokio::fs::remove_file(legacy_path).await?;
+        }
+        Ok(journal)
+    }
+
+    pub fn state(&self) -> &JournalState {
+        &self.state
+    }
+
+    /// Entries are durable when this returns.
+    pub async fn append(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        for entry in entries {
+            encode(entry, &mut buf)?;
+        }
+        self.file.write_all(&buf).await?;
+        self.file.sync_data().await?;
+        for entry in entries {
+            self.state.apply(entry);
+        }
+        self.records += entries.len();
+        if self.records >= COMPACT_AFTER {
+            self.compact().await?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites journal with only the entries that are needed to rebuild current state. Writes to a
+    /// temporary file first so that a crash never leaves a half written journal.
+    pub async fn compact(&mut self) -> Result<()> {
+        let entries = self.state.snapshot();
+        let mut buf = Vec::new();
+        for entry in &entries {
+            encode(entry, &mut buf)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path).await?;
+        tmp.write_all(&buf).await?;
+        tmp.sync_all().await?;
+        drop(tmp);
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        sync_parent_dir(&self.path).await?;
+        self.file = OpenOptions::new().append(true).open(&self.path).await?;
+        self.records = entries.len();
+        Ok(())
+    }
+}
+
+/// Renames are durable only once the directory that holds the file is synced.
+async fn sync_parent_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+async fn load_legacy_state(dir: &Path) -> Result<Vec<JournalEntry>> {
+    match tokio::fs::read(dir.join(LEGACY_STATE_FILE)).await {
+        Ok(data) if data.len() >= 8 => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[..8]);
+            info!("Migrating {} into journal.", LEGACY_STATE_FILE);
+            Ok(vec![JournalEntry::Checkpoint {
+                timestamp_ns: u64::from_le_bytes(bytes),
+            }])
+        }
+        Ok(_) => Ok(Vec::new()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn encode(entry: &JournalEntry, buf: &mut Vec<u8>) -> Result<()> {
+    let data = entry.write_to_vec()?;
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&checksum(&data).to_le_bytes());
+    buf.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Returns entries and length of data that contains valid records.
+fn decode(data: &[u8]) -> (Vec<JournalEntry>, usize) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while data.len() - offset >= HEADER_SIZE {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        let len = u32::from_le_bytes(bytes) as usize;
+        bytes.copy_from_slice(&data[offset + 4..offset + 8]);
+        let sum = u32::from_le_bytes(bytes);
+        let start = offset + HEADER_SIZE;
+        if data.len() - start < len || checksum(&data[start..start + len]) != sum {
+            break;
+        }
+        match JournalEntry::read_from_buffer(&data[start..start + len]) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+        offset = start + len;
+    }
+    (entries, offset)
+}
+
+/// FNV-1a
+fn checksum(data: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for byte in data {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod t_journal {
+    use merovingian::order::IdGenerator;
+
+    use super::*;
+
+    fn intent(order_id: OrderId, sub_orders: &[OrderId]) -> JournalEntry {
+        JournalEntry::Intent {
+            order_id,
+            market: "XBTUSD".into(),
+            max_amount: Decimal::from(sub_orders.len() as u32 * 10),
+            sub_orders: sub_orders.iter().map(|x| (*x, Decimal::from(10))).collect(),
+        }
+    }
+
+    fn fill(order_id: OrderId, timestamp_ns: u64) -> JournalEntry {
+        JournalEntry::Fill {
+            order_id,
+            amount: Decimal::from(5),
+            value: Decimal::from(1),
+            fee_paid: Decimal::zero(),
+            timestamp_ns,
+        }
+    }
+
+    #[tokio::test]
+    async fn t_rebuild_after_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = IdGenerator::new_order_id(1);
+        let b = IdGenerator::new_order_id(2);
+        let c = IdGenerator::new_order_id(3);
+        let mut journal = Journal::open(dir.path()).await?;
+        assert_eq!(journal.state().catch_up_start_ns(), u64::MAX);
+        journal
+            .append(&[intent(a, &[a, b]), JournalEntry::Placed { order_id: a }])
+            .await?;
+        journal.append(&[fill(a, 1_000_000_000)]).await?;
+        journal
+            .append(&[intent(c, &[c]), JournalEntry::Placed { order_id: c }])
+            .await?;
+        journal.append(&[fill(c, 2_000_000_000)]).await?;
+        journal.append(&[fill(c, 3_000_000_000)]).await?;
+        journal
+            .append(&[JournalEntry::Filled {
+                order_id: c,
+                timestamp_ns: 3_000_000_000,
+            }])
+            .await?;
+        // Not checkpointed, will be fetched again.
+        journal.append(&[fill(a, 5_000_000_000)]).await?;
+        drop(journal);
+
+        let journal = Journal::open(dir.path()).await?;
+        let state = journal.state();
+        assert_eq!(state.last_execution_ns, Some(3_000_000_000));
+        assert_eq!(state.catch_up_start_ns(), 4_000_000_000);
+        assert_eq!(state.open_orders.len(), 1);
+        let order = &state.open_orders[&a];
+        assert!(order.placed);
+        assert_eq!(order.amount(), Decimal::from(5));
+        assert_eq!(
+            order.sub_orders,
+            vec![(a, Decimal::from(10)), (b, Decimal::from(10))]
+        );
+        let state = state.clone();
+        drop(journal);
+
+        // Compacted journal rebuilds the same state.
+        let mut journal = Journal::open(dir.path()).await?;
+        journal.append(&[fill(a, 6_000_000_000)]).await?;
+        journal.compact().await?;
+        drop(journal);
+        assert_eq!(Journal::open(dir.path()).await?.state(), &state);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_positions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let position = |model_id, amount| JournalEntry::Position {
+            model_id,
+            market: "XBTUSD".into(),
+            amount: Decimal::from(amount),
+        };
+        let mut journal = Journal::open(dir.path()).await?;
+        journal.append(&[position(1, 10), position(2, -5)]).await?;
+        journal.append(&[position(1, 0), position(2, -15)]).await?;
+        drop(journal);
+
+        let mut journal = Journal::open(dir.path()).await?;
+        let mut positions = HashMap::new();
+        positions.insert((2, "XBTUSD".to_string()), Decimal::from(-15));
+        assert_eq!(journal.state().positions, positions);
+        journal.compact().await?;
+        drop(journal);
+        assert_eq!(
+            Journal::open(dir.path()).await?.state().positions,
+            positions
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_truncate_torn_tail() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = IdGenerator::new_order_id(1);
+        let mut journal = Journal::open(dir.path()).await?;
+        journal.append(&[intent(a, &[a])]).await?;
+        drop(journal);
+        let path = dir.path().join(JOURNAL_FILE);
+        let mut data = std::fs::read(&path)?;
+        let len = data.len();
+        let mut torn = Vec::new();
+        encode(&JournalEntry::Canceled { order_id: a }, &mut torn)?;
+        data.extend_from_slice(&torn[..torn.len() - 1]);
+        std::fs::write(&path, data)?;
+
+        let journal = Journal::open(dir.path()).await?;
+        assert!(journal.state().open_orders.contains_key(&a));
+        assert_eq!(std::fs::metadata(&path)?.len(), len as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_migrate_legacy_state() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join(LEGACY_STATE_FILE),
+            7_000_000_000u64.to_le_bytes(),
+        )?;
+        let journal = Journal::open(dir.path()).await?;
+        assert_eq!(journal.state().last_execution_ns, Some(7_000_000_000));
+        assert!(!dir.path().join(LEGACY_STATE_FILE).exists());
+        Ok(())
+    }
+}
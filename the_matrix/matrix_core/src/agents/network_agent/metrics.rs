@@ -0,0 +1,358 @@
+//! Metrics of the live trading loop. They are served together with metrics of exchange clients
+//! from `nebuchadnezzar_core::metrics` in Prometheus text format. Each of them is labeled with
+//! `exchange`, the name of the exchange or account of the agent, so that agents run in one
+//! process are reported separately.
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use config::MetricsConfig;
+use lazy_static::lazy_static;
+use merovingian::candles::Candles;
+use merovingian::minable_models::Instrument;
+use merovingian::order::{self, Order, OrderId};
+use mouse::error::{Result, ResultCtxExt};
+use mouse::log::*;
+use mouse::num::traits::{ToPrimitive, Zero};
+use mouse::num::{Decimal, IntoDecimal};
+use nebuchadnezzar_core::metrics::{self, Metric};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::agents::network_agents::{ExchangeListener, Execution, InstrumentConfig};
+
+pub const WS_MESSAGES: Metric =
+    Metric::counter("matrix_ws_messages_total", "Websocket messages by topic.");
+pub const WS_LATENCY: Metric = Metric::histogram(
+    "matrix_ws_latency_seconds",
+    "Time from exchange timestamp of a websocket message until it is processed.",
+);
+pub const HANDLE_MESSAGE_DURATION: Metric = Metric::histogram(
+    "matrix_handle_message_duration_seconds",
+    "Time spent handling a websocket message.",
+);
+pub const ON_NEW_CANDLE_DURATION: Metric = Metric::histogram(
+    "matrix_on_new_candle_duration_seconds",
+    "Time spent calling models on a new candle and sending their orders.",
+);
+pub const OPEN_ORDERS_DURATION: Metric = Metric::histogram(
+    "matrix_open_orders_duration_seconds",
+    "Time spent checking, journaling and sending orders.",
+);
+pub const CANDLE_TO_ORDER: Metric = Metric::histogram(
+    "matrix_candle_close_to_order_sent_seconds",
+    "Time from candle close until orders of models have been sent.",
+);
+pub const OPEN_ORDERS: Metric = Metric::gauge("matrix_open_orders", "Orders open on exchange.");
+pub const MODEL_PNL: Metric = Metric::gauge(
+    "matrix_model_pnl",
+    "Realized and unrealized PnL of a model in margin currency with fees, since start.",
+);
+
+lazy_static! {
+    static ref SERVED: Mutex<HashMap<String, SocketAddr>> = Default::default();
+}
+
+/// Name of the exchange or account of the agent run by the current task, see
+/// `config::with_exchange`.
+pub fn exchange_label() -> String {
+    config::exchange_name().unwrap_or_default()
+}
+
+pub fn record_ws_message(topic: &str) {
+    let exchange = exchange_label();
+    metrics::inc(&WS_MESSAGES, &[("exchange", &exchange), ("topic", topic)]);
+}
+
+pub fn record_ws_latency(topic: &str, now_ns: u64, exchange_timestamp_ns: u64) {
+    let latency_ns = now_ns.saturating_sub(exchange_timestamp_ns);
+    let exchange = exchange_label();
+    metrics::observe(
+        &WS_LATENCY,
+        &[("exchange", &exchange), ("topic", topic)],
+        latency_ns as f64 / 1e9,
+    );
+}
+
+/// Starts serving metrics on `/metrics`. Metrics are process wide, so each address is served only
+/// once even when agents are restarted or several of them are run.
+pub async fn serve_metrics(config: &MetricsConfig) -> Result<SocketAddr> {
+    let mut served = SERVED.lock().await;
+    if let Some(address) = served.get(&config.address) {
+        return Ok(*address);
+    }
+    let listener = TcpListener::bind(&config.address)
+        .await
+        .with_context(|| format!("Binding metrics endpoint to {}", config.address))?;
+    let address = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream).await {
+                            debug!("Metrics request failed: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Accepting metrics connection failed: {:?}", e),
+            }
+        }
+    });
+    info!("Metrics endpoint listening on {}.", address);
+    served.insert(config.address.clone(), address);
+    Ok(address)
+}
+
+/// Answers one HTTP request and closes the connection.
+async fn serve(mut stream: TcpStream) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > 8192 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let (status, body) = if request.starts_with(b"GET /metrics ") {
+        ("200 OK", metrics::render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct ModelPosition {
+    amount: Decimal,
+    /// Sum of execution values.
+    cost: Decimal,
+    fee_paid: Decimal,
+}
+
+/// Publishes PnL of each model on every candle.
+#[derive(Default)]
+pub struct MetricsAgent {
+    positions: HashMap<(u32, String), ModelPosition>,
+    mark_prices: HashMap<String, Decimal>,
+}
+
+impl MetricsAgent {
+    /// PnL of each model, positions on markets without a mark price are left out.
+    fn pnl(&self, instruments: &HashMap<String, InstrumentConfig>) -> BTreeMap<u32, Decimal> {
+        let mut pnl = BTreeMap::new();
+        for ((model_id, market), position) in &self.positions {
+            let (mark_price, config) = match (self.mark_prices.get(market), instruments.get(market))
+            {
+                (Some(mark_price), Some(config)) => (*mark_price, config),
+                _ => continue,
+            };
+            let value = order::value(mark_price, position.amount, config.is_inverse);
+            // Value of an inverse position decreases when price increases.
+            let gain = match config.is_inverse {
+                true => position.cost - value,
+                false => value - position.cost,
+            };
+            *pnl.entry(*model_id).or_insert_with(Decimal::zero) += gain - position.fee_paid;
+        }
+        pnl
+    }
+}
+
+#[async_trait]
+impl ExchangeListener for MetricsAgent {
+    async fn on_instrument_changed(
+        &mut self,
+        instrument: &Instrument,
+        symbol: &String,
+        _config: &Option<&InstrumentConfig>,
+    ) -> Result<()> {
+        if let Some(mark_price) = instrument.mark_price.to_decimal() {
+            self.mark_prices.insert(symbol.clone(), mark_price);
+        }
+        Ok(())
+    }
+
+    async fn on_execution<'a>(
+        &'a mut self,
+        execution: &'a Execution,
+        _instruments: &'a HashMap<String, InstrumentConfig>,
+    ) -> Result<()> {
+        let position = self
+            .positions
+            .entry((execution.order_id.model_id(), execution.market.clone()))
+            .or_default();
+        position.amount += execution.amount;
+        position.cost += execution.value;
+        position.fee_paid += execution.fee_paid;
+        Ok(())
+    }
+
+    async fn on_new_candle<'a>(
+        &'a mut self,
+        _candles: &'a HashMap<String, HashMap<u32, Candles>>,
+        _last_timestamp_s: u32,
+        active_instruments: &'a HashMap<String, InstrumentConfig>,
+        _orders_to_open: &Arc<Mutex<Vec<Order>>>,
+        _orders_to_cancel: &Arc<Mutex<Vec<OrderId>>>,
+    ) -> Result<()> {
+        let exchange = exchange_label();
+        for (model_id, pnl) in self.pnl(active_instruments) {
+            metrics::set(
+                &MODEL_PNL,
+                &[("exchange", &exchange), ("model_id", &model_id.to_string())],
+                pnl.to_f64().unwrap_or(f64::NAN),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod t_metrics {
+    use merovingian::order::IdGenerator;
+
+    use super::*;
+
+    fn instrument(mark_price: f32) -> Instrument {
+        Instrument {
+            fair_price: mark_price,
+            mark_price,
+            timestamp_ns: 0,
+        }
+    }
+
+    fn execution(model_id: u32, market: &str, amount: i64, price: i64, inverse: bool) -> Execution {
+        let amount = Decimal::new(amount, 0);
+        let price = Decimal::new(price, 0);
+        Execution {
+            market: market.into(),
+            order_id: IdGenerator::new_order_id(model_id),
+            value: order::value(price, amount, inverse),
+            amount,
+            amount_left: Decimal::zero(),
+            fee_paid: Decimal::new(1, 5),
+            executed_price: price,
+            timestamp_ns: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn t_pnl() -> Result<()> {
+        let mut instruments = HashMap::new();
+        instruments.insert(
+            "XBTUSD".to_string(),
+            InstrumentConfig {
+                is_inverse: true,
+                ..Default::default()
+            },
+        );
+        instruments.insert("ETHUSDT".to_string(), Default::default());
+        let mut agent = MetricsAgent::default();
+        // Long 100 USD at 10000, price doubles.
+        agent
+            .on_execution(&execution(0, "XBTUSD", 100, 10000, true), &instruments)
+            .await?;
+        // Short 2 ETH at 100 and buy 1 back at 50.
+        agent
+            .on_execution(&execution(1, "ETHUSDT", -2, 100, false), &instruments)
+            .await?;
+        agent
+            .on_execution(&execution(1, "ETHUSDT", 1, 50, false), &instruments)
+            .await?;
+        for (market, price) in [("XBTUSD", 20000.), ("ETHUSDT", 80.)].iter() {
+            agent
+                .on_instrument_changed(&instrument(*price), &market.to_string(), &None)
+                .await?;
+        }
+        let pnl = agent.pnl(&instruments);
+        assert_eq!(pnl[&0], Decimal::new(499, 5));
+        assert_eq!(pnl[&1], Decimal::new(6999998, 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_agents_are_labeled() -> Result<()> {
+        let mut instruments = HashMap::new();
+        instruments.insert(
+            "XBTUSD".to_string(),
+            InstrumentConfig {
+                is_inverse: true,
+                ..Default::default()
+            },
+        );
+        for (exchange, price) in [("t_bitmex", 20000.), ("t_bitmex_hedge", 10000.)] {
+            config::with_exchange(Some(exchange.into()), async {
+                let mut agent = MetricsAgent::default();
+                agent
+                    .on_execution(&execution(0, "XBTUSD", 100, 10000, true), &instruments)
+                    .await?;
+                agent
+                    .on_instrument_changed(&instrument(price), &"XBTUSD".to_string(), &None)
+                    .await?;
+                record_ws_message("t_labeled");
+                agent
+                    .on_new_candle(
+                        &HashMap::new(),
+                        0,
+                        &instruments,
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .await
+            })
+            .await?;
+        }
+        let rendered = metrics::render();
+        let lines: Vec<_> = rendered
+            .lines()
+            .filter(|x| x.contains("t_bitmex"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "matrix_model_pnl{exchange=\"t_bitmex\",model_id=\"0\"} 0.00499",
+                "matrix_model_pnl{exchange=\"t_bitmex_hedge\",model_id=\"0\"} -0.00001",
+                "matrix_ws_messages_total{exchange=\"t_bitmex\",topic=\"t_labeled\"} 1",
+                "matrix_ws_messages_total{exchange=\"t_bitmex_hedge\",topic=\"t_labeled\"} 1",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_serve_metrics() -> Result<()> {
+        const REQUESTS: Metric = Metric::counter("t_served_total", "Served.");
+        metrics::inc(&REQUESTS, &[]);
+        let address = serve_metrics(&MetricsConfig {
+            address: "127.0.0.1:0".into(),
+        })
+        .await?;
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await?;
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Result::<_>::Ok(response)
+        };
+        let response = get("/metrics").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE t_served_total counter\nt_served_total 1\n"));
+        assert!(get("/").await?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! Models that agents can run. Every model registers how many variables it has been built with so
+//! that `variable_values` of configs loaded later, by another agent of the process or by a reload,
+//! are validated before they reach a model.
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use config::{validate_exchange, ConfigErrors, ExchangeConfig, Schema};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref MODEL_VARIABLES: Mutex<HashMap<String, usize>> = Default::default();
+}
+
+/// Registers model `name` that takes `variables` values, timeframe included. Registering it again
+/// replaces the count.
+pub fn register_model(name: &str, variables: usize) {
+    MODEL_VARIABLES
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), variables);
+}
+
+/// Number of variables of registered models by their name.
+pub fn model_variables() -> HashMap<String, usize> {
+    MODEL_VARIABLES.lock().unwrap().clone()
+}
+
+/// Validates `exchange` against models that have already been registered and registers models of
+/// `exchange`, they are built with as many variables as their config has values. Returns the schema
+/// that reloads of `exchange` are validated with.
+pub fn register_models(
+    exchange: &ExchangeConfig,
+    markets: Option<HashSet<String>>,
+) -> Result<Schema, ConfigErrors> {
+    let mut registered = MODEL_VARIABLES.lock().unwrap();
+    let mut schema = Schema {
+        markets,
+        model_variables: registered.clone(),
+    };
+    validate_exchange(exchange, &schema)?;
+    for model in &exchange.models {
+        registered.insert(model.name.clone(), model.variable_values.len());
+    }
+    schema.model_variables = registered.clone();
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod t_models {
+    use config::ModelConfig;
+
+    use super::*;
+
+    fn exchange(name: &str, variable_values: Vec<f32>) -> ExchangeConfig {
+        ExchangeConfig {
+            name: name.into(),
+            max_leverage: 1.,
+            max_orders_per_m: 10.,
+            models: vec![ModelConfig {
+                name: "t_models".into(),
+                market: "XBTUSD".into(),
+                target_leverage: 1.,
+                variable_values,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn t_registered_models_are_validated() {
+        let schema = register_models(&exchange("BitMEX", vec![60., 14.]), None).unwrap();
+        assert_eq!(schema.model_variables["t_models"], 2);
+        assert_eq!(model_variables()["t_models"], 2);
+        // Another agent of the process can't run the model with a different number of values.
+        let errors = register_models(&exchange("Birake", vec![60., 14., 30.]), None).unwrap_err();
+        assert_eq!(
+            errors.0,
+            vec!["Birake.models[0].variable_values: model t_models has 2 variables, got 3"]
+        );
+        assert_eq!(model_variables()["t_models"], 2);
+    }
+}
@@ -0,0 +1,585 @@
+//! Paper trading keeps live market data of a real client but matches orders in process. Simulated
+//! executions go through the same `on_execution` path as the ones received by websocket.
+//!
+//! Simulator state isn't persisted, open orders that are restored from journal after a restart are
+//! eventually forgotten by reconciliation.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use config::PaperConfig;
+use merovingian::candles::Candles;
+use merovingian::minable_models::{Margin, Position, Trade};
+use merovingian::order;
+use merovingian::order::{Order, OrderId};
+use merovingian::order_book::OrderBook;
+use mouse::error::Result;
+use mouse::log::*;
+use mouse::num::traits::Zero;
+use mouse::num::{Decimal, IntoDecimal};
+use nebuchadnezzar_core::clock::ServerClock;
+use tokio::sync::Mutex;
+
+use super::client::NetworkClient;
+use super::reconciliation::OrderSnapshot;
+use crate::agents::network_agents::{Execution, InstrumentConfig};
+
+/// Forwards everything to `inner` except orders, which are routed into `Simulator`.
+pub struct PaperClient<C: NetworkClient> {
+    inner: C,
+    simulator: Mutex<Simulator>,
+}
+
+impl<C: NetworkClient> PaperClient<C> {
+    pub fn new(inner: C, config: &PaperConfig) -> PaperClient<C> {
+        info!("Paper trading with balance {}.", config.balance);
+        PaperClient {
+            inner,
+            simulator: Mutex::new(Simulator::new(
+                config.balance.to_decimal().unwrap_or_else(Decimal::zero),
+            )),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<C: NetworkClient> NetworkClient for PaperClient<C> {
+    type Exchange = C::Exchange;
+
+    fn exchange(&self) -> Self::Exchange {
+        self.inner.exchange()
+    }
+
+    fn clock(&self) -> Arc<ServerClock> {
+        self.inner.clock()
+    }
+
+    fn is_simulated(&self) -> bool {
+        true
+    }
+
+    async fn fetch_candles(
+        &self,
+        market: &str,
+        timeframe: u32,
+        start: u32,
+        end: u32,
+        candles: &mut Candles,
+    ) -> Result<()> {
+        self.inner
+            .fetch_candles(market, timeframe, start, end, candles)
+            .await
+    }
+
+    async fn post_orders(&self, orders: &Vec<Order>) -> Result<()> {
+        self.simulator.lock().await.post_orders(orders);
+        Ok(())
+    }
+
+    async fn cancel_orders(&self, orders: &Vec<OrderId>) -> Result<()> {
+        self.simulator.lock().await.cancel_orders(orders);
+        Ok(())
+    }
+
+    async fn fetch_open_orders(&self) -> Result<Vec<OrderSnapshot>> {
+        Ok(self.simulator.lock().await.open_orders())
+    }
+
+    async fn fetch_positions(&self) -> Result<Vec<Position>> {
+        Ok(self.simulator.lock().await.positions())
+    }
+
+    async fn fetch_margin(&self) -> Result<Margin> {
+        Ok(self.simulator.lock().await.margin())
+    }
+
+    async fn kill(&self) -> Result<()> {
+        self.simulator.lock().await.kill();
+        Ok(())
+    }
+
+    async fn on_public_trade(&self, trade: &Trade, market: &str, config: &InstrumentConfig) {
+        self.simulator
+            .lock()
+            .await
+            .on_public_trade(trade, market, config);
+    }
+
+    async fn on_order_book(&self, order_book: &OrderBook, config: &InstrumentConfig) {
+        self.simulator
+            .lock()
+            .await
+            .on_order_book(order_book, config);
+    }
+
+    async fn take_executions(&self) -> Vec<Execution> {
+        std::mem::take(&mut self.simulator.lock().await.executions)
+    }
+
+    async fn take_canceled(&self) -> Vec<OrderId> {
+        std::mem::take(&mut self.simulator.lock().await.canceled)
+    }
+}
+
+struct SimulatedOrder {
+    order: Order,
+    /// Order has already been matched against market data at least once. Resting limit orders
+    /// are filled at their limit as maker, new ones as taker at the touch.
+    resting: bool,
+}
+
+#[derive(Default)]
+struct SimulatedPosition {
+    amount: Decimal,
+    /// Value at entry prices.
+    value: Decimal,
+}
+
+impl SimulatedPosition {
+    /// Returns realized profit.
+    fn apply(&mut self, amount: Decimal, value: Decimal, is_inverse: bool) -> Decimal {
+        if self.amount.is_zero() || self.amount.is_sign_positive() == amount.is_sign_positive() {
+            self.amount += amount;
+            self.value += value;
+            return Decimal::zero();
+        }
+        let closed = amount.abs().min(self.amount.abs());
+        let entry_value = self.value * closed / self.amount.abs();
+        let exit_value = value * closed / amount.abs();
+        self.amount += amount;
+        // Rest of the execution flips the position.
+        self.value += value - exit_value - entry_value;
+        profit(entry_value, -exit_value, is_inverse)
+    }
+}
+
+/// Profit of a position that was opened with `entry_value` and is now worth `exit_value`.
+fn profit(entry_value: Decimal, exit_value: Decimal, is_inverse: bool) -> Decimal {
+    match is_inverse {
+        true => entry_value - exit_value,
+        false => exit_value - entry_value,
+    }
+}
+
+/// Matches orders against public trades and order books of a single account.
+///
+/// Market orders walk the order book or fill at the last trade price. Limit orders fill as taker at
+/// the touch when they are marketable on arrival (post-only ones are canceled instead) and as
+/// maker at their limit once the touch crosses it or a trade prints through it. Stop orders trigger
+/// on public trades and then behave as market or limit orders. Orders are always filled in full.
+pub struct Simulator {
+    /// Realized balance in margin currency.
+    balance: Decimal,
+    orders: Vec<SimulatedOrder>,
+    positions: HashMap<String, SimulatedPosition>,
+    /// Last trade price of each market.
+    prices: HashMap<String, Decimal>,
+    instruments: HashMap<String, InstrumentConfig>,
+    executions: Vec<Execution>,
+    canceled: Vec<OrderId>,
+    timestamp_ns: u64,
+}
+
+impl Simulator {
+    pub fn new(balance: Decimal) -> Simulator {
+        Simulator {
+            balance,
+            orders: Vec::new(),
+            positions: HashMap::new(),
+            prices: HashMap::new(),
+            instruments: HashMap::new(),
+            executions: Vec::new(),
+            canceled: Vec::new(),
+            timestamp_ns: 0,
+        }
+    }
+
+    pub fn post_orders(&mut self, orders: &[Order]) {
+        trace!("Paper orders placed {:?}", orders);
+        self.orders
+            .extend(orders.iter().cloned().map(|order| SimulatedOrder {
+                order,
+                resting: false,
+            }));
+    }
+
+    pub fn cancel_orders(&mut self, ids: &[OrderId]) {
+        let canceled = &mut self.canceled;
+        self.orders.retain(|x| {
+            let is_canceled = ids.contains(&x.order.id);
+            if is_canceled {
+                canceled.push(x.order.id);
+            }
+            !is_canceled
+        });
+    }
+
+    pub fn open_orders(&self) -> Vec<OrderSnapshot> {
+        self.orders
+            .iter()
+            .map(|x| OrderSnapshot {
+                id: x.order.id,
+                market: x.order.market.clone(),
+                amount_left: x.order.amount,
+            })
+            .collect()
+    }
+
+    pub fn positions(&self) -> Vec<Position> {
+        self.positions
+            .iter()
+            .filter(|(_, position)| !position.amount.is_zero())
+            .map(|(market, position)| Position {
+                market: market.clone(),
+                amount: position.amount,
+                timestamp_ns: self.timestamp_ns,
+            })
+            .collect()
+    }
+
+    /// Balance includes unrealized profit at last trade prices.
+    pub fn margin(&self) -> Margin {
+        let mut balance = self.balance;
+        let mut notional = Decimal::zero();
+        for (market, position) in &self.positions {
+            let (price, config) = match (self.prices.get(market), self.instruments.get(market)) {
+                (Some(price), Some(config)) => (*price, config),
+                _ => continue,
+            };
+            let value = order::value(price, position.amount, config.is_inverse);
+            balance += profit(position.value, value, config.is_inverse);
+            notional += value.abs();
+        }
+        Margin {
+            balance,
+            leverage: match balance.is_zero() {
+                true => Decimal::zero(),
+                false => notional / balance,
+            },
+            timestamp_ns: self.timestamp_ns,
+        }
+    }
+
+    /// Cancels all orders and closes all positions at last trade prices. Closing trades are
+    /// reported as executions of unknown orders, like a real exchange does.
+    pub fn kill(&mut self) {
+        self.canceled
+            .extend(self.orders.drain(..).map(|x| x.order.id));
+        for (market, position) in self.positions.iter_mut() {
+            let (price, config) = match (self.prices.get(market), self.instruments.get(market)) {
+                (Some(price), Some(config)) => (*price, config),
+                _ => continue,
+            };
+            let amount = -position.amount;
+            if amount.is_zero() {
+                continue;
+            }
+            let value = order::value(price, amount, config.is_inverse);
+            let fee_paid = value.abs() * config.taker_fee;
+            self.balance -= fee_paid;
+            self.balance += position.apply(amount, value, config.is_inverse);
+            self.executions.push(Execution {
+                market: market.clone(),
+                order_id: OrderId::unknown(),
+                value,
+                amount,
+                amount_left: Decimal::zero(),
+                fee_paid,
+                executed_price: price,
+                timestamp_ns: self.timestamp_ns,
+            });
+        }
+    }
+
+    pub fn on_public_trade(&mut self, trade: &Trade, market: &str, config: &InstrumentConfig) {
+        let price = match trade.price.to_decimal() {
+            None => return,
+            Some(price) => price,
+        };
+        self.timestamp_ns = trade.timestamp_ns;
+        self.prices.insert(market.to_string(), price);
+        for x in self.orders.iter_mut().filter(|x| x.order.market == market) {
+            let triggered = match x.order.trigger_price {
+                None => false,
+                Some(trigger) if x.order.amount.is_sign_positive() => price >= trigger,
+                Some(trigger) => price <= trigger,
+            };
+            if triggered {
+                x.order.trigger_price = None;
+            }
+        }
+        self.fill_orders(market, config, |order, _| match order.limit {
+            None => Some((price, true)),
+            Some(limit) if order.amount.is_sign_positive() && price < limit => Some((limit, false)),
+            Some(limit) if order.amount.is_sign_negative() && price > limit => Some((limit, false)),
+            Some(_) => None,
+        });
+    }
+
+    pub fn on_order_book(&mut self, order_book: &OrderBook, config: &InstrumentConfig) {
+        self.timestamp_ns = order_book.timestamp_ns();
+        let bid = order_book.best_bid().map(|(price, _)| price);
+        let ask = order_book.best_ask().map(|(price, _)| price);
+        let touch = |order: &Order| {
+            let limit = order.limit?;
+            match order.amount.is_sign_positive() {
+                true => ask.filter(|x| *x <= limit),
+                false => bid.filter(|x| *x >= limit),
+            }
+        };
+        // Post-only orders that would take liquidity on arrival are canceled by exchanges.
+        let market = order_book.market();
+        let canceled = &mut self.canceled;
+        self.orders.retain(|x| {
+            let is_canceled = x.order.market == market
+                && x.order.post_only
+                && x.order.trigger_price.is_none()
+                && !x.resting
+                && touch(&x.order).is_some();
+            if is_canceled {
+                trace!(
+                    "Paper post-only order {:?} would cross, canceling it",
+                    x.order.id
+                );
+                canceled.push(x.order.id);
+            }
+            !is_canceled
+        });
+        self.fill_orders(market, config, |order, resting| {
+            let limit = match order.limit {
+                None => return order_book.fill_price(order.amount).map(|x| (x, true)),
+                Some(limit) => limit,
+            };
+            let touch = touch(order)?;
+            match resting {
+                true => Some((limit, false)),
+                false => Some((touch, true)),
+            }
+        });
+    }
+
+    /// `fill_price` returns price and whether order takes liquidity, untriggered stop orders are
+    /// skipped.
+    fn fill_orders(
+        &mut self,
+        market: &str,
+        config: &InstrumentConfig,
+        mut fill_price: impl FnMut(&Order, bool) -> Option<(Decimal, bool)>,
+    ) {
+        self.instruments.insert(market.to_string(), config.clone());
+        for mut x in std::mem::take(&mut self.orders) {
+            if x.order.market != market || x.order.trigger_price.is_some() {
+                self.orders.push(x);
+                continue;
+            }
+            match fill_price(&x.order, x.resting) {
+                Some((price, is_taker)) => self.fill(&x.order, price, is_taker, config),
+                None => {
+                    x.resting = true;
+                    self.orders.push(x);
+                }
+            }
+        }
+    }
+
+    fn fill(&mut self, order: &Order, price: Decimal, is_taker: bool, config: &InstrumentConfig) {
+        let value = order::value(price, order.amount, config.is_inverse);
+        let fee_paid = value.abs()
+            * match is_taker {
+                true => config.taker_fee,
+                false => config.maker_fee,
+            };
+        self.balance -= fee_paid;
+        self.balance += self
+            .positions
+            .entry(order.market.clone())
+            .or_default()
+            .apply(order.amount, value, config.is_inverse);
+        self.executions.push(Execution {
+            market: order.market.clone(),
+            order_id: order.id,
+            value,
+            amount: order.amount,
+            amount_left: Decimal::zero(),
+            fee_paid,
+            executed_price: price,
+            timestamp_ns: self.timestamp_ns,
+        });
+    }
+}
+
+#[cfg(test)]
+mod t_paper {
+    use merovingian::order::IdGenerator;
+    use merovingian::order_book::{BookSide, L2Level};
+
+    use super::*;
+
+    const MARKET: &str = "XBTUSD";
+
+    fn config() -> InstrumentConfig {
+        InstrumentConfig {
+            taker_fee: Decimal::new(1, 2),
+            maker_fee: Decimal::zero(),
+            ..Default::default()
+        }
+    }
+
+    fn order(amount: i32, limit: Option<i32>, trigger_price: Option<i32>) -> Order {
+        Order {
+            amount: Decimal::from(amount),
+            trigger_price: trigger_price.map(Decimal::from),
+            limit: limit.map(Decimal::from),
+            executed_price: None,
+            market: MARKET.to_string(),
+            id: IdGenerator::new_order_id(0),
+            predicted_price: 0.,
+            value: None,
+            timestamp_ns: 0,
+            post_only: false,
+            display_amount: None,
+        }
+    }
+
+    fn trade(price: f32) -> Trade {
+        Trade {
+            timestamp_ns: 1,
+            price,
+            amount: 1.,
+        }
+    }
+
+    fn book(bid: i32, ask: i32) -> OrderBook {
+        let mut book = OrderBook::new(MARKET);
+        let level = |id: i64, side: BookSide, price: i32| L2Level {
+            id,
+            side,
+            price: Some(Decimal::from(price)),
+            size: Some(Decimal::from(10)),
+        };
+        book.apply_snapshot(
+            &[level(1, BookSide::Bid, bid), level(2, BookSide::Ask, ask)],
+            2,
+        )
+        .unwrap();
+        book
+    }
+
+    fn fills(simulator: &mut Simulator) -> Vec<(OrderId, Decimal)> {
+        std::mem::take(&mut simulator.executions)
+            .into_iter()
+            .map(|x| (x.order_id, x.executed_price))
+            .collect()
+    }
+
+    #[test]
+    fn t_market_and_limit_orders() {
+        let mut simulator = Simulator::new(Decimal::from(1000));
+        let market = order(1, None, None);
+        let marketable = order(1, Some(105), None);
+        let resting = order(-1, Some(110), None);
+        let low = order(1, Some(102), None);
+        simulator.post_orders(&[market.clone(), marketable.clone(), resting.clone()]);
+        simulator.on_order_book(&book(100, 101), &config());
+        assert_eq!(
+            fills(&mut simulator),
+            vec![
+                (market.id, Decimal::from(101)),
+                (marketable.id, Decimal::from(101)),
+            ]
+        );
+        assert_eq!(simulator.open_orders().len(), 1);
+
+        // Resting orders fill at their limit.
+        simulator.on_order_book(&book(110, 111), &config());
+        simulator.post_orders(&[low.clone()]);
+        simulator.on_order_book(&book(103, 104), &config());
+        simulator.on_public_trade(&trade(101.5), MARKET, &config());
+        assert_eq!(
+            fills(&mut simulator),
+            vec![
+                (resting.id, Decimal::from(110)),
+                (low.id, Decimal::from(102)),
+            ]
+        );
+        assert!(simulator.open_orders().is_empty());
+    }
+
+    #[test]
+    fn t_post_only_orders() {
+        let mut simulator = Simulator::new(Decimal::from(1000));
+        let mut crossing = order(1, Some(102), None);
+        crossing.post_only = true;
+        let mut passive = order(-1, Some(102), None);
+        passive.post_only = true;
+        simulator.post_orders(&[crossing.clone(), passive.clone()]);
+        simulator.on_order_book(&book(100, 101), &config());
+        assert!(fills(&mut simulator).is_empty());
+        assert_eq!(simulator.canceled, vec![crossing.id]);
+        assert_eq!(simulator.open_orders()[0].id, passive.id);
+
+        // Once resting it fills at its limit as maker.
+        simulator.on_order_book(&book(103, 104), &config());
+        assert_eq!(
+            fills(&mut simulator),
+            vec![(passive.id, Decimal::from(102))]
+        );
+        assert_eq!(simulator.balance, Decimal::from(1000));
+        assert!(simulator.open_orders().is_empty());
+    }
+
+    #[test]
+    fn t_stop_orders_and_cancel() {
+        let mut simulator = Simulator::new(Decimal::from(1000));
+        let stop = order(-1, None, Some(95));
+        let canceled = order(1, Some(90), None);
+        simulator.post_orders(&[stop.clone(), canceled.clone()]);
+        simulator.cancel_orders(&[canceled.id, OrderId::unknown()]);
+        assert_eq!(simulator.canceled, vec![canceled.id]);
+        simulator.on_public_trade(&trade(96.), MARKET, &config());
+        assert!(fills(&mut simulator).is_empty());
+        simulator.on_public_trade(&trade(94.), MARKET, &config());
+        assert_eq!(fills(&mut simulator), vec![(stop.id, Decimal::from(94))]);
+        assert!(simulator.open_orders().is_empty());
+    }
+
+    #[test]
+    fn t_margin_and_kill() {
+        let mut simulator = Simulator::new(Decimal::from(1000));
+        simulator.post_orders(&[order(10, None, None)]);
+        simulator.on_public_trade(&trade(100.), MARKET, &config());
+        // Fee is 1% of 1000.
+        assert_eq!(simulator.margin().balance, Decimal::from(990));
+        simulator.on_public_trade(&trade(110.), MARKET, &config());
+        let margin = simulator.margin();
+        assert_eq!(margin.balance, Decimal::from(1090));
+        assert_eq!(margin.leverage, Decimal::from(1100) / Decimal::from(1090));
+        assert_eq!(simulator.positions()[0].amount, Decimal::from(10));
+
+        // Flipping realizes profit of the closed part.
+        simulator.post_orders(&[order(-15, None, None)]);
+        simulator.on_public_trade(&trade(120.), MARKET, &config());
+        assert_eq!(simulator.balance, Decimal::from(1172));
+        assert_eq!(simulator.positions()[0].amount, Decimal::from(-5));
+
+        let resting = order(1, Some(50), None);
+        simulator.post_orders(&[resting.clone()]);
+        fills(&mut simulator);
+        simulator.kill();
+        assert!(simulator.open_orders().is_empty());
+        assert_eq!(simulator.canceled, vec![resting.id]);
+        assert!(simulator.positions().is_empty());
+        assert_eq!(simulator.balance, Decimal::from(1166));
+        let executions = std::mem::take(&mut simulator.executions);
+        assert_eq!(executions.len(), 1);
+        assert_eq!(executions[0].order_id, OrderId::unknown());
+        assert_eq!(executions[0].amount, Decimal::from(5));
+        assert_eq!(executions[0].executed_price, Decimal::from(120));
+        // Fee is 1% of 600.
+        assert_eq!(executions[0].fee_paid, Decimal::from(6));
+    }
+}
@@ -0,0 +1,94 @@
+//! Exposure of all exchanges that are run by one process. `RiskEngine` of each exchange reports its
+//! notional and balance here and checks portfolio limits against what the others reported last.
+//! Kill switch engaged on one exchange is followed by all of them.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use config::{PortfolioRiskConfig, CONFIG};
+use lazy_static::lazy_static;
+use mouse::num::{Decimal, IntoDecimal};
+
+lazy_static! {
+    static ref PORTFOLIO: Option<Portfolio> =
+        CONFIG.fleet.as_ref().map(|x| Portfolio::new(&x.risk));
+}
+
+/// Portfolio of this process, `None` if fleet isn't configured.
+pub fn portfolio() -> Option<&'static Portfolio> {
+    PORTFOLIO.as_ref()
+}
+
+#[derive(Default)]
+struct Exposure {
+    notional: HashMap<String, Decimal>,
+    balances: HashMap<String, Decimal>,
+    peak_balance: Decimal,
+    /// Drawdown limit has been hit, it is reported only once.
+    breached: bool,
+}
+
+pub struct Portfolio {
+    max_notional: Option<Decimal>,
+    max_drawdown: Option<Decimal>,
+    exposure: Mutex<Exposure>,
+    killed: AtomicBool,
+}
+
+impl Portfolio {
+    pub fn new(config: &PortfolioRiskConfig) -> Portfolio {
+        Portfolio {
+            max_notional: config.max_notional.and_then(|x| x.to_decimal()),
+            max_drawdown: config.max_drawdown.and_then(|x| x.to_decimal()),
+            exposure: Default::default(),
+            killed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn max_notional(&self) -> Option<Decimal> {
+        self.max_notional
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    pub fn engage_kill_switch(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn report_notional(&self, exchange: &str, notional: Decimal) {
+        let mut exposure = self.exposure.lock().unwrap();
+        exposure.notional.insert(exchange.to_string(), notional);
+    }
+
+    /// Notional reported by all exchanges except `exchange`.
+    pub fn notional_of_others(&self, exchange: &str) -> Decimal {
+        let exposure = self.exposure.lock().unwrap();
+        exposure
+            .notional
+            .iter()
+            .filter(|(name, _)| name.as_str() != exchange)
+            .map(|(_, notional)| *notional)
+            .sum()
+    }
+
+    /// Returns drawdown of total balance and its max when the drawdown limit is hit for the first
+    /// time.
+    pub fn report_balance(&self, exchange: &str, balance: Decimal) -> Option<(Decimal, Decimal)> {
+        let mut exposure = self.exposure.lock().unwrap();
+        exposure.balances.insert(exchange.to_string(), balance);
+        let total: Decimal = exposure.balances.values().copied().sum();
+        if total > exposure.peak_balance {
+            exposure.peak_balance = total;
+        }
+        let max = self.max_drawdown?;
+        let peak = exposure.peak_balance;
+        if exposure.breached || !peak.is_sign_positive() || peak.is_zero() {
+            return None;
+        }
+        let drawdown = (peak - total) / peak;
+        exposure.breached = drawdown > max;
+        exposure.breached.then(|| (drawdown, max))
+    }
+}
@@ -0,0 +1,389 @@
+//! Periodic comparison of our own bookkeeping against the state reported by an exchange. Websocket
+//! messages can get lost, in which case open orders and model positions silently diverge from
+//! reality.
+//!
+//! A mismatch has to be observed by two consecutive reconciliations before anything is done about
+//! it, executions that are still waiting in the websocket buffer would otherwise look like lost ones.
+use std::collections::{HashMap, HashSet};
+
+use config::ReconciliationConfig;
+use merovingian::minable_models::Position;
+use merovingian::order::OrderId;
+use mouse::num::traits::Zero;
+use mouse::num::{Decimal, IntoDecimal};
+
+/// Open order, either tracked by us or reported by an exchange.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderSnapshot {
+    /// `OrderId::unknown()` if order wasn't placed by us.
+    pub id: OrderId,
+    pub market: String,
+    /// Signed amount that hasn't been executed yet.
+    pub amount_left: Decimal,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mismatch {
+    /// Order that we track is gone from the exchange (`remote_left` is `None`) or has been partially
+    /// executed without us receiving an execution.
+    MissingFill {
+        order_id: OrderId,
+        market: String,
+        local_left: Decimal,
+        remote_left: Option<Decimal>,
+    },
+    /// Order is open on the exchange but we don't track it.
+    PhantomOrder {
+        order_id: OrderId,
+        market: String,
+        amount_left: Decimal,
+    },
+    /// Position on the exchange differs from the one seen by models.
+    PositionDrift {
+        market: String,
+        local: Decimal,
+        remote: Decimal,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum MismatchKey {
+    Order(OrderId),
+    Market(String),
+}
+
+impl Mismatch {
+    /// Identifies a mismatch across reconciliations, amounts may change in between.
+    fn key(&self) -> MismatchKey {
+        match self {
+            Mismatch::MissingFill { order_id, .. } | Mismatch::PhantomOrder { order_id, .. } => {
+                MismatchKey::Order(*order_id)
+            }
+            Mismatch::PositionDrift { market, .. } => MismatchKey::Market(market.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Repair {
+    /// Cancel order on the exchange.
+    Cancel(OrderId),
+    /// Stop tracking order, it has been canceled without us being notified.
+    Forget(OrderId),
+    /// Cannot be repaired automatically, trading should stop once it is safe.
+    Escalate(Mismatch),
+}
+
+/// Compares tracked orders and positions against remote ones. Position drift is only checked when
+/// `local_positions` are known.
+pub fn diff(
+    local_orders: &[OrderSnapshot],
+    remote_orders: &[OrderSnapshot],
+    local_positions: Option<&HashMap<String, Decimal>>,
+    remote_positions: &[Position],
+    tolerance: Decimal,
+) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    let remote_by_id: HashMap<_, _> = remote_orders.iter().map(|x| (x.id, x)).collect();
+    for local in local_orders {
+        let remote_left = remote_by_id.get(&local.id).map(|x| x.amount_left);
+        if remote_left != Some(local.amount_left) {
+            mismatches.push(Mismatch::MissingFill {
+                order_id: local.id,
+                market: local.market.clone(),
+                local_left: local.amount_left,
+                remote_left,
+            });
+        }
+    }
+    let local_ids: HashSet<_> = local_orders.iter().map(|x| x.id).collect();
+    for remote in remote_orders {
+        if !local_ids.contains(&remote.id) {
+            mismatches.push(Mismatch::PhantomOrder {
+                order_id: remote.id,
+                market: remote.market.clone(),
+                amount_left: remote.amount_left,
+            });
+        }
+    }
+    if let Some(local_positions) = local_positions {
+        let mut markets: Vec<_> = local_positions
+            .keys()
+            .chain(remote_positions.iter().map(|x| &x.market))
+            .collect();
+        markets.sort();
+        markets.dedup();
+        for market in markets {
+            let local = local_positions
+                .get(market)
+                .copied()
+                .unwrap_or_else(Decimal::zero);
+            let remote = remote_positions
+                .iter()
+                .filter(|x| &x.market == market)
+                .map(|x| x.amount)
+                .sum::<Decimal>();
+            if (local - remote).abs() > tolerance {
+                mismatches.push(Mismatch::PositionDrift {
+                    market: market.clone(),
+                    local,
+                    remote,
+                });
+            }
+        }
+    }
+    mismatches
+}
+
+/// Net position of each market, positions of models on the same market offset each other.
+pub fn net_positions(
+    model_positions: &HashMap<(u32, String), Decimal>,
+) -> HashMap<String, Decimal> {
+    let mut positions = HashMap::new();
+    for ((_, market), amount) in model_positions {
+        *positions
+            .entry(market.clone())
+            .or_insert_with(Decimal::zero) += *amount;
+    }
+    positions
+}
+
+/// Decides what to do with mismatches according to `ReconciliationConfig`.
+pub struct Reconciler {
+    config: ReconciliationConfig,
+    /// Mismatches seen by the previous reconciliation.
+    suspected: HashSet<MismatchKey>,
+}
+
+impl Reconciler {
+    pub fn new(config: ReconciliationConfig) -> Reconciler {
+        Reconciler {
+            config,
+            suspected: HashSet::new(),
+        }
+    }
+
+    pub fn interval_s(&self) -> u32 {
+        self.config.interval_s
+    }
+
+    pub fn tolerance(&self) -> Decimal {
+        self.config
+            .position_tolerance
+            .to_decimal()
+            .unwrap_or_else(Decimal::zero)
+    }
+
+    /// Plans repairs for mismatches that have also been seen by the previous call. Missing orders
+    /// are only forgotten when `positions_checked` and their market hasn't drifted, otherwise they
+    /// might have been filled.
+    pub fn plan(&mut self, mismatches: Vec<Mismatch>, positions_checked: bool) -> Vec<Repair> {
+        let drifting: HashSet<_> = mismatches
+            .iter()
+            .filter_map(|x| match x {
+                Mismatch::PositionDrift { market, .. } => Some(market.clone()),
+                _ => None,
+            })
+            .collect();
+        let suspected = mismatches.iter().map(Mismatch::key).collect();
+        let previous = std::mem::replace(&mut self.suspected, suspected);
+        mismatches
+            .into_iter()
+            .filter(|x| previous.contains(&x.key()))
+            .map(|mismatch| match mismatch {
+                Mismatch::PhantomOrder { order_id, .. }
+                    if self.config.cancel_phantom_orders && order_id != OrderId::unknown() =>
+                {
+                    Repair::Cancel(order_id)
+                }
+                Mismatch::MissingFill {
+                    order_id,
+                    ref market,
+                    remote_left: None,
+                    ..
+                } if self.config.drop_missing_orders
+                    && positions_checked
+                    && !drifting.contains(market) =>
+                {
+                    Repair::Forget(order_id)
+                }
+                mismatch => Repair::Escalate(mismatch),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod t_reconciliation {
+    use merovingian::order::IdGenerator;
+
+    use super::*;
+
+    fn order(id: OrderId, amount_left: i32) -> OrderSnapshot {
+        OrderSnapshot {
+            id,
+            market: "XBTUSD".into(),
+            amount_left: Decimal::from(amount_left),
+        }
+    }
+
+    fn position(amount: i32) -> Position {
+        Position {
+            market: "XBTUSD".into(),
+            amount: Decimal::from(amount),
+            timestamp_ns: 0,
+        }
+    }
+
+    fn positions(amount: i32) -> HashMap<String, Decimal> {
+        let mut positions = HashMap::new();
+        positions.insert("XBTUSD".to_string(), Decimal::from(amount));
+        positions
+    }
+
+    #[test]
+    fn t_diff() {
+        let (matching, missing, partial, phantom) = (
+            IdGenerator::new_order_id(0),
+            IdGenerator::new_order_id(0),
+            IdGenerator::new_order_id(1),
+            IdGenerator::new_order_id(1),
+        );
+        let local = [order(matching, 10), order(missing, -5), order(partial, 20)];
+        let remote = [order(matching, 10), order(partial, 15), order(phantom, 3)];
+        let mismatches = diff(
+            &local,
+            &remote,
+            Some(&positions(5)),
+            &[position(10)],
+            Decimal::zero(),
+        );
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch::MissingFill {
+                    order_id: missing,
+                    market: "XBTUSD".into(),
+                    local_left: Decimal::from(-5),
+                    remote_left: None,
+                },
+                Mismatch::MissingFill {
+                    order_id: partial,
+                    market: "XBTUSD".into(),
+                    local_left: Decimal::from(20),
+                    remote_left: Some(Decimal::from(15)),
+                },
+                Mismatch::PhantomOrder {
+                    order_id: phantom,
+                    market: "XBTUSD".into(),
+                    amount_left: Decimal::from(3),
+                },
+                Mismatch::PositionDrift {
+                    market: "XBTUSD".into(),
+                    local: Decimal::from(5),
+                    remote: Decimal::from(10),
+                },
+            ]
+        );
+        // Drift within tolerance and unknown local positions are ignored.
+        assert!(diff(
+            &[],
+            &[],
+            Some(&positions(5)),
+            &[position(6)],
+            Decimal::from(1)
+        )
+        .is_empty());
+        assert!(diff(&[], &[], None, &[position(6)], Decimal::zero()).is_empty());
+    }
+
+    #[test]
+    fn t_plan_requires_confirmation() {
+        let (missing, phantom) = (IdGenerator::new_order_id(0), IdGenerator::new_order_id(0));
+        let mut reconciler = Reconciler::new(ReconciliationConfig::default());
+        let mismatches = diff(
+            &[order(missing, 10)],
+            &[order(phantom, 10), order(OrderId::unknown(), 1)],
+            Some(&positions(0)),
+            &[],
+            Decimal::zero(),
+        );
+        assert!(reconciler.plan(mismatches.clone(), true).is_empty());
+        assert_eq!(
+            reconciler.plan(mismatches.clone(), true),
+            vec![
+                Repair::Forget(missing),
+                Repair::Cancel(phantom),
+                Repair::Escalate(mismatches[2].clone()),
+            ]
+        );
+        // Resolved mismatches are no longer suspected.
+        assert!(reconciler.plan(vec![], true).is_empty());
+        assert!(reconciler.plan(mismatches, true).is_empty());
+    }
+
+    #[test]
+    fn t_plan_escalates_fills() {
+        let missing = IdGenerator::new_order_id(0);
+        let mut reconciler = Reconciler::new(ReconciliationConfig::default());
+        // Order is gone and position has changed, it was most likely filled.
+        let mismatches = diff(
+            &[order(missing, 10)],
+            &[],
+            Some(&positions(0)),
+            &[position(10)],
+            Decimal::zero(),
+        );
+        reconciler.plan(mismatches.clone(), true);
+        let repairs = reconciler.plan(mismatches.clone(), true);
+        assert_eq!(
+            repairs,
+            mismatches
+                .into_iter()
+                .map(Repair::Escalate)
+                .collect::<Vec<_>>()
+        );
+        // Without positions we can't tell if order was canceled or filled.
+        let mismatches = diff(&[order(missing, 10)], &[], None, &[], Decimal::zero());
+        reconciler.plan(mismatches.clone(), false);
+        assert_eq!(
+            reconciler.plan(mismatches.clone(), false),
+            vec![Repair::Escalate(mismatches[0].clone())]
+        );
+    }
+
+    #[cfg(feature = "test")]
+    #[tokio::test]
+    async fn t_mock_client_drift() -> mouse::error::Result<()> {
+        use crate::agents::network_agent::NetworkClient;
+        use crate::agents::network_agents::mock_network_agent::MockClient;
+
+        let client = MockClient::with_position("XBTUSD", Decimal::from(10));
+        let remote = client.fetch_positions().await?;
+        let mut model_positions = HashMap::new();
+        model_positions.insert((0, "XBTUSD".to_string()), Decimal::from(15));
+        model_positions.insert((1, "XBTUSD".to_string()), Decimal::from(-5));
+        let local = net_positions(&model_positions);
+        assert!(diff(&[], &[], Some(&local), &remote, Decimal::zero()).is_empty());
+
+        // An execution of the second model got lost.
+        model_positions.insert((1, "XBTUSD".to_string()), Decimal::from(-10));
+        let local = net_positions(&model_positions);
+        let mismatches = diff(&[], &[], Some(&local), &remote, Decimal::zero());
+        assert_eq!(
+            mismatches,
+            vec![Mismatch::PositionDrift {
+                market: "XBTUSD".into(),
+                local: Decimal::from(5),
+                remote: Decimal::from(10),
+            }]
+        );
+        let mut reconciler = Reconciler::new(ReconciliationConfig::default());
+        assert!(reconciler.plan(mismatches.clone(), true).is_empty());
+        assert_eq!(
+            reconciler.plan(mismatches.clone(), true),
+            vec![Repair::Escalate(mismatches[0].clone())]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,456 @@
+//! Replays market data recorded by `ExchangeDataAgent` through the message loop of a live agent
+//! at simulated time. Together with `PaperClient` this reproduces a live session offline, models
+//! see the same trades and candles in the same order as they did live while reconciliation,
+//! execution algorithms and candle ticks run as they would.
+//!
+//! Only streams that are still on disk are read, parts that have already been archived have to be
+//! downloaded first. Private streams (margin, positions) are replayed as they were recorded.
+use std::collections::VecDeque;
+use std::io::{Cursor, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use async_compression::tokio::bufread::LzmaDecoder;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use iaas::mysql::models::{ExchangeConfig, ModelConfig};
+use merovingian::minable_models::*;
+use merovingian::non_minable_models::ExitCode;
+use mouse::error::{bail, Result};
+use mouse::log::*;
+use mouse::time::IntoDateTime;
+use nebuchadnezzar_core::clock::{Clock, SimulatedClock};
+use speedy::{IsEof, LittleEndian, Readable};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::Mutex;
+
+use super::client::NetworkClient;
+use super::exchange_state::NetworkAgentState;
+use super::websocket::{run_message_loop, Ws};
+use crate::agents::network_agents::{Execution, FundingExecution, NetworkAgent};
+use crate::error::MatrixError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordedEvent {
+    Instrument {
+        market: String,
+        instrument: Instrument,
+    },
+    Funding {
+        market: String,
+        funding: Funding,
+    },
+    OrderBookUpdate {
+        market: String,
+        update: OrderBookUpdate,
+    },
+    PublicTrade {
+        market: String,
+        trade: Trade,
+    },
+    Margin {
+        margin: Margin,
+    },
+    Position {
+        position: Position,
+    },
+}
+
+impl RecordedEvent {
+    pub fn timestamp_ns(&self) -> u64 {
+        match self {
+            RecordedEvent::Instrument { instrument, .. } => instrument.timestamp_ns,
+            RecordedEvent::Funding { funding, .. } => funding.timestamp_s as u64 * 1_000_000_000,
+            RecordedEvent::OrderBookUpdate { update, .. } => update.timestamp_ns,
+            RecordedEvent::PublicTrade { trade, .. } => trade.timestamp_ns,
+            RecordedEvent::Margin { margin } => margin.timestamp_ns,
+            RecordedEvent::Position { position } => position.timestamp_ns,
+        }
+    }
+}
+
+/// Reads streams of `markets` and margin of the account recorded under `exchange_dir`
+/// (`data_dir/<exchange name>`) ordered by time.
+pub async fn read_recording(exchange_dir: &Path, markets: &[String]) -> Result<Vec<RecordedEvent>> {
+    let mut events = Vec::new();
+    for market in markets {
+        let path = |stream: &str| exchange_dir.join(stream).join(market);
+        for instrument in read_stream(&path("instruments")).await? {
+            events.push(RecordedEvent::Instrument {
+                market: market.clone(),
+                instrument,
+            });
+        }
+        for funding in read_stream(&path("funding")).await? {
+            events.push(RecordedEvent::Funding {
+                market: market.clone(),
+                funding,
+            });
+        }
+        for update in read_stream(&path("order_books")).await? {
+            events.push(RecordedEvent::OrderBookUpdate {
+                market: market.clone(),
+                update,
+            });
+        }
+        for trade in read_stream(&path("public_trades")).await? {
+            events.push(RecordedEvent::PublicTrade {
+                market: market.clone(),
+                trade,
+            });
+        }
+    }
+    for margin in read_stream(&exchange_dir.join("margin")).await? {
+        events.push(RecordedEvent::Margin { margin });
+    }
+    for position in read_stream::<Position>(&exchange_dir.join("positions")).await? {
+        if markets.contains(&position.market) {
+            events.push(RecordedEvent::Position { position });
+        }
+    }
+    sort_events(&mut events);
+    Ok(events)
+}
+
+/// Sort is stable, events with the same timestamp keep the order in which streams were read so
+/// that instruments are known before trades of the same instant.
+fn sort_events(events: &mut Vec<RecordedEvent>) {
+    events.sort_by_key(RecordedEvent::timestamp_ns);
+}
+
+/// Reads every record of a stream, missing stream is empty. Record that was cut off by a crash
+/// ends the stream.
+async fn read_stream<T>(path: &Path) -> Result<Vec<T>>
+where
+    T: for<'a> Readable<'a, LittleEndian>,
+{
+    let file = match File::open(path.with_extension("lzma")).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    // Every flush of `DataMiner` appends a new lzma member.
+    let mut reader = LzmaDecoder::new(BufReader::new(file));
+    reader.multiple_members(true);
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    let mut cursor = Cursor::new(data);
+    let mut records = Vec::new();
+    loop {
+        match T::read_from_stream_unbuffered(&mut cursor) {
+            Ok(record) => records.push(record),
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(records)
+}
+
+/// Websocket of a recording, returns events once simulated time reaches them and `None` when all
+/// of them have been returned.
+pub struct ReplayWs {
+    clock: Arc<SimulatedClock>,
+    events: VecDeque<RecordedEvent>,
+}
+
+impl ReplayWs {
+    pub fn new(clock: Arc<SimulatedClock>, events: Vec<RecordedEvent>) -> ReplayWs {
+        ReplayWs {
+            clock,
+            events: events.into(),
+        }
+    }
+
+    pub fn clock(&self) -> &Arc<SimulatedClock> {
+        &self.clock
+    }
+}
+
+#[async_trait]
+impl Ws for ReplayWs {
+    type Message = Option<RecordedEvent>;
+
+    async fn next(&mut self) -> Option<RecordedEvent> {
+        loop {
+            let timestamp = self.events.front()?.timestamp_ns().into_date_time();
+            if self.clock.now() >= timestamp {
+                return self.events.pop_front();
+            }
+            // Sleeps that end before the event are woken first, e.g. candles are ticked.
+            match self.clock.next_deadline().filter(|x| *x < timestamp) {
+                Some(deadline) => {
+                    self.clock.advance_to(deadline);
+                    tokio::task::yield_now().await;
+                }
+                None => self.clock.advance_to(timestamp),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Agent of a recording, it can't be built or reconnected.
+struct ReplayAgent<C: NetworkClient> {
+    state: NetworkAgentState<C, ReplayWs>,
+}
+
+#[async_trait]
+impl<C: NetworkClient> NetworkAgent for ReplayAgent<C> {
+    type Websocket = ReplayWs;
+    type Client = C;
+
+    async fn build(
+        _exchange_config: ExchangeConfig,
+        _model_configs: Vec<ModelConfig>,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        bail!("Replay agent is created from a recording.")
+    }
+
+    async fn catch_up(
+        &mut self,
+        _last_execution_time: DateTime<Utc>,
+    ) -> Result<Vec<Result<Execution, FundingExecution>>> {
+        // Client executes orders of the replay again.
+        Ok(Vec::new())
+    }
+
+    fn new_client(_use_testnet: bool, _api_key: &str, _api_secret: &str) -> Self::Client {
+        unimplemented!()
+    }
+
+    async fn new_subscribed_web_socket(&mut self) -> Result<Self::Websocket> {
+        bail!("Recording has ended, there is nothing to reconnect to.")
+    }
+
+    async fn handle_message(&mut self, msg: Option<RecordedEvent>) -> Result<()> {
+        let state = &mut self.state;
+        match msg {
+            None => {
+                info!("Recording has been replayed.");
+                state.on_shutdown().await?;
+                Err(MatrixError::Shutdown.into())
+            }
+            Some(RecordedEvent::Instrument { market, instrument }) => {
+                state.on_instrument_changed(instrument, &market).await
+            }
+            Some(RecordedEvent::Funding { market, funding }) => {
+                state.on_funding(funding, market).await
+            }
+            Some(RecordedEvent::OrderBookUpdate { market, update }) => {
+                state.on_order_book_updated(update, market).await
+            }
+            Some(RecordedEvent::PublicTrade { market, trade }) => {
+                state.on_public_trade(trade, market).await
+            }
+            Some(RecordedEvent::Margin { margin }) => state.on_margin_changed(margin).await,
+            Some(RecordedEvent::Position { position }) => state.on_position_changed(position).await,
+        }
+    }
+
+    fn state_mut(&mut self) -> &mut NetworkAgentState<C, ReplayWs> {
+        &mut self.state
+    }
+}
+
+/// Runs `state` through the message loop until its `ReplayWs` has returned every event, state
+/// follows the clock of the websocket.
+pub async fn replay<C: NetworkClient>(mut state: NetworkAgentState<C, ReplayWs>) -> ExitCode {
+    let clock = state
+        .ws
+        .as_ref()
+        .expect("State is created with a websocket.")
+        .clock()
+        .clone();
+    state.set_clock(clock);
+    run_message_loop(Arc::new(Mutex::new(ReplayAgent { state }))).await
+}
+
+#[cfg(test)]
+mod t_replay {
+    use async_compression::tokio::write::LzmaEncoder;
+    use speedy::Writable;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn trade(timestamp_ns: u64) -> RecordedEvent {
+        RecordedEvent::PublicTrade {
+            market: "XBTUSD".into(),
+            trade: Trade {
+                timestamp_ns,
+                price: 100.,
+                amount: 1.,
+            },
+        }
+    }
+
+    fn instrument(timestamp_ns: u64) -> RecordedEvent {
+        RecordedEvent::Instrument {
+            market: "XBTUSD".into(),
+            instrument: Instrument {
+                fair_price: 100.,
+                mark_price: 100.,
+                timestamp_ns,
+            },
+        }
+    }
+
+    /// Writes `records` in two lzma members like two flushes of `DataMiner` would.
+    async fn write_stream<T: Writable<LittleEndian>>(path: &Path, records: &[T]) -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = std::fs::File::create(path.with_extension("lzma"))?;
+        for chunk in records.chunks(2) {
+            let mut buf = Vec::new();
+            for record in chunk {
+                record.write_to_stream(&mut buf)?;
+            }
+            let mut encoder = LzmaEncoder::new(Vec::new());
+            encoder.write_all(&buf).await?;
+            encoder.shutdown().await?;
+            std::io::Write::write_all(&mut file, &encoder.into_inner())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t_sort_events() {
+        let mut events = vec![trade(2), instrument(1), instrument(2), trade(1)];
+        sort_events(&mut events);
+        assert_eq!(
+            events,
+            vec![instrument(1), trade(1), instrument(2), trade(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn t_read_recording() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let trades: Vec<_> = (0..3)
+            .map(|x| Trade {
+                timestamp_ns: x * 2 + 1,
+                price: 100.,
+                amount: 1.,
+            })
+            .collect();
+        write_stream(&dir.path().join("public_trades").join("XBTUSD"), &trades).await?;
+        let instruments = [
+            Instrument {
+                fair_price: 100.,
+                mark_price: 100.,
+                timestamp_ns: 1,
+            },
+            Instrument {
+                fair_price: 100.,
+                mark_price: 100.,
+                timestamp_ns: 4,
+            },
+        ];
+        write_stream(&dir.path().join("instruments").join("XBTUSD"), &instruments).await?;
+        let events = read_recording(dir.path(), &["XBTUSD".to_string()]).await?;
+        assert_eq!(
+            events,
+            vec![instrument(1), trade(1), trade(3), instrument(4), trade(5)]
+        );
+        // Markets without a recording are empty.
+        assert!(read_recording(dir.path(), &["ETHUSD".to_string()])
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod t_replay_loop {
+    use std::collections::HashMap;
+
+    use chrono::TimeZone;
+    use merovingian::candles::Candles;
+    use merovingian::order::{Order, OrderId};
+    use mouse::num::Decimal;
+
+    use super::*;
+    use crate::agents::network_agent::exchange_state::t_exchange_state::{mock_state, MARKET};
+    use crate::agents::network_agents::{ExchangeListener, InstrumentConfig};
+
+    /// Keeps what listeners have been told and when, shared because state is moved into the loop.
+    struct Recorder(Arc<std::sync::Mutex<Vec<(&'static str, u32)>>>);
+
+    #[async_trait]
+    impl ExchangeListener for Recorder {
+        async fn on_public_trade(&mut self, trade: &Trade, _market: &String) -> Result<()> {
+            let timestamp_s = (trade.timestamp_ns / 1_000_000_000) as u32;
+            self.0.lock().unwrap().push(("trade", timestamp_s));
+            Ok(())
+        }
+
+        async fn on_margin_changed(&mut self, margin: &Margin) -> Result<()> {
+            let timestamp_s = (margin.timestamp_ns / 1_000_000_000) as u32;
+            self.0.lock().unwrap().push(("margin", timestamp_s));
+            Ok(())
+        }
+
+        async fn on_new_candle<'a>(
+            &'a mut self,
+            _candles: &'a HashMap<String, HashMap<u32, Candles>>,
+            last_timestamp_s: u32,
+            _active_instruments: &'a HashMap<String, InstrumentConfig>,
+            _orders_to_open: &Arc<Mutex<Vec<Order>>>,
+            _orders_to_cancel: &Arc<Mutex<Vec<OrderId>>>,
+        ) -> Result<()> {
+            self.0.lock().unwrap().push(("candle", last_timestamp_s));
+            Ok(())
+        }
+    }
+
+    fn trade(timestamp_s: u64) -> RecordedEvent {
+        RecordedEvent::PublicTrade {
+            market: MARKET.into(),
+            trade: Trade {
+                timestamp_ns: timestamp_s * 1_000_000_000,
+                price: 100.,
+                amount: 1.,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn t_candles_are_ticked_between_events() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let listener = Box::new(Recorder(seen.clone()));
+        let mut state = mock_state(dir.path(), listener, HashMap::new()).await?;
+        let margin = RecordedEvent::Margin {
+            margin: Margin {
+                balance: Decimal::from(1000),
+                leverage: Decimal::from(1),
+                timestamp_ns: 150_000_000_000,
+            },
+        };
+        let events = vec![trade(10), trade(70), margin, trade(200)];
+        let clock = Arc::new(SimulatedClock::new(Utc.timestamp(0, 0)));
+        state.ws = Some(ReplayWs::new(clock.clone(), events));
+        assert!(matches!(replay(state).await, ExitCode::Success));
+        // No event falls between 120 and 180, candle is ticked by the message loop.
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("trade", 10),
+                ("candle", 60),
+                ("trade", 70),
+                ("candle", 120),
+                ("margin", 150),
+                ("candle", 180),
+                ("trade", 200),
+            ]
+        );
+        assert_eq!(clock.now(), Utc.timestamp(200, 0));
+        Ok(())
+    }
+}
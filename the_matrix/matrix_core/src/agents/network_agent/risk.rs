@@ -0,0 +1,818 @@
+//! Pre-trade risk checks between models and an exchange. Orders that would breach a limit are
+//! rejected before they are sent and every breach is reported to listeners.
+//!
+//! Exposure is measured by positions that orders would leave behind if they got filled, resting
+//! orders aren't counted. Orders that reduce exposure are always accepted unless the kill switch
+//! has been engaged or the order rate limit has been hit, reduce-only orders that close positions
+//! of a flattened market are accepted even then.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use config::RiskConfig;
+use merovingian::order::{self, Order, OrderId};
+use mouse::num::traits::Zero;
+use mouse::num::{Decimal, IntoDecimal};
+
+use super::portfolio::Portfolio;
+use crate::agents::network_agents::{Execution, InstrumentConfig};
+
+const MINUTE_NS: u64 = 60_000_000_000;
+const DAY_S: u64 = 60 * 60 * 24;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Limit {
+    AccountNotional,
+    AccountLeverage,
+    ModelNotional(u32),
+    ModelLeverage(u32),
+    PriceBand(String),
+    OrderRate,
+    DailyDrawdown,
+    RollingDrawdown,
+    Position(String),
+    /// Model has been paused, it can only reduce its positions.
+    Paused(u32),
+    /// Kill switch is engaged, nothing is traded anymore.
+    KillSwitch,
+    /// Notional of all exchanges in the portfolio.
+    PortfolioNotional,
+    /// Drawdown of total balance of the portfolio, engages kill switch.
+    PortfolioDrawdown,
+}
+
+/// Limit that has been hit. `order_id` is set when an order has been rejected because of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RiskBreach {
+    pub limit: Limit,
+    pub order_id: Option<OrderId>,
+    pub value: Decimal,
+    pub max: Decimal,
+}
+
+impl RiskBreach {
+    fn new(limit: Limit, order_id: Option<OrderId>, value: Decimal, max: Decimal) -> RiskBreach {
+        RiskBreach {
+            limit,
+            order_id,
+            value,
+            max,
+        }
+    }
+}
+
+struct Limits {
+    account_notional: Option<Decimal>,
+    account_leverage: Option<Decimal>,
+    model_notional: Option<Decimal>,
+    model_leverage: Option<Decimal>,
+    price_deviation: Option<Decimal>,
+    orders_per_minute: Option<u32>,
+    daily_drawdown: Option<Decimal>,
+    rolling_drawdown: Option<Decimal>,
+    position: HashMap<String, Decimal>,
+}
+
+impl From<&RiskConfig> for Limits {
+    fn from(config: &RiskConfig) -> Self {
+        let to_decimal = |x: Option<f32>| x.and_then(|x| x.to_decimal());
+        Limits {
+            account_notional: to_decimal(config.max_account_notional),
+            account_leverage: to_decimal(config.max_account_leverage),
+            model_notional: to_decimal(config.max_model_notional),
+            model_leverage: to_decimal(config.max_model_leverage),
+            price_deviation: to_decimal(config.max_price_deviation),
+            orders_per_minute: config.max_orders_per_minute,
+            daily_drawdown: to_decimal(config.max_daily_drawdown),
+            rolling_drawdown: to_decimal(config.max_rolling_drawdown),
+            position: config
+                .max_position
+                .iter()
+                .filter_map(|(market, max)| Some((market.clone(), max.to_decimal()?)))
+                .collect(),
+        }
+    }
+}
+
+pub struct RiskEngine {
+    limits: Limits,
+    /// `max_leverage` of exchange config, caps account leverage of `limits`.
+    max_leverage: Option<Decimal>,
+    /// `max_orders_per_m` of exchange config, caps order rate of `limits`.
+    max_orders_per_minute: Option<u32>,
+    kill_on_drawdown: bool,
+    rolling_drawdown_window_s: u64,
+    balance: Decimal,
+    /// Position of each model on each market.
+    positions: HashMap<(u32, String), Decimal>,
+    mark_prices: HashMap<String, Decimal>,
+    /// Timestamps of orders accepted within the last minute.
+    accepted_ns: VecDeque<u64>,
+    /// UTC day and balance at its start.
+    day_start: Option<(u64, Decimal)>,
+    balances: VecDeque<(u64, Decimal)>,
+    /// Drawdown limit that has been hit, only orders that reduce positions are accepted until
+    /// restart.
+    halted: Option<Limit>,
+    paused: HashSet<u32>,
+    killed: bool,
+    /// Name of this exchange in `portfolio`.
+    exchange: String,
+    portfolio: Option<&'static Portfolio>,
+}
+
+impl RiskEngine {
+    pub fn new(config: &RiskConfig, balance: Decimal) -> RiskEngine {
+        RiskEngine {
+            limits: config.into(),
+            max_leverage: None,
+            max_orders_per_minute: None,
+            kill_on_drawdown: config.kill_on_drawdown,
+            rolling_drawdown_window_s: config.rolling_drawdown_window_s as u64,
+            balance,
+            positions: HashMap::new(),
+            mark_prices: HashMap::new(),
+            accepted_ns: VecDeque::new(),
+            day_start: None,
+            balances: VecDeque::new(),
+            halted: None,
+            paused: HashSet::new(),
+            killed: false,
+            exchange: String::new(),
+            portfolio: None,
+        }
+    }
+
+    /// Checks portfolio limits too and shares kill switch with other exchanges of the portfolio.
+    pub fn with_portfolio(mut self, exchange: &str, portfolio: &'static Portfolio) -> RiskEngine {
+        self.exchange = exchange.to_string();
+        self.portfolio = Some(portfolio);
+        self
+    }
+
+    /// Positions of models that have been recovered after a restart.
+    pub fn with_positions(mut self, positions: HashMap<(u32, String), Decimal>) -> RiskEngine {
+        self.positions = positions;
+        self
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed || self.portfolio.map_or(false, |x| x.is_killed())
+    }
+
+    /// Kill switch has been engaged on another exchange of the portfolio but not yet on this one.
+    pub fn is_killed_by_portfolio(&self) -> bool {
+        !self.killed && self.portfolio.map_or(false, |x| x.is_killed())
+    }
+
+    /// Replaces limits of a running engine, positions and a halt that has already been triggered
+    /// are kept.
+    pub fn set_limits(&mut self, config: &RiskConfig) {
+        self.limits = config.into();
+        self.kill_on_drawdown = config.kill_on_drawdown;
+        self.rolling_drawdown_window_s = config.rolling_drawdown_window_s as u64;
+    }
+
+    /// Replaces limits of exchange config, the tighter of them and of risk config apply. Order
+    /// rate is counted per minute, a fractional rate is rounded down but allows at least one order.
+    pub fn set_exchange_limits(&mut self, max_leverage: Decimal, max_orders_per_m: f32) {
+        self.max_leverage = Some(max_leverage);
+        self.max_orders_per_minute = Some((max_orders_per_m as u32).max(1));
+    }
+
+    pub fn kill_on_drawdown(&self) -> bool {
+        self.kill_on_drawdown
+    }
+
+    /// Rejects all orders from now on.
+    pub fn engage_kill_switch(&mut self) -> RiskBreach {
+        self.killed = true;
+        if let Some(portfolio) = self.portfolio {
+            portfolio.engage_kill_switch();
+        }
+        RiskBreach::new(Limit::KillSwitch, None, Decimal::zero(), Decimal::zero())
+    }
+
+    /// Rejects orders of a model that would increase its positions until it is resumed.
+    pub fn pause(&mut self, model_id: u32) {
+        self.paused.insert(model_id);
+    }
+
+    /// Returns false if model wasn't paused.
+    pub fn resume(&mut self, model_id: u32) -> bool {
+        self.paused.remove(&model_id)
+    }
+
+    pub fn is_paused(&self, model_id: u32) -> bool {
+        self.paused.contains(&model_id)
+    }
+
+    /// Position of each model on each market.
+    pub fn positions(&self) -> &HashMap<(u32, String), Decimal> {
+        &self.positions
+    }
+
+    pub fn position(&self, model_id: u32, market: &str) -> Decimal {
+        self.positions
+            .get(&(model_id, market.to_string()))
+            .copied()
+            .unwrap_or_else(Decimal::zero)
+    }
+
+    pub fn mark_price(&self, market: &str) -> Option<Decimal> {
+        self.mark_prices.get(market).copied()
+    }
+
+    pub fn on_mark_price(&mut self, market: &str, price: Decimal) {
+        self.mark_prices.insert(market.to_string(), price);
+    }
+
+    /// Replaces position of `model_id`, e.g. when a position nobody owns is adopted.
+    pub fn set_position(&mut self, model_id: u32, market: &str, amount: Decimal) {
+        self.positions
+            .insert((model_id, market.to_string()), amount);
+    }
+
+    /// Must be called with executions of orders placed by models, not the bundled ones.
+    pub fn on_execution(&mut self, execution: &Execution) {
+        *self
+            .positions
+            .entry((execution.order_id.model_id(), execution.market.clone()))
+            .or_insert_with(Decimal::zero) += execution.amount;
+    }
+
+    /// Updates drawdowns of the portfolio and of the account, returns breaches of drawdown limits
+    /// hit for the first time.
+    pub fn on_balance(&mut self, balance: Decimal, timestamp_ns: u64) -> Vec<RiskBreach> {
+        let portfolio_breach = self
+            .portfolio
+            .and_then(|x| x.report_balance(&self.exchange, balance))
+            .map(|(drawdown, max)| RiskBreach::new(Limit::PortfolioDrawdown, None, drawdown, max));
+        let account_breach = self.on_account_balance(balance, timestamp_ns);
+        portfolio_breach.into_iter().chain(account_breach).collect()
+    }
+
+    fn on_account_balance(&mut self, balance: Decimal, timestamp_ns: u64) -> Option<RiskBreach> {
+        self.balance = balance;
+        let timestamp_s = timestamp_ns / 1_000_000_000;
+        let day = timestamp_s / DAY_S;
+        match self.day_start {
+            Some((start_day, _)) if start_day == day => {}
+            _ => self.day_start = Some((day, balance)),
+        }
+        self.balances.push_back((timestamp_s, balance));
+        while let Some((first_s, _)) = self.balances.front() {
+            if first_s + self.rolling_drawdown_window_s >= timestamp_s {
+                break;
+            }
+            self.balances.pop_front();
+        }
+        if self.halted.is_some() {
+            return None;
+        }
+        let peak = self
+            .balances
+            .iter()
+            .map(|(_, balance)| *balance)
+            .max()
+            .unwrap_or(balance);
+        let (_, day_start) = self.day_start.unwrap();
+        let breach = [
+            (Limit::DailyDrawdown, day_start, self.limits.daily_drawdown),
+            (Limit::RollingDrawdown, peak, self.limits.rolling_drawdown),
+        ]
+        .iter()
+        .find_map(|(limit, reference, max)| {
+            let max = (*max)?;
+            if !reference.is_sign_positive() || reference.is_zero() {
+                return None;
+            }
+            let drawdown = (*reference - balance) / *reference;
+            (drawdown > max).then(|| RiskBreach::new(limit.clone(), None, drawdown, max))
+        })?;
+        self.halted = Some(breach.limit.clone());
+        Some(breach)
+    }
+
+    /// Splits orders into accepted ones and rejected ones paired with their breaches. Canceled
+    /// orders are always accepted, so are `reduce_only` orders that don't open or flip a position
+    /// of their model.
+    pub fn check(
+        &mut self,
+        orders: Vec<Order>,
+        reduce_only: &HashSet<OrderId>,
+        instruments: &HashMap<String, InstrumentConfig>,
+        timestamp_ns: u64,
+    ) -> (Vec<Order>, Vec<(Order, RiskBreach)>) {
+        while let Some(first_ns) = self.accepted_ns.front() {
+            if first_ns + MINUTE_NS > timestamp_ns {
+                break;
+            }
+            self.accepted_ns.pop_front();
+        }
+        let mut projected = self.positions.clone();
+        let mut accepted = Vec::with_capacity(orders.len());
+        let mut rejected = Vec::new();
+        for order in orders {
+            if order.is_canceled() {
+                accepted.push(order);
+                continue;
+            }
+            let reduces = reduce_only.contains(&order.id);
+            match self.check_order(&order, reduces, &projected, instruments) {
+                Ok(()) => {
+                    *projected
+                        .entry((order.id.model_id(), order.market.clone()))
+                        .or_insert_with(Decimal::zero) += order.amount;
+                    self.accepted_ns.push_back(timestamp_ns);
+                    accepted.push(order);
+                }
+                Err(breach) => rejected.push((order, breach)),
+            }
+        }
+        if let Some(portfolio) = self.portfolio {
+            portfolio.report_notional(&self.exchange, self.notional(&projected, instruments));
+        }
+        (accepted, rejected)
+    }
+
+    /// Reports notional of current positions to portfolio.
+    pub fn report_notional(&self, instruments: &HashMap<String, InstrumentConfig>) {
+        if let Some(portfolio) = self.portfolio {
+            portfolio.report_notional(&self.exchange, self.notional(&self.positions, instruments));
+        }
+    }
+
+    /// Notional of `positions` at mark prices, markets without a mark price are left out.
+    fn notional(
+        &self,
+        positions: &HashMap<(u32, String), Decimal>,
+        instruments: &HashMap<String, InstrumentConfig>,
+    ) -> Decimal {
+        let mut net = HashMap::new();
+        for ((_, market), amount) in positions {
+            *net.entry(market).or_insert_with(Decimal::zero) += *amount;
+        }
+        net.into_iter()
+            .filter_map(|(market, amount)| {
+                let is_inverse = instruments.get(market)?.is_inverse;
+                let price = self.mark_prices.get(market)?;
+                Some(order::value(*price, amount, is_inverse).abs())
+            })
+            .sum()
+    }
+
+    fn check_order(
+        &self,
+        order: &Order,
+        reduce_only: bool,
+        projected: &HashMap<(u32, String), Decimal>,
+        instruments: &HashMap<String, InstrumentConfig>,
+    ) -> Result<(), RiskBreach> {
+        let id = Some(order.id);
+        let model_id = order.id.model_id();
+        let breach = |limit, value, max| Err(RiskBreach::new(limit, id, value, max));
+        let position = |model: Option<u32>, market: &str| -> Decimal {
+            projected
+                .iter()
+                .filter(|((m, x), _)| model.map_or(true, |model| model == *m) && x == market)
+                .map(|(_, amount)| *amount)
+                .sum()
+        };
+        if reduce_only {
+            let before = position(Some(model_id), &order.market);
+            // Closing positions must work after the kill switch has stopped trading.
+            if order.amount.abs() <= before.abs()
+                && order.amount.is_sign_negative() != before.is_sign_negative()
+            {
+                return Ok(());
+            }
+        }
+        if self.is_killed() {
+            return breach(Limit::KillSwitch, Decimal::zero(), Decimal::zero());
+        }
+        if self.paused.contains(&model_id) {
+            let before = position(Some(model_id), &order.market);
+            let after = before + order.amount;
+            if after.abs() > before.abs() {
+                return breach(Limit::Paused(model_id), before, after);
+            }
+        }
+        // Exchange counts every order towards its rate limit, reducing ones too.
+        if let Some(max) = tighter(self.limits.orders_per_minute, self.max_orders_per_minute) {
+            if self.accepted_ns.len() as u32 >= max {
+                return breach(
+                    Limit::OrderRate,
+                    Decimal::from(self.accepted_ns.len() as u32),
+                    Decimal::from(max),
+                );
+            }
+        }
+        let account_before = position(None, &order.market);
+        let account_after = account_before + order.amount;
+        if account_after.abs() <= account_before.abs() {
+            // Reducing exposure is always allowed.
+            return Ok(());
+        }
+        if let Some(limit) = &self.halted {
+            return breach(limit.clone(), account_before, account_after);
+        }
+        let mark_price = self.mark_prices.get(&order.market).copied();
+        if let (Some(max), Some(limit_price), Some(mark_price)) =
+            (self.limits.price_deviation, order.limit, mark_price)
+        {
+            let deviation = (limit_price - mark_price).abs() / mark_price;
+            if deviation > max {
+                return breach(Limit::PriceBand(order.market.clone()), deviation, max);
+            }
+        }
+        if let Some(max) = self.limits.position.get(&order.market) {
+            if account_after.abs() > *max {
+                return breach(
+                    Limit::Position(order.market.clone()),
+                    account_after.abs(),
+                    *max,
+                );
+            }
+        }
+
+        let price = |market: &str| -> Option<Decimal> {
+            if market != order.market {
+                return self.mark_prices.get(market).copied();
+            }
+            mark_price
+                .or(order.limit)
+                .or(order.trigger_price)
+                .or_else(|| order.predicted_price.to_decimal())
+                .filter(|x| x.is_sign_positive() && !x.is_zero())
+        };
+        let notional = |model: Option<u32>| -> Decimal {
+            let mut markets: Vec<_> = projected.keys().map(|(_, market)| market).collect();
+            markets.push(&order.market);
+            markets.sort();
+            markets.dedup();
+            markets
+                .into_iter()
+                .filter_map(|market| {
+                    let is_inverse = instruments.get(market)?.is_inverse;
+                    let mut amount = position(model, market);
+                    if market == &order.market && model.map_or(true, |x| x == model_id) {
+                        amount += order.amount;
+                    }
+                    Some(order::value(price(market)?, amount, is_inverse).abs())
+                })
+                .sum()
+        };
+        let mut checks = Vec::with_capacity(2);
+        if self.limits.model_notional.is_some() || self.limits.model_leverage.is_some() {
+            checks.push((
+                notional(Some(model_id)),
+                Limit::ModelNotional(model_id),
+                self.limits.model_notional,
+                Limit::ModelLeverage(model_id),
+                self.limits.model_leverage,
+            ));
+        }
+        let account_leverage = tighter(self.limits.account_leverage, self.max_leverage);
+        if self.limits.account_notional.is_some() || account_leverage.is_some() {
+            checks.push((
+                notional(None),
+                Limit::AccountNotional,
+                self.limits.account_notional,
+                Limit::AccountLeverage,
+                account_leverage,
+            ));
+        }
+        for (notional, notional_limit, max_notional, leverage_limit, max_leverage) in checks {
+            if let Some(max) = max_notional {
+                if notional > max {
+                    return breach(notional_limit, notional, max);
+                }
+            }
+            if let Some(max) = max_leverage {
+                if self.balance.is_sign_positive() && !self.balance.is_zero() {
+                    let leverage = notional / self.balance;
+                    if leverage > max {
+                        return breach(leverage_limit, leverage, max);
+                    }
+                }
+            }
+        }
+        if let Some((portfolio, max)) = self.portfolio.and_then(|x| Some((x, x.max_notional()?))) {
+            let notional = notional(None) + portfolio.notional_of_others(&self.exchange);
+            if notional > max {
+                return breach(Limit::PortfolioNotional, notional, max);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The lower of two optional limits.
+fn tighter<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod t_risk {
+    use config::PortfolioRiskConfig;
+    use merovingian::order::IdGenerator;
+
+    use super::*;
+
+    const MARKET: &str = "XBTUSD";
+
+    fn new_engine(config: RiskConfig) -> RiskEngine {
+        let mut engine = RiskEngine::new(&config, Decimal::from(1000));
+        engine.on_mark_price(MARKET, Decimal::from(100));
+        engine
+    }
+
+    fn instruments() -> HashMap<String, InstrumentConfig> {
+        let mut instruments = HashMap::new();
+        instruments.insert(MARKET.to_string(), InstrumentConfig::default());
+        instruments
+    }
+
+    fn order(model_id: u32, amount: i32, limit: Option<i32>) -> Order {
+        Order {
+            amount: Decimal::from(amount),
+            trigger_price: None,
+            limit: limit.map(Decimal::from),
+            executed_price: None,
+            market: MARKET.to_string(),
+            id: IdGenerator::new_order_id(model_id),
+            predicted_price: 100.,
+            value: None,
+            timestamp_ns: 0,
+            post_only: false,
+            display_amount: None,
+        }
+    }
+
+    fn fill(order: &Order) -> Execution {
+        Execution {
+            market: order.market.clone(),
+            order_id: order.id,
+            value: Decimal::zero(),
+            amount: order.amount,
+            amount_left: Decimal::zero(),
+            fee_paid: Decimal::zero(),
+            executed_price: Decimal::from(100),
+            timestamp_ns: 0,
+        }
+    }
+
+    fn check(engine: &mut RiskEngine, orders: Vec<Order>) -> (usize, Vec<Limit>) {
+        let (accepted, rejected) = engine.check(orders, &HashSet::new(), &instruments(), 0);
+        (
+            accepted.len(),
+            rejected.into_iter().map(|(_, x)| x.limit).collect(),
+        )
+    }
+
+    fn limits(breaches: Vec<RiskBreach>) -> Vec<Limit> {
+        breaches.into_iter().map(|x| x.limit).collect()
+    }
+
+    #[test]
+    fn t_notional_and_leverage() {
+        let mut engine = new_engine(RiskConfig {
+            max_model_notional: Some(500.),
+            max_account_leverage: Some(0.8),
+            ..Default::default()
+        });
+        // Model 0 would hold 600 worth of contracts.
+        assert_eq!(
+            check(&mut engine, vec![order(0, 6, None)]),
+            (0, vec![Limit::ModelNotional(0)])
+        );
+        // Orders in the same batch count towards each other.
+        assert_eq!(
+            check(
+                &mut engine,
+                vec![order(0, 4, None), order(1, 4, None), order(2, 1, None)]
+            ),
+            (2, vec![Limit::AccountLeverage])
+        );
+        let existing = order(0, 5, None);
+        engine.on_execution(&fill(&existing));
+        assert_eq!(
+            check(&mut engine, vec![order(0, 1, None)]),
+            (0, vec![Limit::ModelNotional(0)])
+        );
+        // Reducing is fine even if limits are breached.
+        engine.on_mark_price(MARKET, Decimal::from(1000));
+        assert_eq!(check(&mut engine, vec![order(0, -1, None)]), (1, vec![]));
+    }
+
+    #[test]
+    fn t_price_band_position_and_rate() {
+        let mut max_position = HashMap::new();
+        max_position.insert(MARKET.to_string(), 10.);
+        let mut engine = new_engine(RiskConfig {
+            max_price_deviation: Some(0.05),
+            max_orders_per_minute: Some(3),
+            max_position,
+            ..Default::default()
+        });
+        assert_eq!(
+            check(
+                &mut engine,
+                vec![
+                    order(0, 1, Some(94)),
+                    order(0, 1, Some(96)),
+                    order(1, 10, None),
+                    order(1, -2, None),
+                ]
+            ),
+            (
+                2,
+                vec![
+                    Limit::PriceBand(MARKET.to_string()),
+                    Limit::Position(MARKET.to_string())
+                ]
+            )
+        );
+        // Reducing orders count towards the rate and are limited by it too.
+        assert_eq!(
+            check(&mut engine, vec![order(0, 1, None), order(1, -1, None)]),
+            (1, vec![Limit::OrderRate])
+        );
+        let (accepted, _) = engine.check(
+            vec![order(0, 1, None)],
+            &HashSet::new(),
+            &instruments(),
+            MINUTE_NS,
+        );
+        assert_eq!(accepted.len(), 1);
+    }
+
+    #[test]
+    fn t_drawdown_halts_trading() {
+        let mut engine = new_engine(RiskConfig {
+            max_daily_drawdown: Some(0.1),
+            max_rolling_drawdown: Some(0.2),
+            rolling_drawdown_window_s: 2 * DAY_S as u32,
+            ..Default::default()
+        });
+        let day_ns = DAY_S * 1_000_000_000;
+        assert_eq!(limits(engine.on_balance(Decimal::from(1000), 0)), vec![]);
+        assert_eq!(limits(engine.on_balance(Decimal::from(950), 1)), vec![]);
+        // New day starts at 900, rolling drawdown is still measured from 1000.
+        assert_eq!(
+            limits(engine.on_balance(Decimal::from(900), day_ns)),
+            vec![]
+        );
+        assert_eq!(
+            limits(engine.on_balance(Decimal::from(790), day_ns + 1)),
+            vec![Limit::DailyDrawdown]
+        );
+        // Reported only once.
+        assert_eq!(
+            limits(engine.on_balance(Decimal::from(700), day_ns + 2)),
+            vec![]
+        );
+
+        let existing = order(0, 2, None);
+        engine.on_execution(&fill(&existing));
+        assert_eq!(
+            check(&mut engine, vec![order(0, 1, None), order(0, -1, None)]),
+            (1, vec![Limit::DailyDrawdown])
+        );
+
+        let mut engine = new_engine(RiskConfig {
+            max_rolling_drawdown: Some(0.2),
+            rolling_drawdown_window_s: 2 * DAY_S as u32,
+            ..Default::default()
+        });
+        engine.on_balance(Decimal::from(1000), 0);
+        assert_eq!(
+            limits(engine.on_balance(Decimal::from(810), day_ns)),
+            vec![]
+        );
+        assert_eq!(
+            limits(engine.on_balance(Decimal::from(790), 2 * day_ns)),
+            vec![Limit::RollingDrawdown]
+        );
+    }
+
+    #[test]
+    fn t_kill_switch() {
+        let mut engine = new_engine(RiskConfig::default());
+        let existing = order(0, 2, None);
+        engine.on_execution(&fill(&existing));
+        let mut cancel = order(0, 0, None);
+        cancel.cancel();
+        assert_eq!(engine.engage_kill_switch().limit, Limit::KillSwitch);
+        assert!(engine.is_killed());
+        assert_eq!(
+            check(&mut engine, vec![order(0, -1, None), cancel]),
+            (1, vec![Limit::KillSwitch])
+        );
+    }
+
+    #[test]
+    fn t_exchange_limits() {
+        let mut engine = new_engine(RiskConfig {
+            max_account_leverage: Some(0.8),
+            max_orders_per_minute: Some(10),
+            ..Default::default()
+        });
+        engine.set_exchange_limits(Decimal::from(2), 2.5);
+        // Risk config is tighter for leverage, exchange config for order rate.
+        assert_eq!(
+            check(&mut engine, vec![order(0, 9, None)]),
+            (0, vec![Limit::AccountLeverage])
+        );
+        assert_eq!(
+            check(
+                &mut engine,
+                vec![order(0, 1, None), order(0, 1, None), order(0, 1, None)]
+            ),
+            (2, vec![Limit::OrderRate])
+        );
+    }
+
+    #[test]
+    fn t_reduce_only() {
+        let mut engine = new_engine(RiskConfig {
+            max_orders_per_minute: Some(1),
+            ..Default::default()
+        });
+        let existing = order(0, 2, None);
+        engine.on_execution(&fill(&existing));
+        assert_eq!(check(&mut engine, vec![order(1, 1, None)]), (1, vec![]));
+        engine.engage_kill_switch();
+        let (close, flip) = (order(0, -2, None), order(0, -3, None));
+        let reduce_only: HashSet<_> = vec![close.id, flip.id].into_iter().collect();
+        let (accepted, rejected) =
+            engine.check(vec![close.clone(), flip], &reduce_only, &instruments(), 0);
+        // Rate limit has been hit and kill switch is engaged, only the closing order goes through.
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].id, close.id);
+        assert_eq!(rejected[0].1.limit, Limit::KillSwitch);
+    }
+
+    #[test]
+    fn t_portfolio() {
+        let portfolio: &'static Portfolio =
+            Box::leak(Box::new(Portfolio::new(&PortfolioRiskConfig {
+                max_notional: Some(1000.),
+                max_drawdown: Some(0.1),
+            })));
+        let mut a = new_engine(RiskConfig::default()).with_portfolio("a", portfolio);
+        let mut b = new_engine(RiskConfig {
+            max_daily_drawdown: Some(0.2),
+            ..Default::default()
+        })
+        .with_portfolio("b", portfolio);
+        assert_eq!(check(&mut a, vec![order(0, 6, None)]), (1, vec![]));
+        // Accepted orders of `a` count towards notional of `b`.
+        assert_eq!(
+            check(&mut b, vec![order(0, 5, None)]),
+            (0, vec![Limit::PortfolioNotional])
+        );
+        assert_eq!(check(&mut b, vec![order(0, 4, None)]), (1, vec![]));
+
+        assert_eq!(limits(a.on_balance(Decimal::from(1000), 0)), vec![]);
+        assert_eq!(limits(b.on_balance(Decimal::from(1000), 0)), vec![]);
+        // Total balance is 1900, a loss of 5%.
+        assert_eq!(limits(a.on_balance(Decimal::from(900), 1)), vec![]);
+        // Account of `b` is tracked even when the portfolio breaches.
+        assert_eq!(
+            limits(b.on_balance(Decimal::from(700), 2)),
+            vec![Limit::PortfolioDrawdown, Limit::DailyDrawdown]
+        );
+        assert!(!a.is_killed());
+        b.engage_kill_switch();
+        assert!(a.is_killed());
+        assert!(a.is_killed_by_portfolio());
+        assert!(!b.is_killed_by_portfolio());
+        assert_eq!(
+            check(&mut a, vec![order(0, -1, None)]),
+            (0, vec![Limit::KillSwitch])
+        );
+    }
+
+    #[test]
+    fn t_pause() {
+        let mut engine = new_engine(RiskConfig::default());
+        let existing = order(0, 2, None);
+        engine.on_execution(&fill(&existing));
+        engine.pause(0);
+        assert!(engine.is_paused(0));
+        // Paused model can still exit, other models aren't affected.
+        assert_eq!(
+            check(
+                &mut engine,
+                vec![order(0, 1, None), order(0, -3, None), order(1, 1, None)]
+            ),
+            (2, vec![Limit::Paused(0)])
+        );
+        assert!(engine.resume(0));
+        assert!(!engine.resume(0));
+        assert_eq!(check(&mut engine, vec![order(0, 1, None)]), (1, vec![]));
+    }
+}
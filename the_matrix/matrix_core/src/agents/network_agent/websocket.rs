@@ -1,22 +1,27 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{Duration, TimeZone, Utc};
 use futures::FutureExt;
 use merovingian::non_minable_models::ExitCode;
 use mouse::error::Result;
 use mouse::log::*;
 use mouse::time::{IntoDateTime, Timestamp};
-use nebuchadnezzar_core::Exchange;
+use nebuchadnezzar_core::metrics;
 use thiserror::Error;
 use tokio::select;
 use tokio::sync::Mutex;
+use tracing::{info_span, Instrument};
 
-use crate::agents::network_agent::exchange_state::load_last_execution_time;
-use crate::agents::network_agent::NetworkClient;
+use super::metrics::{exchange_label, HANDLE_MESSAGE_DURATION};
+use crate::agents::network_agent::{alert_kill_failed, NetworkClient};
 use crate::agents::network_agents::NetworkAgent;
 use crate::error::MatrixError;
 
+/// How often `NetworkAgent::poll` is called while websocket is quiet.
+const POLL_INTERVAL_MS: u64 = 100;
+
 #[async_trait]
 pub trait Ws: Unpin + Send {
     type Message: Send;
@@ -34,20 +39,37 @@ where
     let mut ws = process_backlog(&agent).await?;
     info!("Message loop started.");
     let mut ups = 0.;
-    let min_timeframe = agent.lock().await.state_mut().min_timeframe() as i64 * 1_000_000_000;
-    let now = Utc::now().timestamp_nanos();
+    let (min_timeframe, reconciliation_interval, clock) = {
+        let mut agent = agent.lock().await;
+        let state = agent.state_mut();
+        (
+            state.min_timeframe() as i64 * 1_000_000_000,
+            state.reconciliation_interval_s() as i64 * 1_000_000_000,
+            state.clock().clone(),
+        )
+    };
+    // Candles close by exchange time.
+    let now = clock.now().timestamp_nanos();
     let mut ups_time = now - now % 60_000_000_000 + 60_000_000_000;
     let mut tick_time = now - now % min_timeframe + min_timeframe;
+    let mut reconciliation_time = match reconciliation_interval {
+        0 => i64::MAX,
+        interval => now + interval,
+    };
     let mut result: Result<()>;
     loop {
         result = try {
             ups += 1.;
-            let now = Utc::now().timestamp_nanos();
+            let now = clock.now().timestamp_nanos();
             if now >= ups_time {
                 ups_time = now - now % 60_000_000_000 + 60_000_000_000;
                 trace!("UPS: {}", ups / 60.);
                 ups = 0.;
             }
+            if now >= reconciliation_time {
+                reconciliation_time = now + reconciliation_interval;
+                agent.lock().await.state_mut().reconcile().await?;
+            }
             if now >= tick_time {
                 // These 3 ticks in loop are here to ensure that candles_builder will be updated
                 // every min_timeframe so that it could add new candle and call models.
@@ -60,24 +82,46 @@ where
             }
             let _agent2 = agent.clone();
             tick_time = now - now % min_timeframe + min_timeframe;
-            let sleep_time = tick_time - now;
-            // Using this timer instead of tokio one because of timer resolution.
-            // Tokio timer fires after 1-2 ms where this one after 0.2 ms.
-            let mut interval = async_timer::Interval::platform_new(
-                core::time::Duration::from_nanos(sleep_time as u64),
-            );
-            let sleep_fut = interval.as_mut().fuse();
+            let sleep_fut = clock.sleep_until(Utc.timestamp_nanos(tick_time)).fuse();
             let ws_fut = Ws::next(&mut ws).fuse();
+            // Real time, polled work doesn't follow simulated clock.
+            let poll_fut =
+                tokio::time::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS)).fuse();
 
-            // Runs 2 futures, when one completes the other is killed.
-            // When future completes a callback is called.
+            // Runs 3 futures, when one completes the others are killed.
+            // When future completes a callback is called. Candles are ticked first when both
+            // complete, replayed messages advance simulated time before they are received.
             select! {
+                biased;
+                _ = sleep_fut => {
+                    let mut agent = agent.lock().await;
+                    agent.state_mut().handle_admin_requests().await?;
+                    agent.state_mut().poll_config();
+                    agent.state_mut().follow_portfolio_kill_switch().await?;
+                    agent.state_mut().poll_execution_algos().await?;
+                    agent
+                        .state_mut()
+                        .tick_candles_on_all_markets(clock.now().timestamp_s() + 1).await?;
+                    // Prevent being called again at the start of next loop.
+                    tick_time = i64::MAX;
+                },
                 msg = ws_fut => {
                     let mut agent = agent.lock().await;
                     #[cfg(not(feature = "test"))]
                     agent.state_mut().check_for_zion_message().await?;
-                    agent.handle_message(msg).await?;
-                    let now = Utc::now().timestamp_nanos();
+                    agent.state_mut().handle_admin_requests().await?;
+                    agent.state_mut().poll_config();
+                    agent.state_mut().follow_portfolio_kill_switch().await?;
+                    let start = Instant::now();
+                    agent.handle_message(msg).instrument(info_span!("handle_message")).await?;
+                    metrics::observe(
+                        &HANDLE_MESSAGE_DURATION,
+                        &[("exchange", &exchange_label())],
+                        start.elapsed().as_secs_f64(),
+                    );
+                    agent.poll().await?;
+                    agent.state_mut().poll_execution_algos().await?;
+                    let now = clock.now().timestamp_nanos();
                     if now >= tick_time {
                         agent
                             .state_mut()
@@ -86,14 +130,8 @@ where
                         tick_time = i64::MAX;
                     }
                 },
-                _ = sleep_fut => {
-                    agent
-                        .lock()
-                        .await
-                        .state_mut()
-                        .tick_candles_on_all_markets(Utc::now().timestamp_s() + 1).await?;
-                    // Prevent being called again at the start of next loop.
-                    tick_time = i64::MAX;
+                _ = poll_fut => {
+                    agent.lock().await.poll().await?;
                 },
             };
         };
@@ -116,8 +154,6 @@ where
                     // TODO: match on err and return success if err is maintenance
                     error!("{:?}", e);
                     trace!("Killing network agent!");
-                    // TODO: if killing wasn't successful send email, sound alarm, display
-                    //  notification
                     let mut agent = agent.lock().await;
                     let state = agent.state_mut();
                     match state.kill().await {
@@ -130,6 +166,7 @@ where
                         }
                         Err(e) => {
                             error!("FATAL: Killing agent FAILED. {:?}.", e);
+                            alert_kill_failed(&e, &**state.clock()).await;
                             if let Err(e) = state.on_shutdown().await {
                                 error!("Shutdown failed: {:#?}", e);
                             }
@@ -165,37 +202,35 @@ async fn process_backlog<T>(agent: &Arc<Mutex<T>>) -> Result<<T as NetworkAgent>
 where
     T: NetworkAgent,
 {
-    let mut ws = {
+    let (mut ws, clock) = {
         let mut agent = agent.lock().await;
         info!("Catching up...");
-        let mut last_execution_time =
-            load_last_execution_time(agent.state_mut().client().exchange().name()).await?;
-        if last_execution_time != u64::MAX {
-            // To not fetch already executed orders
-            last_execution_time += 1_000_000_000;
-        }
-        let executions = agent.catch_up(last_execution_time.into_date_time()).await?;
+        // Open orders have already been restored from journal.
+        let catch_up_start_ns = agent.state_mut().catch_up_start_ns();
+        let executions = agent.catch_up(catch_up_start_ns.into_date_time()).await?;
         agent.state_mut().catch_up(executions).await?;
 
         info!("Processing websocket backlog...");
-        agent.state_mut().ws.take().unwrap()
+        let state = agent.state_mut();
+        (state.ws.take().unwrap(), state.clock().clone())
     };
     loop {
-        // Using this timer instead of tokio one because of timer resolution.
-        // Tokio timer fires after 1-2 ms where this one after 0.2 ms.
-        let sleep_fut = tokio::time::sleep(tokio::time::Duration::from_millis(1)).fuse();
+        let sleep_fut = clock
+            .sleep_until(clock.now() + Duration::milliseconds(1))
+            .fuse();
         let ws_fut = Ws::next(&mut ws).fuse();
 
         // Runs 2 futures, when one completes the other is killed.
         // When future completes a callback is called.
         // If there is backlog in sink the 'next' method of a stream should resolve immediately
         let result: Result<()> = select! {
-            msg = ws_fut => {
-                agent.lock().await.handle_message(msg).await
-            },
+            biased;
             _ = sleep_fut => {
                 Err(MatrixError::Shutdown.into())
             },
+            msg = ws_fut => {
+                agent.lock().await.handle_message(msg).await
+            },
         };
         if let Err(e) = result {
             match e.downcast::<MatrixError>() {
@@ -4,9 +4,7 @@ mod bitmex_agent;
 pub mod mock_network_agent;
 
 use std::collections::HashMap;
-use std::ops::Add;
 use std::sync::Arc;
-use std::time::Instant;
 
 use async_trait::async_trait;
 pub use bitmex_agent::*;
@@ -16,15 +14,17 @@ use iaas::mysql::models::{ExchangeConfig, ModelConfig};
 use merovingian::candles::*;
 use merovingian::minable_models::*;
 use merovingian::order::{Order, OrderId};
+use merovingian::order_book::OrderBook;
 use mouse::error::Result;
 use mouse::log::*;
-use mouse::num::traits::Zero;
 use mouse::num::Decimal;
+use nebuchadnezzar_core::clock::ServerClock;
+use nebuchadnezzar_core::definitions::Market;
 use num_traits::ToPrimitive;
 use serde_json;
 use tokio::sync::Mutex;
 
-use super::network_agent::{NetworkAgentState, Ws};
+use super::network_agent::{NetworkAgentState, RiskBreach, Ws};
 use crate::agents::network_agent::NetworkClient;
 
 extern crate multiqueue;
@@ -78,6 +78,10 @@ pub trait ExchangeListener: Downcast + Send + Sync + 'static {
     ) -> Result<()> {
         Ok(())
     }
+    /// Gets called after a batch of deltas has been applied to a synchronized order book.
+    async fn on_order_book_changed(&mut self, _order_book: &OrderBook) -> Result<()> {
+        Ok(())
+    }
 
     // Private
     // ---------------------------------------------------------------------------------------------
@@ -105,6 +109,27 @@ pub trait ExchangeListener: Downcast + Send + Sync + 'static {
     async fn on_orders_placed(&mut self, _orders: &Vec<Order>) -> Result<()> {
         Ok(())
     }
+    /// Gets called when a risk limit is hit, rejected orders are never sent to exchange.
+    async fn on_risk_breach(&mut self, _breach: &RiskBreach) -> Result<()> {
+        Ok(())
+    }
+    /// Gets called when variable values of a model are changed at runtime, new values are used
+    /// from the next candle on.
+    async fn on_variable_values_changed(
+        &mut self,
+        _model_id: &u32,
+        _values: &Vec<f32>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Gets called when `max_leverage` or `max_orders_per_m` of the exchange is changed at runtime.
+    async fn on_exchange_limits_changed(
+        &mut self,
+        _max_leverage: &Decimal,
+        _max_orders_per_m: &f32,
+    ) -> Result<()> {
+        Ok(())
+    }
     /// Gets called each minute, last candle may be incomplete or already contains new candle.
     async fn on_new_candle<'a>(
         &'a mut self,
@@ -158,17 +183,18 @@ pub trait NetworkAgent: Send {
         &mut self,
         msg: <<Self as NetworkAgent>::Websocket as Ws>::Message,
     ) -> Result<()>;
+    /// Called after every websocket message and periodically while websocket is quiet, for work
+    /// that completes outside of the websocket, e.g. REST requests in flight.
+    async fn poll(&mut self) -> Result<()> {
+        Ok(())
+    }
     fn state_mut(&mut self) -> &mut NetworkAgentState<Self::Client, Self::Websocket>;
 }
 
-pub fn get_and_notify_time_difference(
-    msg_timestamp: DateTime<Utc>,
-    sent_timestamp: Instant,
-    exchange_id: &str,
-) -> Duration {
-    let time_offset = (chrono::offset::Utc::now() - msg_timestamp).add(Duration::nanoseconds(
-        sent_timestamp.elapsed().as_nanos() as i64,
-    ));
+/// Logs how far our clock is from exchange server clock. Positive duration means that our clock is
+/// ahead.
+pub fn get_and_notify_time_difference(clock: &ServerClock, exchange_id: &str) -> Duration {
+    let time_offset = -clock.offset();
     if time_offset.num_milliseconds() > 1000 {
         warn!(
             "{}: Our clock is {}ms ahead.",
@@ -194,11 +220,34 @@ pub struct InstrumentConfig {
     pub lot_size: Decimal,
     pub multiplier: f32,
     pub is_inverse: bool,
+    /// Minimal order value in quote currency.
+    pub min_notional: Decimal,
     pub taker_fee: Decimal,
     pub maker_fee: Decimal,
+    /// 0 if market doesn't have funding.
     pub funding_period: u32,
 }
 
+impl From<&Market> for InstrumentConfig {
+    fn from(market: &Market) -> Self {
+        InstrumentConfig {
+            base_currency: market.base.clone(),
+            quote_currency: market.quote.clone(),
+            tick_size: market.filters.tick_size,
+            lot_size: market.filters.lot_size,
+            multiplier: 1.,
+            is_inverse: market.kind.is_inverse(),
+            min_notional: market.filters.min_notional,
+            taker_fee: market.taker_fee,
+            maker_fee: market.maker_fee,
+            funding_period: match market.kind.has_funding() {
+                true => 60 * 60 * 8,
+                false => 0,
+            },
+        }
+    }
+}
+
 fn option_to_f32<T: ToPrimitive>(option: &Option<T>) -> f32 {
     match option {
         None => f32::NAN,
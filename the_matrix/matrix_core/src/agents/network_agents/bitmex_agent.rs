@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::ops::Sub;
 use std::option::NoneError;
+use std::sync::Arc;
 use std::time::Instant;
 
 use async_std::task;
@@ -15,40 +16,87 @@ use bitmex::requests::*;
 use bitmex::websocket::{Action, BitmexWebSocket, Command, Message, TableMessage, Topic};
 use bitmex::*;
 use chrono::{DateTime, Duration, Timelike, Utc};
+use config::{get_exchange_config, VcrConfig};
 use futures::future;
 use futures::future::{FutureExt, *};
 use futures::stream::StreamExt;
-use lazy_static::lazy_static;
 use merovingian::candles::Candles;
 use merovingian::minable_models::{Margin, *};
 use merovingian::order::Order;
+use merovingian::order_book::{BookAction, BookSide, L2Level, OrderBook, PriceDecoder};
+use mouse::error::anyhow;
 use mouse::ext::AsPinned;
 use mouse::log::*;
-use mouse::num::{dec, FromMaybeDecimal};
+use mouse::num::FromMaybeDecimal;
 use mouse::throw;
 use mouse::time::{IntoDateTime, Timestamp};
 use nebuchadnezzar_core::client::Client;
-use nebuchadnezzar_core::error::NebError;
+use nebuchadnezzar_core::clock::ServerClock;
+use nebuchadnezzar_core::error::{AnyResult, NebError};
 use nebuchadnezzar_core::paginators::{BasicPaginator, BasicPaginatorState};
+use nebuchadnezzar_core::vcr::Vcr;
 use nebuchadnezzar_core::websocket::{tokio_tungstenite, WebSocket};
 use nebuchadnezzar_core::{Credentials, Exchange};
 use serde_json::{from_value, Value};
-use sorted_vec::ReverseSortedVec;
 use stream_flatten_iters::TryStreamExt as _;
+use tokio::task::JoinHandle;
 use tokio::try_join;
 use tungstenite::error::ProtocolError;
 
-use crate::agents::network_agent::NetworkClient;
+use crate::agents::network_agent::{
+    record_ws_latency, record_ws_message, NetworkClient, OrderSnapshot, PaperClient,
+};
 use crate::agents::network_agents::{Execution, *};
 use crate::error::MatrixError;
 
-pub struct BitmexAgent {
-    state: NetworkAgentState<BitmexNetworkClient, BitmexWs>,
+/// Trades with live BitMEX client or simulates orders with `PaperBitmexAgent`.
+pub struct BitmexAgent<C: AsBitmexClient = BitmexNetworkClient> {
+    state: NetworkAgentState<C, BitmexWs>,
     active_bitmex_instruments: HashMap<String, BitmexInstrument>,
+    order_books: HashMap<String, OrderBook>,
+    order_book_snapshot_requests: HashMap<String, Instant>,
+    order_book_snapshots: HashMap<String, PendingSnapshot>,
+}
+
+/// REST order book snapshot in flight with websocket deltas received in the meantime.
+struct PendingSnapshot {
+    request: JoinHandle<AnyResult<Vec<OrderBookL2>>>,
+    deltas: Vec<(BookAction, L2Level)>,
 }
 
 pub struct BitmexNetworkClient {
-    client: BitmexClient,
+    client: Arc<BitmexClient>,
+}
+
+pub type PaperBitmexAgent = BitmexAgent<PaperClient<BitmexNetworkClient>>;
+
+/// Client that `BitmexAgent` can run with, gives access to BitMEX client for market data.
+pub trait AsBitmexClient: NetworkClient<Exchange = Bitmex> {
+    fn from_client(client: BitmexNetworkClient) -> Self;
+    fn bitmex_client(&self) -> &Arc<BitmexClient>;
+}
+
+impl AsBitmexClient for BitmexNetworkClient {
+    fn from_client(client: BitmexNetworkClient) -> Self {
+        client
+    }
+
+    fn bitmex_client(&self) -> &Arc<BitmexClient> {
+        &self.client
+    }
+}
+
+impl AsBitmexClient for PaperClient<BitmexNetworkClient> {
+    fn from_client(client: BitmexNetworkClient) -> Self {
+        let config = get_exchange_config()
+            .and_then(|x| x.paper.clone())
+            .unwrap_or_default();
+        PaperClient::new(client, &config)
+    }
+
+    fn bitmex_client(&self) -> &Arc<BitmexClient> {
+        self.inner().bitmex_client()
+    }
 }
 
 #[async_trait]
@@ -59,6 +107,10 @@ impl NetworkClient for BitmexNetworkClient {
         self.client.exchange()
     }
 
+    fn clock(&self) -> Arc<ServerClock> {
+        self.client.clock().clone()
+    }
+
     async fn fetch_candles(
         &self,
         market: &str,
@@ -152,6 +204,70 @@ impl NetworkClient for BitmexNetworkClient {
         Ok(())
     }
 
+    async fn fetch_open_orders(&self) -> Result<Vec<OrderSnapshot>> {
+        let orders = self
+            .client
+            .request(GetOrderRequest {
+                filter: Some(serde_json::json!({ "open": true })),
+                count: Some(500),
+                ..Default::default()
+            })
+            .await?;
+        Ok(orders
+            .into_iter()
+            .map(|order| OrderSnapshot {
+                id: order
+                    .cl_ord_id
+                    .as_deref()
+                    .map_or_else(OrderId::unknown, OrderId::from_str),
+                amount_left: fix_amount(
+                    &order.ord_status,
+                    &order.side,
+                    &order.leaves_qty.map(Decimal::from),
+                ),
+                market: order.symbol.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn fetch_positions(&self) -> Result<Vec<Position>> {
+        let positions = self
+            .client
+            .request(GetPositionRequest {
+                ..Default::default()
+            })
+            .await?;
+        Ok(positions
+            .into_iter()
+            .filter_map(|position| {
+                Some(Position {
+                    amount: position.current_qty?.into(),
+                    timestamp_ns: position.timestamp?.timestamp_ns(),
+                    market: position.symbol,
+                })
+            })
+            .collect())
+    }
+
+    async fn fetch_margin(&self) -> Result<Margin> {
+        let margin = self
+            .client
+            .request(GetUserMarginRequest {
+                currency: Some("XBt".into()),
+            })
+            .await?;
+        let missing = |field| anyhow!("Margin of {} is missing {}.", margin.currency, field);
+        Ok(Margin {
+            // Converting balance to bitcoin from satoshi.
+            balance: Decimal::new(margin.margin_balance.ok_or_else(|| missing("balance"))?, 8),
+            leverage: margin.margin_leverage.ok_or_else(|| missing("leverage"))?,
+            timestamp_ns: margin
+                .timestamp
+                .ok_or_else(|| missing("timestamp"))?
+                .timestamp_ns(),
+        })
+    }
+
     async fn kill(&self) -> Result<()> {
         trace!("Cancelling all open orders.");
         self.client
@@ -219,30 +335,34 @@ impl Ws for BitmexWs {
 }
 
 #[async_trait]
-impl NetworkAgent for BitmexAgent {
+impl<C: AsBitmexClient> NetworkAgent for BitmexAgent<C> {
     type Websocket = BitmexWs;
-    type Client = BitmexNetworkClient;
+    type Client = C;
 
     async fn build(exchange_config: ExchangeConfig, model_configs: Vec<ModelConfig>) -> Result<Self>
     where
         Self: Sized,
     {
-        let bitmex = Bitmex::new(exchange_config.use_testnet);
+        let mut bitmex = Bitmex::new(exchange_config.use_testnet);
+        if let Some(vcr) = new_vcr()? {
+            bitmex = bitmex.with_vcr(Arc::new(vcr));
+        }
         let mut client = bitmex.new_client();
         let credentials = Credentials::new(&exchange_config.api_key, &exchange_config.api_secret);
         client.authenticate(credentials)?;
-        let ws = new_subscribed_websocket(&client).await?;
+        let client = C::from_client(BitmexNetworkClient {
+            client: Arc::new(client),
+        });
+        let ws = new_subscribed_websocket(client.bitmex_client(), !client.is_simulated()).await?;
         let mut instrument_configs = HashMap::new();
         let mut active_bitmex_instruments = HashMap::new();
         let (active_instruments, margin) = try_join!(
             get_instrument_configs(
-                &client,
+                client.bitmex_client(),
                 &mut active_bitmex_instruments,
                 Some(&mut instrument_configs)
             ),
-            client.request(GetUserMarginRequest {
-                currency: Some("XBt".into())
-            })
+            client.fetch_margin()
         )?;
         let instruments = active_instruments
             .into_iter()
@@ -256,18 +376,20 @@ impl NetworkAgent for BitmexAgent {
             state: NetworkAgentState::new(
                 exchange_config,
                 model_configs,
-                BitmexNetworkClient { client },
+                client,
                 ws,
                 instrument_configs,
                 instruments,
-                // Balance is in satoshis, converting to bitcoin.
-                Decimal::new(margin.margin_balance.unwrap(), 8),
-                margin.margin_leverage.unwrap(),
-                &*SUPPORTED_TIMEFRAMES,
+                margin.balance,
+                margin.leverage,
+                &<BitmexClient as Client>::capability().with_emulation(),
                 1000,
             )
             .await?,
             active_bitmex_instruments,
+            order_books: HashMap::new(),
+            order_book_snapshot_requests: HashMap::new(),
+            order_book_snapshots: HashMap::new(),
         };
         info!("Successfully initialized BitmexAgent.");
         Ok(bitmex_agent)
@@ -278,9 +400,13 @@ impl NetworkAgent for BitmexAgent {
         last_execution_time: DateTime<Utc>,
     ) -> Result<Vec<Result<Execution, FundingExecution>>> {
         let mut history = Vec::new();
+        // Simulated orders have never been sent.
+        if self.state.client().is_simulated() {
+            return Ok(history);
+        }
         for (market, _) in &self.active_bitmex_instruments {
             let _start = usize::MAX;
-            let mut ts = Utc::now();
+            let mut ts = self.state.clock().now();
             loop {
                 // Must be here
                 if ts < last_execution_time {
@@ -289,7 +415,7 @@ impl NetworkAgent for BitmexAgent {
                 let batch = self
                     .state
                     .client()
-                    .client
+                    .bitmex_client()
                     .request(GetUserExecutionHistoryRequest {
                         symbol: market.clone(),
                         timestamp: ts,
@@ -318,6 +444,7 @@ impl NetworkAgent for BitmexAgent {
             }
             Some(Ok(msg)) => match msg {
                 Message::Table(t) => {
+                    record_ws_message(&t.table);
                     match t.table.as_str() {
                         "announcement" => self.handle_announcement_message(*t).await?,
                         "trade" => self.handle_trade_message(*t).await?,
@@ -360,20 +487,47 @@ impl NetworkAgent for BitmexAgent {
         client
             .authenticate(Credentials::new(api_key, api_secret))
             .unwrap();
-        BitmexNetworkClient { client }
+        C::from_client(BitmexNetworkClient {
+            client: Arc::new(client),
+        })
     }
 
     async fn new_subscribed_web_socket(&mut self) -> Result<Self::Websocket> {
-        let ws = new_subscribed_websocket(&self.state.client().client).await?;
+        let client = self.state.client();
+        let ws = new_subscribed_websocket(client.bitmex_client(), !client.is_simulated()).await?;
         Ok(ws)
     }
 
-    fn state_mut(&mut self) -> &mut NetworkAgentState<BitmexNetworkClient, Self::Websocket> {
+    /// Applies order book snapshots that have been received and requests new ones for invalid
+    /// books once `ORDER_BOOK_RESNAPSHOT_INTERVAL_S` has passed, so that books recover without
+    /// waiting for a delta.
+    async fn poll(&mut self) -> Result<()> {
+        let invalid: Vec<_> = self
+            .order_books
+            .iter()
+            .filter(|(_, x)| !x.is_synchronized())
+            .map(|(market, _)| market.clone())
+            .collect();
+        for market in invalid {
+            self.resnapshot_order_book(&market);
+        }
+        let pending: Vec<_> = self.order_book_snapshots.keys().cloned().collect();
+        for market in pending {
+            if self.poll_order_book_snapshot(&market) {
+                self.state
+                    .on_order_book_changed(&self.order_books[&market])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state_mut(&mut self) -> &mut NetworkAgentState<C, Self::Websocket> {
         &mut self.state
     }
 }
 
-impl BitmexAgent {
+impl<C: AsBitmexClient> BitmexAgent<C> {
     async fn handle_announcement_message(&mut self, table: TableMessage<Value>) -> Result<()> {
         for datum in table.data {
             let msg: bitmex::definitions::Announcement = from_value(datum)?;
@@ -453,7 +607,7 @@ impl BitmexAgent {
             let connected = Connected {
                 bots: msg.bots.unwrap() as u32,
                 users: msg.users.unwrap() as u32,
-                timestamp_s: Utc::now().timestamp_s(),
+                timestamp_s: self.state.clock().now().timestamp_s(),
             };
             self.state.on_connected_users_changed(connected).await?;
         }
@@ -487,6 +641,17 @@ impl BitmexAgent {
                         Ok(execution) => self.state.on_execution(execution).await?,
                         Err(_) => unreachable!("funding partially filled"),
                     },
+                    OrdStatus::Canceled => {
+                        if let (Some(cl_ord_id), Some(timestamp)) = (&msg.cl_ord_id, msg.timestamp)
+                        {
+                            self.state
+                                .on_order_canceled(
+                                    OrderId::from_str(cl_ord_id),
+                                    timestamp.timestamp_ns(),
+                                )
+                                .await?;
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -572,7 +737,7 @@ impl BitmexAgent {
         &mut self,
         table: TableMessage<Value>,
     ) -> Result<()> {
-        let timestamp_ns = Utc::now().timestamp_ns();
+        let timestamp_ns = self.state.clock().now().timestamp_ns();
         for datum in table.data {
             let msg: bitmex::definitions::Liquidation = from_value(datum)?;
             let liquidation = PublicLiquidation {
@@ -598,12 +763,9 @@ impl BitmexAgent {
         fn create_update(
             active_instrument: &BitmexInstrument,
             msg: &OrderBookL2,
+            received_ns: u64,
         ) -> Result<OrderBookUpdate, ()> {
-            // Getting price form id.
-            // See https://www.bitmex.com/app/wsAPI#OrderBookL2
-            let price = ((dec!(100_000_000) * Decimal::from(active_instrument.id))
-                - Decimal::from(msg.id))
-                * active_instrument.legacy_tick_size;
+            let price = active_instrument.price_decoder().price(msg.id);
             let size = match msg.size {
                 Some(s) => s as f32,
                 None => 0.,
@@ -619,21 +781,26 @@ impl BitmexAgent {
             Ok(OrderBookUpdate {
                 size,
                 price: price.to_f32().unwrap(),
-                timestamp_ns: Utc::now().timestamp_ns(),
+                timestamp_ns: msg.timestamp.map_or(received_ns, |x| x.timestamp_ns()),
             })
         }
+        let timestamp_ns = self.state.clock().now().timestamp_ns();
+        let mut deltas: HashMap<String, Vec<L2Level>> = HashMap::new();
         for datum in table.data {
             let msg: bitmex::definitions::OrderBookL2 = from_value(datum)?;
+            if let Some(timestamp) = msg.timestamp {
+                record_ws_latency("order_book", timestamp_ns, timestamp.timestamp_ns());
+            }
             let order_book_update = match self.active_bitmex_instruments.get(&msg.symbol) {
-                Some(i) => match create_update(i, &msg) {
+                Some(i) => match create_update(i, &msg, timestamp_ns) {
                     Ok(u) => u,
-                    Err(_) => return Ok(()),
+                    Err(_) => continue,
                 },
                 // reload tick sizes and ids
                 None => {
                     self.active_bitmex_instruments.clear();
                     get_instrument_configs(
-                        &self.state.client().client,
+                        self.state.client().bitmex_client(),
                         &mut self.active_bitmex_instruments,
                         None,
                     )
@@ -642,19 +809,146 @@ impl BitmexAgent {
                         .active_bitmex_instruments
                         .get(&msg.symbol)
                         .expect("Instrument not found");
-                    match create_update(instrument, &msg) {
+                    match create_update(instrument, &msg, timestamp_ns) {
                         Ok(u) => u,
-                        Err(_) => return Ok(()),
+                        Err(_) => continue,
                     }
                 }
             };
+            if let Some(level) = convert_level(&msg) {
+                deltas.entry(msg.symbol.clone()).or_default().push(level);
+            }
             self.state
                 .on_order_book_updated(order_book_update, msg.symbol)
                 .await?;
         }
+        for (market, levels) in deltas {
+            let decoder = self.active_bitmex_instruments[&market].price_decoder();
+            let order_book = self
+                .order_books
+                .entry(market.clone())
+                .or_insert_with(|| OrderBook::new(market.clone()));
+            order_book.set_decoder(Some(decoder));
+            let action = match table.action {
+                Action::Partial => None,
+                Action::Insert => Some(BookAction::Insert),
+                Action::Update => Some(BookAction::Update),
+                Action::Delete => Some(BookAction::Delete),
+            };
+            let result = match action {
+                None => {
+                    // Websocket snapshot supersedes the one requested from REST api.
+                    if let Some(pending) = self.order_book_snapshots.remove(&market) {
+                        pending.request.abort();
+                    }
+                    order_book.apply_snapshot(&levels, timestamp_ns)
+                }
+                Some(action) => match self.order_book_snapshots.get_mut(&market) {
+                    Some(pending) => {
+                        pending
+                            .deltas
+                            .extend(levels.into_iter().map(|level| (action, level)));
+                        self.poll_order_book_snapshot(&market);
+                        Ok(())
+                    }
+                    // Book is known to be invalid until a snapshot arrives.
+                    None if !order_book.is_synchronized() => {
+                        self.resnapshot_order_book(&market);
+                        Ok(())
+                    }
+                    None => match action {
+                        BookAction::Insert => order_book.insert(&levels, timestamp_ns),
+                        BookAction::Update => order_book.update(&levels, timestamp_ns),
+                        BookAction::Delete => order_book.delete(&levels, timestamp_ns),
+                    },
+                },
+            };
+            if let Err(e) = result {
+                warn!("{}", e);
+                self.resnapshot_order_book(&market);
+            }
+            let order_book = &self.order_books[&market];
+            if order_book.is_synchronized() {
+                self.state.on_order_book_changed(order_book).await?;
+            }
+        }
         Ok(())
     }
 
+    /// Requests a snapshot of the order book from REST api without blocking the websocket.
+    /// Deltas that arrive while the request is in flight are buffered and replayed on top of the
+    /// snapshot once it is received, see `poll_order_book_snapshot`.
+    fn resnapshot_order_book(&mut self, market: &String) {
+        if self.order_book_snapshots.contains_key(market) {
+            return;
+        }
+        if let Some(requested) = self.order_book_snapshot_requests.get(market) {
+            if requested.elapsed().as_secs() < ORDER_BOOK_RESNAPSHOT_INTERVAL_S {
+                return;
+            }
+        }
+        self.order_book_snapshot_requests
+            .insert(market.clone(), Instant::now());
+        info!("Requesting {} order book snapshot.", market);
+        let client = self.state.client().bitmex_client().clone();
+        let request = GetOrderBookL2Request {
+            symbol: market.clone(),
+            depth: Some(0),
+        };
+        let request = tokio::spawn(async move { client.request(request).await });
+        self.order_book_snapshots.insert(
+            market.clone(),
+            PendingSnapshot {
+                request,
+                deltas: Vec::new(),
+            },
+        );
+    }
+
+    /// Replaces the order book with the requested snapshot if it has been received and replays
+    /// buffered deltas on top of it. A crossed result is logged and a new snapshot is requested,
+    /// a failed request is retried right away. Returns true if the book has been replaced.
+    fn poll_order_book_snapshot(&mut self, market: &String) -> bool {
+        let snapshot = match self.order_book_snapshots.get_mut(market) {
+            Some(pending) => match (&mut pending.request).now_or_never() {
+                Some(snapshot) => snapshot,
+                None => return false,
+            },
+            None => return false,
+        };
+        let pending = self.order_book_snapshots.remove(market).unwrap();
+        let snapshot = match snapshot {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(e)) => {
+                error!("Failed to fetch {} order book snapshot: {:?}", market, e);
+                self.order_book_snapshot_requests.remove(market);
+                self.resnapshot_order_book(market);
+                return false;
+            }
+            Err(e) => {
+                error!("{} order book snapshot request panicked: {:?}", market, e);
+                self.order_book_snapshot_requests.remove(market);
+                self.resnapshot_order_book(market);
+                return false;
+            }
+        };
+        let levels: Vec<_> = snapshot.iter().filter_map(convert_level).collect();
+        let timestamp_ns = self.state.clock().now().timestamp_ns();
+        let order_book = match self.order_books.get_mut(market) {
+            Some(order_book) => order_book,
+            None => return false,
+        };
+        let result = order_book
+            .apply_snapshot(&levels, timestamp_ns)
+            .and_then(|_| order_book.replay(&pending.deltas, timestamp_ns));
+        if let Err(e) = result {
+            warn!("Invalid {} order book snapshot: {}", market, e);
+            self.resnapshot_order_book(market);
+            return false;
+        }
+        true
+    }
+
     async fn handle_margin_message(&mut self, table: TableMessage<Value>) -> Result<()> {
         for margin in table.data {
             let msg: bitmex::definitions::Margin = from_value(margin)?;
@@ -695,19 +989,18 @@ impl BitmexAgent {
     }
 }
 
-async fn new_subscribed_websocket(client: &BitmexClient) -> Result<BitmexWs> {
+/// Private topics are subscribed only when orders are sent to BitMEX.
+async fn new_subscribed_websocket(client: &BitmexClient, private: bool) -> Result<BitmexWs> {
     let exchange = client.exchange();
-    let watch = Instant::now();
     let mut ws = exchange.new_web_socket().await?;
     match WebSocket::next(&mut ws).await.unwrap()? {
-        Message::Info(msg) => {
-            get_and_notify_time_difference(msg.timestamp, watch, exchange.name());
+        // Websocket has synchronized the clock with info timestamp.
+        Message::Info(_) => {
+            get_and_notify_time_difference(client.clock(), exchange.name());
         }
         _ => panic!("Expected info message while synchronizing clock."),
     }
-    ws.authenticate_raw(client.credential().as_ref().unwrap())
-        .await?;
-    ws.send(Command::Subscribe(vec![
+    let mut topics = vec![
         Topic::Announcement,
         Topic::Chat,
         Topic::Connected,
@@ -719,12 +1012,18 @@ async fn new_subscribed_websocket(client: &BitmexClient) -> Result<BitmexWs> {
         Topic::PublicNotifications,
         //          NOTE: There is significant delay when new 1min candles are received (20s or so).
         Topic::Trade(None),
-        Topic::Order, // live order status from user
-        Topic::Execution,
-        Topic::Margin,
-        Topic::Position,
-    ]))
-    .await?;
+    ];
+    if private {
+        ws.authenticate_raw(client.credential().as_ref().unwrap())
+            .await?;
+        topics.extend(vec![
+            Topic::Order, // live order status from user
+            Topic::Execution,
+            Topic::Margin,
+            Topic::Position,
+        ]);
+    }
+    ws.send(Command::Subscribe(topics)).await?;
     Ok(BitmexWs { inner: ws })
 }
 
@@ -831,7 +1130,7 @@ fn order_to_post_request(order: &Order) -> PostOrderRequest {
         simple_order_qty: None,
         order_qty: Some(order.amount.to_i32().unwrap()),
         price: order.limit,
-        display_qty: None,
+        display_qty: order.display_amount.map(|x| x.to_i32().unwrap()),
         stop_px: order.trigger_price,
         cl_ord_id: None,
         cl_ord_link_id: None,
@@ -843,6 +1142,9 @@ fn order_to_post_request(order: &Order) -> PostOrderRequest {
         contingency_type: None,
         text: None,
     };
+    if order.post_only {
+        request.exec_inst = Some(ExecInst::ParticipateDoNotInitiate);
+    }
     request.cl_ord_id = Some(order.id.to_string());
     if order.amount.is_sign_positive() {
         request.side = Some(Side::Buy)
@@ -941,6 +1243,7 @@ impl From<bitmex::definitions::Instrument> for InstrumentConfig {
             multiplier: instrument.multiplier.unwrap() as f32,
             quote_currency: instrument.quote_currency.unwrap(),
             is_inverse: instrument.is_inverse.unwrap(),
+            min_notional: Decimal::zero(),
             taker_fee: instrument.taker_fee.unwrap(),
             maker_fee: instrument.maker_fee.unwrap(),
             funding_period: match instrument.funding_interval {
@@ -971,6 +1274,7 @@ impl TryFrom<&bitmex::definitions::Instrument> for InstrumentConfig {
             multiplier: instrument.multiplier? as f32,
             quote_currency: instrument.quote_currency.as_ref()?.clone(),
             is_inverse: instrument.is_inverse?,
+            min_notional: Decimal::zero(),
             taker_fee: instrument.taker_fee?, // TODO: sometimes this is null
             maker_fee: instrument.maker_fee?,
             funding_period: match instrument.funding_interval {
@@ -981,6 +1285,35 @@ impl TryFrom<&bitmex::definitions::Instrument> for InstrumentConfig {
     }
 }
 
+/// Cassette from `vcr` of the exchange config that captures or replays a session.
+fn new_vcr() -> Result<Option<Vcr>> {
+    Ok(match get_exchange_config().and_then(|x| x.vcr.as_ref()) {
+        Some(VcrConfig::Record(path)) => {
+            info!("Recording network traffic to {}", path.display());
+            Some(Vcr::record(path))
+        }
+        Some(VcrConfig::Replay(path)) => {
+            info!("Replaying network traffic from {}", path.display());
+            Some(Vcr::replay(path)?)
+        }
+        None => None,
+    })
+}
+
+fn convert_level(msg: &OrderBookL2) -> Option<L2Level> {
+    let side = match msg.side {
+        Side::Buy => BookSide::Bid,
+        Side::Sell => BookSide::Ask,
+        Side::Unknown => return None,
+    };
+    Some(L2Level {
+        id: msg.id,
+        side,
+        price: msg.price,
+        size: msg.size.map(Decimal::from),
+    })
+}
+
 fn fix_amount(
     status: &Option<OrdStatus>,
     side: &Option<Side>,
@@ -1008,7 +1341,50 @@ struct BitmexInstrument {
     legacy_tick_size: Decimal,
 }
 
-lazy_static! {
-    pub static ref SUPPORTED_TIMEFRAMES: ReverseSortedVec<u32> =
-        ReverseSortedVec::from_unsorted(vec![60, 5 * 60, 60 * 60, 60 * 60 * 24]);
+impl BitmexInstrument {
+    fn price_decoder(&self) -> PriceDecoder {
+        PriceDecoder::new(self.id as u64, self.legacy_tick_size)
+    }
+}
+
+/// Minimum time between two REST order book snapshot requests for the same market.
+const ORDER_BOOK_RESNAPSHOT_INTERVAL_S: u64 = 5;
+
+#[cfg(test)]
+mod t_bitmex_agent {
+    use super::*;
+
+    /// Client of the agent that replays a recorded session.
+    fn replay_client() -> Result<BitmexNetworkClient> {
+        let vcr = Vcr::replay(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/bitmex_agent.json"
+        ))?;
+        let mut client = Bitmex::new(false).with_vcr(Arc::new(vcr)).new_client();
+        client.authenticate(Credentials::new("key", "secret"))?;
+        Ok(BitmexNetworkClient {
+            client: Arc::new(client),
+        })
+    }
+
+    #[tokio::test]
+    async fn t_replay_account() -> Result<()> {
+        let client = replay_client()?;
+        let margin = client.fetch_margin().await?;
+        assert_eq!(margin.balance, Decimal::new(15, 1));
+        assert_eq!(margin.leverage, Decimal::new(15, 1));
+        assert_eq!(margin.timestamp_ns, 1_630_454_400_000_000_000);
+
+        let positions = client.fetch_positions().await?;
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].market, "XBTUSD");
+        assert_eq!(positions[0].amount, Decimal::from(-100));
+
+        let orders = client.fetch_open_orders().await?;
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].market, "XBTUSD");
+        assert_eq!(orders[0].amount_left, Decimal::from(-10));
+        assert!(!orders[0].id.is_known());
+        Ok(())
+    }
 }
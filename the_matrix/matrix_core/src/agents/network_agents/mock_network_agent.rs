@@ -10,22 +10,24 @@ use iaas::mysql::models::{ExchangeConfig, ModelConfig};
 use lazy_static::lazy_static;
 use merovingian::candles::Candles;
 use merovingian::candles_builder::CandleAppender;
+use merovingian::minable_models::{Margin, Position};
 use merovingian::order::{Order, OrderId};
+use merovingian::order_book::OrderBook;
 use mock_exchange::MockExchange;
 use mouse::error::Result;
 use mouse::num::dec;
 use nebuchadnezzar_core::chrono::{DateTime, Utc};
-use nebuchadnezzar_core::client::NotClient;
+use nebuchadnezzar_core::client::{ClientCapability, NotClient};
 use nebuchadnezzar_core::error::NebError;
+use nebuchadnezzar_core::sorted_vec::SortedSet;
 use nebuchadnezzar_core::websocket::{NotWebSocket, WebSocket};
 use nebuchadnezzar_core::Exchange;
 use residual_self_image::backtest_report::BacktestReport;
 use rust_decimal::prelude::{One, Zero};
 use rust_decimal::Decimal;
-use sorted_vec::ReverseSortedVec;
 use tokio::sync::Mutex;
 
-use crate::agents::network_agent::{NetworkAgentState, NetworkClient, Ws};
+use crate::agents::network_agent::{NetworkAgentState, NetworkClient, OrderSnapshot, Ws};
 use crate::agents::network_agents::{Execution, FundingExecution, InstrumentConfig, NetworkAgent};
 use crate::agents::trade_guard::{ModelState, TradeGuard};
 
@@ -91,6 +93,15 @@ impl Exchange for MockNebExchange {
     }
 }
 
+impl MockClient {
+    #[cfg(test)]
+    pub fn with_position(market: &str, amount: Decimal) -> MockClient {
+        MockClient {
+            exchange: Arc::new(Mutex::new(MockExchange::with_position(market, amount))),
+        }
+    }
+}
+
 #[async_trait]
 impl NetworkClient for MockClient {
     type Exchange = MockNebExchange;
@@ -138,6 +149,48 @@ impl NetworkClient for MockClient {
         Ok(())
     }
 
+    async fn fetch_open_orders(&self) -> Result<Vec<OrderSnapshot>> {
+        Ok(self
+            .exchange
+            .lock()
+            .await
+            .open_orders()
+            .iter()
+            .map(|order| OrderSnapshot {
+                id: order.id,
+                market: order.market.clone(),
+                amount_left: order.amount,
+            })
+            .collect())
+    }
+
+    async fn fetch_positions(&self) -> Result<Vec<Position>> {
+        let exchange = self.exchange.lock().await;
+        // Mock exchange holds a single position.
+        Ok(exchange
+            .candles()
+            .keys()
+            .take(1)
+            .map(|market| Position {
+                market: market.clone(),
+                amount: exchange.position_amount(),
+                timestamp_ns: 0,
+            })
+            .collect())
+    }
+
+    async fn fetch_margin(&self) -> Result<Margin> {
+        Ok(self.exchange.lock().await.get_margin())
+    }
+
+    async fn on_order_book(&self, order_book: &OrderBook, _config: &InstrumentConfig) {
+        // Market orders are filled against the latest synchronized book.
+        self.exchange
+            .lock()
+            .await
+            .update_order_book(order_book.clone());
+    }
+
     fn exchange(&self) -> Self::Exchange {
         MockNebExchange {}
     }
@@ -245,6 +298,7 @@ impl MockNetworkAgent {
             multiplier: 0.0,
             // MUST be false if we are comparing with construct model
             is_inverse: false,
+            min_notional: Decimal::zero(),
             taker_fee: dec!(0.00075),
             maker_fee: Decimal::zero(),
             funding_period: 0,
@@ -257,7 +311,10 @@ impl MockNetworkAgent {
             exchange.init(&active_instruments);
             instruments = exchange.get_instruments();
         }
-        let supported_timeframes = ReverseSortedVec::from_unsorted(vec![min_timeframe]);
+        let capability = ClientCapability {
+            timeframes: SortedSet::from(vec![min_timeframe]),
+            ..Default::default()
+        };
         let agent = MockNetworkAgent {
             state: NetworkAgentState::new(
                 config,
@@ -268,7 +325,7 @@ impl MockNetworkAgent {
                 instruments,
                 Decimal::one(),
                 Decimal::zero(),
-                &supported_timeframes,
+                &capability,
                 usize::MAX,
             )
             .await?,
@@ -300,6 +357,9 @@ impl MockNetworkAgent {
                 while let Some(execution) = exchange_guard.get_executions().pop() {
                     self.state.on_execution(execution).await?;
                 }
+                while let Some(order_id) = exchange_guard.get_canceled().pop() {
+                    self.state.on_order_canceled(order_id, t1.timestamp_ns).await?;
+                }
                 drop(exchange_guard);
                 #[cfg(not(feature = "assert"))]
                 self.state
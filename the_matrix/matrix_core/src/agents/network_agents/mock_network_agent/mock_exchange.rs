@@ -2,16 +2,18 @@ use std::collections::HashMap;
 use std::path::Path;
 
 use config::{get_exchange_config, CONFIG};
+use dozer::provider::load_cached_funding;
 use iaas::mysql::models::{ExchangeConfig, ModelConfig};
 use merovingian::candles::Candles;
-use merovingian::minable_models::{Instrument, Margin, Trade};
+use merovingian::minable_models::{Funding, Instrument, Margin, Trade};
 use merovingian::non_minable_models::Fees;
 use merovingian::order::{Order, OrderId};
+use merovingian::order_book::OrderBook;
 use mouse::error::Result;
 use mouse::ext::VecExt;
 use mouse::log::*;
 use mouse::num::{FromMaybeDecimal, IntoDecimal};
-use num_traits::{One, Zero};
+use num_traits::{One, ToPrimitive, Zero};
 use rust_decimal::Decimal;
 
 use super::INCEPTION_TIMESTAMP_S;
@@ -23,11 +25,18 @@ pub struct MockExchange {
     candles: HashMap<String, HashMap<u32, Candles>>,
     orders: Vec<Order>,
     executions: Vec<Execution>,
+    /// Orders canceled since they were last taken.
+    canceled: Vec<OrderId>,
     funding_executions: Vec<FundingExecution>,
+    /// Funding history of each market and index of the next funding to be paid.
+    funding: HashMap<String, (Vec<Funding>, usize)>,
     fees: Fees,
     balance: Decimal,
     pnl: Decimal,
     position_amount: Decimal,
+    /// When a synchronized book is available market orders walk its depth instead of filling at
+    /// the last trade price.
+    order_books: HashMap<String, OrderBook>,
 
     #[cfg(not(feature = "assert"))]
     trades: HashMap<String, (Vec<Trade>, usize)>,
@@ -35,8 +44,6 @@ pub struct MockExchange {
     market_type: HashMap<String, bool>,
     #[cfg(not(feature = "assert"))]
     slippage: f32,
-    #[cfg(not(feature = "assert"))]
-    funding_time: u32,
 }
 
 impl MockExchange {
@@ -45,13 +52,8 @@ impl MockExchange {
         let fees = Fees {
             maker: -0.00025,
             taker: 0.00075,
-            // TODO: use real funding history
-            // funding: 0.000378,
-            funding: 0.0,
-            funding_period: 60 * 60 * 8,
         };
         warn!("{:#?}", fees);
-        let mut funding_time = u32::MAX;
         let mut trades = HashMap::new();
         let mut min_timeframes = HashMap::new();
         let mut candle_map: HashMap<String, HashMap<u32, Candles>> = HashMap::new();
@@ -77,8 +79,6 @@ impl MockExchange {
                             data_path,
                             map,
                             &model_config.symbol,
-                            &mut funding_time,
-                            fees.funding_period,
                         )
                         .await?;
                     }
@@ -90,8 +90,6 @@ impl MockExchange {
                         data_path,
                         &mut map,
                         &model_config.symbol,
-                        &mut funding_time,
-                        fees.funding_period,
                     )
                     .await?;
                     candle_map.insert(model_config.symbol.clone(), map);
@@ -99,9 +97,11 @@ impl MockExchange {
             }
         }
 
+        let mut funding = HashMap::new();
         for (market, timeframe) in min_timeframes {
             let candles = candle_map.get(&market).unwrap().get(&timeframe).unwrap();
             min_inception_timestamp.min_mut(candles.timestamp[0]);
+            funding.insert(market.clone(), (load_market_funding(&market, candles).await?, 0));
             trades.insert(market, generate_trades(candles));
         }
 
@@ -115,12 +115,15 @@ impl MockExchange {
             market_type,
             orders: Vec::new(),
             executions: Vec::new(),
+            canceled: Vec::new(),
             position_amount: Decimal::zero(),
             fees,
             slippage: 1.,
             balance: Decimal::one(),
             pnl: Decimal::zero(),
-            funding_time,
+            funding_executions: Vec::new(),
+            funding,
+            order_books: HashMap::new(),
         })
     }
 
@@ -132,15 +135,9 @@ impl MockExchange {
         let fees = Fees {
             maker: -0.00025,
             taker: 0.00075 + 0.0006728571,
-            // TODO: use real funding history
-            // average XBTUSD funding
-            funding: 0.000077490909,
-            funding_period: 60 * 60 * 8,
         };
         warn!("{:#?}", fees);
 
-        let mut _funding_time = 0;
-
         let mut candles_map = HashMap::new();
         let mut timeframe_map = HashMap::new();
         load_and_insert_candles(
@@ -148,12 +145,15 @@ impl MockExchange {
             &CONFIG.data_dir,
             &mut timeframe_map,
             &model_configs[0].market,
-            &mut _funding_time,
-            fees.funding_period,
         )
         .await?;
         let candles = timeframe_map.iter().next().unwrap().1;
         let trades = generate_trades(candles);
+        let mut funding = HashMap::new();
+        funding.insert(
+            model_configs[0].market.clone(),
+            (load_market_funding(&model_configs[0].market, candles).await?, 0),
+        );
         unsafe {
             *(&*INCEPTION_TIMESTAMP_S as *const _ as *mut u32) = candles.timestamp[0];
         }
@@ -164,14 +164,48 @@ impl MockExchange {
             candles: candles_map,
             orders: Vec::new(),
             executions: Vec::new(),
+            canceled: Vec::new(),
             position_amount: Decimal::zero(),
             fees,
             balance: Decimal::one(),
             pnl: Decimal::zero(),
             funding_executions: vec![],
+            funding,
+            order_books: HashMap::new(),
         })
     }
 
+    /// Exchange without any data that holds a position on `market`.
+    #[cfg(test)]
+    pub fn with_position(market: &str, position_amount: Decimal) -> MockExchange {
+        let mut candles = HashMap::new();
+        candles.insert(market.to_string(), HashMap::new());
+        MockExchange {
+            #[cfg(feature = "assert")]
+            trades: (Vec::new(), 0),
+            #[cfg(not(feature = "assert"))]
+            trades: HashMap::new(),
+            candles,
+            orders: Vec::new(),
+            executions: Vec::new(),
+            canceled: Vec::new(),
+            funding_executions: Vec::new(),
+            funding: HashMap::new(),
+            fees: Fees {
+                maker: 0.,
+                taker: 0.,
+            },
+            balance: Decimal::one(),
+            pnl: Decimal::zero(),
+            position_amount,
+            order_books: HashMap::new(),
+            #[cfg(not(feature = "assert"))]
+            market_type: HashMap::new(),
+            #[cfg(not(feature = "assert"))]
+            slippage: 1.,
+        }
+    }
+
     #[cfg(feature = "assert")]
     pub fn init(&mut self, _: &HashMap<String, InstrumentConfig>) {}
 
@@ -221,7 +255,6 @@ impl MockExchange {
             .collect()
     }
 
-    #[cfg(not(feature = "assert"))]
     pub fn get_margin(&mut self) -> Margin {
         if self.position_amount.is_zero() {
             return Margin {
@@ -230,14 +263,19 @@ impl MockExchange {
                 timestamp_ns: 0,
             };
         }
-        let is_inverse = self.market_type.iter().next().unwrap().1;
-        let (trades, i) = self.trades.iter().next().unwrap().1;
+        #[cfg(feature = "assert")]
+        let (is_inverse, (trades, i)) = (false, &self.trades);
+        #[cfg(not(feature = "assert"))]
+        let (is_inverse, (trades, i)) = (
+            *self.market_type.values().next().unwrap(),
+            self.trades.values().next().unwrap(),
+        );
         let price = trades[*i].price.to_decimal().unwrap();
         let open_value = self.pnl;
-        let value = order::value(price, self.position_amount, *is_inverse);
+        let value = order::value(price, self.position_amount, is_inverse);
         let mut diff = value - open_value;
         // Copy-pasted from model_state.rs
-        if *is_inverse {
+        if is_inverse {
             if self.position_amount.is_sign_positive() {
                 diff *= dec!(-1.);
             } else {
@@ -285,51 +323,60 @@ impl MockExchange {
         // }
         *id += 4;
 
-        // Cannot borrow self in closure while part of it (orders) are being borrowed too.
-        let position_amount = &mut self.position_amount;
-        let messages = &mut self.executions;
-        let fees = &self.fees;
-        #[cfg(feature = "assert")]
-        let slippage = 1.;
-        #[cfg(not(feature = "assert"))]
-        let slippage = self.slippage;
-        let balance = &mut self.balance;
-        let pnl = &mut self.pnl;
         #[cfg(feature = "assert")]
         let is_inverse = false;
         #[cfg(not(feature = "assert"))]
         let is_inverse = *self.market_type.get(market).unwrap();
 
-        #[cfg(not(feature = "assert"))]
-        {
-            panic!();
-            // First we pay funding because we open/close positions after rounded time.
-            let trade_timestamp = (prev_trades[0].timestamp_ns / 1_000_000_000) as u32;
-            if trade_timestamp > self.funding_time {
-                self.funding_time = trade_timestamp + self.fees.funding_period;
+        // First we pay funding because we open/close positions after rounded time.
+        let trade_timestamp = (prev_trades[0].timestamp_ns / 1_000_000_000) as u32;
+        if let Some((history, next)) = self.funding.get_mut(market) {
+            while let Some(funding) = history
+                .get(*next)
+                .filter(|x| x.timestamp_s <= trade_timestamp)
+            {
                 new_funding(
-                    &self.fees,
+                    funding,
                     self.position_amount,
                     &mut self.balance,
-                    &mut self.executions,
+                    &mut self.funding_executions,
                     prev_trades[0].price,
-                    prev_trades[0].timestamp_ns,
                     market,
                     is_inverse,
                 );
+                *next += 1;
             }
         }
+
+        // Cannot borrow self in closure while part of it (orders) are being borrowed too.
+        let position_amount = &mut self.position_amount;
+        let messages = &mut self.executions;
+        let fees = &self.fees;
+        #[cfg(feature = "assert")]
+        let slippage = 1.;
+        #[cfg(not(feature = "assert"))]
+        let slippage = self.slippage;
+        let balance = &mut self.balance;
+        let pnl = &mut self.pnl;
+        let order_book = self
+            .order_books
+            .get(market)
+            .filter(|x| x.is_synchronized());
         self.orders.keep(|order| {
             let trigger_price = order.trigger_price.to_f32();
             if order.is_market() {
                 order.timestamp_ns = prev_trades[0].timestamp_ns;
+                let executed_price = match order_book.and_then(|x| x.fill_price(order.amount)) {
+                    Some(price) => price.to_f32().unwrap(),
+                    None => prev_trades[0].price,
+                };
                 new_trade(
                     market,
                     messages,
                     slippage,
                     fees,
                     order,
-                    prev_trades[0].price,
+                    executed_price,
                     balance,
                     position_amount,
                     pnl,
@@ -379,6 +426,12 @@ impl MockExchange {
         Some(&trades[i..i + 4])
     }
 
+    /// Replaces order book that is used to fill market orders.
+    pub fn update_order_book(&mut self, order_book: OrderBook) {
+        self.order_books
+            .insert(order_book.market().to_string(), order_book);
+    }
+
     pub fn cancel_orders(&mut self, ids: &Vec<OrderId>) {
         for id in ids {
             trace!("Order canceled {:?}", id);
@@ -390,6 +443,7 @@ Existing orders: {:#?}, faulty order: {:#?}"#,
                 );
                 panic!("Fatal error.");
             }
+            self.canceled.push(*id);
         }
     }
 
@@ -403,6 +457,14 @@ Existing orders: {:#?}, faulty order: {:#?}"#,
             .extend(orders.iter().filter(|x| x.trigger_price.is_some()).cloned());
     }
 
+    pub fn open_orders(&self) -> &Vec<Order> {
+        &self.orders
+    }
+
+    pub fn position_amount(&self) -> Decimal {
+        self.position_amount
+    }
+
     pub fn get_executions(&mut self) -> &mut Vec<Execution> {
         &mut self.executions
     }
@@ -410,6 +472,10 @@ Existing orders: {:#?}, faulty order: {:#?}"#,
     pub fn get_funding_executions(&mut self) -> &mut Vec<FundingExecution> {
         &mut self.funding_executions
     }
+
+    pub fn get_canceled(&mut self) -> &mut Vec<OrderId> {
+        &mut self.canceled
+    }
 }
 
 fn generate_trades(candles: &Candles) -> (Vec<Trade>, usize) {
@@ -503,13 +569,14 @@ fn new_trade(
     *position_amount += order.amount;
 }
 
+/// Positive rate means that longs pay shorts. Value of a long position is positive for inverse and
+/// linear contracts alike, so shorts get a negative fee.
 fn new_funding(
-    fees: &Fees,
+    funding: &Funding,
     position_amount: Decimal,
     balance: &mut Decimal,
     funding_executions: &mut Vec<FundingExecution>,
     price: f32,
-    timestamp_ns: u64,
     market: &String,
     is_inverse: bool,
 ) {
@@ -518,23 +585,38 @@ fn new_funding(
     }
     let price = price.to_decimal().unwrap();
     let value = merovingian::order::value(price, position_amount, is_inverse);
-    let fee_paid = value.abs() * fees.funding.to_decimal().unwrap();
+    let fee_paid = value * funding.rate.to_decimal().unwrap();
     *balance -= fee_paid;
-    trace!("funding, value: {} fee_paid: {}", value, fee_paid);
+    trace!(
+        "funding, rate: {} value: {} fee_paid: {}",
+        funding.rate,
+        value,
+        fee_paid
+    );
     funding_executions.push(FundingExecution {
         market: market.clone(),
         fee_paid,
-        timestamp_ns,
+        timestamp_ns: funding.timestamp_s as u64 * 1_000_000_000,
     });
 }
 
+/// Cached funding history that covers all candles. It is empty when the exchange has no funding or
+/// it hasn't been cached along with hlcv.
+async fn load_market_funding(market: &str, candles: &Candles) -> Result<Vec<Funding>> {
+    let exchange = match get_exchange_config() {
+        Some(config) => config.exchange_name(),
+        None => return Ok(Vec::new()),
+    };
+    let start_ts = candles.timestamp[0];
+    let end_ts = *candles.timestamp.last().unwrap() + candles.timeframe_step();
+    load_cached_funding(exchange, market, start_ts, end_ts).await
+}
+
 async fn load_and_insert_candles(
     timeframe: u32,
     data_path: impl AsRef<Path>,
     map: &mut HashMap<u32, Candles>,
     market: &str,
-    funding_time: &mut u32,
-    funding_period: u32,
 ) -> Result<()> {
     let path = data_path
         .as_ref()
@@ -551,10 +633,6 @@ async fn load_and_insert_candles(
     //     // candles.timeframe_step() * candles.len() as u32 / 2 + candles.timestamp[0],
     //     *candles.timestamp.last().unwrap(),
     // );
-    if candles.timestamp[0] < *funding_time {
-        *funding_time =
-            candles.timestamp[0] - candles.timestamp[0] % funding_period + funding_period;
-    }
     let mut candles2 = candles.clone();
     if candles.timeframe_step() == timeframe {
         map.insert(timeframe, candles2);
@@ -567,3 +645,70 @@ async fn load_and_insert_candles(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod t_mock_exchange {
+    use mouse::num::dec;
+
+    use super::*;
+
+    fn pay(position_amount: Decimal, rate: f32, is_inverse: bool) -> (Decimal, FundingExecution) {
+        let mut balance = Decimal::one();
+        let mut executions = Vec::new();
+        let funding = Funding {
+            rate,
+            daily_rate: rate * 3.,
+            timestamp_s: 1_600_000_000,
+        };
+        new_funding(
+            &funding,
+            position_amount,
+            &mut balance,
+            &mut executions,
+            50_000.,
+            &"XBTUSD".to_string(),
+            is_inverse,
+        );
+        (balance, executions.pop().unwrap())
+    }
+
+    #[test]
+    fn t_new_funding() {
+        // 1000 contracts of inverse XBTUSD are worth 0.02 XBT
+        let (balance, execution) = pay(dec!(1000), 0.0001, true);
+        assert_eq!(execution.fee_paid, dec!(0.000002));
+        assert_eq!(balance, dec!(0.999998));
+        assert_eq!(execution.timestamp_ns, 1_600_000_000_000_000_000);
+        let (balance, execution) = pay(dec!(-1000), 0.0001, true);
+        assert_eq!(execution.fee_paid, dec!(-0.000002));
+        assert_eq!(balance, dec!(1.000002));
+        // negative rate: shorts pay longs
+        let (_, execution) = pay(dec!(-1000), -0.0001, true);
+        assert_eq!(execution.fee_paid, dec!(0.000002));
+        // 0.001 of linear contract is worth 50 USDT
+        let (balance, execution) = pay(dec!(0.001), 0.0001, false);
+        assert_eq!(execution.fee_paid, dec!(0.005));
+        assert_eq!(balance, dec!(0.995));
+        let (_, execution) = pay(dec!(-0.001), 0.0001, false);
+        assert_eq!(execution.fee_paid, dec!(-0.005));
+
+        let mut executions = Vec::new();
+        let mut balance = Decimal::one();
+        let funding = Funding {
+            rate: 0.0001,
+            daily_rate: 0.0003,
+            timestamp_s: 0,
+        };
+        new_funding(
+            &funding,
+            Decimal::zero(),
+            &mut balance,
+            &mut executions,
+            50_000.,
+            &"XBTUSD".to_string(),
+            true,
+        );
+        assert!(executions.is_empty());
+        assert_eq!(balance, Decimal::one());
+    }
+}
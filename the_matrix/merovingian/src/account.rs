@@ -1,3 +1,4 @@
+use std::convert::TryInto;
 use std::f32::NAN;
 use std::fmt::{Display, Formatter};
 
@@ -43,7 +44,8 @@ impl Default for Account {
 
 field_names! {
     #[repr(C)]
-    #[derive(PartialEq, Debug, Clone, Readable, Writable, Serialize)]
+    #[derive(PartialEq, Debug, Clone, Readable, Writable, Serialize, Deserialize)]
+    #[serde(default)]
     pub struct StatAccount {
         pub can_record: f32,
         pub bought_id: f32,
@@ -118,6 +120,32 @@ impl StatAccount {
         self.bought_id != 1.
     }
 
+    /// Values of fields in the order of `NAMES`.
+    pub fn values(&self) -> Vec<f32> {
+        bincode::serialize(self)
+            .unwrap()
+            .chunks(4)
+            .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<f32> {
+        let i = Self::NAMES.iter().position(|x| *x == name)?;
+        Some(self.values()[i])
+    }
+
+    /// Sets a field by its name, returns `false` if there is no such field.
+    pub fn set(&mut self, name: &str, value: f32) -> bool {
+        let i = match Self::NAMES.iter().position(|x| *x == name) {
+            Some(i) => i,
+            None => return false,
+        };
+        let mut data = bincode::serialize(self).unwrap();
+        data[i * 4..(i + 1) * 4].copy_from_slice(&value.to_le_bytes());
+        *self = bincode::deserialize(&data).unwrap();
+        true
+    }
+
     pub fn expectancy_p(&self) -> f32 {
         self.expectancy_r * self.avg_risk_p
     }
@@ -1,3 +1,4 @@
+use mouse::num::Decimal;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,3 +6,23 @@ pub enum TestError {
     #[error("Test failed.")]
     TestFailed,
 }
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum OrderBookError {
+    #[error("{0} order book is waiting for a snapshot.")]
+    NotSynchronized(String),
+    #[error("{market} order book already contains level {id}.")]
+    DuplicateLevel { market: String, id: i64 },
+    #[error("{market} order book doesn't contain level {id}.")]
+    UnknownLevel { market: String, id: i64 },
+    #[error("{market} order book level {id} has no price and price decoder isn't set.")]
+    MissingPrice { market: String, id: i64 },
+    #[error("{market} order book level {id} has no size.")]
+    MissingSize { market: String, id: i64 },
+    #[error("{market} order book is crossed, bid: {bid}, ask: {ask}.")]
+    Crossed {
+        market: String,
+        bid: Decimal,
+        ask: Decimal,
+    },
+}
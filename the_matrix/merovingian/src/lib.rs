@@ -40,7 +40,11 @@ pub mod minable_models;
 pub mod model_snapshot;
 pub mod non_minable_models;
 pub mod order;
+pub mod order_book;
+pub mod output_index;
 pub mod output_reader;
+pub mod parallel_coordinates;
+pub mod report;
 pub mod structs;
 pub mod variable;
 
@@ -98,14 +98,14 @@ pub struct OrderBookUpdate {
     pub timestamp_ns: u64,
 }
 
-#[derive(Clone, Debug, Writable, Readable)]
+#[derive(Clone, Debug, PartialEq, Writable, Readable)]
 pub struct Position {
     pub market: String,
     pub amount: Decimal,
     pub timestamp_ns: u64,
 }
 
-#[derive(Clone, Debug, Writable, Readable)]
+#[derive(Clone, Debug, PartialEq, Writable, Readable)]
 pub struct Margin {
     pub balance: Decimal,
     pub leverage: Decimal,
@@ -5,8 +5,6 @@ use bevy::prelude::*;
 pub struct Fees {
     pub maker: f32,
     pub taker: f32,
-    pub funding: f32,
-    pub funding_period: u32,
 }
 
 bitflags! {
@@ -10,8 +10,9 @@ use mouse::num::{Decimal, FromMaybeDecimal, IntoDecimal};
 #[derive(Clone, Debug, Readable, Writable)]
 pub struct Order {
     /// How much to buy/sell.
-    /// If order is for inverse market amount is USD otherwise XBT for XBTUSD market.
-    /// Can be negative, indicating short position.
+    /// If order is for inverse market amount is USD otherwise XBT for XBTUSD market. Amount of a
+    /// spot order is in base currency e.g. BIR for BIR_BTC.
+    /// Can be negative, indicating short position or a sell on spot market.
     pub amount: Decimal,
     pub trigger_price: Option<Decimal>,
     pub limit: Option<Decimal>,
@@ -20,11 +21,16 @@ pub struct Order {
     pub id: OrderId,
     pub predicted_price: f32,
     /// Overall value of the order.
-    /// If order is for inverse market then value is in XBT otherwise USD for XBTUSD market.
-    /// Can be negative, indicating short position.
+    /// If order is for inverse market then value is in XBT otherwise USD for XBTUSD market. Value
+    /// of a spot order is in quote currency e.g. BTC for BIR_BTC.
+    /// Can be negative, indicating short position or a sell on spot market.
     pub value: Option<Decimal>,
     //    pub link: Uuid,
     pub timestamp_ns: u64,
+    /// Limit order is canceled instead of taking liquidity.
+    pub post_only: bool,
+    /// Amount of a limit order that is visible in the order book, the rest is hidden.
+    pub display_amount: Option<Decimal>,
 }
 
 impl Order {
@@ -64,6 +70,8 @@ impl Order {
             predicted_price,
             value,
             timestamp_ns: 0,
+            post_only: false,
+            display_amount: None,
         }
     }
 
@@ -87,6 +95,8 @@ impl Order {
                 inverse,
             )),
             timestamp_ns: orders[0].timestamp_ns,
+            post_only: false,
+            display_amount: None,
         })
     }
 
@@ -129,6 +139,29 @@ impl Order {
     }
 }
 
+/// How orders of a model are executed on an exchange.
+#[derive(Clone, Debug, PartialEq, Readable, Writable, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExecutionAlgo {
+    /// Orders are sent as they are, market orders of all models are bundled into one.
+    Direct,
+    /// Market order is split into `slices` equal market orders that are sent evenly over
+    /// `duration_s`.
+    Twap { duration_s: u32, slices: u32 },
+    /// Order rests in the order book showing only `display_amount` at once. Market order is placed
+    /// at the touch.
+    Iceberg { display_amount: f32 },
+    /// Market order is placed as post-only limit order that follows the touch, what isn't filled
+    /// after `timeout_s` is sent as market order.
+    Chase { timeout_s: u32 },
+}
+
+impl Default for ExecutionAlgo {
+    fn default() -> Self {
+        ExecutionAlgo::Direct
+    }
+}
+
 pub fn value(price: Decimal, amount: Decimal, inverse: bool) -> Decimal {
     if inverse {
         amount / price
@@ -199,6 +232,11 @@ impl OrderId {
         self.model_id != u32::MAX && self.i != u32::MAX
     }
 
+    /// Id of a model that has placed this order.
+    pub fn model_id(&self) -> u32 {
+        self.model_id
+    }
+
     pub fn from_str(s: &str) -> Self {
         let mut iter = s.split('-');
         let mut buf = [0u8; 4];
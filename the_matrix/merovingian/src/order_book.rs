@@ -0,0 +1,500 @@
+use std::collections::{BTreeMap, HashMap};
+
+use mouse::num::traits::Zero;
+use mouse::num::Decimal;
+
+use crate::error::OrderBookError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BookSide {
+    Bid,
+    Ask,
+}
+
+impl BookSide {
+    /// Side of the book that gets consumed by a market order with specified amount.
+    /// Positive amount buys and consumes asks.
+    pub fn consumed_by(amount: Decimal) -> BookSide {
+        if amount.is_sign_negative() {
+            BookSide::Bid
+        } else {
+            BookSide::Ask
+        }
+    }
+}
+
+/// Kind of a delta that is replayed on top of a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BookAction {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A single price level as it is received from an exchange.
+/// Updates and deletes usually don't contain price, it is then looked up by id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct L2Level {
+    pub id: i64,
+    pub side: BookSide,
+    pub price: Option<Decimal>,
+    pub size: Option<Decimal>,
+}
+
+/// Decodes price from a level id.
+/// See https://www.bitmex.com/app/wsAPI#OrderBookL2
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PriceDecoder {
+    pub instrument_index: u64,
+    pub tick_size: Decimal,
+}
+
+impl PriceDecoder {
+    pub fn new(instrument_index: u64, tick_size: Decimal) -> PriceDecoder {
+        PriceDecoder {
+            instrument_index,
+            tick_size,
+        }
+    }
+
+    pub fn price(&self, id: i64) -> Decimal {
+        (Decimal::from(100_000_000u64 * self.instrument_index) - Decimal::from(id))
+            * self.tick_size
+    }
+}
+
+/// Level 2 order book that is built from a snapshot followed by deltas keyed by level id.
+/// If a delta cannot be applied the book is invalidated and a new snapshot is required.
+#[derive(Clone, Debug)]
+pub struct OrderBook {
+    market: String,
+    decoder: Option<PriceDecoder>,
+    /// Level id -> (side, price)
+    ids: HashMap<i64, (BookSide, Decimal)>,
+    /// Price -> size
+    bids: BTreeMap<Decimal, Decimal>,
+    /// Price -> size
+    asks: BTreeMap<Decimal, Decimal>,
+    synchronized: bool,
+    timestamp_ns: u64,
+}
+
+impl OrderBook {
+    pub fn new(market: impl Into<String>) -> OrderBook {
+        OrderBook {
+            market: market.into(),
+            decoder: None,
+            ids: Default::default(),
+            bids: Default::default(),
+            asks: Default::default(),
+            synchronized: false,
+            timestamp_ns: 0,
+        }
+    }
+
+    pub fn with_decoder(market: impl Into<String>, decoder: PriceDecoder) -> OrderBook {
+        let mut book = OrderBook::new(market);
+        book.decoder = Some(decoder);
+        book
+    }
+
+    pub fn market(&self) -> &str {
+        &self.market
+    }
+
+    pub fn set_decoder(&mut self, decoder: Option<PriceDecoder>) {
+        self.decoder = decoder;
+    }
+
+    /// False if snapshot hasn't been received yet or if book got invalidated.
+    pub fn is_synchronized(&self) -> bool {
+        self.synchronized
+    }
+
+    pub fn timestamp_ns(&self) -> u64 {
+        self.timestamp_ns
+    }
+
+    /// Clears the book, deltas are rejected until next snapshot.
+    pub fn invalidate(&mut self) {
+        self.ids.clear();
+        self.bids.clear();
+        self.asks.clear();
+        self.synchronized = false;
+    }
+
+    /// Replaces the whole book.
+    pub fn apply_snapshot(
+        &mut self,
+        levels: &[L2Level],
+        timestamp_ns: u64,
+    ) -> Result<(), OrderBookError> {
+        self.invalidate();
+        self.synchronized = true;
+        self.insert(levels, timestamp_ns)
+    }
+
+    pub fn insert(&mut self, levels: &[L2Level], timestamp_ns: u64) -> Result<(), OrderBookError> {
+        self.apply(levels, timestamp_ns, |book, level| {
+            if book.ids.contains_key(&level.id) {
+                return Err(OrderBookError::DuplicateLevel {
+                    market: book.market.clone(),
+                    id: level.id,
+                });
+            }
+            let price = book.level_price(level)?;
+            let size = book.level_size(level)?;
+            book.ids.insert(level.id, (level.side, price));
+            book.side_mut(level.side).insert(price, size);
+            Ok(())
+        })
+    }
+
+    pub fn update(&mut self, levels: &[L2Level], timestamp_ns: u64) -> Result<(), OrderBookError> {
+        self.apply(levels, timestamp_ns, |book, level| {
+            let size = book.level_size(level)?;
+            let (side, price) = book.remove_id(level)?;
+            book.ids.insert(level.id, (level.side, price));
+            if side != level.side {
+                // Level switched sides, happens when the book gets crossed on the exchange.
+                book.side_mut(side).remove(&price);
+            }
+            book.side_mut(level.side).insert(price, size);
+            Ok(())
+        })
+    }
+
+    pub fn delete(&mut self, levels: &[L2Level], timestamp_ns: u64) -> Result<(), OrderBookError> {
+        self.apply(levels, timestamp_ns, |book, level| {
+            let (side, price) = book.remove_id(level)?;
+            book.side_mut(side).remove(&price);
+            Ok(())
+        })
+    }
+
+    /// Applies deltas on top of a snapshot that may already contain some of them, e.g. deltas
+    /// received while the snapshot was being fetched. Inserts and updates set the size of a level
+    /// and deletes of unknown levels are ignored, so the result doesn't depend on when exactly the
+    /// snapshot was taken.
+    pub fn replay(
+        &mut self,
+        deltas: &[(BookAction, L2Level)],
+        timestamp_ns: u64,
+    ) -> Result<(), OrderBookError> {
+        self.apply(deltas, timestamp_ns, |book, (action, level)| {
+            let existing = book.ids.remove(&level.id);
+            if let Some((side, price)) = existing {
+                book.side_mut(side).remove(&price);
+            }
+            if *action == BookAction::Delete {
+                return Ok(());
+            }
+            let price = match existing {
+                Some((_, price)) => price,
+                None => book.level_price(level)?,
+            };
+            let size = book.level_size(level)?;
+            book.ids.insert(level.id, (level.side, price));
+            book.side_mut(level.side).insert(price, size);
+            Ok(())
+        })
+    }
+
+    /// Returns (price, size) of the highest bid.
+    pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
+        self.bids.iter().next_back().map(|(p, s)| (*p, *s))
+    }
+
+    /// Returns (price, size) of the lowest ask.
+    pub fn best_ask(&self) -> Option<(Decimal, Decimal)> {
+        self.asks.iter().next().map(|(p, s)| (*p, *s))
+    }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some((bid + ask) / Decimal::from(2))
+    }
+
+    pub fn spread(&self) -> Option<Decimal> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some(ask - bid)
+    }
+
+    /// Size resting at exact price, zero if there is no level.
+    pub fn depth_at(&self, side: BookSide, price: Decimal) -> Decimal {
+        self.side(side)
+            .get(&price)
+            .cloned()
+            .unwrap_or(Decimal::zero())
+    }
+
+    /// Iterates over (price, size) starting from the top of the book.
+    pub fn levels(&self, side: BookSide) -> Box<dyn Iterator<Item = (&Decimal, &Decimal)> + '_> {
+        match side {
+            BookSide::Bid => Box::new(self.bids.iter().rev()),
+            BookSide::Ask => Box::new(self.asks.iter()),
+        }
+    }
+
+    pub fn len(&self, side: BookSide) -> usize {
+        self.side(side).len()
+    }
+
+    /// Volume weighted average price of consuming `size` from the top of `side`.
+    /// Returns `None` if there isn't enough depth.
+    pub fn vwap(&self, side: BookSide, size: Decimal) -> Option<Decimal> {
+        let size = size.abs();
+        if size.is_zero() {
+            return None;
+        }
+        let mut remaining = size;
+        let mut notional = Decimal::zero();
+        for (price, level_size) in self.levels(side) {
+            let filled = remaining.min(*level_size);
+            notional += filled * price;
+            remaining -= filled;
+            if remaining.is_zero() {
+                return Some(notional / size);
+            }
+        }
+        None
+    }
+
+    /// Average execution price of a market order, positive amount buys.
+    pub fn fill_price(&self, amount: Decimal) -> Option<Decimal> {
+        self.vwap(BookSide::consumed_by(amount), amount)
+    }
+
+    /// (bid volume - ask volume) / (bid volume + ask volume) over top `depth` levels on each side.
+    /// Result is in range [-1, 1], positive values mean more resting bids.
+    pub fn imbalance(&self, depth: usize) -> Option<Decimal> {
+        let bids: Decimal = self.levels(BookSide::Bid).take(depth).map(|x| *x.1).sum();
+        let asks: Decimal = self.levels(BookSide::Ask).take(depth).map(|x| *x.1).sum();
+        let total = bids + asks;
+        if total.is_zero() {
+            return None;
+        }
+        Some((bids - asks) / total)
+    }
+
+    fn apply<T>(
+        &mut self,
+        levels: &[T],
+        timestamp_ns: u64,
+        mut f: impl FnMut(&mut OrderBook, &T) -> Result<(), OrderBookError>,
+    ) -> Result<(), OrderBookError> {
+        if !self.synchronized {
+            return Err(OrderBookError::NotSynchronized(self.market.clone()));
+        }
+        let result: Result<(), OrderBookError> = try {
+            for level in levels {
+                f(self, level)?;
+            }
+            self.check_consistency()?;
+        };
+        match result {
+            Ok(_) => {
+                self.timestamp_ns = timestamp_ns;
+                Ok(())
+            }
+            Err(e) => {
+                self.invalidate();
+                Err(e)
+            }
+        }
+    }
+
+    fn check_consistency(&self) -> Result<(), OrderBookError> {
+        if let (Some((bid, _)), Some((ask, _))) = (self.best_bid(), self.best_ask()) {
+            if bid >= ask {
+                return Err(OrderBookError::Crossed {
+                    market: self.market.clone(),
+                    bid,
+                    ask,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_id(&mut self, level: &L2Level) -> Result<(BookSide, Decimal), OrderBookError> {
+        match self.ids.remove(&level.id) {
+            Some(x) => Ok(x),
+            None => Err(OrderBookError::UnknownLevel {
+                market: self.market.clone(),
+                id: level.id,
+            }),
+        }
+    }
+
+    fn level_price(&self, level: &L2Level) -> Result<Decimal, OrderBookError> {
+        match (level.price, &self.decoder) {
+            (Some(price), _) => Ok(price),
+            (None, Some(decoder)) => Ok(decoder.price(level.id)),
+            (None, None) => Err(OrderBookError::MissingPrice {
+                market: self.market.clone(),
+                id: level.id,
+            }),
+        }
+    }
+
+    fn level_size(&self, level: &L2Level) -> Result<Decimal, OrderBookError> {
+        match level.size {
+            Some(size) => Ok(size.abs()),
+            None => Err(OrderBookError::MissingSize {
+                market: self.market.clone(),
+                id: level.id,
+            }),
+        }
+    }
+
+    fn side(&self, side: BookSide) -> &BTreeMap<Decimal, Decimal> {
+        match side {
+            BookSide::Bid => &self.bids,
+            BookSide::Ask => &self.asks,
+        }
+    }
+
+    fn side_mut(&mut self, side: BookSide) -> &mut BTreeMap<Decimal, Decimal> {
+        match side {
+            BookSide::Bid => &mut self.bids,
+            BookSide::Ask => &mut self.asks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod t_order_book {
+    use mouse::num::dec;
+    use test_helper::*;
+
+    use super::*;
+
+    fn level(id: i64, side: BookSide, price: Option<Decimal>, size: Option<Decimal>) -> L2Level {
+        L2Level {
+            id,
+            side,
+            price,
+            size,
+        }
+    }
+
+    fn snapshot() -> OrderBook {
+        let mut book = OrderBook::with_decoder("XBTUSD", PriceDecoder::new(88, dec!(0.01)));
+        book.apply_snapshot(
+            &[
+                level(1, BookSide::Ask, Some(dec!(101)), Some(dec!(10))),
+                level(2, BookSide::Ask, Some(dec!(102)), Some(dec!(20))),
+                level(3, BookSide::Bid, Some(dec!(100)), Some(dec!(30))),
+                level(4, BookSide::Bid, Some(dec!(99)), Some(dec!(40))),
+            ],
+            1,
+        )
+        .unwrap();
+        book
+    }
+
+    #[test]
+    fn t_price_decoder() {
+        let decoder = PriceDecoder::new(88, dec!(0.01));
+        // Example from BitMEX documentation.
+        a_eq!(decoder.price(8799999900), dec!(1.00));
+    }
+
+    #[test]
+    fn t_queries() {
+        configure_logging_once();
+        let book = snapshot();
+        a_eq!(book.best_bid(), Some((dec!(100), dec!(30))));
+        a_eq!(book.best_ask(), Some((dec!(101), dec!(10))));
+        a_eq!(book.spread(), Some(dec!(1)));
+        a_eq!(book.mid_price(), Some(dec!(100.5)));
+        a_eq!(book.depth_at(BookSide::Ask, dec!(102)), dec!(20));
+        a_eq!(book.depth_at(BookSide::Ask, dec!(103)), dec!(0));
+        // 10 @ 101 + 10 @ 102
+        a_eq!(book.fill_price(dec!(20)), Some(dec!(101.5)));
+        a_eq!(book.fill_price(dec!(-30)), Some(dec!(100)));
+        a_eq!(book.fill_price(dec!(31)), None);
+        // (30 - 10) / 40
+        a_eq!(book.imbalance(1), Some(dec!(0.5)));
+    }
+
+    #[test]
+    fn t_deltas() {
+        configure_logging_once();
+        let mut book = snapshot();
+        book.update(&[level(1, BookSide::Ask, None, Some(dec!(5)))], 2)
+            .unwrap();
+        a_eq!(book.best_ask(), Some((dec!(101), dec!(5))));
+        book.delete(&[level(1, BookSide::Ask, None, None)], 3)
+            .unwrap();
+        a_eq!(book.best_ask(), Some((dec!(102), dec!(20))));
+        // Price is decoded from id.
+        book.insert(&[level(8800000000 - 10050, BookSide::Ask, None, Some(dec!(1)))], 4)
+            .unwrap();
+        a_eq!(book.best_ask(), Some((dec!(100.5), dec!(1))));
+        a_eq!(book.timestamp_ns(), 4);
+    }
+
+    #[test]
+    fn t_replay() {
+        configure_logging_once();
+        // Snapshot was taken after the first two deltas have been applied on the exchange.
+        let mut book = snapshot();
+        book.replay(
+            &[
+                (
+                    BookAction::Insert,
+                    level(3, BookSide::Bid, Some(dec!(100)), Some(dec!(30))),
+                ),
+                (BookAction::Delete, level(5, BookSide::Bid, None, None)),
+                (
+                    BookAction::Update,
+                    level(1, BookSide::Ask, None, Some(dec!(7))),
+                ),
+                (
+                    BookAction::Insert,
+                    level(6, BookSide::Bid, Some(dec!(100.5)), Some(dec!(2))),
+                ),
+                (BookAction::Delete, level(4, BookSide::Bid, None, None)),
+            ],
+            2,
+        )
+        .unwrap();
+        a_eq!(book.best_ask(), Some((dec!(101), dec!(7))));
+        a_eq!(book.best_bid(), Some((dec!(100.5), dec!(2))));
+        a_eq!(book.depth_at(BookSide::Bid, dec!(100)), dec!(30));
+        a_eq!(book.depth_at(BookSide::Bid, dec!(99)), dec!(0));
+        a_eq!(book.timestamp_ns(), 2);
+
+        let result = book.replay(
+            &[(
+                BookAction::Insert,
+                level(7, BookSide::Bid, Some(dec!(102)), Some(dec!(1))),
+            )],
+            3,
+        );
+        assert!(matches!(result, Err(OrderBookError::Crossed { .. })));
+        assert!(!book.is_synchronized());
+    }
+
+    #[test]
+    fn t_inconsistency_invalidates() {
+        configure_logging_once();
+        let mut book = snapshot();
+        let result = book.update(&[level(42, BookSide::Ask, None, Some(dec!(1)))], 2);
+        assert!(matches!(result, Err(OrderBookError::UnknownLevel { id: 42, .. })));
+        assert!(!book.is_synchronized());
+        assert!(matches!(
+            book.delete(&[level(1, BookSide::Ask, None, None)], 3),
+            Err(OrderBookError::NotSynchronized(_))
+        ));
+
+        let mut book = snapshot();
+        let result = book.insert(&[level(5, BookSide::Bid, Some(dec!(101.5)), Some(dec!(1)))], 2);
+        assert!(matches!(result, Err(OrderBookError::Crossed { .. })));
+        assert!(!book.is_synchronized());
+    }
+}
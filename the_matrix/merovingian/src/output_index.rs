@@ -0,0 +1,298 @@
+//! Sidecar index of optimizer output files. Output is a sequence of `BatchHeader`s each followed
+//! by a block of data, the index records where each block starts together with its combinations
+//! and ranges of output values so that blocks can be found and pruned without reading the output.
+//!
+//! Index is stored next to the output as `<output>.idx`. Outputs written by
+//! `IndexedOutputWriter` are indexed while they are written, other ones have to be scanned once.
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use mouse::error::{bail, Result, ResultCtxExt};
+
+use crate::structs::{BatchHeader, RangeInclusive};
+
+const MAGIC: &[u8; 8] = b"MXOIDX02";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexedBlock {
+    pub start_combination: u64,
+    pub end_combination_inclusive: u64,
+    pub n_combinations: u32,
+    /// Position in the output file to seek to, start of a zstd frame if output is compressed.
+    pub offset: u64,
+    /// Number of decompressed bytes between `offset` and the data of the block.
+    pub skip: u64,
+    pub block_size: u64,
+    /// Min and max of each output value in the block.
+    pub ranges: Vec<RangeInclusive<f32>>,
+}
+
+impl IndexedBlock {
+    pub fn contains_combination(&self, combination: u64) -> bool {
+        combination >= self.start_combination && combination <= self.end_combination_inclusive
+    }
+
+    /// False if none of the values in the block can be in all of the ranges, `ranges` are pairs
+    /// of output value index and range.
+    pub fn may_contain(&self, ranges: &[(usize, RangeInclusive<f32>)]) -> bool {
+        ranges
+            .iter()
+            .all(|(i, range)| self.ranges[*i].overlaps(range))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutputIndex {
+    pub n_output_params: usize,
+    /// Size and modification time of the output file when the index has been saved, used to
+    /// detect stale indexes.
+    pub output_len: u64,
+    pub output_modified_ns: u64,
+    /// Blocks in the order they are stored in the output.
+    pub blocks: Vec<IndexedBlock>,
+}
+
+impl OutputIndex {
+    pub fn new(n_output_params: usize) -> Self {
+        Self {
+            n_output_params,
+            output_len: 0,
+            output_modified_ns: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Where the index of `output` is stored.
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = OsString::from(output.as_os_str());
+        path.push(".idx");
+        path.into()
+    }
+
+    /// Adds a block that has been written after `header`, see `IndexedBlock` for `offset` and
+    /// `skip`.
+    pub fn push(&mut self, header: &BatchHeader, offset: u64, skip: u64) -> Result<()> {
+        if header.ranges.len() != self.n_output_params {
+            bail!(
+                "Block has {} output values, index has {}.",
+                header.ranges.len(),
+                self.n_output_params
+            );
+        }
+        self.blocks.push(IndexedBlock {
+            start_combination: header.start_combination,
+            end_combination_inclusive: header.end_combination_inclusive,
+            n_combinations: header.n_combinations,
+            offset,
+            skip,
+            block_size: header.block_size,
+            ranges: header.ranges.clone(),
+        });
+        Ok(())
+    }
+
+    /// Block that can contain `combination`.
+    pub fn find(&self, combination: u64) -> Option<&IndexedBlock> {
+        self.blocks
+            .iter()
+            .find(|x| x.contains_combination(combination))
+    }
+
+    /// Min and max of each output value in the whole output.
+    pub fn ranges(&self) -> Vec<RangeInclusive<f32>> {
+        let mut ranges = vec![RangeInclusive::new(f32::MAX, f32::MIN); self.n_output_params];
+        for block in &self.blocks {
+            for (range, block_range) in ranges.iter_mut().zip(&block.ranges) {
+                range.start = range.start.min(block_range.start);
+                range.end = range.end.max(block_range.end);
+            }
+        }
+        ranges
+    }
+
+    pub fn n_combinations(&self) -> u64 {
+        self.blocks.iter().map(|x| x.n_combinations as u64).sum()
+    }
+
+    /// Saves the index next to `output` once the output has been written.
+    pub fn save_for(&mut self, output: &Path) -> Result<()> {
+        let (output_len, output_modified_ns) = stamp(output)?;
+        self.output_len = output_len;
+        self.output_modified_ns = output_modified_ns;
+        let mut data = MAGIC.to_vec();
+        bincode::serialize_into(&mut data, self)?;
+        let path = Self::path(output);
+        std::fs::write(&path, data).with_context(|| format!("{}", path.display()))?;
+        Ok(())
+    }
+
+    /// Index of `output`, `None` if it doesn't exist or doesn't match the output anymore.
+    pub fn load_for(output: &Path) -> Result<Option<Self>> {
+        let path = Self::path(output);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("{}", path.display())),
+        };
+        if !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        let index: Self = bincode::deserialize(&data[MAGIC.len()..])
+            .with_context(|| format!("{} is corrupted", path.display()))?;
+        let (output_len, output_modified_ns) = stamp(output)?;
+        Ok(Some(index)
+            .filter(|x| x.output_len == output_len && x.output_modified_ns == output_modified_ns))
+    }
+}
+
+/// Size and modification time of `output` in nanoseconds.
+fn stamp(output: &Path) -> Result<(u64, u64)> {
+    let metadata = std::fs::metadata(output).with_context(|| format!("{}", output.display()))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok((metadata.len(), modified.as_nanos() as u64))
+}
+
+/// Writes an uncompressed output block by block and saves its index when it is finished.
+pub struct IndexedOutputWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    index: OutputIndex,
+    /// Bytes written so far.
+    len: u64,
+}
+
+impl IndexedOutputWriter {
+    pub fn create(path: &Path, n_output_params: usize) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("{}", path.display()))?;
+        Ok(Self {
+            path: path.into(),
+            writer: BufWriter::new(file),
+            index: OutputIndex::new(n_output_params),
+            len: 0,
+        })
+    }
+
+    /// Writes `header` followed by `block`, `block_size` of the header is the length of `block`.
+    pub fn write_block(&mut self, header: &BatchHeader, block: &[u8]) -> Result<()> {
+        if header.block_size != block.len() as u64 {
+            bail!(
+                "Header has block size {}, block has {} bytes.",
+                header.block_size,
+                block.len()
+            );
+        }
+        let header_data = bincode::serialize(header)?;
+        let offset = self.len + header_data.len() as u64;
+        self.index.push(header, offset, 0)?;
+        self.writer.write_all(&header_data)?;
+        self.writer.write_all(block)?;
+        self.len = offset + header.block_size;
+        Ok(())
+    }
+
+    /// Flushes the output and saves its index next to it.
+    pub fn finish(self) -> Result<OutputIndex> {
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        let mut index = self.index;
+        index.save_for(&self.path)?;
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod t_output_index {
+    use super::*;
+
+    fn header(start: u64, end: u64, ranges: &[(f32, f32)]) -> BatchHeader {
+        BatchHeader {
+            start_combination: start,
+            end_combination_inclusive: end,
+            block_size: 100,
+            n_combinations: (end - start + 1) as u32,
+            ranges: ranges
+                .iter()
+                .map(|(start, end)| RangeInclusive::new(*start, *end))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn t_index() -> Result<()> {
+        let mut index = OutputIndex::new(2);
+        index.push(&header(0, 9, &[(0., 1.), (-5., 5.)]), 0, 0)?;
+        index.push(&header(10, 19, &[(2., 3.), (0., 10.)]), 200, 0)?;
+        assert!(index.push(&header(20, 29, &[(0., 1.)]), 400, 0).is_err());
+        assert_eq!(index.find(15).unwrap().offset, 200);
+        assert!(index.find(20).is_none());
+        assert_eq!(index.n_combinations(), 20);
+        assert_eq!(
+            index.ranges(),
+            vec![RangeInclusive::new(0., 3.), RangeInclusive::new(-5., 10.)]
+        );
+        let filter = [(0, RangeInclusive::new(1.5, 2.5))];
+        let pruned: Vec<_> = index
+            .blocks
+            .iter()
+            .map(|x| x.may_contain(&filter))
+            .collect();
+        assert_eq!(pruned, vec![false, true]);
+
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("rsi.out");
+        assert_eq!(OutputIndex::path(&output), dir.path().join("rsi.out.idx"));
+        assert_eq!(OutputIndex::load_for(&output)?, None);
+        std::fs::write(&output, [0; 400])?;
+        index.save_for(&output)?;
+        assert_eq!(OutputIndex::load_for(&output)?, Some(index.clone()));
+        // output has been overwritten
+        std::fs::write(&output, [0; 500])?;
+        assert_eq!(OutputIndex::load_for(&output)?, None);
+        index.save_for(&output)?;
+        assert!(OutputIndex::load_for(&output)?.is_some());
+        // overwritten with the same size
+        let modified = std::fs::metadata(&output)?.modified()?;
+        while std::fs::metadata(&output)?.modified()? == modified {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            std::fs::write(&output, [1; 500])?;
+        }
+        assert_eq!(OutputIndex::load_for(&output)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn t_writer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("rsi.out");
+        let first = header(0, 9, &[(0., 1.)]);
+        let mut second = header(10, 14, &[(2., 3.)]);
+        second.block_size = 50;
+        let mut writer = IndexedOutputWriter::create(&output, 1)?;
+        writer.write_block(&first, &[1; 100])?;
+        assert!(writer.write_block(&second, &[2; 10]).is_err());
+        writer.write_block(&second, &[2; 50])?;
+        let index = writer.finish()?;
+
+        assert_eq!(OutputIndex::load_for(&output)?, Some(index.clone()));
+        let data = std::fs::read(&output)?;
+        let header_len = bincode::serialized_size(&first)?;
+        assert_eq!(index.blocks[0].offset, header_len);
+        assert_eq!(index.blocks[1].offset, 2 * header_len + 100);
+        let block = |i: usize| {
+            let start = index.blocks[i].offset as usize;
+            &data[start..start + index.blocks[i].block_size as usize]
+        };
+        assert_eq!(block(0), [1; 100]);
+        assert_eq!(block(1), [2; 50]);
+        let read: BatchHeader = bincode::deserialize(&data[(header_len + 100) as usize..])?;
+        assert_eq!(read.start_combination, 10);
+        Ok(())
+    }
+}
@@ -0,0 +1,516 @@
+//! Selection logic of parallel coordinates plots without rendering. Each combination is a row with a
+//! value on each axis, rows are selected by brushes, i.e. ranges on axes, and selection is kept as a
+//! bitset over rows. Changing a brush only rechecks rows that it can affect.
+//!
+//! Axes are normalized to 0..1 by their bounds, usually ranges of variables and ranges of output
+//! values from `BatchHeader`s. Order and inversion of axes only change how rows are drawn.
+use mouse::error::{bail, ensure, Result};
+
+use crate::structs::{BatchHeader, RangeInclusive};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitset {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitset {
+    pub fn new(len: usize, value: bool) -> Self {
+        let mut bitset = Self {
+            words: vec![if value { u64::MAX } else { 0 }; (len + 63) / 64],
+            len,
+        };
+        bitset.clear_tail();
+        bitset
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        i < self.len && self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn set(&mut self, i: usize, value: bool) {
+        assert!(i < self.len, "bit {} out of {}", i, self.len);
+        match value {
+            true => self.words[i / 64] |= 1 << (i % 64),
+            false => self.words[i / 64] &= !(1 << (i % 64)),
+        }
+    }
+
+    pub fn push(&mut self, value: bool) {
+        if self.len % 64 == 0 {
+            self.words.push(0);
+        }
+        self.len += 1;
+        self.set(self.len - 1, value);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// Indexes of set bits in ascending order.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || match word {
+                0 => None,
+                _ => {
+                    let bit = word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    Some(i * 64 + bit)
+                }
+            })
+        })
+    }
+
+    /// Words of 64 bits, bits after `len` are zero.
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    fn clear_tail(&mut self) {
+        if self.len % 64 != 0 {
+            if let Some(last) = self.words.last_mut() {
+                *last &= (1 << (self.len % 64)) - 1;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Axis {
+    pub name: String,
+    /// Values that are mapped to 0 and 1.
+    pub bounds: RangeInclusive<f32>,
+    /// Maps `bounds.end` to 0 and `bounds.start` to 1.
+    pub inverted: bool,
+}
+
+impl Axis {
+    pub fn new(name: impl Into<String>, bounds: RangeInclusive<f32>) -> Self {
+        Self {
+            name: name.into(),
+            bounds,
+            inverted: false,
+        }
+    }
+
+    pub fn normalize(&self, value: f32) -> f32 {
+        let range = self.bounds.end - self.bounds.start;
+        let normalized = match range > 0. {
+            true => (value - self.bounds.start) / range,
+            false => 0.5,
+        };
+        match self.inverted {
+            true => 1. - normalized,
+            false => normalized,
+        }
+    }
+
+    pub fn denormalize(&self, normalized: f32) -> f32 {
+        let normalized = match self.inverted {
+            true => 1. - normalized,
+            false => normalized,
+        };
+        self.bounds.start + normalized * (self.bounds.end - self.bounds.start)
+    }
+}
+
+pub struct ParallelCoordinatesFilter {
+    axes: Vec<Axis>,
+    /// Axes in the order they are drawn.
+    order: Vec<usize>,
+    combinations: Vec<u64>,
+    /// Values of rows, `axes.len()` values per row.
+    values: Vec<f32>,
+    brushes: Vec<Option<RangeInclusive<f32>>>,
+    /// Rows outside of the brush of each brushed axis.
+    rejected: Vec<Option<Bitset>>,
+    selection: Bitset,
+}
+
+impl ParallelCoordinatesFilter {
+    pub fn new(axes: Vec<Axis>) -> Self {
+        let n_axes = axes.len();
+        Self {
+            axes,
+            order: (0..n_axes).collect(),
+            combinations: Vec::new(),
+            values: Vec::new(),
+            brushes: vec![None; n_axes],
+            rejected: vec![None; n_axes],
+            selection: Bitset::default(),
+        }
+    }
+
+    pub fn axes(&self) -> &[Axis] {
+        &self.axes
+    }
+
+    pub fn axis(&self, name: &str) -> Option<usize> {
+        self.axes.iter().position(|x| x.name == name)
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.combinations.len()
+    }
+
+    /// Sets bounds of axes starting with `first_axis` to ranges of output values in `headers`.
+    pub fn set_bounds_from_headers<'a>(
+        &mut self,
+        first_axis: usize,
+        headers: impl IntoIterator<Item = &'a BatchHeader>,
+    ) -> Result<()> {
+        let mut ranges: Vec<RangeInclusive<f32>> = Vec::new();
+        for header in headers {
+            if ranges.is_empty() {
+                ranges = header.ranges.clone();
+            }
+            ensure!(
+                header.ranges.len() == ranges.len(),
+                "Headers have different number of output values."
+            );
+            for (range, header_range) in ranges.iter_mut().zip(&header.ranges) {
+                range.start = range.start.min(header_range.start);
+                range.end = range.end.max(header_range.end);
+            }
+        }
+        ensure!(
+            first_axis + ranges.len() <= self.axes.len(),
+            "Headers have {} output values, there are only {} axes after axis {}.",
+            ranges.len(),
+            self.axes.len() - first_axis.min(self.axes.len()),
+            first_axis
+        );
+        for (axis, range) in self.axes[first_axis..].iter_mut().zip(ranges) {
+            axis.bounds = range;
+        }
+        Ok(())
+    }
+
+    /// Adds a row, it is selected if it's inside of all brushes.
+    pub fn push(&mut self, combination: u64, values: &[f32]) -> Result<()> {
+        ensure!(
+            values.len() == self.axes.len(),
+            "Row has {} values, there are {} axes.",
+            values.len(),
+            self.axes.len()
+        );
+        let mut selected = true;
+        for (i, value) in values.iter().enumerate() {
+            if let (Some(brush), Some(rejected)) = (&self.brushes[i], &mut self.rejected[i]) {
+                let inside = brush.contains(value);
+                rejected.push(!inside);
+                selected &= inside;
+            }
+        }
+        self.selection.push(selected);
+        self.combinations.push(combination);
+        self.values.extend_from_slice(values);
+        Ok(())
+    }
+
+    pub fn value(&self, row: usize, axis: usize) -> f32 {
+        self.values[row * self.axes.len() + axis]
+    }
+
+    pub fn combination(&self, row: usize) -> u64 {
+        self.combinations[row]
+    }
+
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// Draws axes in `order`, it has to contain each axis once.
+    pub fn reorder(&mut self, order: Vec<usize>) -> Result<()> {
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        if sorted != (0..self.axes.len()).collect::<Vec<_>>() {
+            bail!(
+                "Order {:?} isn't a permutation of {} axes.",
+                order,
+                self.axes.len()
+            );
+        }
+        self.order = order;
+        Ok(())
+    }
+
+    /// Moves axis drawn at `from` to position `to`.
+    pub fn move_axis(&mut self, from: usize, to: usize) -> Result<()> {
+        ensure!(
+            from < self.order.len() && to < self.order.len(),
+            "Can't move axis from {} to {}, there are {} axes.",
+            from,
+            to,
+            self.order.len()
+        );
+        let axis = self.order.remove(from);
+        self.order.insert(to, axis);
+        Ok(())
+    }
+
+    pub fn invert(&mut self, axis: usize, inverted: bool) {
+        self.axes[axis].inverted = inverted;
+    }
+
+    pub fn brush(&self, axis: usize) -> Option<RangeInclusive<f32>> {
+        self.brushes[axis]
+    }
+
+    /// Selects only rows with value of `axis` in `brush`, `None` removes the brush. Only rows that
+    /// can change are rechecked when the brush is narrowed or widened.
+    pub fn set_brush(&mut self, axis: usize, brush: Option<RangeInclusive<f32>>) {
+        let old = std::mem::replace(&mut self.brushes[axis], brush);
+        let n_axes = self.axes.len();
+        let values = &self.values;
+        let value = |row: usize| values[row * n_axes + axis];
+        match (old, brush) {
+            (_, None) => {
+                self.rejected[axis] = None;
+                self.update_selection();
+            }
+            (Some(old), Some(new)) if old.start <= new.start && new.end <= old.end => {
+                // only rows inside of the old brush can be rejected
+                let rejected = self.rejected[axis].as_mut().unwrap();
+                for row in 0..self.combinations.len() {
+                    if !rejected.contains(row) && !new.contains(&value(row)) {
+                        rejected.set(row, true);
+                        self.selection.set(row, false);
+                    }
+                }
+            }
+            (Some(old), Some(new)) if new.start <= old.start && old.end <= new.end => {
+                // only rows outside of the old brush can be accepted
+                let rejected = self.rejected[axis].as_mut().unwrap();
+                let rows: Vec<_> = rejected.ones().collect();
+                for row in rows {
+                    if new.contains(&value(row)) {
+                        rejected.set(row, false);
+                    }
+                }
+                self.update_selection();
+            }
+            (_, Some(new)) => {
+                let mut rejected = Bitset::new(self.combinations.len(), false);
+                for row in 0..self.combinations.len() {
+                    if !new.contains(&value(row)) {
+                        rejected.set(row, true);
+                    }
+                }
+                self.rejected[axis] = Some(rejected);
+                self.update_selection();
+            }
+        }
+    }
+
+    /// Brush in normalized coordinates of `axis`, e.g. as dragged on the plot.
+    pub fn set_normalized_brush(&mut self, axis: usize, brush: RangeInclusive<f32>) {
+        let a = self.axes[axis].denormalize(brush.start);
+        let b = self.axes[axis].denormalize(brush.end);
+        self.set_brush(axis, Some(RangeInclusive::new(a.min(b), a.max(b))));
+    }
+
+    pub fn clear_brushes(&mut self) {
+        for axis in 0..self.axes.len() {
+            self.brushes[axis] = None;
+            self.rejected[axis] = None;
+        }
+        self.update_selection();
+    }
+
+    /// Selected rows.
+    pub fn selection(&self) -> &Bitset {
+        &self.selection
+    }
+
+    pub fn count(&self) -> usize {
+        self.selection.count_ones()
+    }
+
+    pub fn selected_combinations(&self) -> impl Iterator<Item = u64> + '_ {
+        self.selection.ones().map(move |x| self.combinations[x])
+    }
+
+    /// Number of selected rows in each of `n_bins` bins from `bounds.start` to `bounds.end` of
+    /// `axis`, values outside of bounds are counted in the first or the last bin.
+    pub fn histogram(&self, axis: usize, n_bins: usize) -> Vec<u32> {
+        let mut bins = vec![0; n_bins];
+        if n_bins == 0 {
+            return bins;
+        }
+        let bounds = self.axes[axis].bounds;
+        let range = bounds.end - bounds.start;
+        for row in self.selection.ones() {
+            let value = self.value(row, axis);
+            if value.is_nan() {
+                continue;
+            }
+            let normalized = match range > 0. {
+                true => (value - bounds.start) / range,
+                false => 0.,
+            };
+            let bin = (normalized * n_bins as f32).max(0.) as usize;
+            bins[bin.min(n_bins - 1)] += 1;
+        }
+        bins
+    }
+
+    /// Histograms of all axes in the order they are drawn.
+    pub fn histograms(&self, n_bins: usize) -> Vec<Vec<u32>> {
+        self.order
+            .iter()
+            .map(|axis| self.histogram(*axis, n_bins))
+            .collect()
+    }
+
+    /// Normalized values of every `nth` selected row in the order axes are drawn, one value per
+    /// axis for each row.
+    pub fn polylines(&self, nth: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        for row in self.selection.ones().step_by(nth.max(1)) {
+            out.extend(
+                self.order
+                    .iter()
+                    .map(|axis| self.axes[*axis].normalize(self.value(row, *axis))),
+            );
+        }
+        out
+    }
+
+    /// Selects rows that aren't rejected by any brush.
+    fn update_selection(&mut self) {
+        let mut selection = Bitset::new(self.combinations.len(), true);
+        for rejected in self.rejected.iter().flatten() {
+            for (word, rejected) in selection.words.iter_mut().zip(&rejected.words) {
+                *word &= !rejected;
+            }
+        }
+        self.selection = selection;
+    }
+}
+
+#[cfg(test)]
+mod t_parallel_coordinates {
+    use super::*;
+
+    fn filter() -> ParallelCoordinatesFilter {
+        let mut filter = ParallelCoordinatesFilter::new(vec![
+            Axis::new("period", RangeInclusive::new(0., 10.)),
+            Axis::new("profit", RangeInclusive::new(0., 0.)),
+        ]);
+        for i in 0..100u64 {
+            filter.push(i, &[(i % 10) as f32, i as f32 / 100.]).unwrap();
+        }
+        filter
+    }
+
+    /// Selection recomputed from scratch.
+    fn expected(filter: &ParallelCoordinatesFilter) -> Vec<usize> {
+        (0..filter.n_rows())
+            .filter(|row| {
+                (0..filter.axes().len()).all(|axis| match filter.brush(axis) {
+                    Some(brush) => brush.contains(&filter.value(*row, axis)),
+                    None => true,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn t_bitset() {
+        let mut bitset = Bitset::new(70, true);
+        assert_eq!(bitset.count_ones(), 70);
+        bitset.set(3, false);
+        bitset.push(true);
+        bitset.push(false);
+        assert_eq!(bitset.len(), 72);
+        assert!(!bitset.contains(3) && bitset.contains(70) && !bitset.contains(71));
+        assert_eq!(bitset.ones().count(), 70);
+        assert_eq!(bitset.ones().nth(3), Some(4));
+        assert_eq!(Bitset::new(3, true).words(), &[0b111]);
+    }
+
+    #[test]
+    fn t_brushes() {
+        let mut filter = filter();
+        assert_eq!(filter.count(), 100);
+        filter.set_brush(0, Some(RangeInclusive::new(2., 5.)));
+        assert_eq!(filter.count(), 40);
+        filter.set_brush(1, Some(RangeInclusive::new(0.3, 0.6)));
+        assert_eq!(filter.count(), 12);
+        assert_eq!(filter.selected_combinations().next(), Some(32));
+        let steps = [
+            // narrowed
+            (0, Some((3., 4.))),
+            // widened
+            (1, Some((0.1, 0.9))),
+            (0, Some((0., 9.))),
+            // moved
+            (1, Some((0.5, 0.95))),
+            (0, None),
+            (0, Some((7., 8.))),
+        ];
+        for (axis, brush) in steps.iter() {
+            filter.set_brush(*axis, brush.map(|(a, b)| RangeInclusive::new(a, b)));
+            assert_eq!(
+                filter.selection().ones().collect::<Vec<_>>(),
+                expected(&filter)
+            );
+        }
+        assert_eq!(filter.count(), 8);
+        filter.push(100, &[7., 0.6]).unwrap();
+        filter.push(101, &[7., 0.1]).unwrap();
+        assert!(filter.push(102, &[7.]).is_err());
+        assert_eq!(filter.count(), 9);
+        assert_eq!(
+            filter.selection().ones().collect::<Vec<_>>(),
+            expected(&filter)
+        );
+        filter.clear_brushes();
+        assert_eq!(filter.count(), 102);
+    }
+
+    #[test]
+    fn t_axes() {
+        let mut filter = filter();
+        let headers = [
+            BatchHeader {
+                ranges: vec![RangeInclusive::new(0., 0.5)],
+                ..Default::default()
+            },
+            BatchHeader {
+                ranges: vec![RangeInclusive::new(0.5, 1.)],
+                ..Default::default()
+            },
+        ];
+        filter.set_bounds_from_headers(1, &headers).unwrap();
+        assert!(filter.set_bounds_from_headers(2, &headers).is_err());
+        assert_eq!(filter.axes()[1].bounds, RangeInclusive::new(0., 1.));
+
+        filter.invert(0, true);
+        filter.set_normalized_brush(0, RangeInclusive::new(0.75, 1.));
+        assert_eq!(filter.brush(0), Some(RangeInclusive::new(0., 2.5)));
+        assert_eq!(filter.count(), 30);
+        assert_eq!(filter.histogram(0, 5), vec![20, 10, 0, 0, 0]);
+        assert_eq!(filter.histogram(1, 2), vec![15, 15]);
+
+        assert!(filter.reorder(vec![0, 0]).is_err());
+        filter.reorder(vec![1, 0]).unwrap();
+        assert_eq!(filter.histograms(2), vec![vec![15, 15], vec![30, 0]]);
+        assert_eq!(&filter.polylines(1)[..4], &[0., 1., 0.01, 0.9]);
+        assert_eq!(filter.polylines(10).len(), 3 * 2);
+        filter.move_axis(1, 0).unwrap();
+        assert_eq!(filter.order(), &[0, 1]);
+        assert!(filter.move_axis(2, 0).is_err());
+    }
+}
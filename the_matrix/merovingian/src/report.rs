@@ -0,0 +1,1010 @@
+//! Backtest reports rendered on CPU so that they can be generated without tank, e.g. by nightly
+//! jobs. Report of one backtest contains its equity and drawdown charts, stats, monthly returns and
+//! trades. Report of several backtests ranks them by a stat and adds tables of top combinations and
+//! of how the stat depends on each variable, charts and trades are shown for the best one.
+//!
+//! Backtests are given as `BacktestRun`s, nothing in this crate produces them. Whatever runs a
+//! backtest serializes its result as described there, e.g. into a JSON file for `matrix_report`.
+//!
+//! Reports are rendered from templates in `report_template_dir`, `backtest.html` or `top.html`
+//! (`.md` for Markdown). Template is text with `{{placeholder}}`s, see `PLACEHOLDERS`. Built-in
+//! template is used if the file doesn't exist.
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{Datelike, TimeZone, Utc};
+use mouse::error::{anyhow, bail, Result, ResultCtxExt};
+
+use crate::account::StatAccount;
+use crate::non_minable_models::BacktestPlotFlags;
+
+pub const PLACEHOLDERS: &[&str] = &[
+    "style",
+    "title",
+    "exchange",
+    "market",
+    "model",
+    "period",
+    "generated",
+    "metric",
+    "variables",
+    "stats",
+    "equity_chart",
+    "drawdown_chart",
+    "trade_curve_chart",
+    "monthly_returns",
+    "trades",
+    "runs",
+    "sensitivity",
+];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+/// Charts are decimated to this many points.
+const MAX_CHART_POINTS: usize = 2000;
+const CHART_WIDTH: f64 = 800.;
+const CHART_HEIGHT: f64 = 240.;
+const CHART_PADDING: (f64, f64, f64, f64) = (24., 10., 24., 70.); // top, right, bottom, left
+/// Stats that are better when they are lower, others are better when higher.
+const LOWER_IS_BETTER: &[&str] = &[
+    "max_drawdown",
+    "avg_bars_in_loss_trades",
+    "n_loss_trades",
+    "max_n_streak_loss",
+    "volatility_p",
+];
+
+/// Backtested combination of a model, input of a report. Stats that weren't computed can be
+/// omitted, they are `NaN` and shown as `-`. Timestamps are in seconds, returns in %, e.g. in JSON:
+///
+/// ```json
+/// {
+///   "exchange": "BitMEX",
+///   "market": "XBTUSD",
+///   "model": "rsi",
+///   "variables": [["period", 14], ["level", 30]],
+///   "stats": { "balance": 1.8, "max_drawdown": 0.2, "n_trades": 1 },
+///   "equity": [
+///     { "timestamp_s": 1609459200, "balance": 1 },
+///     { "timestamp_s": 1615507200, "balance": 1.8 }
+///   ],
+///   "trades": [
+///     {
+///       "entry_timestamp_s": 1609459200,
+///       "exit_timestamp_s": 1615507200,
+///       "entry_price": 30000,
+///       "exit_price": 36000,
+///       "position": 1,
+///       "balance": 1.8,
+///       "return_p": 80
+///     }
+///   ]
+/// }
+/// ```
+///
+/// `trades` may be omitted. Equity should start with the initial balance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BacktestRun {
+    pub exchange: String,
+    pub market: String,
+    pub model: String,
+    /// Names and values of variables.
+    pub variables: Vec<(String, f32)>,
+    pub stats: StatAccount,
+    /// Balance over time, e.g. after each candle.
+    pub equity: Vec<EquityPoint>,
+    #[serde(default)]
+    pub trades: Vec<ClosedTrade>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EquityPoint {
+    pub timestamp_s: u32,
+    pub balance: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClosedTrade {
+    pub entry_timestamp_s: u32,
+    pub exit_timestamp_s: u32,
+    pub entry_price: f32,
+    pub exit_price: f32,
+    /// Negative for shorts.
+    pub position: f32,
+    /// Balance after the trade.
+    pub balance: f32,
+    pub return_p: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Html,
+    Markdown,
+}
+
+impl ReportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Html => "html",
+            ReportFormat::Markdown => "md",
+        }
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = mouse::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "html" => ReportFormat::Html,
+            "md" | "markdown" => ReportFormat::Markdown,
+            _ => bail!("Unknown report format {}, use html or md.", s),
+        })
+    }
+}
+
+/// Whether runs with the highest or the lowest metric are the best.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rank {
+    Highest,
+    Lowest,
+}
+
+impl Rank {
+    /// Lowest for stats that measure losses or risk, e.g. `max_drawdown`, highest otherwise.
+    pub fn of(metric: &str) -> Self {
+        match LOWER_IS_BETTER.contains(&metric) {
+            true => Rank::Lowest,
+            false => Rank::Highest,
+        }
+    }
+
+    /// Orders better values first, `NaN`s are equal to everything.
+    pub fn compare(&self, a: f32, b: f32) -> Ordering {
+        let ordering = match self {
+            Rank::Highest => b.partial_cmp(&a),
+            Rank::Lowest => a.partial_cmp(&b),
+        };
+        ordering.unwrap_or(Ordering::Equal)
+    }
+}
+
+impl FromStr for Rank {
+    type Err = mouse::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "highest" | "desc" => Rank::Highest,
+            "lowest" | "asc" => Rank::Lowest,
+            _ => bail!("Unknown rank {}, use highest or lowest.", s),
+        })
+    }
+}
+
+pub struct Report {
+    runs: Vec<BacktestRun>,
+    format: ReportFormat,
+    flags: BacktestPlotFlags,
+    metric: String,
+    rank: Option<Rank>,
+    name: String,
+}
+
+impl Report {
+    /// Report of one backtest or of top combinations if there are more runs. By default it is an
+    /// HTML report named `report`, runs are ranked by `balance` and charts have dates on x axis.
+    pub fn new(runs: Vec<BacktestRun>) -> Self {
+        Self {
+            runs,
+            format: ReportFormat::Html,
+            flags: BacktestPlotFlags::TIMESTAMP,
+            metric: "balance".into(),
+            rank: None,
+            name: "report".into(),
+        }
+    }
+
+    pub fn format(mut self, format: ReportFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// `TIMESTAMP` puts dates on x axis instead of indexes and `TRADE_CURVE` adds chart of balance
+    /// after each trade, other flags are only plotted by tank.
+    pub fn flags(mut self, flags: BacktestPlotFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Field of `StatAccount` that runs are ranked by, in direction of `Rank::of` unless it is set.
+    pub fn metric(mut self, metric: impl Into<String>) -> Self {
+        self.metric = metric.into();
+        self
+    }
+
+    pub fn rank(mut self, rank: Rank) -> Self {
+        self.rank = Some(rank);
+        self
+    }
+
+    /// File name of the report without extension.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Writes report into `<reports_dir>/<exchange>/<market>/<model>/`, Markdown reports have their
+    /// charts in separate SVG files. Returns path of the report.
+    pub fn write(&self, reports_dir: &Path, template_dir: &Path) -> Result<PathBuf> {
+        let best = match self.runs.first() {
+            Some(best) => best,
+            None => bail!("Report needs at least one backtest."),
+        };
+        let kind = match self.runs.len() {
+            1 => "backtest",
+            _ => "top",
+        };
+        let template_path = template_dir.join(format!("{}.{}", kind, self.format.extension()));
+        let template = match std::fs::read_to_string(&template_path) {
+            Ok(template) => template,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                default_template(kind, self.format).into()
+            }
+            Err(e) => return Err(e).with_context(|| format!("{}", template_path.display())),
+        };
+        let dir = reports_dir
+            .join(&best.exchange)
+            .join(&best.market)
+            .join(&best.model);
+        std::fs::create_dir_all(&dir).with_context(|| format!("{}", dir.display()))?;
+        let (report, charts) = self.render(&template)?;
+        for (file, svg) in charts {
+            std::fs::write(dir.join(file), svg)?;
+        }
+        let path = dir.join(format!("{}.{}", self.name, self.format.extension()));
+        std::fs::write(&path, report).with_context(|| format!("{}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Rendered report and SVG files it links to.
+    fn render(&self, template: &str) -> Result<(String, Vec<(String, String)>)> {
+        if StatAccount::NAMES.iter().all(|x| *x != self.metric) {
+            bail!("Unknown metric {}.", self.metric);
+        }
+        let rank = self.rank.unwrap_or_else(|| Rank::of(&self.metric));
+        let mut runs: Vec<_> = self.runs.iter().collect();
+        let metric = |run: &BacktestRun| run.stats.get(&self.metric).unwrap();
+        runs.sort_by(|a, b| rank.compare(metric(a), metric(b)));
+        let best = runs[0];
+        let mut values = HashMap::new();
+        let title = match runs.len() {
+            1 => format!("{} on {} {}", best.model, best.exchange, best.market),
+            n => format!(
+                "Top {} combinations of {} on {} {}",
+                n, best.model, best.exchange, best.market
+            ),
+        };
+        values.insert(
+            "style",
+            match self.format {
+                ReportFormat::Html => STYLE.into(),
+                ReportFormat::Markdown => String::new(),
+            },
+        );
+        values.insert("title", self.escape(&title));
+        values.insert("exchange", self.escape(&best.exchange));
+        values.insert("market", self.escape(&best.market));
+        values.insert("model", self.escape(&best.model));
+        values.insert(
+            "period",
+            match (best.equity.first(), best.equity.last()) {
+                (Some(first), Some(last)) => format!(
+                    "{} - {}",
+                    date(first.timestamp_s, "%Y-%m-%d"),
+                    date(last.timestamp_s, "%Y-%m-%d")
+                ),
+                _ => String::new(),
+            },
+        );
+        values.insert(
+            "generated",
+            Utc::now().format("%Y-%m-%d %H:%M UTC").to_string(),
+        );
+        values.insert("metric", self.escape(&self.metric));
+        values.insert(
+            "variables",
+            self.table(
+                vec!["Variable".into(), "Value".into()],
+                best.variables
+                    .iter()
+                    .map(|(name, value)| vec![name.clone(), number(*value)])
+                    .collect(),
+            ),
+        );
+        values.insert(
+            "stats",
+            self.table(
+                vec!["Stat".into(), "Value".into()],
+                StatAccount::field_names_to_plot()
+                    .map(|x| vec![x.to_string(), number(best.stats.get(x).unwrap())])
+                    .collect(),
+            ),
+        );
+
+        let mut charts = Vec::new();
+        let time_axis = self.flags.contains(BacktestPlotFlags::TIMESTAMP);
+        let x = |i: usize, timestamp_s: u32| match time_axis {
+            true => timestamp_s as f64,
+            false => i as f64,
+        };
+        let equity: Vec<_> = best
+            .equity
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (x(i, p.timestamp_s), p.balance))
+            .collect();
+        let drawdown: Vec<_> = best
+            .equity
+            .iter()
+            .zip(drawdowns(&best.equity))
+            .enumerate()
+            .map(|(i, (p, drawdown))| (x(i, p.timestamp_s), drawdown))
+            .collect();
+        let trade_curve: Vec<_> = best
+            .trades
+            .iter()
+            .enumerate()
+            .map(|(i, trade)| (x(i, trade.exit_timestamp_s), trade.balance))
+            .collect();
+        for (placeholder, title, points, enabled) in [
+            ("equity_chart", "Equity", equity, true),
+            ("drawdown_chart", "Drawdown %", drawdown, true),
+            (
+                "trade_curve_chart",
+                "Trade Curve",
+                trade_curve,
+                self.flags.contains(BacktestPlotFlags::TRADE_CURVE),
+            ),
+        ] {
+            if !enabled {
+                values.insert(placeholder, String::new());
+                continue;
+            }
+            let svg = svg_chart(title, &points, time_axis);
+            let value = match self.format {
+                ReportFormat::Html => svg,
+                ReportFormat::Markdown => {
+                    let file = format!("{}_{}.svg", self.name, placeholder);
+                    let link = format!("![{}]({})", title, file);
+                    charts.push((file, svg));
+                    link
+                }
+            };
+            values.insert(placeholder, value);
+        }
+
+        values.insert(
+            "monthly_returns",
+            self.table(
+                std::iter::once("Year".into())
+                    .chain(MONTHS.iter().map(|x| x.to_string()))
+                    .chain(std::iter::once("Year".into()))
+                    .collect(),
+                monthly_returns(&best.equity)
+                    .into_iter()
+                    .map(|(year, returns)| {
+                        let total = returns
+                            .iter()
+                            .flatten()
+                            .fold(1., |total, x| total * (1. + x / 100.));
+                        std::iter::once(year.to_string())
+                            .chain(returns.iter().map(|x| x.map_or(String::new(), number)))
+                            .chain(std::iter::once(number((total - 1.) * 100.)))
+                            .collect()
+                    })
+                    .collect(),
+            ),
+        );
+        values.insert(
+            "trades",
+            self.table(
+                [
+                    "#",
+                    "Entry",
+                    "Exit",
+                    "Side",
+                    "Entry price",
+                    "Exit price",
+                    "Return %",
+                ]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+                best.trades
+                    .iter()
+                    .enumerate()
+                    .map(|(i, trade)| {
+                        vec![
+                            (i + 1).to_string(),
+                            date(trade.entry_timestamp_s, "%Y-%m-%d %H:%M"),
+                            date(trade.exit_timestamp_s, "%Y-%m-%d %H:%M"),
+                            match trade.position < 0. {
+                                true => "Short".into(),
+                                false => "Long".into(),
+                            },
+                            number(trade.entry_price),
+                            number(trade.exit_price),
+                            number(trade.return_p),
+                        ]
+                    })
+                    .collect(),
+            ),
+        );
+        values.insert("runs", self.runs_table(&runs));
+        values.insert("sensitivity", self.sensitivity(&runs, rank));
+        Ok((render_template(template, &values)?, charts))
+    }
+
+    /// Variables and main stats of each run in order of rank.
+    fn runs_table(&self, runs: &[&BacktestRun]) -> String {
+        if runs.len() < 2 {
+            return String::new();
+        }
+        let mut stats = vec![self.metric.as_str()];
+        for stat in &[
+            "balance",
+            "max_drawdown",
+            "n_trades",
+            "win_rate_p",
+            "sharpe_ratio",
+        ] {
+            if !stats.contains(stat) {
+                stats.push(stat);
+            }
+        }
+        self.table(
+            std::iter::once("Rank".into())
+                .chain(runs[0].variables.iter().map(|x| x.0.clone()))
+                .chain(stats.iter().map(|x| x.to_string()))
+                .collect(),
+            runs.iter()
+                .enumerate()
+                .map(|(i, run)| {
+                    std::iter::once((i + 1).to_string())
+                        .chain(run.variables.iter().map(|x| number(x.1)))
+                        .chain(stats.iter().map(|x| number(run.stats.get(x).unwrap())))
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    /// Table for each variable with the metric of runs grouped by value of the variable.
+    fn sensitivity(&self, runs: &[&BacktestRun], rank: Rank) -> String {
+        if runs.len() < 2 {
+            return String::new();
+        }
+        let mut out = String::new();
+        for (i, (name, _)) in runs[0].variables.iter().enumerate() {
+            // value -> metric of each run with that value
+            let mut groups: Vec<(f32, Vec<f32>)> = Vec::new();
+            for run in runs {
+                let value = match run.variables.get(i) {
+                    Some(x) => x.1,
+                    None => continue,
+                };
+                let metric = run.stats.get(&self.metric).unwrap();
+                match groups.iter_mut().find(|x| x.0 == value) {
+                    Some(group) => group.1.push(metric),
+                    None => groups.push((value, vec![metric])),
+                }
+            }
+            groups.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            out.push_str(&match self.format {
+                ReportFormat::Html => format!("<h3>{}</h3>\n", self.escape(name)),
+                ReportFormat::Markdown => format!("### {}\n\n", name),
+            });
+            out.push_str(
+                &self.table(
+                    vec![
+                        "Value".into(),
+                        "Runs".into(),
+                        format!("Mean {}", self.metric),
+                        format!("Best {}", self.metric),
+                    ],
+                    groups
+                        .iter()
+                        .map(|(value, metrics)| {
+                            let mean = metrics.iter().sum::<f32>() / metrics.len() as f32;
+                            let best = metrics
+                                .iter()
+                                .cloned()
+                                .filter(|x| !x.is_nan())
+                                .min_by(|a, b| rank.compare(*a, *b))
+                                .unwrap_or(f32::NAN);
+                            vec![
+                                number(*value),
+                                metrics.len().to_string(),
+                                number(mean),
+                                number(best),
+                            ]
+                        })
+                        .collect(),
+                ),
+            );
+            out.push('\n');
+        }
+        out
+    }
+
+    fn table(&self, header: Vec<String>, rows: Vec<Vec<String>>) -> String {
+        let mut out = String::new();
+        match self.format {
+            ReportFormat::Html => {
+                out.push_str("<table>\n<tr>");
+                for cell in &header {
+                    out.push_str(&format!("<th>{}</th>", self.escape(cell)));
+                }
+                out.push_str("</tr>\n");
+                for row in &rows {
+                    out.push_str("<tr>");
+                    for cell in row {
+                        out.push_str(&format!("<td>{}</td>", self.escape(cell)));
+                    }
+                    out.push_str("</tr>\n");
+                }
+                out.push_str("</table>\n");
+            }
+            ReportFormat::Markdown => {
+                let row = |cells: &[String]| {
+                    format!(
+                        "| {} |\n",
+                        cells
+                            .iter()
+                            .map(|x| self.escape(x))
+                            .collect::<Vec<_>>()
+                            .join(" | ")
+                    )
+                };
+                out.push_str(&row(&header));
+                out.push_str(&format!("|{}\n", "---|".repeat(header.len())));
+                for cells in &rows {
+                    out.push_str(&row(cells));
+                }
+            }
+        }
+        out
+    }
+
+    fn escape(&self, text: &str) -> String {
+        match self.format {
+            ReportFormat::Html => html_escape(text),
+            ReportFormat::Markdown => text.replace('|', "\\|"),
+        }
+    }
+}
+
+fn default_template(kind: &str, format: ReportFormat) -> &'static str {
+    match (kind, format) {
+        ("backtest", ReportFormat::Html) => BACKTEST_HTML,
+        ("backtest", ReportFormat::Markdown) => BACKTEST_MARKDOWN,
+        (_, ReportFormat::Html) => TOP_HTML,
+        (_, ReportFormat::Markdown) => TOP_MARKDOWN,
+    }
+}
+
+/// Replaces `{{placeholder}}`s with `values`, unknown placeholders are an error so that typos in
+/// templates don't go unnoticed.
+fn render_template(template: &str, values: &HashMap<&str, String>) -> Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("Template has unclosed {{{{."))?
+            + start;
+        let name = rest[start + 2..end].trim();
+        let value = values.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown placeholder {} in template, placeholders are: {}",
+                name,
+                PLACEHOLDERS.join(", ")
+            )
+        })?;
+        out.push_str(value);
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Drawdown in % from the highest balance so far for each point.
+fn drawdowns(equity: &[EquityPoint]) -> Vec<f32> {
+    let mut max = f32::MIN;
+    equity
+        .iter()
+        .map(|x| {
+            max = max.max(x.balance);
+            (x.balance / max - 1.) * 100.
+        })
+        .collect()
+}
+
+/// Returns in % of each month by year, a month starts with the last balance of the previous one.
+fn monthly_returns(equity: &[EquityPoint]) -> BTreeMap<i32, [Option<f32>; 12]> {
+    let mut years = BTreeMap::new();
+    let first = match equity.first() {
+        Some(first) => first,
+        None => return years,
+    };
+    let month = |timestamp_s: u32| {
+        let date = Utc.timestamp(timestamp_s as i64, 0);
+        (date.year(), date.month0() as usize)
+    };
+    let mut current = month(first.timestamp_s);
+    let mut start = first.balance;
+    let mut last = first.balance;
+    for point in equity {
+        let point_month = month(point.timestamp_s);
+        if point_month != current {
+            years.entry(current.0).or_insert([None; 12])[current.1] =
+                Some((last / start - 1.) * 100.);
+            start = last;
+            current = point_month;
+        }
+        last = point.balance;
+    }
+    years.entry(current.0).or_insert([None; 12])[current.1] = Some((last / start - 1.) * 100.);
+    years
+}
+
+/// Line chart, `points` are x and y values.
+fn svg_chart(title: &str, points: &[(f64, f32)], time_axis: bool) -> String {
+    let (top, right, bottom, left) = CHART_PADDING;
+    let step = (points.len() + MAX_CHART_POINTS - 1) / MAX_CHART_POINTS.max(1);
+    let mut decimated: Vec<_> = points.iter().step_by(step.max(1)).collect();
+    if let Some(last) = points.last() {
+        if decimated.last() != Some(&last) {
+            decimated.push(last);
+        }
+    }
+    let (x0, x1) = bounds(decimated.iter().map(|x| x.0));
+    let (y0, y1) = bounds(decimated.iter().map(|x| x.1 as f64));
+    let width = CHART_WIDTH - left - right;
+    let height = CHART_HEIGHT - top - bottom;
+    let scale = |value: f64, min: f64, max: f64| match max > min {
+        true => (value - min) / (max - min),
+        false => 0.5,
+    };
+    let polyline: Vec<_> = decimated
+        .iter()
+        .map(|(x, y)| {
+            format!(
+                "{:.1},{:.1}",
+                left + scale(*x, x0, x1) * width,
+                top + (1. - scale(*y as f64, y0, y1)) * height
+            )
+        })
+        .collect();
+    let x_label = |x: f64| match time_axis {
+        true => date(x as u32, "%Y-%m-%d"),
+        false => format!("{}", x),
+    };
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" font-family=\"sans-serif\" font-size=\"11\">\n",
+        w = CHART_WIDTH,
+        h = CHART_HEIGHT
+    );
+    svg.push_str(&format!(
+        "<text x=\"{}\" y=\"16\" font-size=\"13\">{}</text>\n",
+        left,
+        html_escape(title)
+    ));
+    svg.push_str(&format!(
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#ccc\"/>\n",
+        left, top, width, height
+    ));
+    if !decimated.is_empty() {
+        svg.push_str(&format!(
+            "<text x=\"{x}\" y=\"{y1}\" text-anchor=\"end\">{}</text>\n\
+             <text x=\"{x}\" y=\"{y0}\" text-anchor=\"end\">{}</text>\n",
+            number(y1 as f32),
+            number(y0 as f32),
+            x = left - 4.,
+            y1 = top + 4.,
+            y0 = top + height,
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{y}\">{}</text>\n\
+             <text x=\"{}\" y=\"{y}\" text-anchor=\"end\">{}</text>\n",
+            left,
+            x_label(x0),
+            left + width,
+            x_label(x1),
+            y = CHART_HEIGHT - 8.,
+        ));
+        svg.push_str(&format!(
+            "<polyline fill=\"none\" stroke=\"#1f77b4\" stroke-width=\"1\" points=\"{}\"/>\n",
+            polyline.join(" ")
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Min and max of finite values.
+fn bounds(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values
+        .filter(|x| x.is_finite())
+        .fold((f64::MAX, f64::MIN), |(min, max), x| {
+            (min.min(x), max.max(x))
+        })
+}
+
+fn date(timestamp_s: u32, format: &str) -> String {
+    Utc.timestamp(timestamp_s as i64, 0)
+        .format(format)
+        .to_string()
+}
+
+/// At most 4 decimals without trailing zeros, `-` if it isn't a number.
+fn number(value: f32) -> String {
+    if !value.is_finite() {
+        return "-".into();
+    }
+    let text = format!("{:.4}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" => "0".into(),
+        _ => text.into(),
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STYLE: &str = "<style>\n\
+body { font-family: sans-serif; margin: 2em; }\n\
+table { border-collapse: collapse; margin-bottom: 1em; }\n\
+th, td { border: 1px solid #ccc; padding: 2px 8px; text-align: right; }\n\
+</style>";
+
+const BACKTEST_HTML: &str = concat!(
+    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{{title}}</title>\n",
+    "{{style}}\n</head>\n<body>\n<h1>{{title}}</h1>\n<p>{{period}}, generated {{generated}}</p>\n",
+    "<h2>Variables</h2>\n{{variables}}\n<h2>Equity</h2>\n{{equity_chart}}\n{{drawdown_chart}}\n",
+    "{{trade_curve_chart}}\n<h2>Stats</h2>\n{{stats}}\n<h2>Monthly Returns %</h2>\n",
+    "{{monthly_returns}}\n<h2>Trades</h2>\n{{trades}}\n</body>\n</html>\n"
+);
+
+const TOP_HTML: &str = concat!(
+    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{{title}}</title>\n",
+    "{{style}}\n</head>\n<body>\n<h1>{{title}}</h1>\n<p>{{period}}, ranked by {{metric}}, ",
+    "generated {{generated}}</p>\n<h2>Combinations</h2>\n{{runs}}\n",
+    "<h2>Sensitivity of {{metric}}</h2>\n{{sensitivity}}\n<h2>Best Combination</h2>\n",
+    "{{variables}}\n{{equity_chart}}\n{{drawdown_chart}}\n{{trade_curve_chart}}\n",
+    "<h2>Stats</h2>\n{{stats}}\n<h2>Monthly Returns %</h2>\n{{monthly_returns}}\n",
+    "<h2>Trades</h2>\n{{trades}}\n</body>\n</html>\n"
+);
+
+const BACKTEST_MARKDOWN: &str = concat!(
+    "# {{title}}\n\n{{period}}, generated {{generated}}\n\n## Variables\n\n{{variables}}\n",
+    "## Equity\n\n{{equity_chart}}\n\n{{drawdown_chart}}\n\n{{trade_curve_chart}}\n\n",
+    "## Stats\n\n{{stats}}\n## Monthly Returns %\n\n{{monthly_returns}}\n",
+    "## Trades\n\n{{trades}}"
+);
+
+const TOP_MARKDOWN: &str = concat!(
+    "# {{title}}\n\n{{period}}, ranked by {{metric}}, generated {{generated}}\n\n",
+    "## Combinations\n\n{{runs}}\n## Sensitivity of {{metric}}\n\n{{sensitivity}}",
+    "## Best Combination\n\n{{variables}}\n{{equity_chart}}\n\n{{drawdown_chart}}\n\n",
+    "{{trade_curve_chart}}\n\n## Stats\n\n{{stats}}\n## Monthly Returns %\n\n",
+    "{{monthly_returns}}\n## Trades\n\n{{trades}}"
+);
+
+#[cfg(test)]
+mod t_report {
+    use super::*;
+
+    /// 2021-01-01
+    const START_S: u32 = 1609459200;
+    const DAY_S: u32 = 24 * 60 * 60;
+
+    fn run(period: f32, level: f32, balance: f32) -> BacktestRun {
+        BacktestRun {
+            exchange: "BitMEX".into(),
+            market: "XBTUSD".into(),
+            model: "rsi".into(),
+            variables: vec![("period".into(), period), ("level".into(), level)],
+            stats: StatAccount {
+                balance,
+                max_drawdown: 0.2,
+                ..Default::default()
+            },
+            equity: vec![
+                EquityPoint {
+                    timestamp_s: START_S,
+                    balance: 1.,
+                },
+                EquityPoint {
+                    timestamp_s: START_S + 20 * DAY_S,
+                    balance: 1.2,
+                },
+                EquityPoint {
+                    timestamp_s: START_S + 40 * DAY_S,
+                    balance: 0.9,
+                },
+                EquityPoint {
+                    timestamp_s: START_S + 70 * DAY_S,
+                    balance,
+                },
+            ],
+            trades: vec![ClosedTrade {
+                entry_timestamp_s: START_S,
+                exit_timestamp_s: START_S + 20 * DAY_S,
+                entry_price: 30000.,
+                exit_price: 36000.,
+                position: -1.,
+                balance: 1.2,
+                return_p: 20.,
+            }],
+        }
+    }
+
+    #[test]
+    fn t_computations() {
+        let run = run(14., 30., 1.8);
+        assert_eq!(drawdowns(&run.equity), vec![0., 0., -25.000006, 0.]);
+        let returns = monthly_returns(&run.equity);
+        assert_eq!(returns.len(), 1);
+        let returns = returns[&2021];
+        // January ends with 1.2, February with 0.9 and March with 1.8
+        assert_eq!(number(returns[0].unwrap()), "20");
+        assert_eq!(number(returns[1].unwrap()), "-25");
+        assert_eq!(number(returns[2].unwrap()), "100");
+        assert_eq!(returns[3], None);
+        assert_eq!(number(f32::NAN), "-");
+        assert_eq!(number(-0.00001), "0");
+        assert_eq!(number(1.23456), "1.2346");
+    }
+
+    #[test]
+    fn t_template() {
+        let mut values = HashMap::new();
+        values.insert("title", "rsi".to_string());
+        assert_eq!(
+            render_template("<h1>{{ title }}</h1>{{title}}", &values).unwrap(),
+            "<h1>rsi</h1>rsi"
+        );
+        assert!(render_template("{{titel}}", &values).is_err());
+        assert!(render_template("{{title", &values).is_err());
+        for kind in &["backtest", "top"] {
+            for format in &[ReportFormat::Html, ReportFormat::Markdown] {
+                for placeholder in PLACEHOLDERS {
+                    values.insert(placeholder, String::new());
+                }
+                render_template(default_template(kind, *format), &values).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn t_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let reports_dir = dir.path().join("reports");
+        let template_dir = dir.path().join("templates");
+        std::fs::create_dir_all(&template_dir)?;
+
+        let path = Report::new(vec![run(14., 30., 1.8)]).write(&reports_dir, &template_dir)?;
+        assert_eq!(path, reports_dir.join("BitMEX/XBTUSD/rsi/report.html"));
+        let html = std::fs::read_to_string(&path)?;
+        assert!(html.contains("<h1>rsi on BitMEX XBTUSD</h1>"));
+        assert!(html.contains("<polyline"));
+        assert!(html.contains("<tr><td>balance</td><td>1.8</td></tr>"));
+        assert!(html.contains("<td>Short</td><td>30000</td><td>36000</td><td>20</td>"));
+
+        let runs = vec![run(14., 30., 1.8), run(14., 20., 2.4), run(21., 30., 0.6)];
+        std::fs::write(
+            template_dir.join("top.md"),
+            "{{title}}\n{{runs}}{{sensitivity}}{{equity_chart}}\n",
+        )?;
+        let path = Report::new(runs)
+            .format(ReportFormat::Markdown)
+            .name("nightly")
+            .write(&reports_dir, &template_dir)?;
+        let markdown = std::fs::read_to_string(&path)?;
+        assert_eq!(
+            markdown,
+            "Top 3 combinations of rsi on BitMEX XBTUSD\n\
+             | Rank | period | level | balance | max_drawdown | n_trades | win_rate_p | sharpe_ratio |\n\
+             |---|---|---|---|---|---|---|---|\n\
+             | 1 | 14 | 20 | 2.4 | 0.2 | - | - | - |\n\
+             | 2 | 14 | 30 | 1.8 | 0.2 | - | - | - |\n\
+             | 3 | 21 | 30 | 0.6 | 0.2 | - | - | - |\n\
+             ### period\n\n\
+             | Value | Runs | Mean balance | Best balance |\n\
+             |---|---|---|---|\n\
+             | 14 | 2 | 2.1 | 2.4 |\n\
+             | 21 | 1 | 0.6 | 0.6 |\n\n\
+             ### level\n\n\
+             | Value | Runs | Mean balance | Best balance |\n\
+             |---|---|---|---|\n\
+             | 20 | 1 | 2.4 | 2.4 |\n\
+             | 30 | 2 | 1.2 | 1.8 |\n\n\
+             ![Equity](nightly_equity_chart.svg)\n"
+        );
+        let svg = std::fs::read_to_string(path.with_file_name("nightly_equity_chart.svg"))?;
+        assert!(svg.starts_with("<svg") && svg.contains("2021-01-01"));
+
+        assert!(Report::new(vec![run(14., 30., 1.8)])
+            .metric("profit")
+            .write(&reports_dir, &template_dir)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn t_rank() -> Result<()> {
+        assert_eq!(Rank::of("max_drawdown"), Rank::Lowest);
+        assert_eq!(Rank::of("balance"), Rank::Highest);
+        assert_eq!("asc".parse::<Rank>()?, Rank::Lowest);
+        let runs: Vec<_> = [(14., 0.3), (21., 0.1), (28., 0.2)]
+            .iter()
+            .map(|(period, drawdown)| {
+                let mut run = run(*period, 30., 1.);
+                run.stats.max_drawdown = *drawdown;
+                run
+            })
+            .collect();
+        let report = Report::new(runs)
+            .format(ReportFormat::Markdown)
+            .metric("max_drawdown");
+        let (markdown, _) = report.render("{{runs}}{{sensitivity}}")?;
+        assert!(markdown.contains(
+            "| 1 | 21 | 30 | 0.1 | 1 | - | - | - |\n\
+             | 2 | 28 | 30 | 0.2 | 1 | - | - | - |\n\
+             | 3 | 14 | 30 | 0.3 | 1 | - | - | - |\n"
+        ));
+        assert!(markdown.contains("| 30 | 3 | 0.2 | 0.1 |"));
+
+        let (markdown, _) = report.rank(Rank::Highest).render("{{runs}}")?;
+        assert!(markdown.contains("| 1 | 14 | 30 | 0.3 |"));
+        Ok(())
+    }
+
+    #[test]
+    fn t_input() -> Result<()> {
+        let run: BacktestRun = serde_json::from_str(
+            r#"{
+                "exchange": "BitMEX",
+                "market": "XBTUSD",
+                "model": "rsi",
+                "variables": [["period", 14], ["level", 30]],
+                "stats": { "balance": 1.8, "max_drawdown": 0.2, "n_trades": 1 },
+                "equity": [
+                    { "timestamp_s": 1609459200, "balance": 1 },
+                    { "timestamp_s": 1615507200, "balance": 1.8 }
+                ]
+            }"#,
+        )?;
+        assert_eq!(
+            run.variables,
+            vec![("period".into(), 14.), ("level".into(), 30.)]
+        );
+        assert_eq!(run.stats.n_trades, 1.);
+        assert!(run.stats.sharpe_ratio.is_nan());
+        assert!(run.trades.is_empty());
+        let (html, _) = Report::new(vec![run]).render("{{stats}}")?;
+        assert!(html.contains("<tr><td>sharpe_ratio</td><td>-</td></tr>"));
+        Ok(())
+    }
+}
@@ -27,6 +27,10 @@ impl<T: PartialOrd> RangeInclusive<T> {
     pub fn contains(&self, item: &T) -> bool {
         item >= &self.start && item <= &self.end
     }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
 }
 
 impl<T> RangeBounds<T> for RangeInclusive<&T> {
@@ -0,0 +1 @@
+ALTER TABLE market_models DROP COLUMN execution;
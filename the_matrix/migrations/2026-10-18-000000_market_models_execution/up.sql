@@ -0,0 +1,2 @@
+-- Serialized ExecutionAlgo, NULL sends orders directly.
+ALTER TABLE market_models ADD COLUMN execution BLOB;
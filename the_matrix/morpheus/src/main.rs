@@ -90,6 +90,7 @@ async fn test_model(model_setup: TestConfig, mut candles: Candles) -> Result<Can
         target_leverage: 1.0,
         model_source_id: 0,
         serialized_variable_values: variable_values.write_to_vec().unwrap(),
+        serialized_execution: None,
     }];
     let mut agent = MockNetworkAgent::new(exchange_config, model_configs).await?;
     let model_state = agent.test().await?;
@@ -128,10 +129,8 @@ async fn test_model(model_setup: TestConfig, mut candles: Candles) -> Result<Can
 async fn main() -> Result<()> {
     thread_pool::init_global(1, std::usize::MAX);
     let args: Args = Args::parse();
-    unsafe {
-        config::load(&args.config)?;
-    }
-    select_exchange(&args.exchange);
+    config::load(&args.config)?;
+    select_exchange(&args.exchange)?;
     let mut candles = Candles::read(
         CONFIG.data_dir.join(&args.exchange).join("candles").join(
             &get_exchange_config()
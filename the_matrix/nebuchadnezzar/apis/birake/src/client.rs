@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use nebuchadnezzar_core::client::{handle_response, Client, ClientCapability, Request};
+use nebuchadnezzar_core::definitions::{Balance, Market};
+use nebuchadnezzar_core::error::{AnyResult, NebError};
+use nebuchadnezzar_core::prelude::Decimal;
+use nebuchadnezzar_core::reqwest::{self, ReqwestClient, Url};
+use nebuchadnezzar_core::vcr::{record_response, replay_response, Vcr};
+use nebuchadnezzar_core::anyhow::anyhow;
+use nebuchadnezzar_core::tokio::try_join;
+use nebuchadnezzar_core::{async_trait, Credentials, Support};
+use serde::de::DeserializeOwned;
+
+use crate::definitions::AddOrder;
+use crate::exchange::Birake;
+use crate::requests::{GetAssets, GetMarkets, PostAddOrder, PostBalances};
+
+#[derive(Debug)]
+pub struct BirakeClient {
+    client: ReqwestClient,
+    credentials: Option<Credentials>,
+    vcr: Option<Arc<Vcr>>,
+}
+
+#[async_trait]
+impl Client for BirakeClient {
+    type Exchange = Birake;
+
+    fn exchange(&self) -> Self::Exchange {
+        Birake::from_parts(self.vcr.clone())
+    }
+
+    fn authenticate(&mut self, credentials: Credentials) -> AnyResult<()> {
+        if let Some(vcr) = &self.vcr {
+            vcr.redact(&credentials.api_key);
+            vcr.redact(&credentials.api_secret);
+        }
+        self.credentials = Some(credentials);
+        Ok(())
+    }
+
+    fn capability() -> ClientCapability {
+        let mut c = ClientCapability::default();
+        // Only the most recent trades are served, there is no history to build candles from.
+        c.fetch_trades = Support::Yes;
+        c.fetch_markets = Support::Yes;
+        c.fetch_currencies = Support::Yes;
+        c.fetch_tickers = Support::Yes;
+        c.fetch_order_book = Support::Yes;
+        c.fetch_balance = Support::Yes;
+        c.create_order = Support::Yes;
+        c.cancel_order = Support::Yes;
+        c.fetch_open_orders = Support::Yes;
+        c.fetch_closed_orders = Support::Yes;
+        c.deposit = Support::Yes;
+        c.fetch_deposit_address = Support::Yes;
+        c.withdraw = Support::Yes;
+        c
+    }
+
+    async fn request_raw<R>(
+        &self,
+        url: Url,
+        body: String,
+    ) -> AnyResult<<R as Request<Self>>::Response>
+    where
+        Self: Sized,
+        R: Request<Self>,
+        R::Response: DeserializeOwned,
+    {
+        if let Some(vcr) = self.vcr.as_ref().filter(|x| x.is_replaying()) {
+            if R::SIGNED && self.credentials.is_none() {
+                return Err(NebError::NoApiKeySet.into());
+            }
+            return Ok(replay_response(vcr, &R::METHOD, &url)?);
+        }
+        let request = self.build_request::<R>(url.clone(), body.clone())?;
+        let response = self.client.execute(request).await?;
+        Ok(match &self.vcr {
+            Some(vcr) => record_response(vcr, &R::METHOD, &url, &body, response).await?,
+            None => handle_response(response).await?,
+        })
+    }
+}
+
+impl BirakeClient {
+    pub(crate) fn new(vcr: Option<Arc<Vcr>>) -> BirakeClient {
+        BirakeClient {
+            client: Default::default(),
+            credentials: None,
+            vcr,
+        }
+    }
+
+    /// Private endpoints are authenticated with api key and secret in headers, there is no
+    /// signature.
+    pub fn build_request<R>(&self, url: Url, body: String) -> AnyResult<reqwest::Request>
+    where
+        R: Request<Self>,
+    {
+        let mut builder = self
+            .client
+            .request(R::METHOD, url)
+            .body(body)
+            .header("content-type", "application/json");
+        if R::SIGNED {
+            let credentials = self.credentials.as_ref().ok_or(NebError::NoApiKeySet)?;
+            builder = builder
+                .header("birake-user", &credentials.api_key)
+                .header("birake-authorization", &credentials.api_secret);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Markets with fees of their base asset.
+    pub async fn fetch_markets(&self) -> AnyResult<Vec<Market>> {
+        let (markets, assets) = try_join!(self.request(GetMarkets), self.request(GetAssets))?;
+        markets
+            .into_iter()
+            .map(|market| {
+                let asset = assets
+                    .iter()
+                    .find(|x| x.symbol == market.base)
+                    .ok_or_else(|| anyhow!("Fees of {} are unknown.", market.base))?;
+                let (maker_fee, taker_fee) = (asset.maker_fee, asset.taker_fee);
+                Ok(Market {
+                    maker_fee,
+                    taker_fee,
+                    ..market.into()
+                })
+            })
+            .collect()
+    }
+
+    /// Balances of all assets.
+    pub async fn fetch_balances(&self) -> AnyResult<Vec<Balance>> {
+        let balances = self.request(PostBalances).await?;
+        Ok(balances.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Places a limit order, negative `amount` sells. Order is rejected without being sent when it
+    /// doesn't pass filters of `market`.
+    pub async fn post_order(
+        &self,
+        market: &Market,
+        price: Decimal,
+        amount: Decimal,
+    ) -> AnyResult<AddOrder> {
+        self.request(add_order(market, price, amount)?).await
+    }
+}
+
+/// Request of an order with price rounded to tick size and amount rounded to lot size of `market`.
+fn add_order(market: &Market, price: Decimal, amount: Decimal) -> AnyResult<PostAddOrder> {
+    let price = market.filters.round_price(price);
+    let amount = market.filters.round_amount(amount);
+    market.filters.check(price, amount)?;
+    Ok(PostAddOrder {
+        amount: amount.abs(),
+        market: market.symbol.clone(),
+        price,
+        kind: match amount.is_sign_negative() {
+            true => "sell",
+            false => "buy",
+        }
+        .into(),
+    })
+}
+
+#[cfg(test)]
+mod t_client {
+    use nebuchadnezzar_core::client::SuperClient;
+    use nebuchadnezzar_core::definitions::{MarketFilters, MarketKind};
+    use nebuchadnezzar_core::prelude::*;
+    use nebuchadnezzar_core::requests::TradesGetRequest;
+    use nebuchadnezzar_core::{tokio, Exchange};
+
+    use super::*;
+    use crate::requests::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/session.json");
+
+    fn replaying_client() -> BirakeClient {
+        let vcr = Arc::new(Vcr::replay(FIXTURE).unwrap());
+        let mut client = Birake::new().with_vcr(vcr).new_client();
+        Client::authenticate(&mut client, Credentials::new("key", "secret")).unwrap();
+        client
+    }
+
+    fn dec(x: &str) -> Decimal {
+        x.parse().unwrap()
+    }
+
+    #[test]
+    fn t_sign_request() {
+        let url = Url::parse("https://api.birake.com/v5/private/balances").unwrap();
+        let mut client = Birake::new().new_client();
+        assert!(client
+            .build_request::<PostBalances>(url.clone(), "".into())
+            .is_err());
+        Client::authenticate(&mut client, Credentials::new("key", "secret")).unwrap();
+        let request = client
+            .build_request::<PostBalances>(url.clone(), "".into())
+            .unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.headers()["birake-user"], "key");
+        assert_eq!(request.headers()["birake-authorization"], "secret");
+
+        let url = Url::parse("https://api.birake.com/v5/public/markets/").unwrap();
+        let request = Birake::new()
+            .new_client()
+            .build_request::<GetMarkets>(url, "".into())
+            .unwrap();
+        assert!(request.headers().get("birake-user").is_none());
+    }
+
+    #[tokio::test]
+    async fn t_fetch_markets_and_balances() {
+        let client = replaying_client();
+        let markets = client.fetch_markets().await.unwrap();
+        assert_eq!(markets[0].symbol, "BIR_BTC");
+        assert_eq!(markets[0].base, "BIR");
+        assert_eq!(markets[0].kind, MarketKind::Spot);
+        assert_eq!(markets[0].filters.tick_size, dec("0.00000001"));
+        assert_eq!(markets[0].filters.min_amount, dec("1"));
+        assert_eq!(markets[0].maker_fee, dec("0.001"));
+        assert_eq!(markets[1].taker_fee, dec("0.0025"));
+        let balances = client.fetch_balances().await.unwrap();
+        assert_eq!(balances[0].asset, "BIR");
+        assert_eq!(balances[0].total(), dec("150"));
+    }
+
+    #[test]
+    fn t_capability() {
+        // Candles can't be built without trade history.
+        let capability = <BirakeClient as Client>::capability().with_emulation();
+        assert_eq!(capability.fetch_candles, Support::No);
+        assert_eq!(capability.timeframe_support(60), Support::No);
+    }
+
+    #[tokio::test]
+    async fn t_orders() {
+        let client = replaying_client();
+        let orders = client
+            .request(PostClosedOrders {
+                limit: dec("100"),
+                pair: "BIR_BTC".into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(orders[0].order_id, "1.7.9017579");
+        assert_eq!(orders[0].timestamp.timestamp(), 1593516180);
+        let markets = client.fetch_markets().await.unwrap();
+        let order = client
+            .post_order(&markets[0], dec("0.000000604"), dec("100"))
+            .await
+            .unwrap();
+        assert_eq!(order.amount_filled, dec("40"));
+        assert_eq!(order.open[0].id, "1.7.9017752");
+        let cancel = client
+            .request(PostCancel {
+                order_id: "1.7.9017752".into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(cancel.order_id, "1.7.9017752");
+    }
+
+    #[test]
+    fn t_add_order_passes_filters() {
+        let market = Market {
+            symbol: "BIR_BTC".into(),
+            base: "BIR".into(),
+            quote: "BTC".into(),
+            kind: MarketKind::Spot,
+            filters: MarketFilters {
+                tick_size: dec("0.00000001"),
+                lot_size: dec("1"),
+                min_amount: dec("10"),
+                ..Default::default()
+            },
+            maker_fee: Decimal::default(),
+            taker_fee: Decimal::default(),
+        };
+        let request = add_order(&market, dec("0.000000604"), dec("-100.7")).unwrap();
+        assert_eq!(request.price, dec("0.0000006"));
+        assert_eq!(request.amount, dec("100"));
+        assert_eq!(request.kind, "sell");
+        let error = add_order(&market, dec("0.0000006"), dec("9.9")).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<NebError>(),
+            Some(NebError::MarketFilter(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn t_fetch_trades() {
+        let client = replaying_client();
+        let trades = SuperClient::fetch_trades(
+            &client,
+            TradesGetRequest {
+                symbol: "BIR_BTC".into(),
+                count: None,
+                offset: None,
+                start_time: None,
+                end_time: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].price, dec("0.00000061"));
+        assert_eq!(trades[0].amount, dec("250"));
+        assert_eq!(trades[0].timestamp.timestamp_millis(), 1593516180123);
+        assert!(SuperClient::fetch_trades(
+            &client,
+            TradesGetRequest {
+                symbol: "BIR_BTC".into(),
+                count: None,
+                offset: Some(10),
+                start_time: None,
+                end_time: None,
+            },
+        )
+        .await
+        .is_err());
+    }
+}
@@ -1,4 +1,28 @@
+use nebuchadnezzar_core::chrono::{NaiveDateTime, TimeZone};
+use nebuchadnezzar_core::definitions::{self, MarketFilters, MarketKind};
 use nebuchadnezzar_core::prelude::*;
+use serde::de::{self, Deserializer};
+
+/// Exchange sends times in UTC without an offset, epoch milliseconds and RFC 3339 are also
+/// accepted.
+fn deserialize_time<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+    let time = match &value {
+        Value::Number(n) => n.as_i64().map(|x| Utc.timestamp_millis(x)),
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .map(|x| x.with_timezone(&Utc))
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|x| Utc.from_utc_datetime(&x))
+            })
+            .ok(),
+        _ => None,
+    };
+    time.ok_or_else(|| de::Error::custom(format!("invalid time {}", value)))
+}
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Market {
@@ -13,6 +37,26 @@ pub struct Market {
     pub tick_size: Decimal,
 }
 
+impl From<Market> for definitions::Market {
+    fn from(market: Market) -> Self {
+        definitions::Market {
+            symbol: market.symbol,
+            base: market.base,
+            quote: market.quote,
+            kind: MarketKind::Spot,
+            filters: MarketFilters {
+                tick_size: market.tick_size,
+                min_price: market.min_price,
+                min_amount: market.min_volume,
+                ..Default::default()
+            },
+            // Fees are listed with assets, see `BirakeClient::fetch_markets`.
+            maker_fee: Decimal::default(),
+            taker_fee: Decimal::default(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Asset {
     #[serde(rename = "canDeposit")]
@@ -60,7 +104,8 @@ pub struct Trade {
     #[serde(rename = "marketPair")]
     pub market_pair: String,
     pub price: Decimal,
-    pub time: String,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub time: DateTime<Utc>,
     #[serde(rename = "tradeId")]
     pub trade_id: String,
     #[serde(rename = "type")]
@@ -68,6 +113,16 @@ pub struct Trade {
     pub volume: Decimal,
 }
 
+impl From<Trade> for definitions::Trade {
+    fn from(trade: Trade) -> Self {
+        definitions::Trade {
+            timestamp: trade.time,
+            price: trade.price,
+            amount: trade.volume,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Ticker {
     #[serde(rename = "baseVolume24h")]
@@ -96,6 +151,16 @@ pub struct Balance {
     pub used: Decimal,
 }
 
+impl From<Balance> for definitions::Balance {
+    fn from(balance: Balance) -> Self {
+        definitions::Balance {
+            asset: balance.name,
+            free: balance.free,
+            used: balance.used,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct OpenOrder {
     pub amount: Decimal,
@@ -106,7 +171,8 @@ pub struct OpenOrder {
     pub price: Decimal,
     pub side: String,
     pub status: String,
-    pub timestamp: String,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub timestamp: DateTime<Utc>,
     #[serde(rename = "type")]
     pub kind: String,
 }
@@ -122,7 +188,8 @@ pub struct ClosedOrder {
     pub price: Decimal,
     pub side: String,
     pub status: String,
-    pub timestamp: String,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub timestamp: DateTime<Utc>,
     #[serde(rename = "type")]
     pub kind: String,
 }
@@ -1,285 +1,91 @@
-#[macro_use]
-extern crate serde;
+#![deny(unused_must_use)]
+#![feature(extend_one)]
 
+pub mod client;
 pub mod definitions;
 pub mod requests;
 
-pub mod client {
-    use crate::exchange::Birake;
-    use nebuchadnezzar_core::client::{handle_response, Client, ClientCapability, Request};
-    use nebuchadnezzar_core::error::{Error, NebError};
-    use nebuchadnezzar_core::reqwest::{ReqwestClient, Url};
-    use nebuchadnezzar_core::{async_trait, Credentials, Exchange};
-    use serde::de::DeserializeOwned;
-
-    #[derive(Default)]
-    pub struct BirakeClient {
-        client: ReqwestClient,
-        credentials: Option<Credentials>,
-    }
-
-    #[async_trait]
-    impl Client for BirakeClient {
-        type Exchange = Birake;
-
-        fn exchange(&self) -> Self::Exchange {
-            Birake
-        }
+/// Exchange has no websocket api.
+pub mod websocket {}
 
-        fn authenticate(&mut self, credentials: Credentials) -> Result<(), Error> {
-            self.credentials = Some(credentials);
-            Ok(())
-        }
+#[macro_use]
+extern crate nebuchadnezzar_core;
+#[macro_use]
+extern crate serde;
 
-        fn capability() -> ClientCapability {
-            unimplemented!()
-        }
+use nebuchadnezzar_core::SuperExchange;
 
-        async fn request_raw<R>(
-            &self,
-            url: Url,
-            body: String,
-        ) -> Result<<R as Request<Self>>::Response, Error>
-        where
-            Self: Sized,
-            R: Request<Self>,
-            R::Response: DeserializeOwned,
-        {
-            let builder = if R::SIGNED {
-                let credentials = self.credentials.as_ref().ok_or(NebError::NoApiKeySet)?;
-                self.client
-                    .request(R::METHOD, url)
-                    .body(body)
-                    // .header("Content-Type", "application/json")
-                    .header("birake-user", &credentials.api_key)
-                    .header("birake-authorization", &credentials.api_secret)
-            } else {
-                self.client.request(R::METHOD, url).body(body)
-            };
+use crate::exchange::Birake;
 
-            let response = builder.send().await?;
-            handle_response(response).await
-        }
-    }
+pub fn extend_exchanges(collection: &mut impl Extend<Box<dyn SuperExchange>>) {
+    collection.extend_one(Box::new(Birake::new()));
 }
 
 pub mod exchange {
-    use crate::client::BirakeClient;
-    use nebuchadnezzar_core::error::{NebError, Result};
-    use nebuchadnezzar_core::sorted_vec::SortedSet;
+    use std::sync::Arc;
+
+    use nebuchadnezzar_core::vcr::Vcr;
     use nebuchadnezzar_core::websocket::{NotWebSocket, WebSocket};
     use nebuchadnezzar_core::{async_trait, Exchange};
 
+    use crate::client::BirakeClient;
+
+    /// Spot exchange, it doesn't have a websocket api.
     #[derive(Default)]
-    pub struct Birake;
+    pub struct Birake {
+        vcr: Option<Arc<Vcr>>,
+    }
+
+    impl Birake {
+        pub fn new() -> Birake {
+            Birake::default()
+        }
+
+        /// Clients created by this exchange record or replay their traffic.
+        pub fn with_vcr(mut self, vcr: Arc<Vcr>) -> Birake {
+            self.vcr = Some(vcr);
+            self
+        }
+
+        pub(crate) fn from_parts(vcr: Option<Arc<Vcr>>) -> Birake {
+            Birake { vcr }
+        }
+    }
 
     #[async_trait]
     impl Exchange for Birake {
         type Client = BirakeClient;
         type WebSocket = NotWebSocket<Self>;
-        const NAME: &'static str = "Birake Exchange";
-        const API_VERSION: &'static str = "5.0.0";
-        const SITE_URL: &'static str = "https://testnet.bitmex.com/";
-        const API_URL: &'static str = "https://api.birake.com/v5";
-        const WS_API_URL: &'static str = "";
-        const API_DOC_URL: &'static str = "https://api.birake.com/";
-        const IS_DEMO: bool = false;
+        fn name(&self) -> &'static str {
+            "Birake Exchange"
+        }
+        fn api_version(&self) -> &'static str {
+            "5.0.0"
+        }
+        fn site_url(&self) -> &'static str {
+            "https://birake.com/"
+        }
+        fn api_url(&self) -> &'static str {
+            "https://api.birake.com/v5"
+        }
+        fn ws_api_url(&self) -> &'static str {
+            ""
+        }
+        fn api_doc_url(&self) -> &'static str {
+            "https://api.birake.com/"
+        }
+        fn is_demo(&self) -> bool {
+            false
+        }
 
         fn new_client(&self) -> Self::Client {
-            BirakeClient::default()
+            BirakeClient::new(self.vcr.clone())
         }
 
-        async fn new_web_socket(&self) -> Result<Self::WebSocket> {
+        async fn new_web_socket(
+            &self,
+        ) -> Result<Self::WebSocket, <Self::WebSocket as WebSocket>::Error> {
             NotWebSocket::unsupported()
         }
     }
 }
-
-pub mod websocket {}
-
-#[cfg(feature = "schema")]
-pub mod schema {
-    use nebuchadnezzar_core::reqwest::Method;
-    pub use nebuchadnezzar_core::schema::*;
-
-    pub fn schema() -> Schema {
-        Schema {
-            root_url: None,
-            definitions: vec![
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/public/markets/".into(),
-                    method: Method::GET,
-                    payload: r#""#.into(),
-                    is_signed: false,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/public/assets/".into(),
-                    method: Method::GET,
-                    payload: r#""#.into(),
-                    is_signed: false,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/public/depth/?pair=BIR_BTC".into(),
-                    method: Method::GET,
-                    payload: r#""#.into(),
-                    is_signed: false,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/public/trades/?pair=BTC_USDC".into(),
-                    method: Method::GET,
-                    payload: r#""#.into(),
-                    is_signed: false,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/public/ticker".into(),
-                    method: Method::GET,
-                    payload: r#""#.into(),
-                    is_signed: false,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/private/balances".into(),
-                    method: Method::POST,
-                    payload: r#""#.into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionMethod {
-                    endpoint: "https://api.birake.com/v5/private/openOrders".into(),
-                    method: Method::POST,
-                    payload: r#"{
-                "pair": "BTC_USDC"
-            }"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-            ],
-            samples: vec![
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/closedOrders".into(),
-                    method: Method::POST,
-                    payload: r#"{
-                "pair": "BTC_USDC",
-                "limit": 100
-            }"#
-                    .into(),
-                    response: r#"[
-      {
-          "id": "1.11.19516117",
-          "order_id": "1.7.9017579",
-          "pair": "BIR_BTC",
-          "price": 1e-9,
-          "initialAmount": 1000,
-          "amount": 0,
-          "side": "buy",
-          "type": "limit",
-          "timestamp": "2020-06-30T11:23:00",
-          "status": "open"
-      }
-]"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/addOrder".into(),
-                    method: Method::POST,
-                    payload: r#"{
-            "amount": 100,
-            "price": 0.00000060,
-            "type": "buy",
-            "market": "BIR_BTC"
-        }"#
-                    .into(),
-                    response: r#""#.into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/cancel".into(),
-                    method: Method::POST,
-                    payload: r#"{
-    "orderId": "1.7.9017752"
-}"#
-                    .into(),
-                    response: r#"{
-    "orderId": "1.7.9017752"
-}"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/deposits".into(),
-                    method: Method::POST,
-                    payload: r#""#.into(),
-                    response: r#"[
-   {
-       "id": "1.11.20088036",
-       "asset": "BIR",
-       "amount": 50,
-       "tx": "c35e49a136223a7745a446bd7d0e8783004b63960c48e0de63cd6ecd06e59f58"
-   }
-]"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/withdrawals".into(),
-                    method: Method::POST,
-                    payload: r#""#.into(),
-                    response: r#"[
-   {
-       "id": "1.11.20087134",
-       "asset": "BIR",
-       "amount": 36.89192,
-       "address": "KBpSJ64Gbi1mQsPvvA5MhhDpkKPNKyjFDH"
-    }
-]"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/withdraw".into(),
-                    method: Method::POST,
-                    payload: r#"{
-    "asset": "BIR",
-    "address": "KBpSJ64Gbi1mQsPvvA5MhhDpkKPNKyjFDH",
-    "amount": 100
-}"#
-                    .into(),
-                    response: r#"{
-   "id": "1.11.20088626",
-   "amount": 100
-}"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-                DefinitionSample {
-                    endpoint: "https://api.birake.com/v5/private/deposit".into(),
-                    method: Method::POST,
-                    payload: r#"{
-    "asset": "BIR"
-}"#
-                    .into(),
-                    response: r#"{
-   "address": "KSaNft56gjQrkNws2d83tFjtuyjJrLhSf2"
-}"#
-                    .into(),
-                    is_signed: true,
-                    pre_process: PreProcess::empty(),
-                },
-            ],
-            api_key: None,
-            api_secret: None,
-        }
-    }
-}
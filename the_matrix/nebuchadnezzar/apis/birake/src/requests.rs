@@ -1,6 +1,12 @@
+use std::convert::TryFrom;
+
+use nebuchadnezzar_core::client::{Converter, NotRequest, NotResponse, Pageable, SuperRequest};
+use nebuchadnezzar_core::error::NebError;
+use nebuchadnezzar_core::prelude::*;
+use nebuchadnezzar_core::requests::{CandlesGetRequest, FundingGetRequest, TradesGetRequest};
+
 use super::definitions::*;
 use crate::client::BirakeClient;
-use nebuchadnezzar_core::prelude::*;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GetMarkets;
@@ -31,11 +37,27 @@ impl Request<BirakeClient> for GetDepth {
     type Response = Depth;
 }
 
+/// Returns the most recent trades, there is no history.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GetTrades {
     pub pair: String,
 }
-impl_request! {
+impl TryFrom<TradesGetRequest> for GetTrades {
+    type Error = NebError;
+
+    fn try_from(request: TradesGetRequest) -> Result<Self, Self::Error> {
+        if request.offset.is_some() || request.start_time.is_some() || request.end_time.is_some() {
+            return Err(NebError::Unsupported("trade history"));
+        }
+        Ok(GetTrades {
+            pair: request.symbol,
+        })
+    }
+}
+impl Pageable for GetTrades {
+    const MAX_ITEMS_PER_PAGE: u32 = 100;
+}
+converter! {
     from TradesGetRequest;
     impl Request<BirakeClient> for GetTrades {
         const METHOD: Method = Method::GET;
@@ -45,6 +67,32 @@ impl_request! {
     }
 }
 
+/// Exchange doesn't serve candles, they are emulated from trades.
+impl Converter<CandlesGetRequest> for BirakeClient {
+    type Req = NotRequest;
+
+    fn convert_request(_: CandlesGetRequest) -> Result<Self::Req, NebError> {
+        Err(NebError::Unsupported("candles"))
+    }
+
+    fn convert_response(_: NotResponse) -> <CandlesGetRequest as SuperRequest>::SuperResponse {
+        unreachable!("Candles are never requested.")
+    }
+}
+
+/// Exchange has only spot markets.
+impl Converter<FundingGetRequest> for BirakeClient {
+    type Req = NotRequest;
+
+    fn convert_request(_: FundingGetRequest) -> Result<Self::Req, NebError> {
+        Err(NebError::Unsupported("funding"))
+    }
+
+    fn convert_response(_: NotResponse) -> <FundingGetRequest as SuperRequest>::SuperResponse {
+        unreachable!("Funding is never requested.")
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GetTickers;
 impl Request<BirakeClient> for GetTickers {
@@ -110,7 +158,7 @@ impl Request<BirakeClient> for PostCancel {
     const METHOD: Method = Method::POST;
     const SIGNED: bool = true;
     const ENDPOINT: &'static str = "/private/cancel";
-    type Response = bool;
+    type Response = Cancel;
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -1,15 +1,22 @@
+use std::sync::Arc;
+
 use nebuchadnezzar_core::chrono::{Duration, Utc};
 use nebuchadnezzar_core::client::ring::hmac;
 use nebuchadnezzar_core::client::ring::hmac::Key;
-use nebuchadnezzar_core::client::{handle_response, Client, ClientCapability, Request};
+use nebuchadnezzar_core::client::{
+    handle_response, Client, ClientCapability, Request, RATE_LIMITED, RATE_LIMIT_REMAINING,
+};
+use nebuchadnezzar_core::clock::ServerClock;
 use nebuchadnezzar_core::error::{AnyResult, NebError};
 use nebuchadnezzar_core::log::*;
+use nebuchadnezzar_core::metrics;
 use nebuchadnezzar_core::reqwest::{ReqwestClient, StatusCode, Url};
 use nebuchadnezzar_core::signatures::hmac_sha256;
 use nebuchadnezzar_core::sorted_vec::SortedSet;
 use nebuchadnezzar_core::tokio::sync::RwLock;
 use nebuchadnezzar_core::tokio::{self};
-use nebuchadnezzar_core::{async_trait, Credentials};
+use nebuchadnezzar_core::vcr::{record_response, replay_response, Vcr};
+use nebuchadnezzar_core::{async_trait, Credentials, Support};
 use serde::de::DeserializeOwned;
 
 use crate::exchange::Bitmex;
@@ -33,6 +40,8 @@ pub struct BitmexClient {
     credential: Option<Credential>,
     limit: RwLock<Limit>,
     use_testnet: bool,
+    vcr: Option<Arc<Vcr>>,
+    clock: Arc<ServerClock>,
 }
 
 #[async_trait]
@@ -40,10 +49,14 @@ impl Client for BitmexClient {
     type Exchange = Bitmex;
 
     fn exchange(&self) -> Self::Exchange {
-        Bitmex::new(self.use_testnet)
+        Bitmex::from_parts(self.use_testnet, self.vcr.clone(), self.clock.clone())
     }
 
     fn authenticate(&mut self, credentials: Credentials) -> AnyResult<()> {
+        if let Some(vcr) = &self.vcr {
+            vcr.redact(&credentials.api_key);
+            vcr.redact(&credentials.api_secret);
+        }
         self.credential = Some(Credential {
             signed_key: hmac::Key::new(hmac::HMAC_SHA256, credentials.api_secret.as_bytes()),
             api_key: credentials.api_key,
@@ -55,6 +68,12 @@ impl Client for BitmexClient {
         use nebuchadnezzar_core::timeframes::*;
         let mut c = ClientCapability::default();
         c.timeframes = SortedSet::from(vec![m1, m5, h1, d1]);
+        // Bucket of 10:00-10:01 has timestamp 10:01.
+        c.candle_timestamp_at_close = true;
+        c.fetch_candles = Support::Yes;
+        c.fetch_trades = Support::Yes;
+        c.fetch_trade_history = Support::Yes;
+        c.fetch_funding = Support::Yes;
         c
     }
 
@@ -68,6 +87,13 @@ impl Client for BitmexClient {
         R: Request<Self>,
         R::Response: DeserializeOwned,
     {
+        if let Some(vcr) = self.vcr.as_ref().filter(|x| x.is_replaying()) {
+            if R::SIGNED && self.credential.is_none() {
+                return Err(NebError::NoApiKeySet.into());
+            }
+            return Ok(replay_response(vcr, &R::METHOD, &url)?);
+        }
+        let exchange = self.exchange().name();
         // if connection breaks while in the middle of transfering data then it hangs forever
         match tokio::time::timeout(tokio::time::Duration::from_secs(20000), async {
             loop {
@@ -82,7 +108,7 @@ impl Client for BitmexClient {
                     .header("content-type", "application/json");
 
                 if let Some(credential) = &self.credential {
-                    let expires = (Utc::now() + Duration::seconds(10)).timestamp();
+                    let expires = (self.clock.now() + Duration::seconds(10)).timestamp();
                     let signature =
                         hmac_sha256(&credential.signed_key, R::METHOD, expires, &url, &body);
                     builder = builder
@@ -95,7 +121,7 @@ impl Client for BitmexClient {
                 trace!("reading limits");
                 let limit = self.limit.read().await;
                 if limit.remaining == 0 {
-                    let now = Utc::now().timestamp();
+                    let now = self.clock.now().timestamp();
                     let diff = limit.reset_ts - now;
                     drop(limit);
                     if diff > 0 {
@@ -107,10 +133,14 @@ impl Client for BitmexClient {
                 }
                 trace!("sending request");
 
+                let sent = Utc::now();
                 let response = builder.send().await?;
                 trace!("response received");
                 let headers = response.headers();
+                self.clock.observe_headers(sent, Utc::now(), headers);
                 if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                    metrics::inc(&RATE_LIMITED, &[("exchange", exchange)]);
+                    metrics::set(&RATE_LIMIT_REMAINING, &[("exchange", exchange)], 0.);
                     let retry_after = headers
                         .get("retry-after")
                         .unwrap()
@@ -119,7 +149,7 @@ impl Client for BitmexClient {
                     trace!("writing limits");
                     let mut limit = self.limit.write().await;
                     trace!("limits written");
-                    limit.reset_ts = Utc::now().timestamp() + retry_after;
+                    limit.reset_ts = self.clock.now().timestamp() + retry_after;
                     limit.remaining = 0;
                     continue;
                 }
@@ -134,10 +164,15 @@ impl Client for BitmexClient {
                         .unwrap()
                         .to_str()?
                         .parse::<i64>()?;
+                    metrics::set(
+                        &RATE_LIMIT_REMAINING,
+                        &[("exchange", exchange)],
+                        remaining as f64,
+                    );
                     trace!("writing limits");
                     let mut limit = self.limit.write().await;
                     trace!("limits written");
-                    let now = Utc::now().timestamp();
+                    let now = self.clock.now().timestamp();
                     if limit.updated_ts < now {
                         limit.updated_ts = now;
                         limit.remaining = remaining;
@@ -145,7 +180,10 @@ impl Client for BitmexClient {
                     }
                 }
                 trace!("processing response");
-                let response = handle_response(response).await?;
+                let response = match &self.vcr {
+                    Some(vcr) => record_response(vcr, &R::METHOD, &url, &body, response).await?,
+                    None => handle_response(response).await?,
+                };
                 trace!("response processed");
                 return Ok(response);
             }
@@ -159,7 +197,11 @@ impl Client for BitmexClient {
 }
 
 impl BitmexClient {
-    pub(crate) fn new(use_testnet: bool) -> BitmexClient {
+    pub(crate) fn new(
+        use_testnet: bool,
+        vcr: Option<Arc<Vcr>>,
+        clock: Arc<ServerClock>,
+    ) -> BitmexClient {
         BitmexClient {
             client: Default::default(),
             credential: None,
@@ -169,10 +211,116 @@ impl BitmexClient {
                 reset_ts: i64::MIN,
             }),
             use_testnet,
+            vcr,
+            clock,
         }
     }
 
     pub fn credential(&self) -> &Option<Credential> {
         &self.credential
     }
+
+    pub fn clock(&self) -> &Arc<ServerClock> {
+        &self.clock
+    }
+}
+
+#[cfg(test)]
+mod t_client {
+    use std::sync::Arc;
+
+    use nebuchadnezzar_core::chrono::{Duration, TimeZone, Utc};
+    use nebuchadnezzar_core::client::{Client, SuperClient};
+    use nebuchadnezzar_core::definitions::Trade;
+    use nebuchadnezzar_core::error::{AnyResult, NebError};
+    use nebuchadnezzar_core::futures_util::StreamExt;
+    use nebuchadnezzar_core::paginators::WhileSuperPaginator;
+    use nebuchadnezzar_core::requests::TradesGetRequest;
+    use nebuchadnezzar_core::vcr::Vcr;
+    use nebuchadnezzar_core::{tokio, Exchange};
+
+    use crate::exchange::Bitmex;
+    use crate::requests::{GetAnnouncementRequest, GetAnnouncementUrgentRequest};
+
+    async fn replay_requests() -> AnyResult<()> {
+        let vcr = Vcr::replay(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/session.json"))?;
+        let client = Bitmex::new(false).with_vcr(Arc::new(vcr)).new_client();
+        let announcements = client
+            .request(GetAnnouncementRequest { columns: None })
+            .await?;
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(announcements[0].title.as_deref(), Some("Maintenance"));
+        let error = client
+            .request(GetAnnouncementUrgentRequest)
+            .await
+            .unwrap_err();
+        match error.downcast_ref::<NebError>() {
+            Some(NebError::RemoteError(e)) => assert_eq!(e.status.as_u16(), 401),
+            e => panic!("Expected remote error, got {:?}", e),
+        }
+        // Every interaction is replayed only once.
+        assert!(client
+            .request(GetAnnouncementRequest { columns: None })
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn t_replay_requests() -> AnyResult<()> {
+        tokio::runtime::Runtime::new()?.block_on(replay_requests())
+    }
+
+    /// Next page starts at the last received timestamp and skips trades at it that have already
+    /// been received, same as emulated trade and hlcv downloads do.
+    async fn paginate_trades() -> AnyResult<()> {
+        let vcr = Vcr::replay(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/trades.json"))?;
+        let client = Bitmex::new(false).with_vcr(Arc::new(vcr)).new_client();
+        let start_time = Utc.ymd(2021, 9, 1).and_hms(0, 0, 0);
+        let end_time = start_time + Duration::seconds(10);
+        let mut last_time = start_time;
+        let mut offset = 0;
+        let paginator = WhileSuperPaginator::new(
+            Ok(TradesGetRequest {
+                symbol: "XBTUSD".into(),
+                count: None,
+                offset: None,
+                start_time: Some(start_time),
+                end_time: Some(end_time),
+            }),
+            move |response: &AnyResult<Vec<Trade>>, max_count: u32| {
+                let response = response.as_ref().ok()?;
+                let last_ts = response.last()?.timestamp;
+                if last_ts >= end_time {
+                    return None;
+                }
+                if last_ts != last_time {
+                    last_time = last_ts;
+                    offset = response.iter().filter(|x| x.timestamp == last_ts).count();
+                } else {
+                    offset += response.len();
+                }
+                Some(Ok(TradesGetRequest {
+                    symbol: "XBTUSD".into(),
+                    count: Some(max_count),
+                    offset: Some(offset as i32),
+                    start_time: Some(last_time),
+                    end_time: Some(end_time),
+                }))
+            },
+        );
+        let mut stream = client.paginate_trades(Box::pin(paginator));
+        let mut pages = Vec::new();
+        while let Some(page) = stream.next().await {
+            pages.push(page?);
+        }
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(pages[1][0].timestamp, start_time + Duration::seconds(5));
+        Ok(())
+    }
+
+    #[test]
+    fn t_paginate_trades() -> AnyResult<()> {
+        tokio::runtime::Runtime::new()?.block_on(paginate_trades())
+    }
 }
@@ -158,6 +158,15 @@ pub struct Funding {
     #[serde(rename = "fundingRateDaily")]
     pub funding_rate_daily: Option<Decimal>,
 }
+impl From<Funding> for nebuchadnezzar_core::definitions::Funding {
+    fn from(funding: Funding) -> Self {
+        nebuchadnezzar_core::definitions::Funding {
+            timestamp: funding.timestamp,
+            rate: funding.funding_rate.unwrap_or_default(),
+            daily_rate: funding.funding_rate_daily.unwrap_or_default(),
+        }
+    }
+}
 #[derive(Clone, Debug, Deserialize, Serialize)]
 /// Tradeable Contracts, Indices, and History
 pub struct Instrument {
@@ -477,6 +486,7 @@ pub struct OrderBookL2 {
     pub side: Side,
     pub size: Option<i64>,
     pub price: Option<Decimal>,
+    pub timestamp: Option<DateTime<Utc>>,
 }
 #[derive(Clone, Debug, Deserialize, Serialize)]
 /// Summary of Open and Closed Positions
@@ -28,7 +28,11 @@ pub fn extend_exchanges(collection: &mut impl Extend<Box<dyn SuperExchange>>) {
 }
 
 pub mod exchange {
+    use std::sync::Arc;
+
+    use nebuchadnezzar_core::clock::ServerClock;
     use nebuchadnezzar_core::error::Result;
+    use nebuchadnezzar_core::vcr::Vcr;
     use nebuchadnezzar_core::websocket::WebSocket;
     use nebuchadnezzar_core::{async_trait, Exchange};
 
@@ -37,11 +41,40 @@ pub mod exchange {
 
     pub struct Bitmex {
         use_testnet: bool,
+        vcr: Option<Arc<Vcr>>,
+        clock: Arc<ServerClock>,
     }
 
     impl Bitmex {
         pub fn new(use_testnet: bool) -> Bitmex {
-            Bitmex { use_testnet }
+            Bitmex {
+                use_testnet,
+                vcr: None,
+                clock: Default::default(),
+            }
+        }
+
+        /// Clients and websockets created by this exchange record or replay their traffic.
+        pub fn with_vcr(mut self, vcr: Arc<Vcr>) -> Bitmex {
+            self.vcr = Some(vcr);
+            self
+        }
+
+        /// Server time estimate that is shared between clients and websockets of this exchange.
+        pub fn clock(&self) -> &Arc<ServerClock> {
+            &self.clock
+        }
+
+        pub(crate) fn from_parts(
+            use_testnet: bool,
+            vcr: Option<Arc<Vcr>>,
+            clock: Arc<ServerClock>,
+        ) -> Bitmex {
+            Bitmex {
+                use_testnet,
+                vcr,
+                clock,
+            }
         }
     }
 
@@ -90,13 +123,13 @@ pub mod exchange {
         }
 
         fn new_client(&self) -> Self::Client {
-            BitmexClient::new(self.use_testnet)
+            BitmexClient::new(self.use_testnet, self.vcr.clone(), self.clock.clone())
         }
 
         async fn new_web_socket(
             &self,
         ) -> Result<Self::WebSocket, <Self::WebSocket as WebSocket>::Error> {
-            BitmexWebSocket::connect(self.use_testnet).await
+            BitmexWebSocket::connect(self.use_testnet, self.vcr.clone(), self.clock.clone()).await
         }
     }
 }
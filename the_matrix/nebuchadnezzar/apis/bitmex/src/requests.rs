@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 use converters::try_from;
 use nebuchadnezzar_core::client::*;
 use nebuchadnezzar_core::error::NebError;
@@ -129,6 +131,22 @@ pub struct GetFundingRequest {
     #[serde(rename = "endTime")]
     pub end_time: Option<DateTime<Utc>>,
 }
+impl TryFrom<FundingGetRequest> for GetFundingRequest {
+    type Error = NebError;
+
+    fn try_from(request: FundingGetRequest) -> Result<Self, Self::Error> {
+        Ok(GetFundingRequest {
+            symbol: Some(request.symbol),
+            count: request.count.map(|x| x as i32),
+            start_time: request.start_time,
+            end_time: request.end_time,
+            ..Default::default()
+        })
+    }
+}
+impl Pageable for GetFundingRequest {
+    const MAX_ITEMS_PER_PAGE: u32 = 500;
+}
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 /// Get instruments.
 pub struct GetInstrumentRequest {
@@ -849,11 +867,14 @@ impl Request<BitmexClient> for GetExecutionTradeHistoryRequest {
     const ENDPOINT: &'static str = "/execution/tradeHistory";
     type Response = Vec<Execution>;
 }
-impl Request<BitmexClient> for GetFundingRequest {
-    const METHOD: Method = Method::GET;
-    const SIGNED: bool = false;
-    const ENDPOINT: &'static str = "/funding";
-    type Response = Vec<Funding>;
+converter! {
+    from FundingGetRequest;
+    impl Request<BitmexClient> for GetFundingRequest {
+        const METHOD: Method = Method::GET;
+        const SIGNED: bool = false;
+        const ENDPOINT: &'static str = "/funding";
+        type Response = Vec<Funding>;
+    }
 }
 impl Request<BitmexClient> for GetInstrumentRequest {
     const METHOD: Method = Method::GET;
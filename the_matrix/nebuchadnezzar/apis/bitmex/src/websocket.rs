@@ -2,8 +2,11 @@ mod command;
 mod message;
 mod topic;
 
+use std::sync::Arc;
+
 use nebuchadnezzar_core::chrono::Duration;
 use nebuchadnezzar_core::client::ring::hmac;
+use nebuchadnezzar_core::clock::ServerClock;
 use nebuchadnezzar_core::error::Result;
 use nebuchadnezzar_core::futures_util::stream::Fuse;
 use nebuchadnezzar_core::futures_util::{FutureExt, StreamExt};
@@ -11,6 +14,7 @@ use nebuchadnezzar_core::prelude::Utc;
 use nebuchadnezzar_core::reqwest::{Method, Url};
 use nebuchadnezzar_core::signatures::hmac_sha256;
 use nebuchadnezzar_core::tokio::net::TcpStream;
+use nebuchadnezzar_core::vcr::Vcr;
 use nebuchadnezzar_core::websocket::tokio_tungstenite::tungstenite::Message as RawMessage;
 use nebuchadnezzar_core::websocket::tokio_tungstenite::{
     connect_async, MaybeTlsStream, WebSocketStream,
@@ -30,8 +34,11 @@ use crate::exchange::Bitmex;
 use crate::nebuchadnezzar_core::futures_util::SinkExt;
 
 pub struct BitmexWebSocket {
-    inner: Fuse<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    /// `None` while replaying.
+    inner: Option<Fuse<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
     use_testnet: bool,
+    vcr: Option<Arc<Vcr>>,
+    clock: Arc<ServerClock>,
 }
 
 #[async_trait]
@@ -42,7 +49,7 @@ impl WebSocket for BitmexWebSocket {
     type RawCommand = RawMessage;
 
     fn exchange(&self) -> Self::Exchange {
-        Bitmex::new(self.use_testnet)
+        Bitmex::from_parts(self.use_testnet, self.vcr.clone(), self.clock.clone())
     }
 
     fn capability() -> WebSocketCapability {
@@ -62,7 +69,9 @@ impl WebSocket for BitmexWebSocket {
         Self: Sized,
         C: WsCommand<Self>,
     {
-        self.inner.send(command.serialize()).await?;
+        if let Some(inner) = &mut self.inner {
+            inner.send(command.serialize()).await?;
+        }
         Ok(())
     }
 
@@ -70,6 +79,15 @@ impl WebSocket for BitmexWebSocket {
     where
         Self: Sized,
     {
+        let inner = match &mut self.inner {
+            Some(inner) => inner,
+            None => {
+                let vcr = self.vcr.as_ref()?;
+                return vcr
+                    .next_frame()
+                    .map(|m| serde_json::from_str(&m).map_err(|x| x.into()));
+            }
+        };
         let mut timeouted = false;
         loop {
             let timeout = nebuchadnezzar_core::tokio::time::sleep(
@@ -77,16 +95,24 @@ impl WebSocket for BitmexWebSocket {
             )
             .fuse();
             nebuchadnezzar_core::tokio::select! {
-                message = self.inner.next() => match message {
+                message = inner.next() => match message {
                     None => return None,
                     Some(Ok(RawMessage::Text(m))) => {
-                        return Some(serde_json::from_str(&m).map_err(|x| x.into()))
+                        if let Some(vcr) = &self.vcr {
+                            vcr.record_frame(&m);
+                        }
+                        let message = serde_json::from_str(&m).map_err(|x| x.into());
+                        // Info is sent by the server right after connecting.
+                        if let Ok(Message::Info(info)) = &message {
+                            self.clock.observe_received(Utc::now(), info.timestamp);
+                        }
+                        return Some(message)
                     }
                     Some(Ok(RawMessage::Binary(_))) => {
                         return Some(Err(BitmexWsError::UnexpectedBinaryMessage))
                     }
                     Some(Ok(RawMessage::Ping(_))) => {
-                        if let Err(e) = self.inner.send(RawMessage::Pong("pong".into())).await {
+                        if let Err(e) = inner.send(RawMessage::Pong("pong".into())).await {
                             return Some(Err(e.into()));
                         }
                     }
@@ -107,10 +133,10 @@ impl WebSocket for BitmexWebSocket {
                 _ = timeout => {
                     #[allow(unused_must_use)]
                     if timeouted {
-                        self.inner.get_mut().close(None).await;
+                        inner.get_mut().close(None).await;
                         return None;
                     }
-                    let result = self.inner.send(RawMessage::Ping("ping".into())).await;
+                    let result = inner.send(RawMessage::Ping("ping".into())).await;
                     if result.is_err() {
                         return None;
                     }
@@ -121,21 +147,36 @@ impl WebSocket for BitmexWebSocket {
     }
 
     async fn close(&mut self) -> Result<(), Self::Error> {
-        Ok(self.inner.get_mut().close(None).await?)
+        match &mut self.inner {
+            Some(inner) => Ok(inner.get_mut().close(None).await?),
+            None => Ok(()),
+        }
     }
 }
 
 impl BitmexWebSocket {
-    pub(crate) async fn connect(use_testnet: bool) -> Result<BitmexWebSocket, BitmexWsError> {
-        let url = Url::parse(Bitmex::new(use_testnet).ws_api_url()).unwrap();
+    pub(crate) async fn connect(
+        use_testnet: bool,
+        vcr: Option<Arc<Vcr>>,
+        clock: Arc<ServerClock>,
+    ) -> Result<BitmexWebSocket, BitmexWsError> {
+        let inner = match &vcr {
+            Some(vcr) if vcr.is_replaying() => None,
+            _ => {
+                let url = Url::parse(Bitmex::new(use_testnet).ws_api_url()).unwrap();
+                Some(connect_async(url).await?.0.fuse())
+            }
+        };
         Ok(Self {
-            inner: connect_async(url).await?.0.fuse(),
+            inner,
             use_testnet,
+            vcr,
+            clock,
         })
     }
 
     pub async fn authenticate_raw(&mut self, credential: &Credential) -> Result<(), BitmexWsError> {
-        let expires = (Utc::now() + Duration::seconds(5)).timestamp();
+        let expires = (self.clock.now() + Duration::seconds(5)).timestamp();
         let sig = hmac_sha256(
             &credential.signed_key,
             Method::GET,
@@ -143,6 +184,10 @@ impl BitmexWebSocket {
             &Url::parse(self.exchange().ws_api_url()).unwrap(),
             "",
         );
+        if let Some(vcr) = &self.vcr {
+            vcr.redact(&credential.api_key);
+            vcr.redact(&sig);
+        }
         self.send(Command::Authenticate(
             credential.api_key.clone(),
             expires,
@@ -151,3 +196,37 @@ impl BitmexWebSocket {
         .await
     }
 }
+
+#[cfg(test)]
+mod t_websocket {
+    use std::sync::Arc;
+
+    use nebuchadnezzar_core::error::AnyResult;
+    use nebuchadnezzar_core::vcr::Vcr;
+    use nebuchadnezzar_core::websocket::WebSocket;
+    use nebuchadnezzar_core::{tokio, Exchange};
+
+    use crate::exchange::Bitmex;
+    use crate::websocket::Message;
+
+    async fn replay_frames() -> AnyResult<()> {
+        let vcr = Vcr::replay(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/session.json"))?;
+        let mut ws = Bitmex::new(false)
+            .with_vcr(Arc::new(vcr))
+            .new_web_socket()
+            .await?;
+        assert!(matches!(WebSocket::next(&mut ws).await, Some(Ok(Message::Info(_)))));
+        assert!(matches!(WebSocket::next(&mut ws).await, Some(Ok(Message::Success(_)))));
+        match WebSocket::next(&mut ws).await {
+            Some(Ok(Message::Table(table))) => assert_eq!(table.table, "trade"),
+            m => panic!("Expected table message, got {:?}", m),
+        }
+        assert!(WebSocket::next(&mut ws).await.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn t_replay_frames() -> AnyResult<()> {
+        tokio::runtime::Runtime::new()?.block_on(replay_frames())
+    }
+}
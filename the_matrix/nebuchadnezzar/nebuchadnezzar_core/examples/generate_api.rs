@@ -0,0 +1,12 @@
+//! cargo run --example generate_api --features schema -- bitmex.json BitmexClient ../apis/bitmex/src
+use nebuchadnezzar_core::error::AnyResult;
+use nebuchadnezzar_core::schema::Generator;
+
+fn main() -> AnyResult<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 3 {
+        eprintln!("usage: generate_api <swagger.json> <ClientName> <src dir>");
+        std::process::exit(1);
+    }
+    Generator::from_path(&args[0], args[1].as_str())?.write(&args[2])
+}
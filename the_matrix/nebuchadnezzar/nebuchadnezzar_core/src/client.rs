@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::time::Instant;
 
 use async_trait::async_trait;
 
@@ -11,15 +12,35 @@ use serde::{Deserialize, Serialize};
 use sorted_vec::SortedSet;
 
 
+use crate::emulation;
 use crate::error::{AnyResult, NebError, RemoteError, Result};
+use crate::metrics::{self, Metric};
 use crate::paginators::{Paginator, PaginatorStream, SuperPaginatorStream};
-use crate::requests::{CandlesGetRequest, TradesGetRequest};
+use crate::requests::{CandlesGetRequest, FundingGetRequest, TradesGetRequest};
 use crate::reqwest::Method;
 use crate::{Credentials, Exchange, SuperExchange, Support};
 
+pub const REST_DURATION: Metric = Metric::histogram(
+    "neb_rest_request_duration_seconds",
+    "Duration of REST requests.",
+);
+pub const REST_ERRORS: Metric = Metric::counter("neb_rest_errors_total", "Failed REST requests.");
+/// Set by clients of exchanges that report it.
+pub const RATE_LIMIT_REMAINING: Metric = Metric::gauge(
+    "neb_rate_limit_remaining",
+    "Requests left until the exchange starts rate limiting.",
+);
+pub const RATE_LIMITED: Metric = Metric::counter(
+    "neb_rate_limited_total",
+    "Requests rejected because of rate limit.",
+);
+
 #[derive(Default, Clone, Debug)]
 pub struct ClientCapability {
+    /// Timeframes of candles that exchange natively supports, see `timeframe_support` for others.
     pub timeframes: SortedSet<u32>,
+    /// True if candle timestamp marks the end of a candle instead of its beginning.
+    pub candle_timestamp_at_close: bool,
     pub cors: Support,
     pub cancel_order: Support,
     pub create_deposit_address: Support,
@@ -41,9 +62,45 @@ pub struct ClientCapability {
     pub fetch_tickers: Support,
     pub fetch_bids_asks: Support,
     pub fetch_trades: Support,
+    /// Trades can be fetched for a time range, candles can only be built from such trades.
+    pub fetch_trade_history: Support,
+    pub fetch_funding: Support,
     pub withdraw: Support,
 }
 
+impl ClientCapability {
+    /// Marks candle fetching as emulated if it can be built from trades.
+    pub fn with_emulation(mut self) -> Self {
+        if self.fetch_candles == Support::No && self.fetch_trade_history != Support::No {
+            self.fetch_candles = Support::Emulated;
+        }
+        self
+    }
+
+    /// Largest natively supported timeframe that `timeframe` can be resampled from.
+    pub fn base_timeframe(&self, timeframe: u32) -> Option<u32> {
+        self.timeframes
+            .iter()
+            .rev()
+            .find(|x| timeframe % **x == 0)
+            .copied()
+    }
+
+    pub fn timeframe_support(&self, timeframe: u32) -> Support {
+        if timeframe == 0 {
+            Support::No
+        } else if self.timeframes.contains(&timeframe) {
+            Support::Yes
+        } else if self.base_timeframe(timeframe).is_some()
+            || self.fetch_trade_history != Support::No
+        {
+            Support::Emulated
+        } else {
+            Support::No
+        }
+    }
+}
+
 pub trait Pageable: Sized {
     const MAX_ITEMS_PER_PAGE: u32;
 }
@@ -57,6 +114,11 @@ pub trait Request<C: Client>: Serialize + Send {
     const SIGNED: bool;
     const ENDPOINT: &'static str;
     type Response: DeserializeOwned;
+
+    /// Endpoint with path parameters filled in.
+    fn endpoint(&self) -> String {
+        Self::ENDPOINT.into()
+    }
 }
 
 trait ToUrlQuery: Serialize {
@@ -105,11 +167,15 @@ pub trait Client: Send {
         R: Request<Self>,
         R::Response: DeserializeOwned,
     {
-        let url = format!("{}{}", self.exchange().api_url(), R::ENDPOINT);
+        let url = format!("{}{}", self.exchange().api_url(), req.endpoint());
         let url = match R::METHOD {
             Method::GET | Method::DELETE => {
-                if std::mem::size_of::<R>() != 0 {
-                    Url::parse_with_params(&url, req.to_url_query())?
+                let query = match std::mem::size_of::<R>() {
+                    0 => vec![],
+                    _ => req.to_url_query(),
+                };
+                if !query.is_empty() {
+                    Url::parse_with_params(&url, query)?
                 } else {
                     Url::parse(&url)?
                 }
@@ -124,7 +190,19 @@ pub trait Client: Send {
             _ => "".to_string(),
         };
 
-        self.request_raw::<R>(url, body).await
+        let start = Instant::now();
+        let result = self.request_raw::<R>(url, body).await;
+        let method = R::METHOD;
+        let labels = [
+            ("exchange", self.exchange().name()),
+            ("method", method.as_str()),
+            ("endpoint", R::ENDPOINT),
+        ];
+        metrics::observe(&REST_DURATION, &labels, start.elapsed().as_secs_f64());
+        if result.is_err() {
+            metrics::inc(&REST_ERRORS, &labels);
+        }
+        result
     }
 
     fn paginate<'c, 'p, P, R>(
@@ -147,18 +225,31 @@ pub trait Converter<SR: SuperRequest>: Client + Sized {
 }
 
 #[async_trait]
-pub trait SuperClient: Send {
+pub trait SuperClient: Send + Sync {
     fn capability(&self) -> ClientCapability;
     fn exchange_dyn(&self) -> Box<dyn SuperExchange>;
     fn authenticate(&mut self, credentials: Credentials) -> AnyResult<()>;
+    /// Fetches candles of any timeframe, timeframes that aren't natively supported are resampled
+    /// from a supported timeframe or built from trades.
     async fn fetch_candles(
         &self,
         req: CandlesGetRequest,
+    ) -> AnyResult<<CandlesGetRequest as SuperRequest>::SuperResponse> {
+        emulation::fetch_candles(self, req).await
+    }
+    /// Fails if timeframe isn't natively supported.
+    async fn fetch_native_candles(
+        &self,
+        req: CandlesGetRequest,
     ) -> AnyResult<<CandlesGetRequest as SuperRequest>::SuperResponse>;
     async fn fetch_trades(
         &self,
         req: TradesGetRequest,
     ) -> AnyResult<<TradesGetRequest as SuperRequest>::SuperResponse>;
+    async fn fetch_funding(
+        &self,
+        req: FundingGetRequest,
+    ) -> AnyResult<<FundingGetRequest as SuperRequest>::SuperResponse>;
     fn paginate_candles<'c: 'p, 'p>(
         &'c self,
         paginator: Pin<
@@ -183,6 +274,18 @@ pub trait SuperClient: Send {
             >,
         >,
     ) -> SuperPaginatorStream<'p, TradesGetRequest>;
+    fn paginate_funding<'c: 'p, 'p>(
+        &'c self,
+        paginator: Pin<
+            Box<
+                dyn Paginator<
+                        FundingGetRequest,
+                        <FundingGetRequest as SuperRequest>::SuperResponse,
+                        Item = AnyResult<FundingGetRequest>,
+                    > + 'p,
+            >,
+        >,
+    ) -> SuperPaginatorStream<'p, FundingGetRequest>;
 }
 
 // impl<C, SR, P> Paginator<C, C::Req, SR> for P
@@ -215,19 +318,23 @@ pub async fn handle_response<T: DeserializeOwned>(resp: Response) -> Result<T> {
     return if resp.status().is_success() {
         let resp = resp.text().await?;
         // debug!("response body: |{}|", resp);
-        match serde_json::from_str::<T>(&resp) {
-            Ok(resp) => Ok(resp),
-            Err(e) => {
-                error!("Cannot deserialize '{}'", resp);
-                error!("{:?}", e);
-                Err(e.into())
-            }
-        }
+        parse_response(&resp)
     } else {
         Err(NebError::RemoteError(RemoteError::from(resp).await).into())
     };
 }
 
+pub fn parse_response<T: DeserializeOwned>(resp: &str) -> Result<T> {
+    match serde_json::from_str::<T>(resp) {
+        Ok(resp) => Ok(resp),
+        Err(e) => {
+            error!("Cannot deserialize '{}'", resp);
+            error!("{:?}", e);
+            Err(e.into())
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct NotResponse(());
 
@@ -241,6 +348,10 @@ impl<C: Client> Request<C> for NotRequest {
     type Response = NotResponse;
 }
 
+impl Pageable for NotRequest {
+    const MAX_ITEMS_PER_PAGE: u32 = 0;
+}
+
 pub struct NotClient<E>(PhantomData<E>);
 
 #[async_trait]
@@ -0,0 +1,309 @@
+//! Estimates exchange server time. Local clock can drift which causes rejected signatures and
+//! late candles. Offset and round-trip time are smoothed over samples taken from http `Date`
+//! headers and websocket timestamps. `Date` header only has a resolution of a second, so it is
+//! only used for offset until a sample with millisecond resolution arrives.
+//!
+//! Code that needs current time or has to wait for it should go through `Clock` so that it can be
+//! driven by `SimulatedClock` when recorded data is replayed.
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use futures_util::future::{BoxFuture, FutureExt};
+use reqwest::header::{HeaderMap, DATE};
+use tokio::sync::Notify;
+
+/// Weight of a new sample once enough samples have been collected.
+const SMOOTHING: f64 = 0.1;
+/// Samples with round-trip time that is this many times larger than average are ignored, most of
+/// their latency is on one side which makes offset inaccurate.
+const MAX_RTT_RATIO: f64 = 3.;
+/// Minimum accepted round-trip time before a sample is considered an outlier.
+const MIN_RTT_LIMIT_MS: f64 = 100.;
+
+/// Source of current time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+    /// Resolves once the clock has reached `deadline`.
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> BoxFuture<'_, ()>;
+}
+
+#[derive(Debug, Default)]
+pub struct ServerClock {
+    state: Mutex<ClockState>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ClockState {
+    /// Server time - local time.
+    offset_ms: f64,
+    rtt_ms: Option<f64>,
+    samples: u32,
+    /// Offset has been estimated from samples with millisecond resolution.
+    precise: bool,
+}
+
+impl ServerClock {
+    /// Current server time.
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset()
+    }
+
+    /// Server time - local time.
+    pub fn offset(&self) -> Duration {
+        Duration::microseconds((self.state.lock().unwrap().offset_ms * 1000.) as i64)
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .unwrap()
+            .rtt_ms
+            .map(|x| Duration::microseconds((x * 1000.) as i64))
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.state.lock().unwrap().samples
+    }
+
+    /// Adds a sample of a request that was sent at `sent` and received at `received` local time.
+    /// Server is assumed to have created `server_time` halfway through.
+    pub fn observe(
+        &self,
+        sent: DateTime<Utc>,
+        received: DateTime<Utc>,
+        server_time: DateTime<Utc>,
+    ) {
+        self.observe_request(sent, received, server_time, true);
+    }
+
+    fn observe_request(
+        &self,
+        sent: DateTime<Utc>,
+        received: DateTime<Utc>,
+        server_time: DateTime<Utc>,
+        precise: bool,
+    ) {
+        let rtt_ms = (received - sent).num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.;
+        let mut state = self.state.lock().unwrap();
+        if let Some(avg_rtt_ms) = state.rtt_ms {
+            if rtt_ms > (avg_rtt_ms * MAX_RTT_RATIO).max(MIN_RTT_LIMIT_MS) {
+                trace!("Ignoring clock sample with {}ms round-trip time.", rtt_ms);
+                return;
+            }
+        }
+        let local_ms = sent.timestamp_millis() as f64 + rtt_ms / 2.;
+        let offset_ms = server_time.timestamp_millis() as f64 - local_ms;
+        state.add(offset_ms, Some(rtt_ms), precise);
+    }
+
+    /// Adds a sample of a message that server has sent at `server_time` and we have received at
+    /// `received`. Half of the average round-trip time is used as latency.
+    pub fn observe_received(&self, received: DateTime<Utc>, server_time: DateTime<Utc>) {
+        let mut state = self.state.lock().unwrap();
+        let latency_ms = state.rtt_ms.unwrap_or_default() / 2.;
+        let offset_ms = server_time.timestamp_millis() as f64
+            - (received.timestamp_millis() as f64 - latency_ms);
+        state.add(offset_ms, None, true);
+    }
+
+    /// Adds a sample from `Date` header of a response. Header has a resolution of a second, middle
+    /// of the second is used. Once there are precise samples only round-trip time is taken.
+    pub fn observe_headers(
+        &self,
+        sent: DateTime<Utc>,
+        received: DateTime<Utc>,
+        headers: &HeaderMap,
+    ) {
+        let date = headers
+            .get(DATE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| DateTime::parse_from_rfc2822(x).ok());
+        match date {
+            Some(date) => {
+                let server_time = date.with_timezone(&Utc) + Duration::milliseconds(500);
+                self.observe_request(sent, received, server_time, false)
+            }
+            None => trace!("Response without a valid date header."),
+        }
+    }
+}
+
+impl Clock for ServerClock {
+    fn now(&self) -> DateTime<Utc> {
+        ServerClock::now(self)
+    }
+
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> BoxFuture<'_, ()> {
+        let duration = match (deadline - self.now()).to_std() {
+            Ok(duration) if duration > std::time::Duration::from_secs(0) => duration,
+            _ => return async {}.boxed(),
+        };
+        async move {
+            // Using this timer instead of tokio one because of timer resolution.
+            // Tokio timer fires after 1-2 ms where this one after 0.2 ms.
+            let mut interval = async_timer::Interval::platform_new(duration);
+            interval.as_mut().await;
+        }
+        .boxed()
+    }
+}
+
+/// Clock that only moves when it is told to.
+#[derive(Debug)]
+pub struct SimulatedClock {
+    now: Mutex<DateTime<Utc>>,
+    /// Deadlines of sleeps that haven't been reached yet, some of them may have been dropped.
+    deadlines: Mutex<BTreeSet<DateTime<Utc>>>,
+    advanced: Notify,
+}
+
+impl SimulatedClock {
+    pub fn new(now: DateTime<Utc>) -> SimulatedClock {
+        SimulatedClock {
+            now: Mutex::new(now),
+            deadlines: Mutex::new(BTreeSet::new()),
+            advanced: Notify::new(),
+        }
+    }
+
+    /// Moves clock to `now` and wakes sleeps that have reached their deadline, time never goes
+    /// backwards.
+    pub fn advance_to(&self, now: DateTime<Utc>) {
+        {
+            let mut current = self.now.lock().unwrap();
+            if now <= *current {
+                return;
+            }
+            *current = now;
+        }
+        self.deadlines.lock().unwrap().retain(|x| *x > now);
+        self.advanced.notify_waiters();
+    }
+
+    /// Earliest deadline of a sleep, time should be advanced to it before going past it so that
+    /// sleeping tasks run in order.
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.deadlines.lock().unwrap().iter().next().copied()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> BoxFuture<'_, ()> {
+        async move {
+            loop {
+                // Created before checking time so that an advance in between isn't missed.
+                let advanced = self.advanced.notified();
+                if self.now() >= deadline {
+                    return;
+                }
+                self.deadlines.lock().unwrap().insert(deadline);
+                advanced.await;
+            }
+        }
+        .boxed()
+    }
+}
+
+impl ClockState {
+    fn add(&mut self, offset_ms: f64, rtt_ms: Option<f64>, precise: bool) {
+        if let Some(rtt_ms) = rtt_ms {
+            self.rtt_ms = Some(match self.rtt_ms {
+                Some(avg) => avg + (rtt_ms - avg) * SMOOTHING,
+                None => rtt_ms,
+            });
+        }
+        if self.precise && !precise {
+            return;
+        }
+        if precise && !self.precise {
+            // Estimate from coarse samples is replaced.
+            self.precise = true;
+            self.samples = 0;
+        }
+        self.samples = self.samples.saturating_add(1);
+        // Averages first samples equally so that estimate converges quickly.
+        let weight = (1. / self.samples as f64).max(SMOOTHING);
+        self.offset_ms += (offset_ms - self.offset_ms) * weight;
+    }
+}
+
+#[cfg(test)]
+mod t_clock {
+    use chrono::TimeZone;
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn ms(ms: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis(ms)
+    }
+
+    #[test]
+    fn t_observe() {
+        let clock = ServerClock::default();
+        assert_eq!(clock.offset(), Duration::zero());
+        // Server is 2s ahead, 100ms round trip.
+        clock.observe(ms(10_000), ms(10_100), ms(12_050));
+        assert_eq!(clock.offset(), Duration::seconds(2));
+        assert_eq!(clock.rtt(), Some(Duration::milliseconds(100)));
+        clock.observe(ms(20_000), ms(20_100), ms(22_250));
+        assert_eq!(clock.offset(), Duration::milliseconds(2100));
+        // Outlier is ignored.
+        clock.observe(ms(30_000), ms(31_000), ms(40_000));
+        assert_eq!(clock.samples(), 2);
+        clock.observe_received(ms(40_000), ms(42_050));
+        assert_eq!(clock.offset(), Duration::milliseconds(2100));
+        assert!((clock.now() - Utc::now() - Duration::milliseconds(2100)).num_milliseconds() < 50);
+    }
+
+    #[test]
+    fn t_observe_headers() {
+        let clock = ServerClock::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            DATE,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:00:12 GMT"),
+        );
+        clock.observe_headers(ms(10_000), ms(10_000), &headers);
+        assert_eq!(clock.offset(), Duration::milliseconds(2500));
+        clock.observe_headers(ms(10_000), ms(10_000), &HeaderMap::new());
+        assert_eq!(clock.samples(), 1);
+        // Precise sample replaces the estimate, later headers only update round-trip time.
+        clock.observe(ms(20_000), ms(20_100), ms(22_050));
+        assert_eq!(clock.offset(), Duration::seconds(2));
+        clock.observe_headers(ms(30_000), ms(30_050), &headers);
+        assert_eq!(clock.offset(), Duration::seconds(2));
+        assert_eq!(clock.samples(), 1);
+        assert_eq!(clock.rtt().unwrap().num_milliseconds(), 14);
+    }
+
+    #[test]
+    fn t_simulated_clock() {
+        let clock = SimulatedClock::new(ms(10_000));
+        assert_eq!(clock.now(), ms(10_000));
+        clock.advance_to(ms(12_000));
+        assert_eq!(clock.now(), ms(12_000));
+        clock.advance_to(ms(11_000));
+        assert_eq!(clock.now(), ms(12_000));
+    }
+
+    #[tokio::test]
+    async fn t_simulated_sleep() {
+        let clock = SimulatedClock::new(ms(10_000));
+        clock.sleep_until(ms(9_000)).await;
+        let mut sleep = clock.sleep_until(ms(11_000));
+        assert!(futures_util::poll!(&mut sleep).is_pending());
+        assert_eq!(clock.next_deadline(), Some(ms(11_000)));
+        clock.advance_to(ms(10_500));
+        assert!(futures_util::poll!(&mut sleep).is_pending());
+        clock.advance_to(ms(11_000));
+        assert!(futures_util::poll!(&mut sleep).is_ready());
+        assert_eq!(clock.next_deadline(), None);
+    }
+}
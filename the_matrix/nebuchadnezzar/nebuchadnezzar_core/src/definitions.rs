@@ -1,3 +1,4 @@
+use crate::error::{NebError, Result};
 use crate::prelude::{DateTime, Decimal, Utc};
 
 #[derive(Clone, Debug)]
@@ -16,3 +17,192 @@ pub struct Trade {
     pub price: Decimal,
     pub amount: Decimal,
 }
+
+/// Funding exchanged between longs and shorts of a perpetual contract at `timestamp`. Positive
+/// rate means that longs pay shorts.
+#[derive(Clone, Debug)]
+pub struct Funding {
+    pub timestamp: DateTime<Utc>,
+    pub rate: Decimal,
+    /// Rate scaled to one day.
+    pub daily_rate: Decimal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarketKind {
+    /// Base asset is exchanged for quote asset. Amount is in base and value in quote currency,
+    /// there is no leverage and no funding.
+    Spot,
+    /// Contract without expiry that is kept close to index price with funding payments. Amount of
+    /// an inverse contract is in quote currency and its value in base currency.
+    Perpetual { inverse: bool },
+}
+
+impl MarketKind {
+    pub fn is_inverse(&self) -> bool {
+        matches!(self, MarketKind::Perpetual { inverse: true })
+    }
+
+    pub fn has_funding(&self) -> bool {
+        matches!(self, MarketKind::Perpetual { .. })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Market {
+    pub symbol: String,
+    pub base: String,
+    pub quote: String,
+    pub kind: MarketKind,
+    pub filters: MarketFilters,
+    /// Fee rate of orders that add liquidity, negative for rebates.
+    pub maker_fee: Decimal,
+    /// Fee rate of orders that take liquidity.
+    pub taker_fee: Decimal,
+}
+
+/// Limits that exchange places on orders. Zero means that there is no limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MarketFilters {
+    /// Price must be a multiple of tick size.
+    pub tick_size: Decimal,
+    /// Amount must be a multiple of lot size.
+    pub lot_size: Decimal,
+    pub min_amount: Decimal,
+    pub min_price: Decimal,
+    /// Minimal order value (`price * amount`) in quote currency.
+    pub min_notional: Decimal,
+}
+
+impl MarketFilters {
+    /// Rounds to the nearest tick.
+    pub fn round_price(&self, price: Decimal) -> Decimal {
+        if self.tick_size.is_zero() {
+            return price;
+        }
+        (price / self.tick_size).round() * self.tick_size
+    }
+
+    /// Rounds towards zero so that we never trade more than requested.
+    pub fn round_amount(&self, amount: Decimal) -> Decimal {
+        if self.lot_size.is_zero() {
+            return amount;
+        }
+        (amount / self.lot_size).trunc() * self.lot_size
+    }
+
+    /// Checks an order with rounded price and amount. Amount can be negative for sell orders.
+    pub fn check(&self, price: Decimal, amount: Decimal) -> Result<()> {
+        let amount = amount.abs();
+        if !self.tick_size.is_zero() && !(price % self.tick_size).is_zero() {
+            return Err(NebError::MarketFilter(format!(
+                "price {} is not a multiple of tick size {}",
+                price, self.tick_size
+            )));
+        }
+        if !self.lot_size.is_zero() && !(amount % self.lot_size).is_zero() {
+            return Err(NebError::MarketFilter(format!(
+                "amount {} is not a multiple of lot size {}",
+                amount, self.lot_size
+            )));
+        }
+        if price < self.min_price {
+            return Err(NebError::MarketFilter(format!(
+                "price {} is below {}",
+                price, self.min_price
+            )));
+        }
+        if amount < self.min_amount {
+            return Err(NebError::MarketFilter(format!(
+                "amount {} is below {}",
+                amount, self.min_amount
+            )));
+        }
+        if price * amount < self.min_notional {
+            return Err(NebError::MarketFilter(format!(
+                "value {} is below {}",
+                price * amount,
+                self.min_notional
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Balance of a single asset, spot markets have one for base and one for quote currency.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Balance {
+    pub asset: String,
+    /// Available for new orders.
+    pub free: Decimal,
+    /// Locked in open orders.
+    pub used: Decimal,
+}
+
+impl Balance {
+    pub fn total(&self) -> Decimal {
+        self.free + self.used
+    }
+}
+
+#[cfg(test)]
+mod t_definitions {
+    use super::*;
+
+    fn dec(x: &str) -> Decimal {
+        x.parse().unwrap()
+    }
+
+    fn filters() -> MarketFilters {
+        MarketFilters {
+            tick_size: dec("0.05"),
+            lot_size: dec("0.001"),
+            min_amount: dec("0.01"),
+            min_price: dec("0"),
+            min_notional: dec("10"),
+        }
+    }
+
+    #[test]
+    fn t_round() {
+        let filters = filters();
+        assert_eq!(filters.round_price(dec("100.03")), dec("100.05"));
+        assert_eq!(filters.round_price(dec("100.02")), dec("100.00"));
+        assert_eq!(filters.round_amount(dec("1.2349")), dec("1.234"));
+        assert_eq!(filters.round_amount(dec("-1.2349")), dec("-1.234"));
+        assert_eq!(
+            MarketFilters::default().round_amount(dec("1.2349")),
+            dec("1.2349")
+        );
+    }
+
+    #[test]
+    fn t_check() {
+        let filters = filters();
+        assert!(filters.check(dec("100.05"), dec("0.1")).is_ok());
+        assert!(filters.check(dec("100.05"), dec("-0.1")).is_ok());
+        assert!(filters.check(dec("100.03"), dec("0.1")).is_err());
+        assert!(filters.check(dec("100.05"), dec("0.1001")).is_err());
+        assert!(filters.check(dec("1000"), dec("0.005")).is_err());
+        // 0.09 * 100 < 10
+        assert!(filters.check(dec("100"), dec("0.09")).is_err());
+    }
+
+    #[test]
+    fn t_market_kind() {
+        assert!(!MarketKind::Spot.has_funding());
+        assert!(!MarketKind::Spot.is_inverse());
+        assert!(MarketKind::Perpetual { inverse: true }.is_inverse());
+        assert!(MarketKind::Perpetual { inverse: false }.has_funding());
+    }
+
+    #[test]
+    fn t_balance() {
+        let balance = Balance {
+            asset: "BTC".into(),
+            free: dec("1.5"),
+            used: dec("0.5"),
+        };
+        assert_eq!(balance.total(), dec("2"));
+    }
+}
@@ -0,0 +1,472 @@
+//! Serves candles of timeframes that exchange doesn't support by resampling a supported timeframe
+//! or by building them from trades.
+use chrono::{TimeZone, Utc};
+use futures_util::StreamExt;
+
+use crate::client::SuperClient;
+use crate::definitions::{Candle, Trade};
+use crate::error::{AnyResult, NebError};
+use crate::paginators::{BasicPaginatorState, BasicSuperPaginator, WhileSuperPaginator};
+use crate::requests::{CandlesGetRequest, TradesGetRequest};
+use crate::Support;
+
+pub async fn fetch_candles<C>(client: &C, req: CandlesGetRequest) -> AnyResult<Vec<Candle>>
+where
+    C: SuperClient + ?Sized,
+{
+    let capability = client.capability();
+    match capability.timeframe_support(req.timeframe) {
+        Support::Yes => client.fetch_native_candles(req).await,
+        Support::Emulated => {
+            let (start, end) = candle_range(&req)?;
+            let at_close = capability.candle_timestamp_at_close;
+            let mut candles = match capability.base_timeframe(req.timeframe) {
+                Some(base) => {
+                    let candles = fetch_base_candles(client, &req, base, start, end, at_close);
+                    resample_candles(&candles.await?, req.timeframe, at_close)
+                }
+                None => {
+                    let trades = fetch_trades(client, &req.symbol, start, end).await?;
+                    candles_from_trades(&trades, req.timeframe, at_close)
+                }
+            };
+            let (start, end) = if at_close {
+                (start + req.timeframe as i64, end + req.timeframe as i64)
+            } else {
+                (start, end)
+            };
+            candles.retain(|x| x.timestamp.timestamp() >= start && x.timestamp.timestamp() < end);
+            if let Some(count) = req.count {
+                candles.truncate(count as usize);
+            }
+            Ok(candles)
+        }
+        Support::No => Err(NebError::Unsupported("timeframe").into()),
+    }
+}
+
+/// Merges sorted candles of a smaller timeframe into `timeframe`. Candles that belong to
+/// the same period don't need to be complete.
+pub fn resample_candles(candles: &[Candle], timeframe: u32, at_close: bool) -> Vec<Candle> {
+    let mut resampled: Vec<Candle> = Vec::new();
+    for candle in candles {
+        let period = period(candle.timestamp.timestamp(), timeframe, at_close);
+        match resampled.last_mut() {
+            Some(last) if last.timestamp.timestamp() == period => {
+                last.high = last.high.max(candle.high);
+                last.low = last.low.min(candle.low);
+                last.close = candle.close;
+                last.volume += candle.volume;
+            }
+            _ => resampled.push(Candle {
+                timestamp: Utc.timestamp(period, 0),
+                ..candle.clone()
+            }),
+        }
+    }
+    resampled
+}
+
+/// Builds candles from sorted trades. Periods without trades are skipped.
+pub fn candles_from_trades(trades: &[Trade], timeframe: u32, at_close: bool) -> Vec<Candle> {
+    let mut candles: Vec<Candle> = Vec::new();
+    for trade in trades {
+        let period = period(trade.timestamp.timestamp(), timeframe, at_close);
+        match candles.last_mut() {
+            Some(last) if last.timestamp.timestamp() == period => {
+                last.high = last.high.max(trade.price);
+                last.low = last.low.min(trade.price);
+                last.close = trade.price;
+                last.volume += trade.amount.abs();
+            }
+            _ => candles.push(Candle {
+                timestamp: Utc.timestamp(period, 0),
+                open: trade.price,
+                high: trade.price,
+                low: trade.price,
+                close: trade.price,
+                volume: trade.amount.abs(),
+            }),
+        }
+    }
+    candles
+}
+
+/// Returns timestamp of a candle in `timeframe` that contains `timestamp` of a smaller candle or
+/// a trade.
+fn period(timestamp: i64, timeframe: u32, at_close: bool) -> i64 {
+    let timeframe = timeframe as i64;
+    if at_close {
+        // (end - timeframe, end]
+        (timestamp + timeframe - 1).div_euclid(timeframe) * timeframe
+    } else {
+        timestamp.div_euclid(timeframe) * timeframe
+    }
+}
+
+/// Returns start and end of requested candles in seconds aligned to the timeframe. Start and end
+/// are always opening times of candles.
+fn candle_range(req: &CandlesGetRequest) -> AnyResult<(i64, i64)> {
+    let timeframe = req.timeframe as i64;
+    let align = |x: i64| x - x.rem_euclid(timeframe);
+    let count = req.count.map(|x| x as i64 * timeframe);
+    let start = req.start_time.map(|x| align(x.timestamp()));
+    let end = req.end_time.map(|x| x.timestamp());
+    Ok(match (start, end, count) {
+        (Some(start), Some(end), _) => (start, end),
+        (Some(start), None, Some(count)) => (start, start + count),
+        (None, Some(end), Some(count)) => (align(end) - count, end),
+        (None, None, Some(count)) => {
+            // Includes partial candle.
+            let end = align(Utc::now().timestamp()) + timeframe;
+            (end - count, end)
+        }
+        _ => return Err(NebError::InvalidRequest.into()),
+    })
+}
+
+async fn fetch_base_candles<C>(
+    client: &C,
+    req: &CandlesGetRequest,
+    base: u32,
+    start: i64,
+    end: i64,
+    at_close: bool,
+) -> AnyResult<Vec<Candle>>
+where
+    C: SuperClient + ?Sized,
+{
+    // Candles that are timestamped at close are shifted by one base candle.
+    let offset = at_close as i64 * base as i64;
+    let symbol = req.symbol.clone();
+    let paginator = BasicSuperPaginator::new(
+        (start + offset) as u32,
+        (end + offset) as u32,
+        base,
+        move |state: &BasicPaginatorState| {
+            Ok(CandlesGetRequest {
+                timeframe: base,
+                symbol: symbol.clone(),
+                count: Some(state.count),
+                start_time: Some(Utc.timestamp(state.i as i64, 0)),
+                end_time: None,
+            })
+        },
+    );
+    let mut stream = client.paginate_candles(Box::pin(paginator));
+    let mut candles = Vec::new();
+    while let Some(page) = stream.next().await {
+        candles.extend(page?);
+    }
+    Ok(candles)
+}
+
+async fn fetch_trades<C>(client: &C, symbol: &str, start: i64, end: i64) -> AnyResult<Vec<Trade>>
+where
+    C: SuperClient + ?Sized,
+{
+    let end_time = Utc.timestamp(end, 0);
+    let mut start_time = Utc.timestamp(start, 0);
+    // Number of already received trades at `start_time`.
+    let mut offset = 0;
+    let paginator = WhileSuperPaginator::new(
+        Ok(TradesGetRequest {
+            symbol: symbol.into(),
+            count: None,
+            offset: None,
+            start_time: Some(start_time),
+            end_time: Some(end_time),
+        }),
+        move |response: &AnyResult<Vec<Trade>>, max_count| {
+            let response = match response {
+                Ok(response) if !response.is_empty() => response,
+                _ => return None,
+            };
+            let last_ts = response.last().unwrap().timestamp;
+            if last_ts >= end_time {
+                return None;
+            }
+            if last_ts != start_time {
+                start_time = last_ts;
+                offset = response
+                    .iter()
+                    .rev()
+                    .filter(|x| x.timestamp == last_ts)
+                    .count();
+            } else {
+                offset += response.len();
+            }
+            Some(Ok(TradesGetRequest {
+                symbol: symbol.into(),
+                count: Some(max_count),
+                offset: Some(offset as i32),
+                start_time: Some(start_time),
+                end_time: Some(end_time),
+            }))
+        },
+    );
+    let mut stream = client.paginate_trades(Box::pin(paginator));
+    let mut trades = Vec::new();
+    while let Some(page) = stream.next().await {
+        trades.extend(page?.into_iter().filter(|x| x.timestamp < end_time));
+    }
+    Ok(trades)
+}
+
+#[cfg(test)]
+mod t_emulation {
+    use std::pin::Pin;
+
+    use async_trait::async_trait;
+    use rust_decimal::prelude::Zero;
+
+    use super::*;
+    use crate::client::{ClientCapability, SuperRequest};
+    use crate::paginators::{Paginator, SuperPaginatorStream};
+    use crate::requests::FundingGetRequest;
+    use crate::timeframes::*;
+    use crate::{Credentials, SuperExchange};
+
+    /// Serves 5 minute candles from memory, two candles per page.
+    struct FakeClient {
+        candles: Vec<Candle>,
+    }
+
+    impl FakeClient {
+        fn page(&self, req: &CandlesGetRequest) -> AnyResult<Vec<Candle>> {
+            if req.timeframe != m5 {
+                return Err(NebError::Unsupported("timeframe").into());
+            }
+            let start = req.start_time.ok_or(NebError::InvalidRequest)?;
+            Ok(self
+                .candles
+                .iter()
+                .filter(|x| x.timestamp >= start)
+                .take(req.count.unwrap_or(u32::MAX) as usize)
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[async_trait]
+    impl SuperClient for FakeClient {
+        fn capability(&self) -> ClientCapability {
+            let mut capability = ClientCapability::default();
+            capability.timeframes = vec![m5].into();
+            capability.fetch_candles = Support::Yes;
+            capability
+        }
+
+        fn exchange_dyn(&self) -> Box<dyn SuperExchange> {
+            unimplemented!()
+        }
+
+        fn authenticate(&mut self, _credentials: Credentials) -> AnyResult<()> {
+            Ok(())
+        }
+
+        async fn fetch_native_candles(&self, req: CandlesGetRequest) -> AnyResult<Vec<Candle>> {
+            self.page(&req)
+        }
+
+        async fn fetch_trades(&self, _req: TradesGetRequest) -> AnyResult<Vec<Trade>> {
+            Err(NebError::Unsupported("trades").into())
+        }
+
+        async fn fetch_funding(
+            &self,
+            _req: FundingGetRequest,
+        ) -> AnyResult<<FundingGetRequest as SuperRequest>::SuperResponse> {
+            Err(NebError::Unsupported("funding").into())
+        }
+
+        fn paginate_candles<'c: 'p, 'p>(
+            &'c self,
+            mut paginator: Pin<Box<dyn Paginator<CandlesGetRequest, Vec<Candle>> + 'p>>,
+        ) -> SuperPaginatorStream<'p, CandlesGetRequest> {
+            paginator.as_mut().set_max_items_per_page(2);
+            SuperPaginatorStream::new(Box::pin(futures_util::stream::unfold(
+                paginator,
+                move |mut paginator| async move {
+                    let page = paginator.next().await?.and_then(|req| self.page(&req));
+                    paginator.as_mut().on_page(&page);
+                    Some((page, paginator))
+                },
+            )))
+        }
+
+        fn paginate_trades<'c: 'p, 'p>(
+            &'c self,
+            _paginator: Pin<Box<dyn Paginator<TradesGetRequest, Vec<Trade>> + 'p>>,
+        ) -> SuperPaginatorStream<'p, TradesGetRequest> {
+            unimplemented!()
+        }
+
+        fn paginate_funding<'c: 'p, 'p>(
+            &'c self,
+            _paginator: Pin<
+                Box<
+                    dyn Paginator<
+                            FundingGetRequest,
+                            <FundingGetRequest as SuperRequest>::SuperResponse,
+                        > + 'p,
+                >,
+            >,
+        ) -> SuperPaginatorStream<'p, FundingGetRequest> {
+            unimplemented!()
+        }
+    }
+
+    fn candle(timestamp: i64, open: i64, high: i64, low: i64, close: i64) -> Candle {
+        Candle {
+            timestamp: Utc.timestamp(timestamp, 0),
+            open: open.into(),
+            high: high.into(),
+            low: low.into(),
+            close: close.into(),
+            volume: 1.into(),
+        }
+    }
+
+    fn trade(timestamp: i64, price: i64, amount: i64) -> Trade {
+        Trade {
+            timestamp: Utc.timestamp(timestamp, 0),
+            price: price.into(),
+            amount: amount.into(),
+        }
+    }
+
+    #[test]
+    fn t_resample_candles() {
+        let m1_candles: Vec<_> = (0..5)
+            .map(|i| candle(i * 60, 10 + i, 20 + i, 5 - i, 11 + i))
+            .collect();
+        let candles = resample_candles(&m1_candles, 2 * m1, false);
+        assert_eq!(candles.len(), 3);
+        assert_eq!(candles[0].timestamp.timestamp(), 0);
+        assert_eq!(candles[0].open, 10.into());
+        assert_eq!(candles[0].high, 21.into());
+        assert_eq!(candles[0].low, 4.into());
+        assert_eq!(candles[0].close, 12.into());
+        assert_eq!(candles[0].volume, 2.into());
+        assert_eq!(candles[2].timestamp.timestamp(), 240);
+        assert_eq!(candles[2].volume, 1.into());
+        // Bins that are timestamped at close: (0, 120], (120, 240], (240, 360]
+        let candles = resample_candles(&m1_candles, 2 * m1, true);
+        assert_eq!(candles.len(), 3);
+        assert_eq!(candles[0].timestamp.timestamp(), 0);
+        assert_eq!(candles[1].timestamp.timestamp(), 120);
+        assert_eq!(candles[1].open, 11.into());
+        assert_eq!(candles[1].close, 13.into());
+        assert_eq!(candles[2].timestamp.timestamp(), 240);
+    }
+
+    #[test]
+    fn t_candles_from_trades() {
+        let trades = vec![
+            trade(1, 10, 2),
+            trade(30, 12, -1),
+            trade(59, 9, 3),
+            trade(130, 11, 1),
+        ];
+        let candles = candles_from_trades(&trades, m1, false);
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].open, 10.into());
+        assert_eq!(candles[0].high, 12.into());
+        assert_eq!(candles[0].low, 9.into());
+        assert_eq!(candles[0].close, 9.into());
+        assert_eq!(candles[0].volume, 6.into());
+        assert_eq!(candles[1].timestamp.timestamp(), 120);
+        assert!(!candles[1].volume.is_zero());
+        let candles = candles_from_trades(&trades, m1, true);
+        assert_eq!(candles[0].timestamp.timestamp(), 60);
+        assert_eq!(candles[1].timestamp.timestamp(), 180);
+    }
+
+    #[test]
+    fn t_timeframe_support() {
+        let mut capability = ClientCapability::default();
+        capability.timeframes = vec![m1, m5, h1, d1].into();
+        assert_eq!(capability.timeframe_support(m5), Support::Yes);
+        assert_eq!(capability.timeframe_support(15 * m1), Support::Emulated);
+        assert_eq!(capability.base_timeframe(15 * m1), Some(m5));
+        assert_eq!(capability.base_timeframe(4 * h1), Some(h1));
+        assert_eq!(capability.timeframe_support(30), Support::No);
+        // Recent trades aren't enough to build candles.
+        capability.fetch_trades = Support::Yes;
+        assert_eq!(capability.timeframe_support(30), Support::No);
+        capability.fetch_trade_history = Support::Yes;
+        assert_eq!(capability.timeframe_support(30), Support::Emulated);
+        assert_eq!(capability.base_timeframe(30), None);
+    }
+
+    #[test]
+    fn t_candle_range() -> AnyResult<()> {
+        let req = CandlesGetRequest {
+            timeframe: 2 * m1,
+            symbol: "XBTUSD".into(),
+            count: Some(3),
+            start_time: Some(Utc.timestamp(130, 0)),
+            end_time: None,
+        };
+        assert_eq!(candle_range(&req)?, (120, 480));
+        let req = CandlesGetRequest {
+            start_time: None,
+            end_time: Some(Utc.timestamp(500, 0)),
+            ..req
+        };
+        assert_eq!(candle_range(&req)?, (120, 500));
+        let req = CandlesGetRequest {
+            count: None,
+            end_time: None,
+            ..req
+        };
+        assert!(candle_range(&req).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn t_fetch_candles() -> AnyResult<()> {
+        let client = FakeClient {
+            candles: (0..12)
+                .map(|i| candle(i * m5 as i64, 10 + i, 20 + i, 5 - i, 11 + i))
+                .collect(),
+        };
+        let req = CandlesGetRequest {
+            timeframe: 15 * m1,
+            symbol: "XBTUSD".into(),
+            count: Some(3),
+            start_time: Some(Utc.timestamp(20 * m1 as i64, 0)),
+            end_time: None,
+        };
+        // Starts at the beginning of the candle that contains start time.
+        let candles = client.fetch_candles(req.clone()).await?;
+        assert_eq!(candles.len(), 3);
+        assert_eq!(candles[0].timestamp.timestamp(), 15 * m1 as i64);
+        assert_eq!(candles[0].open, 13.into());
+        assert_eq!(candles[0].high, 25.into());
+        assert_eq!(candles[0].low, 0.into());
+        assert_eq!(candles[0].close, 16.into());
+        assert_eq!(candles[0].volume, 3.into());
+        assert_eq!(candles[2].timestamp.timestamp(), 45 * m1 as i64);
+        assert_eq!(candles[2].close, 22.into());
+        // Natively supported timeframe isn't emulated.
+        let candles = client
+            .fetch_candles(CandlesGetRequest {
+                timeframe: m5,
+                ..req.clone()
+            })
+            .await?;
+        assert_eq!(candles.len(), 3);
+        assert_eq!(candles[0].timestamp.timestamp(), 20 * m1 as i64);
+        // Can't be built from trades.
+        let result = client
+            .fetch_candles(CandlesGetRequest {
+                timeframe: 30,
+                ..req
+            })
+            .await;
+        assert!(result.is_err());
+        Ok(())
+    }
+}
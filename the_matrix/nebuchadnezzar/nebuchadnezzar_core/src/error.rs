@@ -33,6 +33,10 @@ pub enum NebError {
     /// hangs forever.
     #[error("Request took too long to process")]
     Timeout,
+    #[error("No recorded interaction for {0}")]
+    CassetteMiss(String),
+    #[error("Order rejected by market filter: {0}")]
+    MarketFilter(String),
 }
 
 // #[derive(Error, Debug)]
@@ -6,15 +6,19 @@
 #![feature(unboxed_closures)]
 
 pub mod client;
+pub mod clock;
 pub mod commands;
 pub mod definitions;
+pub mod emulation;
 pub mod error;
+pub mod metrics;
 pub mod paginators;
 pub mod requests;
 #[cfg(feature = "schema")]
 pub mod schema;
 pub mod serializers;
 pub mod signatures;
+pub mod vcr;
 pub mod websocket;
 
 #[macro_use]
@@ -46,7 +50,7 @@ use std::pin::Pin;
 pub use async_trait::async_trait;
 
 use futures_util::StreamExt;
-pub use {chrono, futures_util, log, serde, serde_json, sorted_vec, tokio};
+pub use {anyhow, chrono, futures_util, log, serde, serde_json, sorted_vec, tokio};
 
 // pub use tokio_tungstenite;
 use crate::client::{
@@ -93,7 +97,7 @@ pub trait SuperExchange {
     async fn new_web_socket_dyn(&self) -> AnyResult<Box<dyn SuperWebSocket>>;
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Support {
     No,
     Yes,
@@ -222,7 +226,7 @@ macro_rules! def_and_impl_traits_bounded_by {
             }
 
             fn client_capability(&self) -> ClientCapability {
-                <E::Client as Client>::capability()
+                <E::Client as Client>::capability().with_emulation()
             }
 
             fn web_socket_capability(&self) -> WebSocketCapability {
@@ -254,7 +258,7 @@ macro_rules! def_and_impl_traits_bounded_by {
                 $(+ CommandConverter<$command>)+,
         {
             fn capability(&self) -> ClientCapability {
-                Self::capability()
+                Self::capability().with_emulation()
             }
 
             fn exchange_dyn(&self) -> Box<dyn SuperExchange> {
@@ -265,10 +269,12 @@ macro_rules! def_and_impl_traits_bounded_by {
                 Ok(<Self as Client>::authenticate(self, credentials)?)
             }
 
-            def_fetch!(fetch_candles, CandlesGetRequest);
+            def_fetch!(fetch_native_candles, CandlesGetRequest);
             def_fetch!(fetch_trades, TradesGetRequest);
+            def_fetch!(fetch_funding, FundingGetRequest);
             def_paginate!(paginate_candles, CandlesGetRequest);
             def_paginate!(paginate_trades, TradesGetRequest);
+            def_paginate!(paginate_funding, FundingGetRequest);
         }
 
         #[async_trait]
@@ -325,7 +331,10 @@ macro_rules! def_and_impl_traits_bounded_by {
     };
 }
 
-def_and_impl_traits_bounded_by!((CandlesGetRequest TradesGetRequest), (PingSuperCommand));
+def_and_impl_traits_bounded_by!(
+    (CandlesGetRequest TradesGetRequest FundingGetRequest),
+    (PingSuperCommand)
+);
 
 pub struct NotExchange(());
 #[async_trait]
@@ -0,0 +1,256 @@
+//! Process wide metrics in Prometheus text format. Metrics are declared as `Metric` constants next
+//! to the code that records them, series are created on first use.
+//!
+//! ```
+//! use nebuchadnezzar_core::metrics::{self, Metric};
+//!
+//! const REQUESTS: Metric = Metric::counter("requests_total", "Requests sent.");
+//! metrics::inc(&REQUESTS, &[("endpoint", "/order")]);
+//! ```
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+/// Upper bounds of histogram buckets in seconds.
+const BUCKETS_S: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.,
+];
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    /// Durations in seconds.
+    Histogram,
+}
+
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+impl Metric {
+    pub const fn counter(name: &'static str, help: &'static str) -> Metric {
+        Metric {
+            name,
+            help,
+            kind: Kind::Counter,
+        }
+    }
+
+    pub const fn gauge(name: &'static str, help: &'static str) -> Metric {
+        Metric {
+            name,
+            help,
+            kind: Kind::Gauge,
+        }
+    }
+
+    pub const fn histogram(name: &'static str, help: &'static str) -> Metric {
+        Metric {
+            name,
+            help,
+            kind: Kind::Histogram,
+        }
+    }
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<Vec<(&'static str, String)>, Series>,
+}
+
+enum Series {
+    Value(f64),
+    Histogram {
+        /// Not cumulative, `+Inf` bucket is `count`.
+        buckets: [u64; BUCKETS_S.len()],
+        sum: f64,
+        count: u64,
+    },
+}
+
+fn with_series(metric: &Metric, labels: &[(&'static str, &str)], f: impl FnOnce(&mut Series)) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(metric.name).or_insert_with(|| Family {
+        help: metric.help,
+        kind: metric.kind,
+        series: BTreeMap::new(),
+    });
+    let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    let series = family
+        .series
+        .entry(labels)
+        .or_insert_with(|| match metric.kind {
+            Kind::Counter | Kind::Gauge => Series::Value(0.),
+            Kind::Histogram => Series::Histogram {
+                buckets: [0; BUCKETS_S.len()],
+                sum: 0.,
+                count: 0,
+            },
+        });
+    f(series);
+}
+
+/// Increments a counter by 1.
+pub fn inc(metric: &Metric, labels: &[(&'static str, &str)]) {
+    add(metric, labels, 1.);
+}
+
+pub fn add(metric: &Metric, labels: &[(&'static str, &str)], value: f64) {
+    debug_assert_eq!(metric.kind, Kind::Counter);
+    with_series(metric, labels, |series| {
+        if let Series::Value(x) = series {
+            *x += value;
+        }
+    });
+}
+
+pub fn set(metric: &Metric, labels: &[(&'static str, &str)], value: f64) {
+    debug_assert_eq!(metric.kind, Kind::Gauge);
+    with_series(metric, labels, |series| {
+        if let Series::Value(x) = series {
+            *x = value;
+        }
+    });
+}
+
+/// Records a duration in seconds.
+pub fn observe(metric: &Metric, labels: &[(&'static str, &str)], seconds: f64) {
+    debug_assert_eq!(metric.kind, Kind::Histogram);
+    with_series(metric, labels, |series| {
+        if let Series::Histogram {
+            buckets,
+            sum,
+            count,
+        } = series
+        {
+            if let Some(i) = BUCKETS_S.iter().position(|x| seconds <= *x) {
+                buckets[i] += 1;
+            }
+            *sum += seconds;
+            *count += 1;
+        }
+    });
+}
+
+fn write_labels(out: &mut String, labels: &[(&'static str, String)], le: Option<&str>) {
+    let mut labels: Vec<_> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{}\"", le));
+    }
+    if !labels.is_empty() {
+        write!(out, "{{{}}}", labels.join(",")).unwrap();
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders all metrics in Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, series) in &family.series {
+            match series {
+                Series::Value(value) => {
+                    out.push_str(name);
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", value).unwrap();
+                }
+                Series::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let mut cumulative = 0;
+                    for (le, n) in BUCKETS_S.iter().zip(buckets.iter()) {
+                        cumulative += n;
+                        write!(out, "{}_bucket", name).unwrap();
+                        write_labels(&mut out, labels, Some(&le.to_string()));
+                        writeln!(out, " {}", cumulative).unwrap();
+                    }
+                    write!(out, "{}_bucket", name).unwrap();
+                    write_labels(&mut out, labels, Some("+Inf"));
+                    writeln!(out, " {}", count).unwrap();
+                    write!(out, "{}_sum", name).unwrap();
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", sum).unwrap();
+                    write!(out, "{}_count", name).unwrap();
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", count).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod t_metrics {
+    use super::*;
+
+    const REQUESTS: Metric = Metric::counter("t_requests_total", "Requests sent.");
+    const OPEN: Metric = Metric::gauge("t_open", "Open \"things\".");
+    const LATENCY: Metric = Metric::histogram("t_latency_seconds", "Latency.");
+
+    fn lines_of(name: &str) -> Vec<String> {
+        render()
+            .lines()
+            .filter(|x| x.contains(name))
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn t_render() {
+        inc(&REQUESTS, &[("endpoint", "/order")]);
+        add(&REQUESTS, &[("endpoint", "/order")], 2.);
+        inc(&REQUESTS, &[("endpoint", "/position\"")]);
+        assert_eq!(
+            lines_of("t_requests_total"),
+            vec![
+                "# HELP t_requests_total Requests sent.",
+                "# TYPE t_requests_total counter",
+                "t_requests_total{endpoint=\"/order\"} 3",
+                "t_requests_total{endpoint=\"/position\\\"\"} 1",
+            ]
+        );
+        set(&OPEN, &[], 2.);
+        set(&OPEN, &[], 1.);
+        assert_eq!(lines_of("t_open")[2], "t_open 1");
+        observe(&LATENCY, &[], 0.003);
+        observe(&LATENCY, &[], 0.003);
+        observe(&LATENCY, &[], 20.);
+        let lines = lines_of("t_latency_seconds");
+        assert!(lines.contains(&"t_latency_seconds_bucket{le=\"0.0025\"} 0".to_string()));
+        assert!(lines.contains(&"t_latency_seconds_bucket{le=\"0.005\"} 2".to_string()));
+        assert!(lines.contains(&"t_latency_seconds_bucket{le=\"10\"} 2".to_string()));
+        assert!(lines.contains(&"t_latency_seconds_bucket{le=\"+Inf\"} 3".to_string()));
+        assert!(lines.contains(&"t_latency_seconds_count 3".to_string()));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::client::SuperRequest;
-use crate::definitions::{Candle, Trade};
+use crate::definitions::{Candle, Funding, Trade};
 use crate::prelude::{DateTime, Utc};
 
 #[derive(Clone, Debug)]
@@ -26,6 +26,18 @@ impl SuperRequest for TradesGetRequest {
     type SuperResponse = Vec<Trade>;
 }
 
+/// Funding history of a perpetual contract, oldest first.
+#[derive(Clone, Debug)]
+pub struct FundingGetRequest {
+    pub symbol: String,
+    pub count: Option<u32>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+impl SuperRequest for FundingGetRequest {
+    type SuperResponse = Vec<Funding>;
+}
+
 #[derive(Clone, Debug)]
 pub struct NotSuperRequest;
 impl SuperRequest for NotSuperRequest {
@@ -1,659 +1,540 @@
-use bitflags::bitflags;
-use reqwest::Method;
+//! Generates `definitions.rs` and `requests.rs` of an exchange api crate from a Swagger 2.0
+//! (OpenAPI) document.
+//! ```ignore
+//! let generator = Generator::from_path("bitmex.json", "BitmexClient")?;
+//! generator.write("nebuchadnezzar/apis/bitmex/src")?;
+//! ```
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
 
-const EXPIRES: i64 = 5;
+use anyhow::anyhow;
+use inflector::Inflector;
+use serde::Deserialize;
 
-bitflags! {
-    pub struct PreProcess: u8 {
-        const StringifyKeys = 0b00000001;
-    }
+use crate::error::AnyResult;
+use crate::prelude::Value;
+
+const MAX_LINE_WIDTH: usize = 100;
+const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+const KEYWORDS: [&str; 50] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+/// Keywords that can't be used as raw identifiers.
+const NOT_RAW_KEYWORDS: [&str; 3] = ["crate", "self", "super"];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    #[serde(default)]
+    pub base_path: String,
+    #[serde(default)]
+    pub paths: BTreeMap<String, BTreeMap<String, Operation>>,
+    #[serde(default)]
+    pub definitions: BTreeMap<String, Schema>,
+    /// Requirements that apply to every operation that doesn't override them.
+    #[serde(default)]
+    pub security: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Operation {
+    pub operation_id: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub parameters: Vec<Parameter>,
+    #[serde(default)]
+    pub responses: BTreeMap<String, Response>,
+    pub security: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(rename = "in")]
+    pub location: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(flatten)]
+    pub schema: Schema,
+    /// Only set for body parameters.
+    #[serde(rename = "schema")]
+    pub body: Option<Schema>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Response {
+    pub description: Option<String>,
+    pub schema: Option<Schema>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Schema {
-    pub root_url: Option<String>,
-    pub definitions: Vec<DefinitionMethod>,
-    pub samples: Vec<DefinitionSample>,
-    pub api_key: Option<String>,
-    pub api_secret: Option<String>,
+    #[serde(rename = "$ref")]
+    pub reference: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub format: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub properties: BTreeMap<String, Schema>,
+    #[serde(default)]
+    pub required: Vec<String>,
+    pub items: Option<Box<Schema>>,
 }
-//
-// impl Schema {
-//     pub fn write_code<Client, P>(&mut self, dir: P, api_name: &str) -> Result<()>
-//     where
-//         Client: Requestable,
-//         P: AsRef<OsStr>,
-//     {
-//         self.fix_common_errors();
-//         let snake_name = api_name.to_snake_case();
-//         let dir = Path::new(&dir).join(&snake_name);
-//         let src = dir.join("src");
-//         std::fs::create_dir_all(&src)?;
-//
-//         info!("Generating project structure...");
-//         Schema::write_cargo_toml(&dir, &snake_name)?;
-//         self.write_lib_rs(&src, &snake_name)?;
-//         info!("Project structure done");
-//
-//         let client = if self.api_key.is_some() {
-//             Client::with_credentials(
-//                 self.api_key.take().unwrap(),
-//                 self.api_secret.take().unwrap(),
-//             )
-//         } else {
-//             Client::default()
-//         };
-//         let mut definitions = OpenOptions::new()
-//             .write(true)
-//             .create(true)
-//             .open(src.join("definitions.rs"))?;
-//
-//         let mut requests = OpenOptions::new()
-//             .write(true)
-//             .create(true)
-//             .open(src.join("requests.rs"))?;
-//
-//         definitions.write(
-//             b"use rust_decimal::Decimal;
-// use serde::{Deserialize, Serialize};\n\n",
-//         )?;
-//         requests.write_all(
-//             b"use rust_decimal::Decimal;
-// use super::definitions::*;
-// use request::{Method, Request};\n\n",
-//         )?;
-//
-//         info!("Generating from definitions...");
-//         for (i, definition) in self.definitions.iter().enumerate() {
-//             let code = definition.to_method_code(&client)?;
-//             definitions.write_all(code.definition.as_bytes())?;
-//             requests.write_all(code.request.as_bytes())?;
-//             trace!(
-//                 "Finished generating definition {} / {}",
-//                 i + 1,
-//                 self.definitions.len()
-//             );
-//         }
-//         info!("Definitions done");
-//         info!("Generating from samples...");
-//         for (i, sample) in self.samples.iter().enumerate() {
-//             let code = sample.to_method_code()?;
-//             definitions.write_all(code.definition.as_bytes())?;
-//             requests.write_all(code.request.as_bytes())?;
-//             trace!(
-//                 "Finished generating definition {} / {}",
-//                 i + 1,
-//                 self.samples.len()
-//             );
-//         }
-//         info!("Samples done");
-//         Ok(())
-//     }
-//
-//     fn write_cargo_toml(dir: &Path, snake_name: &str) -> Result<()> {
-//         let mut cargo = OpenOptions::new()
-//             .write(true)
-//             .create(true)
-//             .open(dir.join("Cargo.toml"))?;
-//         let template = format!(
-//             r#"[package]
-// name = "{}"
-// version = "0.1.0"
-// authors = ["Stock84-dev <leontk8@gmail.com>"]
-// edition = "2018"
-//
-// [features]
-// schema = ["request/schema"]
-//
-// # See more keys and their definitions at https://doc.rust-lang.org/cargo/reference/manifest.html
-//
-// [dependencies]
-// request = {{ path = "../request" }}
-// rust_decimal = "1.8.1"
-// serde = {{ version = "1.0.104", features = ["derive"] }}
-//
-// [dev-dependencies]
-// request = {{ path = "../request", features = ["schema"] }}
-// "#,
-//             snake_name
-//         );
-//         Ok(cargo.write_all(template.as_bytes())?)
-//     }
-//
-//     fn write_lib_rs(&self, src: &Path, snake_name: &str) -> Result<()> {
-//         let client_type = format!("{}Client", snake_name.to_pascal_case());
-//         let mut lib = OpenOptions::new()
-//             .write(true)
-//             .create(true)
-//             .open(src.join("lib.rs"))?;
-//         let template = format!(
-//             r#"#[macro_use]
-// extern crate serde;
-//
-// pub use request;
-// use request::*;
-//
-// pub mod definitions;
-// pub mod requests;
-//
-// #[derive(Default)]
-// pub struct {0} {{
-//     credentials: Option<Credentials>,
-//     client: ReqwestClient,
-// }}
-//
-// impl Requestable for {0} {{
-//     fn with_credentials(api_key: String, api_secret: String) -> Self {{
-//         Self {{
-//             credentials: Some(Credentials {{
-//                 api_key,
-//                 api_secret,
-//             }}),
-//             client: request::Client::builder().build().unwrap(),
-//         }}
-//     }}
-//     fn get_credentials(&self) -> &Option<Credentials> {{
-//         &self.credentials
-//     }}
-//
-//     fn get_client(&self) -> &ReqwestClient {{
-//         &self.client
-//     }}
-//
-//     fn build_request(builder: RequestBuilder, headers: RequestHeaders) -> RequestBuilder {{
-//         match headers {{
-//             RequestHeaders::Public => {{
-//                 builder
-//             }},
-//             RequestHeaders::Private(headers) => {{
-//                 builder
-//             }},
-//         }}
-//     }}
-// }}
-//
-//
-// #[cfg(feature = "schema")]
-// pub mod schema {{
-//     pub use request::schema::*;
-//     pub use request::*;
-//
-//     pub fn schema() -> Schema {{
-// {1}
-//     }}
-// }}
-//
-// "#,
-//             client_type,
-//             self.to_string()
-//         );
-//         Ok(lib.write_all(template.as_bytes())?)
-//     }
-//
-//     fn to_string(&self) -> String {
-//         let mut definitions = String::new();
-//         for definition in &self.definitions {
-//             definitions.push_str(&definition.to_string());
-//             definitions.push_str(",\n");
-//         }
-//         let mut samples = String::new();
-//         for sample in &self.samples {
-//             samples.push_str(&sample.to_string());
-//             samples.push_str(",\n");
-//         }
-//         let mut schema = format!(
-//             "       Schema {{
-//             root_url: None,
-//             definitions: vec![
-// {}
-//             ],
-//             samples: vec![
-// {}
-//             ],
-//             api_key: None,
-//             api_secret: None,
-//         }}",
-//             definitions, samples
-//         );
-//
-//         schema
-//     }
-//
-//     fn fix_common_errors(&mut self) {
-//         if let Some(root) = &self.root_url {
-//             for definition in &mut self.definitions {
-//                 definition.endpoint = format!("{}{}", root, &definition.endpoint);
-//             }
-//         }
-//
-//         for definition in &mut self.definitions {
-//             if definition.pre_process.contains(PreProcess::StringifyKeys) {
-//                 definition.payload = stringify_keys(&definition.payload);
-//             }
-//             let mut url = Url::parse(&definition.endpoint).unwrap();
-//             let mut json = String::from("{\n");
-//             let count = url.query_pairs().count();
-//             if count != 0 {
-//                 for (i, (name, value)) in url.query_pairs().enumerate() {
-//                     json.push('"');
-//                     json.push_str(&*name);
-//                     json.push_str(r#"":"#);
-//                     if value == "true" {
-//                         json.push_str("true");
-//                     } else if value == "false" {
-//                         json.push_str("false");
-//                     } else {
-//                         match Decimal::from_str(&value) {
-//                             Ok(_) => json.push_str(&*value),
-//                             Err(_) => {
-//                                 json.push('"');
-//                                 json.push_str(&*value);
-//                                 json.push('"');
-//                             }
-//                         };
-//                     };
-//                     if i != count - 1 {
-//                         json.push_str(",\n");
-//                     }
-//                 }
-//                 json.push_str("\n}");
-//                 if !definition.payload.is_empty() {
-//                     panic!("Payload already contains elements, tried to insert from url");
-//                 }
-//                 definition.payload = json;
-//             }
-//         }
-//         for sample in &mut self.samples {
-//             if sample.pre_process.contains(PreProcess::StringifyKeys) {
-//                 sample.payload = stringify_keys(&sample.payload);
-//                 sample.response = stringify_keys(&sample.response);
-//             }
-//         }
-//     }
-// }
-//
-#[derive(Debug)]
-pub struct DefinitionSample {
-    pub endpoint: String,
-    pub method: Method,
-    pub payload: String,
-    pub response: String,
-    pub is_signed: bool,
-    pub pre_process: PreProcess,
+
+/// Writes Rust code for one exchange api.
+pub struct Generator {
+    spec: Spec,
+    client: String,
 }
-//
-// impl DefinitionSample {
-//     fn from_method(method: &DefinitionMethod, response: String) -> DefinitionSample {
-//         DefinitionSample {
-//             endpoint: method.endpoint.clone(),
-//             method: method.method.clone(),
-//             payload: method.payload.clone(),
-//             response,
-//             is_signed: method.is_signed,
-//             pre_process: method.pre_process,
-//         }
-//     }
-//
-//     fn to_string(&self) -> String {
-//         format!(
-//             r##"                DefinitionSample {{
-//                     endpoint: "{}".into(),
-//                     method: Method::{},
-//                     payload: r#"{}"#.into(),
-//                     response: r#"{}"#.into(),
-//                     is_signed: {},
-//                     pre_process: PreProcess::empty(),
-//                 }}"##,
-//             self.endpoint,
-//             self.method.as_str().to_screaming_snake_case(),
-//             self.payload.trim(),
-//             self.response.trim(),
-//             if self.is_signed { "true" } else { "false" }
-//         )
-//     }
-//
-//     pub fn to_method_code(&self) -> Result<MethodCode> {
-//         // TODO: does this always clone?
-//         debug!("payload = |{}|", self.payload);
-//         debug!("response = |{}|", self.response);
-//         let response = if self.response.is_empty() {
-//             Structure::from_value(Value::Null, self.get_name_from_url())
-//         } else {
-//             let value = serde_json::from_str(&self.response).context(self.response.clone())?;
-//             Structure::from_value(value, self.get_name_from_url())
-//         };
-//         let (response_name, response_type) = DefinitionMethod::response_name_and_type(&response);
-//         let definition = response.to_definition();
-//         let request = self.to_request(&response_name, &response_type)?;
-//
-//         Ok(MethodCode {
-//             definition,
-//             request,
-//         })
-//     }
-//
-//     fn to_request(&self, response_name: &str, response_type: &str) -> Result<String> {
-//         let request_name = format!("{}{}", self.method.as_str().to_pascal_case(), response_name);
-//         let mut request = format!(
-//             r#"impl Request for {} {{
-//     const METHOD: Method = Method::{};
-//     const URL: &'static str = "{}";
-//     type Response = {};
-// }}
-// "#,
-//             request_name,
-//             self.method.as_str().to_screaming_snake_case(),
-//             match self.endpoint.rfind('?') {
-//                 Some(i) => &self.endpoint[..i],
-//                 None => &self.endpoint,
-//             },
-//             response_type,
-//         );
-//         if self.payload.is_empty() {
-//             request.insert_str(
-//                 0,
-//                 &format!(
-//                     "\n#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-// pub struct {};\n",
-//                     request_name
-//                 ),
-//             );
-//         } else {
-//             let value = serde_json::from_str(&self.payload)?;
-//             let payload = Structure::from_value(value, request_name);
-//             let mut definition = payload.to_definition();
-//             request.insert_str(0, &definition);
-//         };
-//         Ok(request)
-//     }
-//
-//     fn get_name_from_url(&self) -> String {
-//         let url = &self.endpoint;
-//         let name = match url.rfind("?") {
-//             None => {
-//                 let i = url.rfind("/").unwrap() + 1;
-//                 if i == url.len() {
-//                     let start = url[..i - 1].rfind("/").unwrap() + 1;
-//                     &url[start..i - 1]
-//                 } else {
-//                     &url[i..]
-//                 }
-//             }
-//             Some(mut i) => {
-//                 if url.chars().skip(i - 1).next().unwrap() == '/' {
-//                     i -= 1;
-//                 }
-//                 let slice = &url[url[..i].rfind("/").unwrap() + 1..i];
-//                 slice
-//             }
-//         };
-//         name.to_pascal_case()
-//     }
-// }
-//
-#[derive(Debug)]
-pub struct DefinitionMethod {
-    pub endpoint: String,
-    pub method: Method,
-    pub payload: String,
-    pub is_signed: bool,
-    pub pre_process: PreProcess,
+
+impl Generator {
+    pub fn new(spec: Spec, client: impl Into<String>) -> Generator {
+        Generator {
+            spec,
+            client: client.into(),
+        }
+    }
+
+    pub fn from_str(json: &str, client: impl Into<String>) -> AnyResult<Generator> {
+        Ok(Generator::new(serde_json::from_str(json)?, client))
+    }
+
+    pub fn from_path(path: impl AsRef<Path>, client: impl Into<String>) -> AnyResult<Generator> {
+        Generator::from_str(&std::fs::read_to_string(path)?, client)
+    }
+
+    /// Overwrites `definitions.rs` and `requests.rs` inside of `src_dir`.
+    pub fn write(&self, src_dir: impl AsRef<Path>) -> AnyResult<()> {
+        let src_dir = src_dir.as_ref();
+        std::fs::create_dir_all(src_dir)?;
+        std::fs::write(src_dir.join("definitions.rs"), self.definitions()?)?;
+        std::fs::write(src_dir.join("requests.rs"), self.requests()?)?;
+        info!("Api written to {:?}", src_dir);
+        Ok(())
+    }
+
+    pub fn definitions(&self) -> AnyResult<String> {
+        let mut code = String::from("use nebuchadnezzar_core::prelude::*;\n\n");
+        let mut structs = Vec::new();
+        for (name, schema) in &self.spec.definitions {
+            self.push_struct(&mut structs, &name.to_pascal_case(), schema)?;
+        }
+        for s in structs {
+            code.push_str(&s);
+        }
+        Ok(code)
+    }
+
+    pub fn requests(&self) -> AnyResult<String> {
+        let mut code = format!(
+            "use nebuchadnezzar_core::client::*;
+use nebuchadnezzar_core::prelude::*;
+
+use super::definitions::*;
+use crate::client::{};
+",
+            self.client
+        );
+        let mut structs = Vec::new();
+        let mut impls = String::new();
+        let mut names = HashSet::new();
+        for (path, operations) in &self.spec.paths {
+            for method in METHODS.iter() {
+                let operation = match operations.get(*method) {
+                    Some(operation) => operation,
+                    None => continue,
+                };
+                let name = operation_name(method, path);
+                if !names.insert(name.clone()) {
+                    return Err(anyhow!("Duplicate request name {}.", name));
+                }
+                let request = self.request_schema(operation)?;
+                let request_name = format!("{}Request", name);
+                let doc = operation.summary.as_ref().or(operation.description.as_ref());
+                let path_parameters: Vec<_> = operation
+                    .parameters
+                    .iter()
+                    .filter(|x| x.location == "path")
+                    .map(|x| x.name.clone())
+                    .collect();
+                let mut nested = Vec::new();
+                push_struct_code(
+                    &mut structs,
+                    &request_name,
+                    doc,
+                    &request,
+                    &path_parameters,
+                    |field, schema| self.field_type(&mut nested, &request_name, field, schema),
+                )?;
+                structs.append(&mut nested);
+                let response = self.response_type(&mut structs, &name, operation)?;
+                write!(
+                    impls,
+                    "impl Request<{}> for {}Request {{
+    const METHOD: Method = Method::{};
+    const SIGNED: bool = {};
+    const ENDPOINT: &'static str = \"{}\";
+    type Response = {};
+{}}}
+",
+                    self.client,
+                    name,
+                    method.to_uppercase(),
+                    self.is_signed(operation),
+                    path,
+                    response,
+                    endpoint_fn(path)?,
+                )?;
+            }
+        }
+        code.push('\n');
+        for s in structs {
+            code.push_str(&s);
+        }
+        code.push_str(&impls);
+        Ok(code)
+    }
+
+    fn is_signed(&self, operation: &Operation) -> bool {
+        match &operation.security {
+            Some(security) => !security.is_empty(),
+            None => !self.spec.security.is_empty(),
+        }
+    }
+
+    /// Merges path, query, form and body parameters into one object schema.
+    fn request_schema(&self, operation: &Operation) -> AnyResult<Schema> {
+        let mut request = Schema::default();
+        for parameter in &operation.parameters {
+            match (parameter.location.as_str(), &parameter.body) {
+                ("body", Some(body)) => {
+                    let body = self.resolve(body)?;
+                    for (name, property) in &body.properties {
+                        request
+                            .properties
+                            .insert(name.clone(), self.clone_schema(property));
+                    }
+                    request.required.extend(body.required.iter().cloned());
+                }
+                ("path", _) | ("query", _) | ("formData", _) => {
+                    let mut schema = self.clone_schema(&parameter.schema);
+                    if schema.description.is_none() {
+                        schema.description = parameter.description.clone();
+                    }
+                    request.properties.insert(parameter.name.clone(), schema);
+                    if parameter.required {
+                        request.required.push(parameter.name.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+
+    fn response_type(
+        &self,
+        structs: &mut Vec<String>,
+        name: &str,
+        operation: &Operation,
+    ) -> AnyResult<String> {
+        let response = operation
+            .responses
+            .iter()
+            .find(|(status, _)| status.starts_with('2'))
+            .and_then(|(_, response)| response.schema.as_ref());
+        let response_name = format!("{}Response", name);
+        match response {
+            Some(schema) if schema.reference.is_some() || schema.kind.as_deref() == Some("array") => {
+                self.rust_type(structs, &response_name, schema)
+            }
+            Some(schema) if !schema.properties.is_empty() => {
+                self.push_struct(structs, &response_name, schema)?;
+                Ok(response_name)
+            }
+            _ => {
+                structs.push(format!(
+                    "#[derive(Clone, Debug, Deserialize, Serialize, Default)]\npub struct {}(Value);\n",
+                    response_name
+                ));
+                Ok(response_name)
+            }
+        }
+    }
+
+    fn push_struct(&self, structs: &mut Vec<String>, name: &str, schema: &Schema) -> AnyResult<()> {
+        let mut nested = Vec::new();
+        push_struct_code(
+            structs,
+            name,
+            schema.description.as_ref(),
+            schema,
+            &[],
+            |field, property| self.field_type(&mut nested, name, field, property),
+        )?;
+        structs.append(&mut nested);
+        Ok(())
+    }
+
+    fn field_type(
+        &self,
+        structs: &mut Vec<String>,
+        parent: &str,
+        field: &str,
+        schema: &Schema,
+    ) -> AnyResult<String> {
+        self.rust_type(
+            structs,
+            &format!("{}{}", parent, field.to_pascal_case()),
+            schema,
+        )
+    }
+
+    /// Inline objects are emitted as a new struct named `name`.
+    fn rust_type(&self, structs: &mut Vec<String>, name: &str, schema: &Schema) -> AnyResult<String> {
+        if let Some(reference) = &schema.reference {
+            return Ok(reference_name(reference)?.to_pascal_case());
+        }
+        Ok(
+            match (schema.kind.as_deref(), schema.format.as_deref()) {
+                (Some("string"), Some("date-time")) => "DateTime<Utc>".into(),
+                (Some("string"), Some("guid")) | (Some("string"), Some("uuid")) => "Uuid".into(),
+                (Some("string"), _) => "String".into(),
+                (Some("integer"), Some("int64")) => "i64".into(),
+                (Some("integer"), _) => "i32".into(),
+                (Some("number"), Some("double")) => "f64".into(),
+                (Some("number"), _) => "Decimal".into(),
+                (Some("boolean"), _) => "bool".into(),
+                (Some("array"), _) => match &schema.items {
+                    Some(items) => format!("Vec<{}>", self.rust_type(structs, name, items)?),
+                    None => "Vec<Value>".into(),
+                },
+                (Some("object"), _) | (None, _) if !schema.properties.is_empty() => {
+                    self.push_struct(structs, name, schema)?;
+                    name.into()
+                }
+                _ => "Value".into(),
+            },
+        )
+    }
+
+    fn resolve<'a>(&'a self, schema: &'a Schema) -> AnyResult<&'a Schema> {
+        match &schema.reference {
+            Some(reference) => {
+                let name = reference_name(reference)?;
+                self.spec
+                    .definitions
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Definition {} not found.", name))
+            }
+            None => Ok(schema),
+        }
+    }
+
+    fn clone_schema(&self, schema: &Schema) -> Schema {
+        Schema {
+            reference: schema.reference.clone(),
+            kind: schema.kind.clone(),
+            format: schema.format.clone(),
+            description: schema.description.clone(),
+            properties: schema
+                .properties
+                .iter()
+                .map(|(k, v)| (k.clone(), self.clone_schema(v)))
+                .collect(),
+            required: schema.required.clone(),
+            items: schema.items.as_ref().map(|x| Box::new(self.clone_schema(x))),
+        }
+    }
+}
+
+fn push_struct_code(
+    structs: &mut Vec<String>,
+    name: &str,
+    doc: Option<&String>,
+    schema: &Schema,
+    path_parameters: &[String],
+    mut field_type: impl FnMut(&str, &Schema) -> AnyResult<String>,
+) -> AnyResult<()> {
+    let mut code = String::new();
+    let has_required = schema
+        .required
+        .iter()
+        .any(|x| schema.properties.contains_key(x));
+    let is_empty = schema.properties.is_empty();
+    if let Some(doc) = doc {
+        push_doc(&mut code, "", doc);
+    }
+    if has_required {
+        code.push_str("#[derive(Clone, Debug, Deserialize, Serialize)]\n");
+    } else {
+        code.push_str("#[derive(Clone, Debug, Deserialize, Serialize, Default)]\n");
+    }
+    if is_empty {
+        if name.ends_with("Request") {
+            writeln!(code, "pub struct {};", name)?;
+        } else {
+            writeln!(code, "pub struct {}(Value);", name)?;
+        }
+        structs.push(code);
+        return Ok(());
+    }
+    writeln!(code, "pub struct {} {{", name)?;
+    for (field, property) in &schema.properties {
+        let mut ty = field_type(field, property)?;
+        if !schema.required.contains(field) {
+            ty = format!("Option<{}>", ty);
+        }
+        if let Some(doc) = &property.description {
+            push_doc(&mut code, "    ", doc);
+        }
+        let rust_name = field_name(field);
+        if rust_name.trim_start_matches("r#") != field {
+            writeln!(code, "    #[serde(rename = \"{}\")]", field)?;
+        }
+        if path_parameters.contains(field) {
+            // Path parameters are part of the endpoint, see `endpoint_fn`.
+            code.push_str("    #[serde(skip_serializing)]\n");
+        }
+        writeln!(code, "    pub {}: {},", rust_name, ty)?;
+    }
+    code.push_str("}\n");
+    structs.push(code);
+    Ok(())
+}
+
+/// GET /order/closePosition -> GetOrderClosePosition, GET /order/{orderID} -> GetOrderByOrderId
+fn operation_name(method: &str, path: &str) -> String {
+    let mut name = method.to_pascal_case();
+    for segment in path.split('/').filter(|x| !x.is_empty()) {
+        if let Some(parameter) = path_parameter(segment) {
+            name.push_str("By");
+            name.push_str(&parameter.to_snake_case().to_pascal_case());
+        } else {
+            name.push_str(&segment.to_pascal_case());
+        }
+    }
+    name
+}
+
+/// {orderID} -> orderID
+fn path_parameter(segment: &str) -> Option<&str> {
+    segment.strip_prefix('{')?.strip_suffix('}')
+}
+
+/// Snake case name of a field that is escaped if it is a keyword, orderID -> order_id,
+/// type -> r#type, self -> self_.
+fn field_name(field: &str) -> String {
+    let name = field.to_snake_case();
+    if NOT_RAW_KEYWORDS.contains(&name.as_str()) {
+        format!("{}_", name)
+    } else if KEYWORDS.contains(&name.as_str()) {
+        format!("r#{}", name)
+    } else {
+        name
+    }
+}
+
+/// Overrides `Request::endpoint` for paths with parameters, e.g. /order/{orderID}.
+fn endpoint_fn(path: &str) -> AnyResult<String> {
+    let mut format = String::new();
+    let mut args = String::new();
+    for segment in path.split('/').filter(|x| !x.is_empty()) {
+        format.push('/');
+        match path_parameter(segment) {
+            Some(parameter) => {
+                format.push_str("{}");
+                write!(args, ", self.{}", field_name(parameter))?;
+            }
+            None => format.push_str(segment),
+        }
+    }
+    if args.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!(
+        "
+    fn endpoint(&self) -> String {{
+        format!(\"{}\"{})
+    }}
+",
+        format, args
+    ))
+}
+
+/// #/definitions/Order -> Order
+fn reference_name(reference: &str) -> AnyResult<&str> {
+    reference
+        .strip_prefix("#/definitions/")
+        .ok_or_else(|| anyhow!("Unsupported reference {}.", reference))
+}
+
+/// Writes doc comment that is wrapped at `MAX_LINE_WIDTH`.
+fn push_doc(code: &mut String, indent: &str, doc: &str) {
+    let prefix = format!("{}///", indent);
+    for paragraph in doc.trim().lines() {
+        let mut line = prefix.clone();
+        for word in paragraph.split_whitespace() {
+            if line.len() > prefix.len() && line.len() + 1 + word.len() > MAX_LINE_WIDTH {
+                code.push_str(&line);
+                code.push('\n');
+                line = prefix.clone();
+            }
+            line.push(' ');
+            line.push_str(word);
+        }
+        code.push_str(&line);
+        code.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod t_schema {
+    use super::*;
+
+    const SPEC: &str = include_str!("../fixtures/swagger.json");
+    const DEFINITIONS: &str = include_str!("../fixtures/definitions.rs.expected");
+    const REQUESTS: &str = include_str!("../fixtures/requests.rs.expected");
+
+    #[test]
+    fn t_generate_from_fixture() -> AnyResult<()> {
+        let generator = Generator::from_str(SPEC, "TestClient")?;
+        assert_eq!(generator.definitions()?, DEFINITIONS);
+        assert_eq!(generator.requests()?, REQUESTS);
+        Ok(())
+    }
+
+    #[test]
+    fn t_operation_name() {
+        assert_eq!(operation_name("get", "/chat/channels"), "GetChatChannels");
+        assert_eq!(
+            operation_name("post", "/order/closePosition"),
+            "PostOrderClosePosition"
+        );
+        assert_eq!(
+            operation_name("get", "/order/{orderID}"),
+            "GetOrderByOrderId"
+        );
+    }
+
+    #[test]
+    fn t_field_name() {
+        assert_eq!(field_name("orderID"), "order_id");
+        assert_eq!(field_name("type"), "r#type");
+        assert_eq!(field_name("async"), "r#async");
+        assert_eq!(field_name("self"), "self_");
+        assert_eq!(endpoint_fn("/order").unwrap(), "");
+        assert!(endpoint_fn("/order/{orderID}/fills")
+            .unwrap()
+            .contains("format!(\"/order/{}/fills\", self.order_id)"));
+    }
 }
-//
-// pub struct MethodCode {
-//     pub definition: String,
-//     pub request: String,
-// }
-//
-// impl DefinitionMethod {
-//     pub fn to_method_code<Client: Requestable>(&self, client: &Client) -> Result<MethodCode> {
-//         let mut runtime = tokio::runtime::Runtime::new()?;
-//         let json = runtime.block_on(request_definition(client, self))?;
-//         let sample = DefinitionSample::from_method(&self, json);
-//         sample.to_method_code()
-//     }
-//
-//     fn to_string(&self) -> String {
-//         format!(
-//             r##"                DefinitionMethod {{
-//                     endpoint: "{}".into(),
-//                     method: Method::{},
-//                     payload: r#"{}"#.into(),
-//                     is_signed: {},
-//                     pre_process: PreProcess::empty(),
-//                 }}"##,
-//             self.endpoint,
-//             self.method.as_str().to_screaming_snake_case(),
-//             self.payload.trim(),
-//             if self.is_signed { "true" } else { "false" },
-//         )
-//     }
-//
-//     fn response_name_and_type(response: &Structure) -> (String, String) {
-//         let mut response_type = response.name.to_pascal_case();
-//         let response_name = match response.kind {
-//             Kind::Array(_) => {
-//                 response_type = format!("Vec<{}>", &response_type[..response_type.len() - 1]);
-//                 format!("{}", response.name)
-//             }
-//             _ => response.name.clone(),
-//         };
-//         (response_name, response_type)
-//     }
-// }
-//
-// #[derive(Clone, Debug, PartialEq)]
-// pub enum Kind {
-//     Object(Vec<Structure>),
-//     Array(Box<Structure>),
-//     Number,
-//     Bool,
-//     String,
-// }
-//
-// #[derive(Clone, Debug, PartialEq)]
-// pub struct Structure {
-//     name: String,
-//     kind: Kind,
-//     optional: bool,
-// }
-//
-// impl Structure {
-//     pub fn from_value(value: Value, name: String) -> Structure {
-//         match value {
-//             Value::Null => Structure::new(name, Kind::Object(Vec::new())),
-//             Value::Bool(_) => Structure::new(name, Kind::Bool),
-//             Value::Number(_) => Structure::new(name, Kind::Number),
-//             Value::String(s) => match Decimal::from_str(&s) {
-//                 Ok(_) => Structure::new(name, Kind::Number),
-//                 Err(_) => Structure::new(name, Kind::String),
-//             },
-//             Value::Array(a) => Structure::from_array(a, name),
-//             Value::Object(o) => Structure::from_object(o, name),
-//         }
-//     }
-//
-//     pub fn to_definition(self) -> String {
-//         let s = match self.kind {
-//             Kind::Object(_) => self,
-//             Kind::Array(s) => *s,
-//             _ => panic!(),
-//         };
-//         let mut definition =
-//             String::from("\n#[derive(Clone, Debug, Default, Deserialize, Serialize)]\n");
-//         definition.push_str("pub struct ");
-//         definition.push_str(&s.name.to_pascal_case());
-//
-//         let structs = match s.kind {
-//             Kind::Object(o) => o,
-//             _ => panic!(),
-//         };
-//
-//         if structs.is_empty() {
-//             definition.push_str(";\n");
-//         } else {
-//             definition.push_str(" {\n");
-//             for s in structs {
-//                 let name = s.name.clone();
-//                 let optional = s.optional;
-//                 let mut kind = match s.kind {
-//                     Kind::Number => "Decimal".into(),
-//                     Kind::Bool => "bool".into(),
-//                     Kind::String => "String".into(),
-//                     Kind::Array(_) | Kind::Object(_) => {
-//                         let kind = s.name.to_pascal_case();
-//                         definition.insert_str(0, &s.to_definition());
-//                         kind
-//                     }
-//                 };
-//                 let name = if !name.is_snake_case() {
-//                     Structure::rename(&mut definition, &name);
-//                     name.to_snake_case()
-//                 } else if name == "type" {
-//                     Structure::rename(&mut definition, "type");
-//                     "kind".into()
-//                 } else {
-//                     name
-//                 };
-//                 definition.push_str("   pub ");
-//                 definition.push_str(&name);
-//                 definition.push_str(": ");
-//                 if optional {
-//                     definition.push_str("Option<");
-//                     definition.push_str(&kind);
-//                     definition.push('>');
-//                 } else {
-//                     definition.push_str(&kind);
-//                 }
-//                 definition.push_str(",\n");
-//             }
-//             definition.push_str("}\n");
-//         }
-//
-//         definition
-//     }
-//
-//     fn rename(definition: &mut String, name: &str) {
-//         definition.push_str("   #[serde(rename = \"");
-//         definition.push_str(&name);
-//         definition.push_str("\")]\n");
-//     }
-//
-//     fn from_object(object: Map<String, Value>, name: String) -> Structure {
-//         let mut structs = Vec::with_capacity(object.len());
-//         for (k, v) in object {
-//             structs.push(Structure::from_value(v, k));
-//         }
-//         Structure::new(name, Kind::Object(structs))
-//     }
-//
-//     fn from_array(array: Vec<Value>, collection_name: String) -> Structure {
-//         let object_name = &collection_name[..collection_name.len() - 1];
-//         let mut master = Structure::new(object_name.into(), Kind::Bool);
-//         for value in array {
-//             let s = Structure::from_value(value, object_name.into());
-//             if std::mem::discriminant(&master.kind) != std::mem::discriminant(&s.kind) {
-//                 master = s.clone();
-//             }
-//             match &mut master.kind {
-//                 Kind::Object(o) => {
-//                     let slave = s.get_object();
-//                     for mut so in slave {
-//                         if !o.contains(&so) {
-//                             so.optional = true;
-//                             o.push(so);
-//                         }
-//                     }
-//                 }
-//                 _ => {}
-//             }
-//         }
-//         Structure {
-//             name: collection_name,
-//             kind: Kind::Array(Box::new(master)),
-//             optional: false,
-//         }
-//     }
-//
-//     fn get_object(self) -> Vec<Structure> {
-//         match self.kind {
-//             Kind::Object(o) => o,
-//             _ => panic!(),
-//         }
-//     }
-//
-//     fn new(name: String, kind: Kind) -> Structure {
-//         Structure {
-//             name,
-//             kind,
-//             optional: false,
-//         }
-//     }
-// }
-//
-// async fn request_definition<C>(client: &C, definition_method: &DefinitionMethod) -> Result<String>
-// where
-//     C: Requestable,
-// {
-//     let expires = (Utc::now() + Duration::seconds(EXPIRES)).timestamp();
-//     let url = Url::parse(&definition_method.endpoint)?;
-//     let mut builder = client
-//         .client()
-//         .request(definition_method.method.clone(), url.clone());
-//     let body = match definition_method.method {
-//         Method::PUT | Method::POST => definition_method.payload.clone(),
-//         _ => "".to_string(),
-//     };
-//     builder = if definition_method.is_signed {
-//         let (key, signature) =
-//             client.signature(definition_method.method.clone(), expires, &url, &body)?;
-//         client.build_request(
-//             builder,
-//             RequestHeaders::Private(SignedRequestHeaders {
-//                 expires,
-//                 signature: &signature,
-//                 key,
-//             }),
-//         )
-//     } else {
-//         client.build_request(builder, RequestHeaders::Public)
-//     };
-//     if !body.is_empty() {
-//         debug!("body = |{}|", body);
-//         builder = builder.body(body);
-//     }
-//
-//     let resp = builder.send().await?;
-//     Ok(resp.text().await?)
-// }
-//
-// fn stringify_keys(string: &str) -> String {
-//     if string.is_empty() {
-//         return String::new();
-//     }
-//     let mut result = String::with_capacity(string.len());
-//     let mut iter = string.chars();
-//     let mut prev = iter.next().unwrap();
-//
-//     for line in string.lines() {
-//         if line.contains(':') {
-//             let mut is_key = false;
-//             let mut finished = false;
-//             for c in line.chars() {
-//                 if !finished && prev.is_whitespace() && !c.is_whitespace() {
-//                     is_key = true;
-//                     result.push('"');
-//                 } else if !finished && is_key && c == ':' {
-//                     result.push('"');
-//                     finished = true;
-//                 }
-//                 result.push(c);
-//                 prev = c;
-//             }
-//         } else {
-//             result.push_str(line);
-//         }
-//         result.push('\n');
-//     }
-//     result
-// }
@@ -0,0 +1,380 @@
+//! Record/replay transport for http and websocket traffic.
+//! While recording every http response and received websocket text frame is written to a cassette
+//! file. While replaying no connection is made, responses are looked up by method, path and query
+//! and websocket frames are returned in recorded order.
+//! ```ignore
+//! let vcr = Arc::new(Vcr::replay("fixtures/bitmex_session.json")?);
+//! let client = Bitmex::new(false).with_vcr(vcr).new_client();
+//! ```
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use reqwest::{Method, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::client::parse_response;
+use crate::error::{AnyResult, NebError, RemoteError, Result};
+
+pub const RECORD_ENV: &str = "NEB_VCR_RECORD";
+pub const REPLAY_ENV: &str = "NEB_VCR_REPLAY";
+const REDACTED: &str = "<REDACTED>";
+/// Query parameters that are used for authentication or change on every request. They are neither
+/// recorded nor matched.
+const IGNORED_QUERY_KEYS: [&str; 7] = [
+    "signature",
+    "api_key",
+    "apiKey",
+    "api-key",
+    "nonce",
+    "timestamp",
+    "recvWindow",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcrMode {
+    Record,
+    Replay,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+    /// Received websocket text frames.
+    pub frames: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Interaction {
+    pub method: String,
+    pub path: String,
+    /// Sorted by key.
+    pub query: Vec<(String, String)>,
+    pub body: String,
+    pub status: u16,
+    pub response: String,
+}
+
+#[derive(Debug)]
+pub struct Vcr {
+    mode: VcrMode,
+    path: PathBuf,
+    state: Mutex<VcrState>,
+}
+
+#[derive(Debug, Default)]
+struct VcrState {
+    cassette: Cassette,
+    /// Interactions that have already been replayed.
+    used: Vec<bool>,
+    next_frame: usize,
+    secrets: Vec<String>,
+    dirty: bool,
+}
+
+impl Vcr {
+    /// Starts an empty cassette that gets written to `path` on `save` or drop.
+    pub fn record(path: impl Into<PathBuf>) -> Vcr {
+        Vcr {
+            mode: VcrMode::Record,
+            path: path.into(),
+            state: Mutex::new(VcrState::default()),
+        }
+    }
+
+    pub fn replay(path: impl Into<PathBuf>) -> AnyResult<Vcr> {
+        let path = path.into();
+        let cassette: Cassette = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        Ok(Vcr {
+            mode: VcrMode::Replay,
+            path,
+            state: Mutex::new(VcrState {
+                used: vec![false; cassette.interactions.len()],
+                cassette,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Reads cassette path from `NEB_VCR_RECORD` or `NEB_VCR_REPLAY` environment variable, for
+    /// tools and examples, agents take it from their config.
+    pub fn from_env() -> AnyResult<Option<Vcr>> {
+        if let Ok(path) = std::env::var(REPLAY_ENV) {
+            info!("Replaying network traffic from {}", path);
+            return Ok(Some(Vcr::replay(path)?));
+        }
+        if let Ok(path) = std::env::var(RECORD_ENV) {
+            info!("Recording network traffic to {}", path);
+            return Ok(Some(Vcr::record(path)));
+        }
+        Ok(None)
+    }
+
+    pub fn mode(&self) -> VcrMode {
+        self.mode
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.mode == VcrMode::Replay
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every occurrence of `secret` gets replaced before it is written to a cassette.
+    pub fn redact(&self, secret: impl Into<String>) {
+        let secret = secret.into();
+        if secret.is_empty() {
+            return;
+        }
+        let mut state = self.state();
+        if !state.secrets.contains(&secret) {
+            state.secrets.push(secret);
+        }
+    }
+
+    pub fn record_http(&self, method: &Method, url: &Url, body: &str, status: u16, response: &str) {
+        let mut state = self.state();
+        let interaction = Interaction {
+            method: method.to_string(),
+            path: url.path().into(),
+            query: state.query(url),
+            body: state.redacted(body),
+            status,
+            response: state.redacted(response),
+        };
+        state.cassette.interactions.push(interaction);
+        state.used.push(true);
+        state.dirty = true;
+    }
+
+    /// Returns status and body of the first interaction that hasn't been replayed yet and matches
+    /// method, path and query.
+    pub fn replay_http(&self, method: &Method, url: &Url) -> Result<(u16, String)> {
+        let mut state = self.state();
+        let method = method.to_string();
+        let query = state.query(url);
+        let state = &mut *state;
+        let position = state
+            .cassette
+            .interactions
+            .iter()
+            .zip(state.used.iter())
+            .position(|(x, used)| {
+                !used && x.method == method && x.path == url.path() && x.query == query
+            });
+        match position {
+            Some(i) => {
+                state.used[i] = true;
+                let interaction = &state.cassette.interactions[i];
+                Ok((interaction.status, interaction.response.clone()))
+            }
+            None => Err(NebError::CassetteMiss(format!("{} {}", method, url))),
+        }
+    }
+
+    pub fn record_frame(&self, frame: &str) {
+        let mut state = self.state();
+        let frame = state.redacted(frame);
+        state.cassette.frames.push(frame);
+        state.dirty = true;
+    }
+
+    /// Returns `None` when all frames have been replayed.
+    pub fn next_frame(&self) -> Option<String> {
+        let mut state = self.state();
+        let frame = state.cassette.frames.get(state.next_frame).cloned();
+        state.next_frame += frame.is_some() as usize;
+        frame
+    }
+
+    pub fn cassette(&self) -> Cassette {
+        self.state().cassette.clone()
+    }
+
+    pub fn save(&self) -> AnyResult<()> {
+        let mut state = self.state();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(&state.cassette)?)?;
+        state.dirty = false;
+        Ok(())
+    }
+}
+
+impl Vcr {
+    /// State stays consistent after a panic because it is only appended to.
+    fn state(&self) -> MutexGuard<VcrState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Vcr {
+    fn drop(&mut self) {
+        // Cassette is still saved when the lock has been poisoned by a panicking test.
+        if self.mode == VcrMode::Record && self.state().dirty {
+            if let Err(e) = self.save() {
+                error!("Failed to save cassette {:?}: {:?}", self.path, e);
+            }
+        }
+    }
+}
+
+impl VcrState {
+    fn redacted(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in &self.secrets {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        text
+    }
+
+    fn query(&self, url: &Url) -> Vec<(String, String)> {
+        let mut query: Vec<_> = url
+            .query_pairs()
+            .filter(|(k, _)| !IGNORED_QUERY_KEYS.contains(&k.as_ref()))
+            .map(|(k, v)| (k.into_owned(), self.redacted(&v)))
+            .collect();
+        query.sort();
+        query
+    }
+}
+
+/// Same as `handle_response` but the response also gets recorded.
+pub async fn record_response<T: DeserializeOwned>(
+    vcr: &Vcr,
+    method: &Method,
+    url: &Url,
+    body: &str,
+    response: Response,
+) -> Result<T> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let text = response.text().await?;
+    vcr.record_http(method, url, body, status.as_u16(), &text);
+    if status.is_success() {
+        parse_response(&text)
+    } else {
+        Err(NebError::RemoteError(RemoteError {
+            url: url.clone(),
+            status,
+            headers,
+            text: Ok(text),
+        }))
+    }
+}
+
+pub fn replay_response<T: DeserializeOwned>(vcr: &Vcr, method: &Method, url: &Url) -> Result<T> {
+    let (status, text) = vcr.replay_http(method, url)?;
+    let status = StatusCode::from_u16(status).map_err(|e| NebError::Other(e.into()))?;
+    if status.is_success() {
+        parse_response(&text)
+    } else {
+        Err(NebError::RemoteError(RemoteError {
+            url: url.clone(),
+            status,
+            headers: Default::default(),
+            text: Ok(text),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod t_vcr {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn t_record_and_replay() -> AnyResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cassette.json");
+        let vcr = Vcr::record(&path);
+        vcr.redact("my-key");
+        vcr.record_http(
+            &Method::GET,
+            &url("https://x.com/api/v1/trade?symbol=XBTUSD&count=2&signature=abc"),
+            "",
+            200,
+            "[1,2]",
+        );
+        vcr.record_http(
+            &Method::GET,
+            &url("https://x.com/api/v1/trade?count=2&symbol=XBTUSD"),
+            "",
+            200,
+            "[3]",
+        );
+        vcr.record_frame(r#"{"request":{"args":["my-key"]},"success":true}"#);
+        drop(vcr);
+
+        let vcr = Vcr::replay(&path)?;
+        let cassette = vcr.cassette();
+        assert_eq!(
+            cassette.interactions[0].query,
+            vec![("count".into(), "2".into()), ("symbol".into(), "XBTUSD".into())]
+        );
+        assert!(!cassette.frames[0].contains("my-key"));
+        // Query order and ignored keys don't affect matching, equal requests replay in order.
+        let request = url("https://y.com/api/v1/trade?symbol=XBTUSD&count=2");
+        let first: Vec<u32> = replay_response(&vcr, &Method::GET, &request)?;
+        let second: Vec<u32> = replay_response(&vcr, &Method::GET, &request)?;
+        assert_eq!(first, vec![1, 2]);
+        assert_eq!(second, vec![3]);
+        assert!(matches!(
+            replay_response::<Vec<u32>>(&vcr, &Method::GET, &request),
+            Err(NebError::CassetteMiss(_))
+        ));
+        assert!(vcr.replay_http(&Method::POST, &request).is_err());
+        assert_eq!(vcr.next_frame(), Some(cassette.frames[0].clone()));
+        assert_eq!(vcr.next_frame(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn t_save_poisoned() -> AnyResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cassette.json");
+        let vcr = std::sync::Arc::new(Vcr::record(&path));
+        vcr.record_frame("frame");
+        let poisoner = vcr.clone();
+        let result = std::thread::spawn(move || {
+            let _state = poisoner.state.lock().unwrap();
+            panic!("poison");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(vcr.state.is_poisoned());
+        vcr.record_frame("after panic");
+        drop(vcr);
+
+        let vcr = Vcr::replay(&path)?;
+        assert_eq!(vcr.next_frame().as_deref(), Some("frame"));
+        assert_eq!(vcr.next_frame().as_deref(), Some("after panic"));
+        Ok(())
+    }
+
+    #[test]
+    fn t_replay_remote_error() -> AnyResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cassette.json");
+        let vcr = Vcr::record(&path);
+        let request = url("https://x.com/api/v1/order");
+        vcr.record_http(&Method::POST, &request, "{}", 400, "bad request");
+        vcr.save()?;
+
+        let vcr = Vcr::replay(&path)?;
+        match replay_response::<Vec<u32>>(&vcr, &Method::POST, &request) {
+            Err(NebError::RemoteError(e)) => {
+                assert_eq!(e.status, StatusCode::BAD_REQUEST);
+                assert_eq!(e.text.unwrap(), "bad request");
+            }
+            _ => panic!("Expected remote error."),
+        }
+        Ok(())
+    }
+}
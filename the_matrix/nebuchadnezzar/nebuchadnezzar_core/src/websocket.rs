@@ -8,7 +8,7 @@ pub use tokio::net::TcpStream;
 
 pub use {futures_util, tokio_tungstenite};
 
-use crate::error::{AnyResult, NebError, NotError, Result};
+use crate::error::{AnyResult, NebError, Result};
 
 use crate::{async_trait, Credentials, Exchange, SuperExchange, Support};
 
@@ -105,9 +105,35 @@ pub struct NotMessage(());
 
 impl<W: WebSocket> WsMessage<W> for NotMessage {}
 
+pub struct NotCommand(());
+
+impl<W: WebSocket<RawCommand = ()>> WsCommand<W> for NotCommand {
+    fn serialize(&self) -> W::RawCommand {}
+}
+
+impl<E: Exchange<WebSocket = NotWebSocket<E>> + Sync + Send> MessageConverter for NotWebSocket<E> {
+    type Msg = NotMessage;
+
+    fn convert_message(_: Self::Msg) -> SuperMessage {
+        unreachable!("This is not a WebSocket.")
+    }
+}
+
+impl<E, C> CommandConverter<C> for NotWebSocket<E>
+where
+    E: Exchange<WebSocket = NotWebSocket<E>> + Sync + Send,
+    C: SuperCommand,
+{
+    type Command = NotCommand;
+
+    fn convert_command(_: C) -> Self::Command {
+        unreachable!("This is not a WebSocket.")
+    }
+}
+
 #[async_trait]
 impl<E: Exchange<WebSocket = NotWebSocket<E>> + Sync + Send> WebSocket for NotWebSocket<E> {
-    type Error = NotError;
+    type Error = NebError;
     type Exchange = E;
     type Message = NotMessage;
     type RawCommand = ();
@@ -117,7 +143,7 @@ impl<E: Exchange<WebSocket = NotWebSocket<E>> + Sync + Send> WebSocket for NotWe
     }
 
     fn capability() -> WebSocketCapability {
-        unreachable!("This is not a WebSocket.")
+        WebSocketCapability::default()
     }
 
     async fn authenticate(&mut self, _: Credentials) -> Result<(), Self::Error> {
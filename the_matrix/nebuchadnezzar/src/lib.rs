@@ -23,4 +23,4 @@ macro_rules! include_all {
     }
 }
 
-include_all!(bitmex);
+include_all!(bitmex, birake);
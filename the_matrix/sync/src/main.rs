@@ -13,21 +13,17 @@ use half::f16;
 use iaas::mouse::num::traits::Float;
 use iaas::mysql::{get_model_source_id, insert_model_source, insert_model_values};
 use memmap2::MmapOptions;
-use merovingian::candles::Candles;
-use merovingian::candles_builder::CandleAppender;
-use merovingian::minable_models::Trade;
+use merovingian::candles::{Candle, Candles};
 use merovingian::speedy::{IsEof, LittleEndian, Readable, Writable};
-use mouse::error::Result;
+use mouse::error::{bail, Result};
 use mouse::log::*;
 use mouse::macros::futures_util::io::ErrorKind;
 use mouse::num::traits::{ToPrimitive, Zero};
 use mouse::num::NumExt;
 use mouse::time::{IntoDateTime, Timestamp};
 use nebuchadnezzar::core::client::SuperClient;
-use nebuchadnezzar::core::futures_util::StreamExt;
-use nebuchadnezzar::core::paginators::BasicPaginator;
 use nebuchadnezzar::core::requests::CandlesGetRequest;
-use nebuchadnezzar::core::Credentials;
+use nebuchadnezzar::core::{Credentials, Support};
 use residual_self_image::seek_report::find_max;
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
@@ -160,98 +156,54 @@ async fn load_bounds<'a, T: Readable<'a, LittleEndian> + Clone>(
     Ok(Some(Bounds { start, end }))
 }
 
+/// Maximum number of candles that are requested at once, the client paginates on its own.
+const CANDLES_PER_REQUEST: u32 = 1000;
+
+/// Appends candles between start and end to `tmp_path`, timeframes that exchange doesn't support
+/// are emulated by the client.
 async fn load_candles(
     tmp_path: impl AsRef<Path>,
     client: &Box<dyn SuperClient>,
     symbol: String,
     start: u32,
     end: u32,
-    fetching_timeframe: u32,
-    target_timeframe: u32,
+    timeframe: u32,
 ) -> Result<()> {
-    unimplemented!()
-    // let mut stream;
-    // if target_timeframe == u32::MAX {
-    //     panic!("Unsupported timeframe")
-    // } else {
-    //     stream = client
-    //         .paginate_candles(BasicPaginator::new_super(
-    //             start,
-    //             end,
-    //             fetching_timeframe,
-    //             move |state| {
-    //                 // trace!("{}% completed", (state.end - start) as f32 / (state.i - start) as
-    // f32);                 Ok(CandlesGetRequest {
-    //                     timeframe: fetching_timeframe,
-    //                     symbol: symbol.clone(),
-    //                     count: Some(state.count),
-    //                     start_time: Some(state.i.into_date_time()),
-    //                     end_time: None,
-    //                 })
-    //             },
-    //         ))
-    //         .map(|result| {
-    //             result.map(|response| {
-    //                 response
-    //                     .into_iter()
-    //                     .map(|candle| {
-    //                         let timestamp_ns = candle.timestamp.timestamp_ns() - 1;
-    //                         vec![
-    //                             Trade {
-    //                                 timestamp_ns,
-    //                                 price: candle.open.to_f32().unwrap(),
-    //                                 amount: 0.0,
-    //                             },
-    //                             Trade {
-    //                                 timestamp_ns,
-    //                                 price: candle.high.to_f32().unwrap(),
-    //                                 amount: 0.0,
-    //                             },
-    //                             Trade {
-    //                                 timestamp_ns,
-    //                                 price: candle.low.to_f32().unwrap(),
-    //                                 amount: 0.0,
-    //                             },
-    //                             Trade {
-    //                                 timestamp_ns,
-    //                                 price: candle.close.to_f32().unwrap(),
-    //                                 amount: candle.volume.to_f32().unwrap(),
-    //                             },
-    //                         ]
-    //                         .into_iter()
-    //                     })
-    //                     .flatten()
-    //             })
-    //         })
-    //         .boxed_local();
-    // }
-    // let mut writer = std::io::BufWriter::new(
-    //     std::fs::OpenOptions::new()
-    //         .create(true)
-    //         .write(true)
-    //         .append(true)
-    //         .open(tmp_path)?,
-    // );
-    // let mut candle_appender = CandleAppender::new(target_timeframe);
-    // while let Some(trades) = stream.next().await {
-    //     let trades = trades?;
-    //     for trade in trades {
-    //         candle_appender.tick(
-    //             ((trade.timestamp_ns - 1) / 1_000_000_000) as u32,
-    //             trade.price,
-    //             trade.amount,
-    //         );
-    //     }
-    //     for candle in candle_appender.candles() {
-    //         Writable::write_to_stream(candle, &mut writer)?;
-    //     }
-    //     trace!(
-    //         "{}",
-    //         candle_appender.candles()[0].timestamp.into_date_time()
-    //     );
-    //     candle_appender.clear();
-    // }
-    // Ok(())
+    let mut writer = std::io::BufWriter::new(
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(true)
+            .open(tmp_path)?,
+    );
+    let mut current = start - start % timeframe;
+    while current < end {
+        let count = ((end - current + timeframe - 1) / timeframe).min(CANDLES_PER_REQUEST);
+        let candles = client
+            .fetch_candles(CandlesGetRequest {
+                timeframe,
+                symbol: symbol.clone(),
+                count: Some(count),
+                start_time: Some(current.into_date_time()),
+                end_time: None,
+            })
+            .await?;
+        for candle in &candles {
+            let candle = Candle {
+                timestamp: candle.timestamp.timestamp_s(),
+                open: candle.open.to_f32().unwrap(),
+                high: candle.high.to_f32().unwrap(),
+                low: candle.low.to_f32().unwrap(),
+                close: candle.close.to_f32().unwrap(),
+                volume: candle.volume.to_f32().unwrap(),
+            };
+            Writable::write_to_stream(&candle, &mut writer)?;
+        }
+        trace!("{}", current.into_date_time());
+        current += count * timeframe;
+    }
+    writer.flush()?;
+    Ok(())
 }
 
 async fn merge_files(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
@@ -274,20 +226,21 @@ async fn fetch_candles(fetch_args: FetchConfig, candles_args: CandlesArgs) -> Re
         .into_iter()
         .find(|x| x.name() == fetch_args.exchange)
         .expect("Valid exchange name");
-    if candles_args.timeframe == 0 {
-        panic!("Invalid timeframe");
-    }
-    let mut timeframe = u32::MAX;
-    for t in exchange.client_capability().timeframes.iter().rev() {
-        if candles_args.timeframe % t == 0 {
-            timeframe = *t;
-            break;
-        }
+    let timeframe = candles_args.timeframe;
+    if exchange.client_capability().timeframe_support(timeframe) == Support::No {
+        bail!(
+            "{} s candles can't be fetched from {}.",
+            timeframe,
+            exchange.name()
+        );
     }
     let mut client = exchange.new_client_dyn();
     if let Some(config) = get_exchange_config() {
         if !config.api_key.is_empty() {
-            client.authenticate(Credentials::new(&config.api_key, &config.api_secret))?;
+            client.authenticate(Credentials::new(
+                config.api_key.expose(),
+                config.api_secret.expose(),
+            ))?;
         }
     }
     let mut relative_path = PathBuf::from("candles");
@@ -313,7 +266,6 @@ async fn fetch_candles(fetch_args: FetchConfig, candles_args: CandlesArgs) -> Re
                     start,
                     end,
                     timeframe,
-                    candles_args.timeframe,
                 )
                 .await?;
                 merge_files(&final_path, &tmp_path).await?;
@@ -327,7 +279,6 @@ async fn fetch_candles(fetch_args: FetchConfig, candles_args: CandlesArgs) -> Re
                 start,
                 end,
                 timeframe,
-                candles_args.timeframe,
             )
             .await?;
             merge_files(&tmp_path, &final_path).await?;
@@ -342,7 +293,6 @@ async fn fetch_candles(fetch_args: FetchConfig, candles_args: CandlesArgs) -> Re
                         fetch_args.from.timestamp_s(),
                         fetch_args.to.timestamp_s(),
                         timeframe,
-                        candles_args.timeframe,
                     )
                     .await?;
                     tokio::fs::rename(tmp_path, &final_path).await?;
@@ -510,9 +460,7 @@ async fn main() -> Result<()> {
     // mmap.flush()?;
 
     return Ok(());
-    unsafe {
-        config::load("config.yaml")?;
-    }
+    config::load("config.yaml")?;
     // #![feature(thread_id_value)]
     use rayon::prelude::*;
     std::env::set_var("RAYON_NUM_THREADS", "4");
@@ -680,12 +628,10 @@ async fn main() -> Result<()> {
     // println!("{} {} {}", close_dif, low_dif, high_dif);
     // panic!();
     let args = Args::parse();
-    unsafe {
-        config::load(&args.config)?;
-    }
+    config::load(&args.config)?;
     match args.sub_command {
         SubCommand::Fetch(args) => {
-            select_exchange(&args.args.exchange);
+            select_exchange(&args.args.exchange)?;
             match args.fetch_command {
                 FetchCommand::Candles(candles_args) => {
                     fetch_candles(args.args, candles_args).await?;
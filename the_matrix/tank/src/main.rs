@@ -106,9 +106,7 @@ fn main2() -> Result<()> {
     //        .expect("set up the subscriber");
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(set_tokio_handle());
-    unsafe {
-        config::load("config.yaml")?;
-    }
+    config::load("config.yaml")?;
     let mut app = App::empty();
     app.insert_resource(LogSettings {
         filter: "wgpu=trace".to_string(),
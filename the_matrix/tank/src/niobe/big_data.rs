@@ -25,8 +25,8 @@ use crate::imgui_plugin::ImguiPlugin;
 use crate::niobe::{FlexNode, FlexStyle};
 use crate::render::pipelines::d2::line::LinePlugin;
 use crate::render::pipelines::d2::parallel_coordinates::{
-    FilterParallelCoordinates, ParallelCoordinates, ParallelCoordinatesConfig,
-    ParallelCoordinatesLabel, ParallelCoordinatesPlugin,
+    ParallelCoordinates, ParallelCoordinatesConfig, ParallelCoordinatesLabel,
+    ParallelCoordinatesPlugin, ParallelCoordinatesRows,
 };
 use crate::render::{
     upload_mesh, Buffers, ChildRenderTargetBundle, Material, RenderTarget, RenderTargetBundle,
@@ -151,17 +151,16 @@ fn start_load(
         info!("spawn load");
         let config: &Carc<AsyncRwLock<OutputPlotConfig>> = config;
         let _guard = config.write().block();
-        let watch = AsyncPipeline::spawn(id, &mut commands);
+        AsyncPipeline::spawn(id, &mut commands);
         //        guard.load_sender = Some(txc);
         //        guard.load_receiver = Some(rxc);
         let config = config.clone();
         let context = config.clone();
 
         commands.entity(id).insert(
-            load_output(id, config, watch, portal.clone(), arena.clone())
-                .spawn_handled_with_context(async move {
-                    format!("{:?}", context.read().await.output_source_kind)
-                }),
+            load_output(id, config, portal.clone(), arena.clone()).spawn_handled_with_context(
+                async move { format!("{:?}", context.read().await.output_source_kind) },
+            ),
         );
     }
 }
@@ -221,48 +220,15 @@ async fn find_max_bounds(
     Ok(max_bounds)
 }
 
+/// Reads the next block, all blocks are read because brushes are applied by the filter of the plot.
 async fn read(
     id: &Entity,
-    config: &Carc<AsyncRwLock<OutputPlotConfig>>,
     reader: &mut OutputReader<Pin<Box<dyn AsyncReadSeek>>>,
     arena: &Arena<Vec<u8>>,
-    min_variables: &mut Variables,
-    max_variables: &mut Variables,
     portal: &PipelinedPortal<OutputLoaded>,
 ) -> Result<(), OutputReadError> {
     let mut dest = arena.alloc();
-    let mut header;
-    loop {
-        let mut skip = true;
-        header = reader.read_header().await?;
-        let config = config.read().await;
-        min_variables.set_combination(header.start_combination);
-        max_variables.set_combination(header.end_combination_inclusive);
-        for i in 0..min_variables.len() {
-            if config.bounds[i].contains(&min_variables[i].value)
-                || config.bounds[i].contains(&max_variables[i].value)
-            {
-                skip = false;
-                break;
-            }
-        }
-        if skip {
-            for i in min_variables.len()..config.bounds.len() {
-                if config.bounds[i].contains(&header.ranges[i].start)
-                    || config.bounds[i].contains(&header.ranges[i].end)
-                {
-                    skip = false;
-                    break;
-                }
-            }
-        }
-        drop(config);
-        if skip {
-            reader.skip(&header).await?;
-        } else {
-            break;
-        }
-    }
+    let header = reader.read_header().await?;
     reader.read_block(&header, &mut *dest).await?;
     portal.send(OutputLoaded { data: dest.into() }, *id).await;
     Ok(())
@@ -271,7 +237,6 @@ async fn read(
 async fn load_output(
     id: Entity,
     config: Carc<AsyncRwLock<OutputPlotConfig>>,
-    mut watch: AsyncPipelineWatch,
     portal: PipelinedPortal<OutputLoaded>,
     arena: Arena<Vec<u8>>,
 ) -> Result<()> {
@@ -302,29 +267,15 @@ async fn load_output(
         guard.max_bounds = Some(bounds);
     }
     info!("reading...");
+    // rows are kept by the plot, output is read once
     loop {
-        tokio::select! {
-            result = watch.on_flushing() => {
-                warn!("flushing");
-                ok_if_err!(result);
-                reader.seek_start().await?;
-                ok_if_err!(watch.on_flushed().await);
-                warn!("flushed");
-            }
-            result = read(&id, &config, &mut reader, &arena, &mut min_variables, &mut max_variables, &portal) => {
-                match result {
-                    Ok(_) => {},
-                    Err(OutputReadError::AllRead) => {
-                        ok_if_err!(watch.on_flushing().await);
-                        ok_if_err!(result);
-                        reader.seek_start().await?;
-                        ok_if_err!(watch.on_flushed().await);
-                    },
-                    Err(e) => return Err(e.into()),
-                };
-            }
+        match read(&id, &mut reader, &arena, &portal).await {
+            Ok(_) => {}
+            Err(OutputReadError::AllRead) => break,
+            Err(e) => return Err(e.into()),
         }
     }
+    info!("read");
     Ok(())
 }
 
@@ -347,7 +298,7 @@ fn decompress_output(
 
 fn deserialize_output(
     arena: Res<Arena<Vec<f32>>>,
-    writer: PipelinedWriter<FilterParallelCoordinates>,
+    writer: PipelinedWriter<ParallelCoordinatesRows>,
     reader: PipelinedReader<Decompressed>,
     query: Query<&Carc<AsyncRwLock<OutputPlotConfig>>>,
 ) {
@@ -360,6 +311,7 @@ fn deserialize_output(
         let n_elements = e.0.len() / element_size;
         let mut output = arena.alloc();
         output.reserve(n_elements * config.bounds.len());
+        let mut combinations = Vec::with_capacity(n_elements);
         drop(config);
         let mut reader = &**e.0;
         // consume number of items
@@ -374,6 +326,7 @@ fn deserialize_output(
             };
             // error is NOT here
             variables.set_combination(combination);
+            combinations.push(combination);
             for v in variables.variables() {
                 output.push(v.value);
                 values.push(v.value);
@@ -422,7 +375,13 @@ fn deserialize_output(
                 },
             }
         }
-        writer.send(FilterParallelCoordinates(output.into()), e.id);
+        writer.send(
+            ParallelCoordinatesRows {
+                combinations,
+                values: output.into(),
+            },
+            e.id,
+        );
     }
 }
 
@@ -5,6 +5,7 @@ use std::time::Instant;
 use bevy::ecs::change_detection::Mut;
 use bevy::prelude::*;
 use glyph_brush::{OwnedSection, OwnedText};
+use merovingian::parallel_coordinates::{Axis, ParallelCoordinatesFilter};
 use merovingian::structs::RangeInclusive;
 use mouse::mem::{Arena, Const};
 use mouse::minipre::PreprocessorContext;
@@ -53,7 +54,7 @@ impl Plug for ParallelCoordinatesPlugin {
     }
 
     fn load<'a>(app: &'a mut App) -> &mut App {
-        app.add_pipeline::<FilterParallelCoordinates>()
+        app.add_pipeline::<ParallelCoordinatesRows>()
             .add_pipeline::<DrawParallelCoordinates>()
             .added_system(
                 material_added::<ParallelCoordinates>
@@ -65,8 +66,6 @@ impl Plug for ParallelCoordinatesPlugin {
             )
             .added_system(parallel_coordinates_change_mesh)
             .post_system(parallel_coordinates_configure_texture)
-            // must happen after configure
-            .render_system(parallel_coordinates_clear)
             //            .pre_system(sync_render_target_size_a.label(SyncRenderTargetSizeLabel))
             //            .pre_system(sync_render_target_size_b.after(SyncRenderTargetSizeLabel))
             .add_system_set(
@@ -262,10 +261,15 @@ impl ParallelCoordinatesPlugin {
                 actual_len: n_props,
                 // synced
                 max_bounds: vec![],
-                bounds: vec![],
+                filter: ParallelCoordinatesFilter::new(
+                    scale_names
+                        .iter()
+                        .map(|x| Axis::new(x, RangeInclusive::new(0., 0.)))
+                        .collect(),
+                ),
                 scales,
-                orders: (0..n_props).into_iter().collect(),
                 plot_every_nth_item: 1000,
+                redraw: false,
             })
             .insert(GpuMesh::with_capacity(
                 4 * 1024 * 1024,
@@ -365,17 +369,14 @@ fn parallel_coordinates_update_groups(
 }
 
 fn parallel_coordinates_update_material(
-    mut writer: EventWriter<FlushPipeline>,
     mut query: Query<(
         &mut Material<ParallelCoordinates>,
-        &ParallelCoordinatesConfig,
-        Entity,
+        &mut ParallelCoordinatesConfig,
     )>,
     groups: Query<&Material<GroupMaterial>, Changed<Material<GroupMaterial>>>,
 ) {
     let mut updated = false;
-    for (mut material, config, id) in query.iter_mut() {
-        let config: &ParallelCoordinatesConfig = config;
+    for (mut material, mut config) in query.iter_mut() {
         for link in &config.scales {
             let group = ok_loop!(groups.get(link.group_id));
             // must not create an alias to not trigger change detection
@@ -385,7 +386,7 @@ fn parallel_coordinates_update_material(
             break;
         }
         if updated {
-            writer.send(FlushPipeline::new(id));
+            config.redraw = true;
         }
     }
 }
@@ -394,7 +395,6 @@ fn parallel_coordinates_update_material(
 struct ReorderLabel;
 
 fn parallel_coordinates_handle_reorder(
-    mut writer: EventWriter<FlushPipeline>,
     mut grabbed: Local<Option<(usize, Entity, Vec2)>>,
     flex: Flex,
     mut cursor_moved: EventReader<CursorMoved>,
@@ -459,9 +459,9 @@ fn parallel_coordinates_handle_reorder(
                 }
                 let old_group_id = config.scales[grabbed.0].group_id;
                 let new_group_id = config.scales[new_i].group_id;
-                let original_order = config.orders.iter().position(|x| *x == grabbed.0).unwrap();
-                let new_order = config.orders.iter().position(|x| *x == new_i).unwrap();
-                config.orders.swap(original_order, new_order);
+                let mut order = config.filter.order().to_vec();
+                order.swap(grabbed.0, new_i);
+                some_loop!(config.filter.reorder(order).log());
                 let material: &mut Material<ParallelCoordinates> = &mut *material;
                 debug!("swap {} {}", grabbed.0, new_i);
                 material.data.scales.swap(new_i, grabbed.0);
@@ -509,8 +509,8 @@ fn parallel_coordinates_handle_reorder(
 
                 //                style.0.position_type = PositionType::Relative;
                 //                style.0.position = Default::default();
-                writer.send(FlushPipeline::new(grabbed.1));
-                dbg!(&config.orders, material);
+                config.redraw = true;
+                dbg!(config.filter.order(), material);
             }
         }
     }
@@ -596,9 +596,17 @@ struct ParallelCoordinatesClearColor(Operations<wgpu::Color>);
 #[derive(SystemLabel, Clone, Hash, Eq, PartialEq, Debug)]
 pub struct ParallelCoordinatesLabel;
 
-pub struct FilterParallelCoordinates(pub Const<Vec<f32>>);
-// struct ReorderParallelCoordinates(pub Const<Vec<f32>>);
-struct DrawParallelCoordinates(pub Const<Vec<f32>>);
+/// Rows of an output, each combination has a value for each axis.
+pub struct ParallelCoordinatesRows {
+    pub combinations: Vec<u64>,
+    pub values: Const<Vec<f32>>,
+}
+
+struct DrawParallelCoordinates {
+    data: Const<Vec<f32>>,
+    /// Texture is cleared before drawing.
+    clear: bool,
+}
 
 #[derive(Debug)]
 pub struct ParallelCoordinates {
@@ -644,15 +652,30 @@ fn required_len(actual_len: usize) -> usize {
 pub struct ParallelCoordinatesConfig {
     actual_len: usize,
     max_bounds: Vec<RangeInclusive<f32>>,
-    bounds: Vec<RangeInclusive<f32>>,
+    /// Rows, brushes and order of axes, scales are in the order axes are drawn.
+    filter: ParallelCoordinatesFilter,
     scales: Vec<ScaleLink>,
-    orders: Vec<usize>,
     plot_every_nth_item: usize,
+    /// Texture is cleared and selected rows are drawn again.
+    redraw: bool,
 }
 
 impl ParallelCoordinatesConfig {
     pub fn set_plot_every_nth_item(&mut self, nth_item: usize) {
         self.plot_every_nth_item = nth_item;
+        self.redraw = true;
+    }
+
+    pub fn filter(&self) -> &ParallelCoordinatesFilter {
+        &self.filter
+    }
+
+    /// Appends values of `row` in the order axes are drawn, padded to the length the shader
+    /// expects.
+    fn push_polyline(&self, row: usize, output: &mut Vec<f32>) {
+        let order = self.filter.order();
+        output.extend(order.iter().map(|axis| self.filter.value(row, *axis)));
+        output.extend((self.actual_len..required_len(self.actual_len)).map(|_| 0.));
     }
 
     pub fn max_bounds(&self) -> &[RangeInclusive<f32>] {
@@ -684,8 +707,7 @@ impl ParallelCoordinatesConfig {
                 .push(RangeInclusive::new(f32::MIN, f32::MAX));
             material.data.scales.push(1.);
         }
-        self.bounds.clear();
-        self.bounds.extend_from_slice(&self.max_bounds);
+        self.redraw = true;
     }
 }
 
@@ -720,8 +742,7 @@ fn parallel_coordinates_filter_handle_input(
     flex: Flex,
     mut cursor_moved: EventReader<CursorMoved>,
     views: Query<(Entity, &Children), With<View>>,
-    mut configs: Query<(&mut ParallelCoordinatesConfig, &mut Pipeline)>,
-    mut flusher: PipelineFlusher,
+    mut configs: Query<&mut ParallelCoordinatesConfig>,
     mut writer: UniqueEventWriter<RedrawRenderTarget>,
     mouse_state: Res<MouseState>,
     render_targets: Query<(
@@ -751,8 +772,8 @@ fn parallel_coordinates_filter_handle_input(
         }
         for group_id in children.iter() {
             for config_id in ok_loop!(children_query.get(*group_id)).iter() {
-                let (config, mut pipeline) = ok_loop!(configs.get_mut(*config_id));
-                let mut config: Mut<ParallelCoordinatesConfig> = config;
+                let mut config: Mut<ParallelCoordinatesConfig> =
+                    ok_loop!(configs.get_mut(*config_id));
                 for scale_id in 0..config.scales.len() {
                     let scale = &config.scales[scale_id];
                     let layout = flex.layout(scale.scale_view_id).unwrap();
@@ -767,17 +788,20 @@ fn parallel_coordinates_filter_handle_input(
                         error!("clicked {}", world.y);
                         mesh.data[0].y = world.y;
                         mesh.data[1].y = world.y;
-                        config.bounds[scale_id].start = world.y;
                     }
                     error!("expanding {}", world.y);
                     mesh.data[2].y = world.y;
                     mesh.data[3].y = world.y;
                     mesh.data[4] = mesh.data[0];
-                    config.bounds[scale_id].end = world.y;
-                    dbg!(&config.bounds);
+                    let start = mesh.data[0].y;
+                    let axis = config.filter.order()[scale_id];
+                    config.filter.set_brush(
+                        axis,
+                        Some(RangeInclusive::new(start.min(world.y), start.max(world.y))),
+                    );
+                    config.redraw = true;
 
                     writer.send(RedrawRenderTarget::new(id));
-                    flusher.flush(&mut *pipeline, *config_id);
                     break;
                 }
             }
@@ -785,102 +809,80 @@ fn parallel_coordinates_filter_handle_input(
     }
 }
 
+/// Adds rows to filters and draws the selected ones, every `plot_every_nth_item` selected row is
+/// drawn. Filters are redrawn after a brush, order or scale has changed.
 fn parallel_coordinates_filter(
     arena: Res<Arena<Vec<f32>>>,
     writer: PipelinedWriter<DrawParallelCoordinates>,
-    reader: PipelinedReader<FilterParallelCoordinates>,
-    mut query: Query<(&ParallelCoordinatesConfig, &mut Cursor)>,
+    reader: PipelinedReader<ParallelCoordinatesRows>,
+    mut query: Query<(Entity, &mut ParallelCoordinatesConfig, &mut Cursor)>,
 ) {
-    for e in reader.iter() {
-        let e: &Pipelined<FilterParallelCoordinates> = e;
-        let (config, mut cursor) = ok_loop!(query.get_mut(e.id));
+    for (id, mut config, mut cursor) in query.iter_mut() {
+        if !config.redraw {
+            continue;
+        }
+        config.redraw = false;
+        let config: &ParallelCoordinatesConfig = &config;
         let mut output = arena.alloc();
-        let additional = config.bounds.len() - config.actual_len;
-        'element: for element_start in (0..e.0.len()).step_by(config.actual_len) {
-            // data is aligned to bounds.len()
-            for i in 0..config.actual_len {
-                if !config.bounds[i].contains(&e.0[element_start + i]) {
-                    continue 'element;
-                }
-            }
+        *cursor = Cursor::default();
+        for row in config.filter.selection().ones() {
             cursor.0 = cursor.0.wrapping_add(1);
-            if cursor.0 % config.plot_every_nth_item != 0 {
-                continue;
-            }
-            let output_start = output.len();
-            output.reserve(config.bounds.len());
-            unsafe {
-                output.set_len(output_start + config.actual_len);
+            if cursor.0 % config.plot_every_nth_item == 0 {
+                config.push_polyline(row, &mut output);
             }
-            for i in 0..config.actual_len {
-                output[output_start + config.orders[i]] = e.0[element_start + i];
-            }
-            //            dbg!(cursor.0);
-            //            output.extend_from_slice(&e.0[element_start..element_start +
-            // config.actual_len]);
-            output.extend((0..additional).into_iter().map(|_| 0.));
         }
-        dbg!(&config.orders);
-        writer.send(DrawParallelCoordinates(output.into()), e.id);
+        writer.send(
+            DrawParallelCoordinates {
+                data: output.into(),
+                clear: true,
+            },
+            id,
+        );
     }
-}
-
-fn parallel_coordinates_clear(
-    device: Res<Device>,
-    queue: Res<Queue>,
-    mut writer: UniqueEventWriter<RedrawRenderTarget>,
-    mut reader: EventReader<FlushPipeline>,
-    mut query: Query<(
-        &mut Cursor,
-        &ParallelCoordinatesClearColor,
-        &RenderTexture,
-        &ParentRenderTarget,
-    )>,
-) {
-    let mut command_buffers = Vec::new();
     for e in reader.iter() {
-        let (mut cursor, color, texture, parent_rt) = ok_loop!(query.get_mut(e.id()));
-        *cursor = Cursor::default();
-        writer.send(RedrawRenderTarget { id: parent_rt.0 });
-
-        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
-            label: Some("clear pc"),
-        });
-        let pass = encoder.begin_render_pass(&RenderPassDescriptor {
-            label: None,
-            color_attachments: &[RenderPassColorAttachment {
-                view: &texture.view,
-                resolve_target: None,
-                ops: color.0,
-            }],
-            depth_stencil_attachment: None,
-        });
-        drop(pass);
-        command_buffers.push(encoder.finish());
+        let e: &Pipelined<ParallelCoordinatesRows> = e;
+        let (_, mut config, mut cursor) = ok_loop!(query.get_mut(e.id));
+        let config: &mut ParallelCoordinatesConfig = &mut config;
+        let mut output = arena.alloc();
+        let rows = e
+            .combinations
+            .iter()
+            .zip(e.values.chunks(config.actual_len));
+        for (combination, values) in rows {
+            some_loop!(config.filter.push(*combination, values).log());
+            let row = config.filter.n_rows() - 1;
+            if !config.filter.selection().contains(row) {
+                continue;
+            }
+            cursor.0 = cursor.0.wrapping_add(1);
+            if cursor.0 % config.plot_every_nth_item == 0 {
+                config.push_polyline(row, &mut output);
+            }
+        }
+        writer.send(
+            DrawParallelCoordinates {
+                data: output.into(),
+                clear: false,
+            },
+            e.id,
+        );
     }
-    queue.submit(command_buffers);
 }
 
 fn parallel_coordinates_configure_texture(
-    mut flusher: PipelineFlusher,
     mut reader: EventReader<ResizeRenderTarget>,
     format: Res<PreferredSurfaceFormat>,
-    mut configs: Query<
-        (
-            &mut RenderTexture,
-            &ParentRenderTarget,
-            &mut Pipeline,
-            Entity,
-        ),
-        With<ParallelCoordinatesConfig>,
-    >,
+    mut configs: Query<(
+        &mut RenderTexture,
+        &ParentRenderTarget,
+        &mut ParallelCoordinatesConfig,
+    )>,
     device: Res<Device>,
     _commands: Commands,
 ) {
     for e in reader.iter() {
-        let (mut texture, _, mut pipeline, id) =
-            some_loop!(configs.iter_mut().find(|x| x.1 .0 == e.id));
-        flusher.flush(&mut *pipeline, id);
+        let (mut texture, _, mut config) = some_loop!(configs.iter_mut().find(|x| x.1 .0 == e.id));
+        config.redraw = true;
         *texture = RenderTexture::new(e.size.x, e.size.y, format.0, &device);
     }
 }
@@ -909,7 +911,7 @@ fn parallel_coordinates_draw(
         &Material<ParallelCoordinates>,
         &GpuMesh,
         &RenderTexture,
-        &mut ParallelCoordinatesClearColor,
+        &ParallelCoordinatesClearColor,
     )>,
     mut writer: UniqueEventWriter<RedrawRenderTarget>,
 ) {
@@ -924,6 +926,7 @@ fn parallel_coordinates_draw(
         inner_pos: usize,
         stride: usize,
         data: Vec<Const<Vec<f32>>>,
+        clear: bool,
     }
 
     impl Job {
@@ -980,11 +983,17 @@ fn parallel_coordinates_draw(
                         event_pos: 0,
                         inner_pos: 0,
                         stride: required_len(config.actual_len) * f32::size(),
-                        data: vec![e.0.clone()],
+                        data: vec![e.data.clone()],
+                        clear: e.clear,
                     },
                 );
             }
-            Some(v) => v.data.push(e.0.clone()),
+            // everything before is drawn again
+            Some(v) if e.clear => {
+                v.data = vec![e.data.clone()];
+                v.clear = true;
+            }
+            Some(v) => v.data.push(e.data.clone()),
         }
     }
     while !map.is_empty() {
@@ -993,25 +1002,35 @@ fn parallel_coordinates_draw(
             let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
                 label: Some("output plot"),
             });
-            let (config, _, material, mesh, texture, _color) = query.get_mut(*k).unwrap();
+            let (config, _, material, mesh, texture, color) = query.get_mut(*k).unwrap();
             let written = v.write(&buffers.get(mesh.buffer_id), &queue);
-            if written == 0 {
+            if written == 0 && !v.clear {
                 return false;
             }
+            let ops = match std::mem::take(&mut v.clear) {
+                true => color.0,
+                false => Operations {
+                    load: LoadOp::Load,
+                    store: true,
+                },
+            };
             let n_vertices = written / f32::size();
-            let _n_instances = written / (config.bounds.len() * f32::size());
+            let _n_instances = written / (required_len(config.actual_len) * f32::size());
             let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
                 label: None,
                 color_attachments: &[RenderPassColorAttachment {
                     view: &texture.view,
                     resolve_target: None,
-                    ops: Operations {
-                        load: LoadOp::Load,
-                        store: true,
-                    },
+                    ops,
                 }],
                 depth_stencil_attachment: None,
             });
+            if written == 0 {
+                // nothing is selected, texture is only cleared
+                drop(pass);
+                command_buffers.push(encoder.finish());
+                return false;
+            }
             pass.set_pipeline(&pipelines.pipelines[&ParallelCoordinatesPipeline::id()]);
 
             pass.set_vertex_buffer(
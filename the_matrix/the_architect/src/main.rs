@@ -63,9 +63,7 @@ struct Settings {
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    unsafe {
-        config::load(&args.log_config_path)?;
-    }
+    config::load(&args.log_config_path)?;
     match args.command {
         Command::Bump { package, kind } => {
             bump(package, kind)?;